@@ -0,0 +1,99 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// The number of keys a persona re-checks after churn before letting other events through.
+pub const CHURN_SLICE_SIZE: usize = 200;
+
+/// Keys waiting to be re-checked after churn, in the order they were queued.  Each key is held at
+/// most once: queuing one which is already waiting just replaces its tag (e.g. the ID of the churn
+/// event), so overlapping churn events don't repeat the work.
+pub struct ChurnQueue<Key, Tag> {
+    order: VecDeque<Key>,
+    pending: HashMap<Key, Tag>,
+}
+
+impl<Key: Hash + Eq + Clone, Tag> ChurnQueue<Key, Tag> {
+    /// Constructor.
+    pub fn new() -> ChurnQueue<Key, Tag> {
+        ChurnQueue {
+            order: VecDeque::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Queues `key` unless it's already waiting, and sets its tag.
+    pub fn push(&mut self, key: Key, tag: Tag) {
+        if self.pending.insert(key.clone(), tag).is_none() {
+            self.order.push_back(key);
+        }
+    }
+
+    /// Takes the key which has been waiting longest.
+    pub fn pop(&mut self) -> Option<(Key, Tag)> {
+        let key = match self.order.pop_front() {
+            Some(key) => key,
+            None => return None,
+        };
+        self.pending.remove(&key).map(|tag| (key, tag))
+    }
+
+    /// Returns the number of keys waiting.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns whether there are no keys waiting.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+impl<Key: Hash + Eq + Clone, Tag> Default for ChurnQueue<Key, Tag> {
+    fn default() -> ChurnQueue<Key, Tag> {
+        ChurnQueue::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn coalesce() {
+        let mut churn_queue = ChurnQueue::new();
+        for key in 0..5 {
+            churn_queue.push(key, "first");
+        }
+        assert_eq!(churn_queue.pop(), Some((0, "first")));
+
+        // Keys still waiting keep their place but take the latest tag.
+        for key in 0..3 {
+            churn_queue.push(key, "second");
+        }
+        assert_eq!(churn_queue.len(), 5);
+
+        let popped = (0..5).filter_map(|_| churn_queue.pop()).collect::<Vec<_>>();
+        assert_eq!(popped,
+                   vec![(1, "second"), (2, "second"), (3, "first"), (4, "first"), (0, "second")]);
+        assert!(churn_queue.is_empty());
+        assert!(churn_queue.pop().is_none());
+    }
+}
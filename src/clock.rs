@@ -0,0 +1,97 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::sync::{Arc, Mutex};
+use time::{self, SteadyTime, Timespec};
+#[cfg(test)]
+use time::Duration;
+
+/// Source of the current time for anything which expires entries.  Clones share the same time, so
+/// a manual clock can be handed to a persona and then advanced from a test.
+#[derive(Clone)]
+pub struct Clock {
+    // `None` for the system clock, otherwise the steady and wall-clock times at which the manual
+    // clock is stopped.
+    manual_time: Option<Arc<Mutex<(SteadyTime, Timespec)>>>,
+}
+
+impl Clock {
+    /// Returns a clock which follows `SteadyTime::now()`.
+    pub fn system() -> Clock {
+        Clock { manual_time: None }
+    }
+
+    /// Returns a clock which stands still until `advance` is called.  Its wall-clock time starts at
+    /// the Unix epoch, so that periods counted from there start along with it.
+    #[cfg(test)]
+    pub fn manual() -> Clock {
+        Clock { manual_time: Some(Arc::new(Mutex::new((SteadyTime::now(), Timespec::new(0, 0))))) }
+    }
+
+    /// Returns the current time according to this clock.
+    pub fn now(&self) -> SteadyTime {
+        match self.manual_time {
+            Some(ref manual_time) => unwrap_result!(manual_time.lock()).0,
+            None => SteadyTime::now(),
+        }
+    }
+
+    /// Returns the current wall-clock time according to this clock, for periods which different
+    /// nodes need to agree on.
+    pub fn wall_time(&self) -> Timespec {
+        match self.manual_time {
+            Some(ref manual_time) => unwrap_result!(manual_time.lock()).1,
+            None => time::get_time(),
+        }
+    }
+
+    /// Moves a manual clock forwards.  Has no effect on the system clock.
+    #[cfg(test)]
+    pub fn advance(&self, duration: Duration) {
+        if let Some(ref manual_time) = self.manual_time {
+            let mut manual_time = unwrap_result!(manual_time.lock());
+            manual_time.0 = manual_time.0 + duration;
+            manual_time.1 = manual_time.1 + duration;
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock::system()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::Duration;
+
+    #[test]
+    fn manual_clock() {
+        let clock = Clock::manual();
+        let clone = clock.clone();
+        let start = clock.now();
+        assert_eq!(start, clock.now());
+
+        let wall_start = clock.wall_time();
+        clone.advance(Duration::minutes(5));
+        assert_eq!(start + Duration::minutes(5), clock.now());
+        assert_eq!(clock.now(), clone.now());
+        assert_eq!(wall_start + Duration::minutes(5), clock.wall_time());
+    }
+}
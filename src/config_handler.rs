@@ -27,6 +27,54 @@ pub struct Config {
     pub wallet_address: Option<XorName>,
     /// Upper limit for allowed network storage on this vault.
     pub max_capacity: Option<u64>, // measured by Bytes
+    /// Memory available for holding chunks while they're being stored on the network.
+    pub ongoing_put_memory_budget: Option<u64>, // measured by Bytes
+    /// Disk space available for holding chunks which don't fit in the memory budget.
+    pub ongoing_put_disk_budget: Option<u64>, // measured by Bytes
+    /// Memory available for keeping recently retrieved chunks to serve further Gets.
+    pub get_cache_memory_budget: Option<u64>, // measured by Bytes
+    /// The longest to wait for a chunk's holder to respond before asking another.  Holders known
+    /// to respond quickly are waited for less.  Zero asks all holders at once.
+    pub get_hedge_delay_ms: Option<u64>,
+    /// How often each PmidNode is challenged to prove it still holds a chunk.  Zero disables
+    /// challenges.
+    pub storage_challenge_interval_secs: Option<u64>,
+    /// Number of fragments "Normal" chunks are split into, any this many of which are enough to
+    /// rebuild the chunk.  Unset or zero stores whole copies instead.
+    pub erasure_data_fragments: Option<usize>,
+    /// Number of extra fragments stored for erasure-coded chunks, i.e. how many can be lost.
+    pub erasure_parity_fragments: Option<usize>,
+    /// Number of threads manager requests are spread across, each handling its own share of names.
+    /// Requests for chunks held by the vault are always handled on a further thread of their own.
+    pub shard_count: Option<usize>,
+    /// Number of threads the chunks held by the vault are read and written on, each handling its
+    /// own share of chunk names.
+    pub chunk_io_thread_count: Option<usize>,
+    /// Most churn and refresh events each shard queues before holding back further events.
+    pub churn_queue_limit: Option<usize>,
+    /// Most node-to-node messages each shard queues before holding back further events.
+    pub node_queue_limit: Option<usize>,
+    /// Most client requests each shard queues.  Any more are refused as the vault being busy.
+    pub client_queue_limit: Option<usize>,
+    /// Puts per second each client is allowed on average.
+    pub client_puts_per_second: Option<u64>,
+    /// Most puts a client can make in a burst, after not putting for a while.
+    pub client_put_burst: Option<u64>,
+    /// Posts per second each client is allowed on average, to each piece of data.
+    pub client_posts_per_second: Option<u64>,
+    /// Most posts a client can send to a piece of data in a burst.
+    pub client_post_burst: Option<u64>,
+    /// Deletes per second each client is allowed on average, to each piece of data.
+    pub client_deletes_per_second: Option<u64>,
+    /// Most deletes a client can send to a piece of data in a burst.
+    pub client_delete_burst: Option<u64>,
+    /// Gets per second each client is allowed on average, of each piece of data.
+    pub client_gets_per_second: Option<u64>,
+    /// Most gets of a piece of data a client can send in a burst.
+    pub client_get_burst: Option<u64>,
+    /// Whether clients' storage is limited by the bytes they store rather than by their number of
+    /// chunks.
+    pub charge_clients_by_bytes: Option<bool>,
 }
 
 impl Default for Config {
@@ -34,6 +82,27 @@ impl Default for Config {
         Config {
             wallet_address: None,
             max_capacity: None,
+            ongoing_put_memory_budget: None,
+            ongoing_put_disk_budget: None,
+            get_cache_memory_budget: None,
+            get_hedge_delay_ms: None,
+            storage_challenge_interval_secs: None,
+            erasure_data_fragments: None,
+            erasure_parity_fragments: None,
+            shard_count: None,
+            chunk_io_thread_count: None,
+            churn_queue_limit: None,
+            node_queue_limit: None,
+            client_queue_limit: None,
+            client_puts_per_second: None,
+            client_put_burst: None,
+            client_posts_per_second: None,
+            client_post_burst: None,
+            client_deletes_per_second: None,
+            client_delete_burst: None,
+            client_gets_per_second: None,
+            client_get_burst: None,
+            charge_clients_by_bytes: None,
         }
     }
 }
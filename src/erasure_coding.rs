@@ -0,0 +1,285 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Reed-Solomon erasure coding over GF(2^8).  Data is split into `data` fragments, followed by
+//! `parity` fragments from a Cauchy matrix, so that any `data` of them are enough to rebuild it.
+
+use std::cmp;
+use std::collections::BTreeMap;
+
+use error::InternalError;
+
+// Each fragment's row of the coding matrix is identified by a distinct field element.
+const MAX_FRAGMENTS: usize = 256;
+
+/// The number of fragments data is split into, and the number of parity fragments added.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, RustcEncodable, RustcDecodable)]
+pub struct FragmentCounts {
+    /// Fragments holding the data itself, any this many of the total being enough to rebuild it.
+    pub data: usize,
+    /// Extra fragments, i.e. how many can be lost.
+    pub parity: usize,
+}
+
+impl FragmentCounts {
+    /// Returns `None` unless there is at least one data fragment, and at most 256 in total.
+    pub fn new(data: usize, parity: usize) -> Option<FragmentCounts> {
+        if data == 0 || data + parity > MAX_FRAGMENTS {
+            return None;
+        }
+        Some(FragmentCounts {
+            data: data,
+            parity: parity,
+        })
+    }
+
+    /// The total number of fragments.
+    pub fn total(&self) -> usize {
+        self.data + self.parity
+    }
+
+    fn fragment_size(&self, data_size: usize) -> usize {
+        (data_size + self.data - 1) / self.data
+    }
+}
+
+/// Splits `data` into `counts.total()` equally sized fragments, padding the last data fragment
+/// with zeros.
+pub fn encode(data: &[u8], counts: FragmentCounts) -> Vec<Vec<u8>> {
+    let field = Field::new();
+    let fragment_size = counts.fragment_size(data.len());
+    let mut fragments = (0..counts.data)
+                            .map(|index| {
+                                let mut fragment = data.iter()
+                                                       .skip(index * fragment_size)
+                                                       .take(fragment_size)
+                                                       .cloned()
+                                                       .collect::<Vec<_>>();
+                                fragment.resize(fragment_size, 0);
+                                fragment
+                            })
+                            .collect::<Vec<_>>();
+    for index in counts.data..counts.total() {
+        let mut parity = vec![0; fragment_size];
+        let row = field.generator_row(counts, index);
+        for (coefficient, fragment) in row.into_iter().zip(&fragments) {
+            field.add_multiple(&mut parity, coefficient, fragment);
+        }
+        fragments.push(parity);
+    }
+    fragments
+}
+
+/// Rebuilds `size` bytes of data from at least `counts.data` of its fragments, keyed by index.
+pub fn decode(fragments: &BTreeMap<usize, Vec<u8>>,
+              counts: FragmentCounts,
+              size: usize)
+              -> Result<Vec<u8>, InternalError> {
+    let fragment_size = counts.fragment_size(size);
+    let chosen = fragments.iter()
+                          .filter(|&(index, _)| *index < counts.total())
+                          .take(counts.data)
+                          .collect::<Vec<_>>();
+    if chosen.len() < counts.data ||
+       chosen.iter().any(|&(_, fragment)| fragment.len() != fragment_size) {
+        return Err(InternalError::InvalidFragments);
+    }
+
+    let field = Field::new();
+    let matrix = chosen.iter()
+                       .map(|&(index, _)| field.generator_row(counts, *index))
+                       .collect();
+    let inverse = if let Some(inverse) = field.invert(matrix) {
+        inverse
+    } else {
+        return Err(InternalError::InvalidFragments);
+    };
+    let mut data = Vec::with_capacity(counts.data * fragment_size);
+    for row in inverse {
+        let mut fragment = vec![0; fragment_size];
+        for (coefficient, &(_, source)) in row.into_iter().zip(&chosen) {
+            field.add_multiple(&mut fragment, coefficient, source);
+        }
+        data.extend(fragment);
+    }
+    data.truncate(size);
+    Ok(data)
+}
+
+// Arithmetic in GF(2^8) with the polynomial x^8 + x^4 + x^3 + x^2 + 1, via log tables.
+struct Field {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+#[cfg_attr(feature="clippy", allow(indexing_slicing))]
+impl Field {
+    fn new() -> Field {
+        let mut exp = [0; 512];
+        let mut log = [0; 256];
+        let mut value = 1u16;
+        for power in 0..255 {
+            exp[power] = value as u8;
+            log[value as usize] = power as u8;
+            value <<= 1;
+            if value & 0x100 != 0 {
+                value ^= 0x11d;
+            }
+        }
+        for power in 255..512 {
+            exp[power] = exp[power - 255];
+        }
+        Field {
+            exp: exp,
+            log: log,
+        }
+    }
+
+    fn mul(&self, lhs: u8, rhs: u8) -> u8 {
+        if lhs == 0 || rhs == 0 {
+            return 0;
+        }
+        self.exp[self.log[lhs as usize] as usize + self.log[rhs as usize] as usize]
+    }
+
+    // `value` must be non-zero.
+    fn inv(&self, value: u8) -> u8 {
+        self.exp[255 - self.log[value as usize] as usize]
+    }
+
+    // Adds `coefficient` times `source` to `target`, element by element.
+    fn add_multiple(&self, target: &mut [u8], coefficient: u8, source: &[u8]) {
+        for (byte, &value) in target.iter_mut().zip(source) {
+            *byte ^= self.mul(coefficient, value);
+        }
+    }
+
+    // The row of the coding matrix producing fragment `index`: the identity for data fragments,
+    // then a Cauchy matrix for parity ones, so that any `counts.data` rows are independent.
+    fn generator_row(&self, counts: FragmentCounts, index: usize) -> Vec<u8> {
+        (0..counts.data)
+            .map(|column| {
+                if index < counts.data {
+                    if column == index {
+                        1
+                    } else {
+                        0
+                    }
+                } else {
+                    self.inv((index ^ column) as u8)
+                }
+            })
+            .collect()
+    }
+
+    // Gauss-Jordan elimination, returning `None` if the matrix is singular.
+    fn invert(&self, mut matrix: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+        let size = matrix.len();
+        let mut inverse = (0..size)
+                              .map(|row| {
+                                  (0..size)
+                                      .map(|column| {
+                                          if row == column {
+                                              1
+                                          } else {
+                                              0
+                                          }
+                                      })
+                                      .collect::<Vec<u8>>()
+                              })
+                              .collect::<Vec<_>>();
+        for column in 0..size {
+            let pivot = match (column..size).find(|&row| matrix[row][column] != 0) {
+                Some(pivot) => pivot,
+                None => return None,
+            };
+            matrix.swap(column, pivot);
+            inverse.swap(column, pivot);
+            let scale = self.inv(matrix[column][column]);
+            for value in matrix[column].iter_mut().chain(inverse[column].iter_mut()) {
+                *value = self.mul(*value, scale);
+            }
+            for row in 0..size {
+                let factor = matrix[row][column];
+                if row == column || factor == 0 {
+                    continue;
+                }
+                for index in 0..size {
+                    let matrix_term = self.mul(factor, matrix[column][index]);
+                    matrix[row][index] ^= matrix_term;
+                    let inverse_term = self.mul(factor, inverse[column][index]);
+                    inverse[row][index] ^= inverse_term;
+                }
+            }
+        }
+        Some(inverse)
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature="use-mock-crust"))]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use utils::generate_random_vec_u8;
+
+    fn indexed(fragments: Vec<Vec<u8>>) -> BTreeMap<usize, Vec<u8>> {
+        fragments.into_iter().enumerate().collect()
+    }
+
+    #[test]
+    fn any_data_count_fragments_rebuild() {
+        let counts = unwrap_option!(FragmentCounts::new(4, 2), "");
+        let data = generate_random_vec_u8(1001);
+        let fragments = encode(&data, counts);
+        assert_eq!(fragments.len(), 6);
+        assert!(fragments.iter().all(|fragment| fragment.len() == 251));
+
+        for first_lost in 0..counts.total() {
+            for second_lost in first_lost + 1..counts.total() {
+                let mut remaining = indexed(fragments.clone());
+                let _ = remaining.remove(&first_lost);
+                let _ = remaining.remove(&second_lost);
+                assert_eq!(unwrap_result!(decode(&remaining, counts, data.len())), data);
+            }
+        }
+    }
+
+    #[test]
+    fn too_few_fragments() {
+        let counts = unwrap_option!(FragmentCounts::new(3, 2), "");
+        let data = generate_random_vec_u8(100);
+        let mut fragments = indexed(encode(&data, counts));
+        for index in 0..3 {
+            let _ = fragments.remove(&index);
+        }
+        assert!(decode(&fragments, counts, data.len()).is_err());
+    }
+
+    #[test]
+    fn small_data() {
+        let counts = unwrap_option!(FragmentCounts::new(4, 1), "");
+        for size in 0..6 {
+            let data = generate_random_vec_u8(size);
+            let mut fragments = indexed(encode(&data, counts));
+            let _ = fragments.remove(&0);
+            assert_eq!(unwrap_result!(decode(&fragments, counts, size)), data);
+        }
+        assert!(FragmentCounts::new(0, 2).is_none());
+        assert!(FragmentCounts::new(200, 57).is_none());
+    }
+}
@@ -20,6 +20,7 @@ use chunk_store;
 use safe_network_common::messaging;
 use safe_network_common::client_errors::{MutationError, GetError};
 use maidsafe_utilities::serialisation::SerialisationError;
+use personas::Route;
 use routing::{Authority, InterfaceError, MessageId, RoutingError, RoutingMessage};
 use std::io;
 use types::Refresh;
@@ -29,12 +30,20 @@ pub enum InternalError {
     ChunkStore(chunk_store::Error),
     ClientGet(GetError),
     ClientMutation(MutationError),
+    DuplicateRoute(Route),
     FailedToFindCachedRequest(MessageId),
     FileHandler(config_file_handler::Error),
+    // Too few, or inconsistent, fragments to rebuild erasure-coded data.
+    InvalidFragments,
+    InvalidMessage,
     InvalidResponse,
     Io(io::Error),
     MpidMessaging(messaging::Error),
     NotInCloseGroup,
+    // A retransmitted request, whose first attempt failed with the given error.
+    PreviousFailure(String),
+    // A client request refused without being handled.
+    Refused(Refusal),
     Routing(InterfaceError),
     RoutingInternal(RoutingError),
     Serialisation(SerialisationError),
@@ -43,6 +52,53 @@ pub enum InternalError {
     UnknownRefreshType(Authority, Authority, Refresh),
 }
 
+/// A client request which the vault refused without handling it, and which the client may retry
+/// later.  The client error types have no variants for these, so they reach the client as
+/// `NetworkOther` carrying the refusal's description, which `Refusal::from_description` recognises.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    /// The vault had too many client requests queued already.
+    Busy,
+    /// The client has used up its allowance of this kind of request for now.
+    RateLimited,
+}
+
+impl Refusal {
+    /// The description sent to the client.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Refusal::Busy => "Vault busy",
+            Refusal::RateLimited => "Rate limit exceeded",
+        }
+    }
+
+    /// Recognises the description sent to the client for a refusal.
+    pub fn from_description(description: &str) -> Option<Refusal> {
+        [Refusal::Busy, Refusal::RateLimited]
+            .iter()
+            .find(|refusal| refusal.description() == description)
+            .cloned()
+    }
+}
+
+impl From<Refusal> for MutationError {
+    fn from(refusal: Refusal) -> MutationError {
+        MutationError::NetworkOther(refusal.description().to_owned())
+    }
+}
+
+impl From<Refusal> for GetError {
+    fn from(refusal: Refusal) -> GetError {
+        GetError::NetworkOther(refusal.description().to_owned())
+    }
+}
+
+impl From<Refusal> for InternalError {
+    fn from(refusal: Refusal) -> InternalError {
+        InternalError::Refused(refusal)
+    }
+}
+
 impl From<MutationError> for InternalError {
     fn from(error: MutationError) -> InternalError {
         InternalError::ClientMutation(error)
@@ -48,6 +48,8 @@
 #![cfg_attr(feature="clippy", deny(clippy, clippy_pedantic))]
 #![cfg_attr(feature="clippy", allow(use_debug))]
 
+#![cfg_attr(feature="bench", feature(test))]
+
 #[macro_use]
 extern crate log;
 #[macro_use]
@@ -64,14 +66,24 @@ extern crate routing;
 extern crate rustc_serialize;
 extern crate safe_network_common;
 extern crate sodiumoxide;
+#[cfg(all(test, feature = "bench"))]
+extern crate test;
 extern crate time;
 extern crate xor_name;
 
+mod churn_queue;
+mod clock;
 mod config_handler;
+mod erasure_coding;
 mod error;
 #[cfg(test)]
 mod mock_routing;
+mod neighbourhood;
 mod personas;
+#[cfg(not(feature = "use-mock-crust"))]
+mod priority_queue;
+#[cfg(all(not(test), not(feature = "use-mock-crust")))]
+mod shared_routing_node;
 mod timed_buffer;
 mod types;
 mod utils;
@@ -79,3 +91,4 @@ mod vault;
 
 pub use vault::Vault;
 pub use config_handler::Config;
+pub use error::Refusal;
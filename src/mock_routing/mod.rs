@@ -31,6 +31,7 @@ use sodiumoxide::crypto::sign::PublicKey;
 use std::sync::{Arc, Mutex, mpsc};
 use xor_name::XorName;
 
+#[derive(Clone)]
 pub struct MockRoutingNode {
     pimpl: Arc<Mutex<MockRoutingNodeImpl>>,
 }
@@ -42,6 +43,9 @@ impl MockRoutingNode {
         Ok(MockRoutingNode { pimpl: Arc::new(Mutex::new(MockRoutingNodeImpl::new(event_sender))) })
     }
 
+    // Nothing to shut down: the mock doesn't own a network connection.
+    pub fn stop(&self) {}
+
     pub fn get_client_receiver(&self) -> mpsc::Receiver<Event> {
         unwrap_result!(self.pimpl.lock()).get_client_receiver()
     }
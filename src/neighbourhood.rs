@@ -0,0 +1,332 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::cmp::{self, Ordering};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use vault::RoutingNode;
+use xor_name::XorName;
+
+// The number of nodes in a close group.  Must match routing's.
+const GROUP_SIZE: usize = 8;
+
+/// Returns the number of leading bits `lhs` and `rhs` have in common.
+pub fn common_prefix_len(lhs: &XorName, rhs: &XorName) -> usize {
+    for (index, (lhs_byte, rhs_byte)) in lhs.0.iter().zip(rhs.0.iter()).enumerate() {
+        let diff = lhs_byte ^ rhs_byte;
+        if diff != 0 {
+            return index * 8 + diff.leading_zeros() as usize;
+        }
+    }
+    lhs.0.len() * 8
+}
+
+/// Compares the distances of `lhs` and `rhs` from `target`.
+pub fn cmp_distance(target: &XorName, lhs: &XorName, rhs: &XorName) -> Ordering {
+    for ((target_byte, lhs_byte), rhs_byte) in target.0.iter().zip(lhs.0.iter()).zip(rhs.0.iter()) {
+        let ordering = (lhs_byte ^ target_byte).cmp(&(rhs_byte ^ target_byte));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// The names whose close group could have gained or lost a given node.
+///
+/// If our close group spans the names sharing `g` leading bits with us, then the close group of a
+/// name `k` we manage lies within twice the distance from `k` of the furthest of those, so the
+/// changed node `n` can only be in it if `k` and `n` share at least `min(cpl(k, us), g) - 1` bits.
+/// This is a cheap, conservative test: it never excludes a name whose close group did change.
+pub struct Neighbourhood {
+    our_name: XorName,
+    node_changed: XorName,
+    // The number of leading bits shared by our name and every member of our close group, or zero
+    // if nothing can be ruled out.
+    group_prefix_len: usize,
+}
+
+impl Neighbourhood {
+    /// Constructs the neighbourhood of `node_changed`, which has just been added to or removed
+    /// from `routing_node`'s routing table.
+    pub fn new(routing_node: &RoutingNode, node_changed: &XorName) -> Neighbourhood {
+        let mut neighbourhood = Neighbourhood {
+            our_name: *node_changed,
+            node_changed: *node_changed,
+            group_prefix_len: 0,
+        };
+        neighbourhood.our_name = match routing_node.name() {
+            Ok(name) => name,
+            Err(error) => {
+                error!("Failed to get our name: {:?}", error);
+                return neighbourhood;
+            }
+        };
+        match routing_node.close_group(neighbourhood.our_name) {
+            // While the network is smaller than a group, every node is in every close group.
+            Ok(Some(ref close_group)) if close_group.len() >= GROUP_SIZE => {
+                neighbourhood.group_prefix_len =
+                    close_group.iter()
+                               .map(|name| common_prefix_len(name, &neighbourhood.our_name))
+                               .min()
+                               .unwrap_or(0);
+            }
+            Ok(_) => (),
+            Err(error) => error!("Failed to get our close group: {:?}", error),
+        }
+        neighbourhood
+    }
+
+    /// Returns whether `name`'s close group could include the changed node.
+    pub fn contains(&self, name: &XorName) -> bool {
+        let required = cmp::min(common_prefix_len(name, &self.our_name),
+                                self.group_prefix_len);
+        common_prefix_len(name, &self.node_changed) + 1 >= required
+    }
+
+    // Names sharing more leading bits than this with our name can't be in the neighbourhood.
+    fn max_prefix_len(&self) -> usize {
+        let prefix_len = common_prefix_len(&self.node_changed, &self.our_name);
+        if self.group_prefix_len <= prefix_len + 1 {
+            usize::MAX
+        } else {
+            prefix_len + 1
+        }
+    }
+}
+
+/// A map keyed by `XorName`, which also groups its keys by how many leading bits they share with
+/// a reference name (normally our own).  This lets the keys in a `Neighbourhood` be found without
+/// visiting the rest.
+pub struct XorMap<Value> {
+    entries: HashMap<XorName, Value>,
+    buckets: BTreeMap<usize, HashSet<XorName>>,
+    reference: XorName,
+}
+
+impl<Value> XorMap<Value> {
+    /// Constructor.
+    pub fn new() -> XorMap<Value> {
+        XorMap {
+            entries: HashMap::new(),
+            buckets: BTreeMap::new(),
+            reference: XorName([0; 64]),
+        }
+    }
+
+    /// Inserts `value` under `name`, returning the value previously held there, if any.
+    pub fn insert(&mut self, name: XorName, value: Value) -> Option<Value> {
+        let old_value = self.entries.insert(name, value);
+        if old_value.is_none() {
+            let _ = self.buckets
+                        .entry(common_prefix_len(&name, &self.reference))
+                        .or_insert_with(HashSet::new)
+                        .insert(name);
+        }
+        old_value
+    }
+
+    /// Returns the value under `name`, inserting the result of `default` first if there is none.
+    pub fn get_or_insert_with<F: FnOnce() -> Value>(&mut self,
+                                                    name: XorName,
+                                                    default: F)
+                                                    -> &mut Value {
+        if !self.entries.contains_key(&name) {
+            let _ = self.insert(name, default());
+        }
+        unwrap_option!(self.entries.get_mut(&name), "Entry was just inserted.")
+    }
+
+    /// Removes and returns the value under `name`.
+    pub fn remove(&mut self, name: &XorName) -> Option<Value> {
+        let value = self.entries.remove(name);
+        if value.is_some() {
+            let bucket_index = common_prefix_len(name, &self.reference);
+            let now_empty = match self.buckets.get_mut(&bucket_index) {
+                Some(bucket) => {
+                    let _ = bucket.remove(name);
+                    bucket.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                let _ = self.buckets.remove(&bucket_index);
+            }
+        }
+        value
+    }
+
+    /// Returns the value under `name`.
+    pub fn get(&self, name: &XorName) -> Option<&Value> {
+        self.entries.get(name)
+    }
+
+    /// Returns the value under `name` mutably.
+    pub fn get_mut(&mut self, name: &XorName) -> Option<&mut Value> {
+        self.entries.get_mut(name)
+    }
+
+    /// Returns whether there is a value under `name`.
+    pub fn contains_key(&self, name: &XorName) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    /// Returns an iterator over the entries, in arbitrary order.
+    pub fn iter(&self) -> ::std::collections::hash_map::Iter<XorName, Value> {
+        self.entries.iter()
+    }
+
+    /// Returns an iterator over the names held, in arbitrary order.
+    #[cfg(all(test, not(feature = "use-mock-crust")))]
+    pub fn keys(&self) -> ::std::collections::hash_map::Keys<XorName, Value> {
+        self.entries.keys()
+    }
+
+    /// Returns the names held whose close group could include the changed node.
+    pub fn names_in(&mut self, neighbourhood: &Neighbourhood) -> Vec<XorName> {
+        self.set_reference(neighbourhood.our_name);
+        let max_prefix_len = neighbourhood.max_prefix_len();
+        self.buckets
+            .iter()
+            .take_while(|&(prefix_len, _)| *prefix_len <= max_prefix_len)
+            .flat_map(|(_, bucket)| bucket.iter())
+            .filter(|name| neighbourhood.contains(name))
+            .cloned()
+            .collect()
+    }
+
+    // Regroups the keys if the reference name has changed, e.g. after we've been relocated.
+    fn set_reference(&mut self, reference: XorName) {
+        if self.reference == reference {
+            return;
+        }
+        self.reference = reference;
+        self.buckets.clear();
+        for name in self.entries.keys() {
+            let _ = self.buckets
+                        .entry(common_prefix_len(name, &reference))
+                        .or_insert_with(HashSet::new)
+                        .insert(*name);
+        }
+    }
+}
+
+impl<Value> Default for XorMap<Value> {
+    fn default() -> XorMap<Value> {
+        XorMap::new()
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature="use-mock-crust"))]
+mod test {
+    use super::*;
+
+    use rand::random;
+    use std::cmp::Ordering;
+    use std::sync::mpsc;
+    use vault::RoutingNode;
+    use xor_name::XorName;
+
+    fn close_names(routing_node: &RoutingNode, count: usize) -> Vec<XorName> {
+        let mut names = Vec::with_capacity(count);
+        while names.len() < count {
+            let name = random::<XorName>();
+            if let Ok(Some(_)) = routing_node.close_group(name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    fn close_groups_including(routing_node: &RoutingNode,
+                              names: &[XorName],
+                              node: &XorName)
+                              -> Vec<XorName> {
+        names.iter()
+             .filter(|name| {
+                 match routing_node.close_group(**name) {
+                     Ok(Some(close_group)) => close_group.contains(node),
+                     _ => false,
+                 }
+             })
+             .cloned()
+             .collect()
+    }
+
+    #[test]
+    fn prefix_len() {
+        let name = random::<XorName>();
+        assert_eq!(common_prefix_len(&name, &name), 512);
+        let mut other = name;
+        other.0[0] ^= 0b1000_0000;
+        assert_eq!(common_prefix_len(&name, &other), 0);
+        other = name;
+        other.0[2] ^= 0b0000_0100;
+        assert_eq!(common_prefix_len(&name, &other), 21);
+    }
+
+    #[test]
+    fn distance_order() {
+        let target = random::<XorName>();
+        let mut near = target;
+        near.0[3] ^= 0b0001_0000;
+        let mut far = near;
+        far.0[1] ^= 0b0000_0001;
+        assert_eq!(cmp_distance(&target, &near, &far), Ordering::Less);
+        assert_eq!(cmp_distance(&target, &far, &near), Ordering::Greater);
+        assert_eq!(cmp_distance(&target, &target, &near), Ordering::Less);
+        assert_eq!(cmp_distance(&target, &far, &far), Ordering::Equal);
+    }
+
+    #[test]
+    fn affected_names_are_in_neighbourhood() {
+        let mut routing_node = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
+        let names = close_names(&routing_node, 100);
+        let mut xor_map = XorMap::new();
+        for name in &names {
+            let _ = xor_map.insert(*name, ());
+        }
+
+        // A node joining amongst us.
+        let node_added = close_names(&routing_node, 1)[0];
+        routing_node.add_node_into_routing_table(&node_added);
+        let neighbourhood = Neighbourhood::new(&routing_node, &node_added);
+        let candidates = xor_map.names_in(&neighbourhood);
+        for name in close_groups_including(&routing_node, &names, &node_added) {
+            assert!(candidates.contains(&name));
+        }
+
+        // A member of one of those close groups leaving.
+        let our_name = unwrap_result!(routing_node.name());
+        let close_group = unwrap_option!(unwrap_result!(routing_node.close_group(names[0])), "");
+        let node_lost = unwrap_option!(close_group.into_iter().find(|name| *name != our_name),
+                                       "");
+        let affected = close_groups_including(&routing_node, &names, &node_lost);
+        routing_node.remove_node_from_routing_table(&node_lost);
+        let neighbourhood = Neighbourhood::new(&routing_node, &node_lost);
+        let candidates = xor_map.names_in(&neighbourhood);
+        for name in affected {
+            assert!(candidates.contains(&name));
+        }
+
+        // A node on the far side of the network.
+        let mut far_node = our_name;
+        far_node.0[0] ^= 0b1000_0000;
+        let neighbourhood = Neighbourhood::new(&routing_node, &far_node);
+        assert!(xor_map.names_in(&neighbourhood).is_empty());
+    }
+}
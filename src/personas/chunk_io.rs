@@ -0,0 +1,486 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::u64;
+
+use chunk_store::{self, ChunkStore};
+use error::InternalError;
+#[cfg(not(feature = "use-mock-crust"))]
+use maidsafe_utilities::thread::RaiiThreadJoiner;
+use maidsafe_utilities::serialisation;
+use personas::Success;
+use routing::{Authority, Data, ImmutableData, MessageId, PlainData, RequestContent,
+              RequestMessage};
+use safe_network_common::client_errors::GetError;
+use types::VaultMessage;
+use utils;
+use vault::{CHUNK_STORE_PREFIX, RoutingNode};
+use xor_name::XorName;
+
+// What became of a chunk passed to `ChunkIo::store`.
+pub enum Stored {
+    // Written, with the success response sent for it.
+    Written(Success),
+    // Not written, so the put failure was sent instead.
+    Failed(XorName),
+}
+
+// The disk work done for a chunk, along with any response it's needed for.
+enum Job {
+    Get(XorName, MessageId, RequestMessage),
+    Challenge(XorName, Vec<u8>, MessageId, RequestMessage),
+    Store(XorName, Vec<u8>, RequestMessage, Success),
+    HandOff(XorName, Authority, Authority),
+    Read(XorName, Sender<Result<Vec<u8>, chunk_store::Error>>),
+    Delete(XorName),
+}
+
+// A thread doing the chunk IO for its partition of chunk names.  The sender is dropped first,
+// ending the thread's loop so that it can be joined.
+#[cfg(not(feature = "use-mock-crust"))]
+struct Worker {
+    jobs: Sender<(Job, RoutingNode)>,
+    _joiner: RaiiThreadJoiner,
+}
+
+enum Partitions {
+    // A single store, read and written on the caller's thread.
+    Inline(ChunkStore),
+    // A store per worker thread.
+    #[cfg(not(feature = "use-mock-crust"))]
+    Workers(Vec<Worker>),
+}
+
+// Reads and writes a PmidNode's chunks, answering the requests they're read or written for.  With
+// worker threads, each owns the chunks whose names fall in its partition, so chunks in different
+// partitions are read and written in parallel while the IO for any one chunk is still done in the
+// order it was asked for.  The sizes of the chunks held are kept here as soon as they're asked to
+// be stored, so that their names and the space used are known without waiting for the workers.
+pub struct ChunkIo {
+    partitions: Partitions,
+    sizes: HashMap<XorName, u64>,
+    used_space: u64,
+    stored_sender: Sender<Stored>,
+    stored_receiver: Receiver<Stored>,
+}
+
+impl ChunkIo {
+    // With no worker threads, all IO is done inline.  Under mock-crust it always is, as the routing
+    // node can't be shared with other threads there.  The stores are unbounded, as the PmidNode
+    // keeps to its allowance itself.
+    pub fn new(worker_count: usize) -> Result<ChunkIo, InternalError> {
+        let (stored_sender, stored_receiver) = mpsc::channel();
+        Ok(ChunkIo {
+            partitions: try!(Self::partitions(worker_count, &stored_sender)),
+            sizes: HashMap::new(),
+            used_space: 0,
+            stored_sender: stored_sender,
+            stored_receiver: stored_receiver,
+        })
+    }
+
+    #[cfg(not(feature = "use-mock-crust"))]
+    fn partitions(worker_count: usize,
+                  stored_sender: &Sender<Stored>)
+                  -> Result<Partitions, InternalError> {
+        if worker_count == 0 {
+            return Ok(Partitions::Inline(try!(ChunkStore::new(CHUNK_STORE_PREFIX, u64::MAX))));
+        }
+        let mut workers = Vec::with_capacity(worker_count);
+        for _ in 0..worker_count {
+            let mut chunk_store = try!(ChunkStore::new(CHUNK_STORE_PREFIX, u64::MAX));
+            let (job_sender, job_receiver) = mpsc::channel::<(Job, RoutingNode)>();
+            let stored_sender = stored_sender.clone();
+            let joiner = RaiiThreadJoiner::new(thread!("ChunkIo", move || {
+                for (job, routing_node) in job_receiver.iter() {
+                    run(&mut chunk_store, &routing_node, job, &stored_sender);
+                }
+            }));
+            workers.push(Worker {
+                jobs: job_sender,
+                _joiner: joiner,
+            });
+        }
+        Ok(Partitions::Workers(workers))
+    }
+
+    #[cfg(feature = "use-mock-crust")]
+    fn partitions(_worker_count: usize,
+                  _stored_sender: &Sender<Stored>)
+                  -> Result<Partitions, InternalError> {
+        Ok(Partitions::Inline(try!(ChunkStore::new(CHUNK_STORE_PREFIX, u64::MAX))))
+    }
+
+    pub fn names(&self) -> Vec<XorName> {
+        self.sizes.keys().cloned().collect()
+    }
+
+    pub fn has_chunk(&self, name: &XorName) -> bool {
+        self.sizes.contains_key(name)
+    }
+
+    pub fn used_space(&self) -> u64 {
+        self.used_space
+    }
+
+    // Answers a Get for the chunk with it, or with `NoSuchData` if it isn't held.
+    pub fn answer_get(&mut self,
+                      routing_node: &RoutingNode,
+                      name: XorName,
+                      message_id: MessageId,
+                      request: &RequestMessage) {
+        self.submit(routing_node, Job::Get(name, message_id, request.clone()));
+    }
+
+    // Answers a storage challenge with the proof of holding the chunk, left empty if it isn't held.
+    pub fn answer_challenge(&mut self,
+                            routing_node: &RoutingNode,
+                            name: XorName,
+                            nonce: Vec<u8>,
+                            message_id: MessageId,
+                            request: &RequestMessage) {
+        self.submit(routing_node,
+                    Job::Challenge(name, nonce, message_id, request.clone()));
+    }
+
+    // Stores the chunk, then sends `success`, or the put failure if it can't be written.  Either
+    // way, the outcome is reported by `take_stored`.  The chunk is counted as held straight away.
+    pub fn store(&mut self,
+                 routing_node: &RoutingNode,
+                 name: XorName,
+                 serialised_data: Vec<u8>,
+                 request: &RequestMessage,
+                 success: Success) {
+        self.add_size(name, serialised_data.len() as u64);
+        self.submit(routing_node,
+                    Job::Store(name, serialised_data, request.clone(), success));
+    }
+
+    // Sends the chunk from `src` to `dst` to be stored elsewhere.
+    pub fn hand_off(&mut self,
+                    routing_node: &RoutingNode,
+                    name: XorName,
+                    src: Authority,
+                    dst: Authority) {
+        self.submit(routing_node, Job::HandOff(name, src, dst));
+    }
+
+    // Reads the chunk, waiting for any IO already asked for on it to be done first.
+    pub fn get(&mut self,
+               routing_node: &RoutingNode,
+               name: &XorName)
+               -> Result<Vec<u8>, InternalError> {
+        let (result_sender, result_receiver) = mpsc::channel();
+        self.submit(routing_node, Job::Read(*name, result_sender));
+        match result_receiver.recv() {
+            Ok(result) => Ok(try!(result)),
+            Err(_) => {
+                Err(InternalError::Io(io::Error::new(io::ErrorKind::BrokenPipe,
+                                                     "Chunk IO worker has stopped")))
+            }
+        }
+    }
+
+    // Deletes the chunk, returning whether it was held.
+    pub fn delete(&mut self, routing_node: &RoutingNode, name: &XorName) -> bool {
+        if !self.remove_size(name) {
+            return false;
+        }
+        self.submit(routing_node, Job::Delete(*name));
+        true
+    }
+
+    // The outcomes of the chunks stored since last called.  Chunks which couldn't be written are
+    // no longer counted as held.
+    pub fn take_stored(&mut self) -> Vec<Stored> {
+        let mut stored = vec![];
+        while let Ok(outcome) = self.stored_receiver.try_recv() {
+            if let Stored::Failed(ref name) = outcome {
+                let _ = self.remove_size(name);
+            }
+            stored.push(outcome);
+        }
+        stored
+    }
+
+    // Writes a chunk straight to the store, as if it had been left there by an earlier run.
+    #[cfg(all(test, not(feature = "use-mock-crust")))]
+    pub fn put_existing(&mut self, name: &XorName, data: &[u8]) -> Result<(), InternalError> {
+        if let Partitions::Inline(ref mut chunk_store) = self.partitions {
+            try!(chunk_store.put(name, data));
+        }
+        self.add_size(*name, data.len() as u64);
+        Ok(())
+    }
+
+    fn add_size(&mut self, name: XorName, size: u64) {
+        if let Some(old_size) = self.sizes.insert(name, size) {
+            self.used_space -= old_size;
+        }
+        self.used_space += size;
+    }
+
+    fn remove_size(&mut self, name: &XorName) -> bool {
+        match self.sizes.remove(name) {
+            Some(size) => {
+                self.used_space -= size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn submit(&mut self, routing_node: &RoutingNode, job: Job) {
+        match self.partitions {
+            Partitions::Inline(ref mut chunk_store) => {
+                run(chunk_store, routing_node, job, &self.stored_sender)
+            }
+            #[cfg(not(feature = "use-mock-crust"))]
+            Partitions::Workers(ref workers) => {
+                let worker = &workers[partition(job.name(), workers.len())];
+                if worker.jobs.send((job, routing_node.clone())).is_err() {
+                    error!("A chunk IO worker has stopped");
+                }
+            }
+        }
+    }
+}
+
+impl Job {
+    #[cfg(not(feature = "use-mock-crust"))]
+    fn name(&self) -> &XorName {
+        match *self {
+            Job::Get(ref name, _, _) |
+            Job::Challenge(ref name, _, _, _) |
+            Job::Store(ref name, _, _, _) |
+            Job::HandOff(ref name, _, _) |
+            Job::Read(ref name, _) |
+            Job::Delete(ref name) => name,
+        }
+    }
+}
+
+// The worker owning the chunk with the given name.
+#[cfg(not(feature = "use-mock-crust"))]
+fn partition(name: &XorName, worker_count: usize) -> usize {
+    ((name.0[0] as usize) << 8 | name.0[1] as usize) % worker_count
+}
+
+fn run(chunk_store: &mut ChunkStore,
+       routing_node: &RoutingNode,
+       job: Job,
+       stored_sender: &Sender<Stored>) {
+    let result = match job {
+        Job::Get(name, message_id, request) => {
+            send_chunk(chunk_store, routing_node, &name, message_id, &request)
+        }
+        Job::Challenge(name, nonce, message_id, request) => {
+            send_proof(chunk_store, routing_node, name, nonce, message_id, &request)
+        }
+        Job::Store(name, serialised_data, request, success) => {
+            let outcome = match chunk_store.put(&name, &serialised_data) {
+                Ok(()) => Stored::Written(success.send(routing_node)),
+                Err(error) => {
+                    warn!("Failed to store {}: {:?}", name, error);
+                    let _ = send_put_failure(routing_node, &name, &request);
+                    Stored::Failed(name)
+                }
+            };
+            let _ = stored_sender.send(outcome);
+            Ok(())
+        }
+        Job::HandOff(name, src, dst) => {
+            send_for_hand_off(chunk_store, routing_node, &name, src, dst)
+        }
+        Job::Read(name, result_sender) => {
+            let _ = result_sender.send(chunk_store.get(&name));
+            Ok(())
+        }
+        Job::Delete(name) => chunk_store.delete(&name).map_err(InternalError::from),
+    };
+    if let Err(error) = result {
+        warn!("Chunk IO failed: {:?}", error);
+    }
+}
+
+fn send_chunk(chunk_store: &ChunkStore,
+              routing_node: &RoutingNode,
+              name: &XorName,
+              message_id: MessageId,
+              request: &RequestMessage)
+              -> Result<(), InternalError> {
+    if let Ok(data) = chunk_store.get(name) {
+        if let Ok(decoded) = serialisation::deserialise::<ImmutableData>(&data) {
+            let immutable_data = Data::Immutable(decoded);
+            trace!("As {:?} sending data {:?} to {:?}",
+                   request.dst,
+                   immutable_data,
+                   request.src);
+            let _ = routing_node.send_get_success(request.dst.clone(),
+                                                  request.src.clone(),
+                                                  immutable_data,
+                                                  message_id);
+            return Ok(());
+        }
+    }
+    let error = GetError::NoSuchData;
+    let external_error_indicator = try!(serialisation::serialise(&error));
+    trace!("As {:?} sending get failure of data {} to {:?}",
+           request.dst,
+           name,
+           request.src);
+    let _ = routing_node.send_get_failure(request.dst.clone(),
+                                          request.src.clone(),
+                                          request.clone(),
+                                          external_error_indicator,
+                                          message_id);
+    Ok(())
+}
+
+fn send_proof(chunk_store: &ChunkStore,
+              routing_node: &RoutingNode,
+              name: XorName,
+              nonce: Vec<u8>,
+              message_id: MessageId,
+              request: &RequestMessage)
+              -> Result<(), InternalError> {
+    let proof = match chunk_store.get(&name) {
+        Ok(serialised_data) => utils::storage_proof(&nonce, &serialised_data),
+        Err(_) => vec![],
+    };
+    let message = VaultMessage::StorageProof {
+        nonce: nonce,
+        proof: proof,
+    };
+    let serialised_message = try!(serialisation::serialise(&message));
+    let src = request.dst.clone();
+    let dst = request.src.clone();
+    trace!("As {:?} answering storage challenge for {} from {:?}",
+           src,
+           name,
+           dst);
+    let _ = routing_node.send_post_request(src,
+                                           dst,
+                                           Data::Plain(PlainData::new(name, serialised_message)),
+                                           message_id);
+    Ok(())
+}
+
+fn send_for_hand_off(chunk_store: &ChunkStore,
+                     routing_node: &RoutingNode,
+                     name: &XorName,
+                     src: Authority,
+                     dst: Authority)
+                     -> Result<(), InternalError> {
+    let serialised_data = try!(chunk_store.get(name));
+    let data = try!(serialisation::deserialise::<ImmutableData>(&serialised_data));
+    trace!("As {:?} handing off {} to {:?}", src, name, dst);
+    let _ = routing_node.send_post_request(src, dst, Data::Immutable(data), MessageId::new());
+    Ok(())
+}
+
+// Tells the chunk's PmidManagers that we haven't stored it.
+pub fn send_put_failure(routing_node: &RoutingNode,
+                        name: &XorName,
+                        request: &RequestMessage)
+                        -> Result<(), InternalError> {
+    let message_id = if let RequestContent::Put(_, message_id) = request.content {
+        message_id
+    } else {
+        return Err(InternalError::InvalidMessage);
+    };
+    let src = request.dst.clone();
+    let dst = request.src.clone();
+    trace!("As {:?} sending Put failure of data {} to {:?} ", src, name, dst);
+    let external_error_indicator = try!(serialisation::serialise(&Vec::<u8>::new()));
+    let _ = routing_node.send_put_failure(src,
+                                          dst,
+                                          request.clone(),
+                                          external_error_indicator,
+                                          message_id);
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(not(feature="use-mock-crust"))]
+mod test {
+    use super::{ChunkIo, Stored};
+    use maidsafe_utilities::serialisation;
+    use personas::{Success, SuccessKind};
+    use routing::{Authority, Data, ImmutableData, ImmutableDataType, MessageId, RequestContent,
+                  RequestMessage};
+    use std::sync::mpsc;
+    use utils::generate_random_vec_u8;
+    use vault::RoutingNode;
+
+    const CHUNKS: usize = 20;
+
+    #[test]
+    fn workers() {
+        let routing = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
+        let our_name = unwrap_result!(routing.name());
+        let pmid_node = Authority::ManagedNode(our_name);
+        let pmid_managers = Authority::NodeManager(our_name);
+        let mut chunk_io = unwrap_result!(ChunkIo::new(4));
+
+        let mut chunks = Vec::with_capacity(CHUNKS);
+        for _ in 0..CHUNKS {
+            let data = ImmutableData::new(ImmutableDataType::Normal, generate_random_vec_u8(64));
+            let serialised_data = unwrap_result!(serialisation::serialise(&data));
+            let message_id = MessageId::new();
+            let request = RequestMessage {
+                src: pmid_managers.clone(),
+                dst: pmid_node.clone(),
+                content: RequestContent::Put(Data::Immutable(data.clone()), message_id),
+            };
+            let success = Success::new(SuccessKind::Put,
+                                       pmid_node.clone(),
+                                       pmid_managers.clone(),
+                                       data.name(),
+                                       message_id);
+            chunk_io.store(&routing, data.name(), serialised_data.clone(), &request, success);
+            chunks.push((data.name(), serialised_data));
+        }
+        // The chunks are counted as held while they're still being written.
+        assert_eq!(chunk_io.names().len(), CHUNKS);
+        let used_space = chunks.iter().fold(0, |total, &(_, ref data)| total + data.len() as u64);
+        assert_eq!(chunk_io.used_space(), used_space);
+
+        // Each is read only once it's been written, which was confirmed first.
+        for &(ref name, ref serialised_data) in &chunks {
+            assert_eq!(unwrap_result!(chunk_io.get(&routing, name)), *serialised_data);
+        }
+        assert_eq!(routing.put_successes_given().len(), CHUNKS);
+        assert!(routing.put_failures_given().is_empty());
+        let stored = chunk_io.take_stored();
+        assert_eq!(stored.len(), CHUNKS);
+        for outcome in stored {
+            if let Stored::Failed(name) = outcome {
+                panic!("Failed to store {}", name);
+            }
+        }
+
+        let (ref deleted_name, _) = chunks[0];
+        assert!(chunk_io.delete(&routing, deleted_name));
+        assert!(!chunk_io.has_chunk(deleted_name));
+        assert!(chunk_io.get(&routing, deleted_name).is_err());
+        assert!(!chunk_io.delete(&routing, deleted_name));
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::collections::{BTreeMap, HashMap};
+
+use routing::ImmutableData;
+use xor_name::XorName;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub memory_used: u64,
+}
+
+// Recently retrieved chunks, kept so that popular data can be served without going back to the
+// PmidNodes.  Holds up to `memory_budget` bytes of payload, evicting the least recently used
+// chunks to make room for new ones.
+pub struct DataCache {
+    // <Data name, (data, recency stamp)>
+    entries: HashMap<XorName, (ImmutableData, u64)>,
+    // <Recency stamp, data name>, oldest first
+    recency: BTreeMap<u64, XorName>,
+    next_stamp: u64,
+    memory_budget: u64,
+    memory_used: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl DataCache {
+    pub fn new(memory_budget: u64) -> DataCache {
+        DataCache {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            next_stamp: 0,
+            memory_budget: memory_budget,
+            memory_used: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, data_name: &XorName) -> Option<ImmutableData> {
+        let stamp = self.next_stamp;
+        let data = match self.entries.get_mut(data_name) {
+            Some(&mut (ref data, ref mut entry_stamp)) => {
+                let _ = self.recency.remove(entry_stamp);
+                *entry_stamp = stamp;
+                data.clone()
+            }
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+        let _ = self.recency.insert(stamp, *data_name);
+        self.next_stamp += 1;
+        self.hits += 1;
+        Some(data)
+    }
+
+    pub fn insert(&mut self, data: ImmutableData) {
+        let data_name = data.name();
+        let size = data.value().len() as u64;
+        if size > self.memory_budget || self.entries.contains_key(&data_name) {
+            return;
+        }
+        while self.memory_used + size > self.memory_budget {
+            let oldest = match self.recency.keys().next() {
+                Some(stamp) => *stamp,
+                None => break,
+            };
+            if let Some(name) = self.recency.remove(&oldest) {
+                self.remove(&name);
+            }
+        }
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.memory_used += size;
+        let _ = self.recency.insert(stamp, data_name);
+        let _ = self.entries.insert(data_name, (data, stamp));
+    }
+
+    pub fn remove(&mut self, data_name: &XorName) {
+        if let Some((data, stamp)) = self.entries.remove(data_name) {
+            let _ = self.recency.remove(&stamp);
+            self.memory_used -= data.value().len() as u64;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+            memory_used: self.memory_used,
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature="use-mock-crust"))]
+mod test {
+    use super::*;
+    use routing::{ImmutableData, ImmutableDataType};
+    use utils::generate_random_vec_u8;
+
+    fn random_data(size: usize) -> ImmutableData {
+        ImmutableData::new(ImmutableDataType::Normal, generate_random_vec_u8(size))
+    }
+
+    #[test]
+    fn hits_and_misses() {
+        let mut cache = DataCache::new(1024);
+        let data = random_data(100);
+        assert!(cache.get(&data.name()).is_none());
+        cache.insert(data.clone());
+        assert_eq!(cache.get(&data.name()), Some(data.clone()));
+        assert_eq!(cache.get(&data.name()), Some(data.clone()));
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.entries, 1);
+        assert_eq!(stats.memory_used, 100);
+
+        cache.remove(&data.name());
+        assert!(cache.get(&data.name()).is_none());
+        assert_eq!(cache.stats().memory_used, 0);
+    }
+
+    #[test]
+    fn least_recently_used_evicted() {
+        let mut cache = DataCache::new(1000);
+        let first = random_data(400);
+        let second = random_data(400);
+        let third = random_data(400);
+        cache.insert(first.clone());
+        cache.insert(second.clone());
+
+        // Touching the first chunk leaves the second as the least recently used.
+        assert!(cache.get(&first.name()).is_some());
+        cache.insert(third.clone());
+        assert!(cache.get(&second.name()).is_none());
+        assert!(cache.get(&first.name()).is_some());
+        assert!(cache.get(&third.name()).is_some());
+        assert_eq!(cache.stats().memory_used, 800);
+    }
+
+    #[test]
+    fn oversized_data_not_cached() {
+        let mut cache = DataCache::new(1000);
+        let small = random_data(500);
+        cache.insert(small.clone());
+        cache.insert(random_data(1001));
+        assert_eq!(cache.stats().entries, 1);
+        assert!(cache.get(&small.name()).is_some());
+    }
+}
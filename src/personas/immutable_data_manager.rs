@@ -15,62 +15,280 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use std::mem;
 use std::convert::From;
-use std::collections::{HashMap, HashSet};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
+use churn_queue::{CHURN_SLICE_SIZE, ChurnQueue};
+use clock::Clock;
+use erasure_coding::{self, FragmentCounts};
 use error::InternalError;
+use neighbourhood::{self, Neighbourhood, XorMap};
+use personas::{Context, Persona, Route, Success, SuccessKind};
+use personas::data_cache::DataCache;
+use personas::ongoing_puts::OngoingPuts;
+use personas::pmid_manager::{MAX_REPUTATION, MISBEHAVING_REPUTATION};
+use personas::pmid_node_history::PmidNodeHistory;
+use personas::replication_queue::{MAX_REPLICATION_ATTEMPTS, Replication, ReplicationQueue};
 use safe_network_common::client_errors::GetError;
-use timed_buffer::TimedBuffer;
+use timed_buffer::{EvictionPolicy, TimedBuffer};
 use maidsafe_utilities::serialisation;
 use routing::{self, Authority, Data, DataRequest, ImmutableData, ImmutableDataType, MessageId,
               PlainData, RequestContent, RequestMessage, ResponseContent, ResponseMessage};
 use time::{Duration, SteadyTime};
-use types::{Refresh, RefreshValue};
+use types::{Fragment, Refresh, RefreshValue, VaultMessage};
+use utils;
 use vault::RoutingNode;
 use xor_name::XorName;
 
 pub const REPLICANTS: usize = 2;
+// The most Gets which can be in progress at any one time.
+const MAX_ONGOING_GETS: usize = 10_000;
+// How often to look for chunks which have lost too many of their holders.
+const REPAIR_INTERVAL_SECS: i64 = 60;
+// The most repairs to start on each pass, leaving room in `ongoing_gets` for clients' Gets.
+const MAX_REPAIRS_PER_PASS: usize = 100;
+// How long PmidNodes' reported free space is relied on before asking their managers again.
+const CAPACITY_REPORT_TTL_SECS: i64 = 300;
+// How long to wait for a handed off chunk to be stored on its new holder.  A departing PmidNode
+// keeps its copy for a similar time anyway.
+const HAND_OFF_TTL_SECS: i64 = 600;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct RepairStats {
+    // Chunks stored on new holders after losing some of their copies
+    pub repaired: u64,
+    // Chunks which couldn't be retrieved from any holder or other location
+    pub unrecoverable: u64,
+}
 
-// Collection of PmidNodes holding a copy of the chunk
+// Collection of PmidNodes holding a copy of the chunk, along with the size of the chunk's content
+// so that a holder which loses it can be charged for exactly that
 #[derive(Clone, PartialEq, Eq, Debug, RustcEncodable, RustcDecodable)]
 pub enum Account {
-    Normal(HashSet<DataHolder>),
-    Backup(HashSet<DataHolder>),
-    Sacrificial(HashSet<DataHolder>),
+    Normal(HashSet<DataHolder>, u64),
+    Backup(HashSet<DataHolder>, u64),
+    Sacrificial(HashSet<DataHolder>, u64),
+    // A "Normal" chunk split into erasure-coded fragments, each PmidNode holding one of them
+    Fragmented(HashSet<DataHolder>, FragmentLayout),
 }
 
 impl Account {
-    pub fn new(data_type: &ImmutableDataType, pmid_nodes: HashSet<DataHolder>) -> Account {
+    pub fn new(data_type: &ImmutableDataType,
+               pmid_nodes: HashSet<DataHolder>,
+               size: u64)
+               -> Account {
         match *data_type {
-            ImmutableDataType::Normal => Account::Normal(pmid_nodes),
-            ImmutableDataType::Backup => Account::Backup(pmid_nodes),
-            ImmutableDataType::Sacrificial => Account::Sacrificial(pmid_nodes),
+            ImmutableDataType::Normal => Account::Normal(pmid_nodes, size),
+            ImmutableDataType::Backup => Account::Backup(pmid_nodes, size),
+            ImmutableDataType::Sacrificial => Account::Sacrificial(pmid_nodes, size),
         }
     }
 
     pub fn pmid_nodes(&self) -> &HashSet<DataHolder> {
         match *self {
-            Account::Normal(ref nodes) |
-            Account::Backup(ref nodes) |
-            Account::Sacrificial(ref nodes) => nodes,
+            Account::Normal(ref nodes, _) |
+            Account::Backup(ref nodes, _) |
+            Account::Sacrificial(ref nodes, _) |
+            Account::Fragmented(ref nodes, _) => nodes,
         }
     }
 
     pub fn pmid_nodes_mut(&mut self) -> &mut HashSet<DataHolder> {
         match *self {
-            Account::Normal(ref mut nodes) |
-            Account::Backup(ref mut nodes) |
-            Account::Sacrificial(ref mut nodes) => nodes,
+            Account::Normal(ref mut nodes, _) |
+            Account::Backup(ref mut nodes, _) |
+            Account::Sacrificial(ref mut nodes, _) |
+            Account::Fragmented(ref mut nodes, _) => nodes,
         }
     }
 
     pub fn data_type(&self) -> ImmutableDataType {
         match *self {
-            Account::Normal(_) => ImmutableDataType::Normal,
-            Account::Backup(_) => ImmutableDataType::Backup,
-            Account::Sacrificial(_) => ImmutableDataType::Sacrificial,
+            Account::Normal(..) |
+            Account::Fragmented(..) => ImmutableDataType::Normal,
+            Account::Backup(..) => ImmutableDataType::Backup,
+            Account::Sacrificial(..) => ImmutableDataType::Sacrificial,
+        }
+    }
+
+    // The size of the content each holder was sent: a fragment if the chunk is erasure-coded,
+    // otherwise the whole chunk.
+    pub fn copy_size(&self) -> u64 {
+        match *self {
+            Account::Normal(_, size) |
+            Account::Backup(_, size) |
+            Account::Sacrificial(_, size) => size,
+            Account::Fragmented(_, ref layout) => layout.fragment_size,
+        }
+    }
+
+    pub fn layout(&self) -> Option<&FragmentLayout> {
+        match *self {
+            Account::Fragmented(_, ref layout) => Some(layout),
+            _ => None,
+        }
+    }
+
+    // The number of holders the chunk should have: one per fragment if it's erasure-coded.
+    pub fn required_holders(&self) -> usize {
+        self.layout().map_or(REPLICANTS, |layout| layout.counts.total())
+    }
+
+    // The index of the fragment held by `pmid_node`, if the chunk is erasure-coded.
+    pub fn fragment_index(&self, pmid_node: &XorName) -> Option<usize> {
+        self.layout().and_then(|layout| layout.indices.get(pmid_node).cloned())
+    }
+
+    // Which copy of the chunk `chunk_name` a holder's data named `name` is: the index of the
+    // fragment if the chunk is erasure-coded, otherwise `None` for the whole chunk.  It's an error
+    // if the data is neither.
+    pub fn copy_index(&self,
+                      chunk_name: &XorName,
+                      name: &XorName)
+                      -> Result<Option<usize>, InternalError> {
+        match self.layout() {
+            Some(layout) => {
+                layout.names
+                      .iter()
+                      .position(|fragment_name| fragment_name == name)
+                      .map(Some)
+                      .ok_or(InternalError::InvalidMessage)
+            }
+            None if name == chunk_name => Ok(None),
+            None => Err(InternalError::InvalidMessage),
+        }
+    }
+
+    // The fragments, by index, which have no holders other than failed ones.
+    pub fn missing_fragments(&self) -> Vec<usize> {
+        let layout = if let Some(layout) = self.layout() {
+            layout
+        } else {
+            return vec![];
+        };
+        let held = self.pmid_nodes()
+                       .iter()
+                       .filter_map(|pmid_node| {
+                           match *pmid_node {
+                               DataHolder::Good(ref name) |
+                               DataHolder::Pending(ref name) => layout.indices.get(name).cloned(),
+                               DataHolder::Failed(_) => None,
+                           }
+                       })
+                       .collect::<HashSet<_>>();
+        (0..layout.counts.total()).filter(|index| !held.contains(index)).collect()
+    }
+
+    // What to store on the holder of the fragment with `index`: that fragment if the chunk is
+    // erasure-coded, otherwise the whole chunk.
+    pub fn copy_for(&self,
+                    data: &ImmutableData,
+                    index: Option<usize>)
+                    -> Result<ImmutableData, InternalError> {
+        match (self.layout(), index) {
+            (Some(layout), Some(index)) => layout.fragment(data, index),
+            _ => Ok(data.clone()),
+        }
+    }
+
+    // Adds a holder which is being sent the chunk, or the fragment with `index` if it's
+    // erasure-coded.
+    pub fn add_pending(&mut self, pmid_node: XorName, index: Option<usize>) {
+        self.add_holder(DataHolder::Pending(pmid_node), index)
+    }
+
+    // Adds a holder of the chunk, or of the fragment with `index` if it's erasure-coded.
+    pub fn add_holder(&mut self, holder: DataHolder, index: Option<usize>) {
+        if let Some(index) = index {
+            if let Account::Fragmented(_, ref mut layout) = *self {
+                let _ = layout.indices.insert(*holder.name(), index);
+            }
+        }
+        self.pmid_nodes_mut().insert(holder);
+    }
+
+    // Forgets which fragments were sent to PmidNodes which are no longer holders.
+    pub fn forget_departed_holders(&mut self) {
+        if let Account::Fragmented(ref nodes, ref mut layout) = *self {
+            layout.indices = layout.indices
+                                   .iter()
+                                   .filter(|&(name, _)| {
+                                       nodes.iter().any(|pmid_node| pmid_node.name() == name)
+                                   })
+                                   .map(|(name, index)| (*name, *index))
+                                   .collect();
+        }
+    }
+}
+
+
+
+// How an erasure-coded chunk was split, and which fragment was sent to each of its holders.
+#[derive(Clone, PartialEq, Eq, Debug, RustcEncodable, RustcDecodable)]
+pub struct FragmentLayout {
+    pub counts: FragmentCounts,
+    // Size of the chunk's content before it was split
+    pub size: u64,
+    // Size of each fragment's content, as sent to its holder
+    pub fragment_size: u64,
+    // Names of the fragments, by index
+    pub names: Vec<XorName>,
+    // <PmidNode, index of the fragment sent to it>
+    pub indices: HashMap<XorName, usize>,
+}
+
+impl FragmentLayout {
+    // Splits `data` into fragments, returning them along with a layout which has no holders yet.
+    pub fn split(data: &ImmutableData,
+                 counts: FragmentCounts)
+                 -> Result<(FragmentLayout, Vec<ImmutableData>), InternalError> {
+        let mut fragments = vec![];
+        for (index, value) in erasure_coding::encode(data.value(), counts).into_iter().enumerate() {
+            let fragment = Fragment {
+                chunk_name: data.name(),
+                index: index,
+                value: value,
+            };
+            fragments.push(try!(fragment.to_data()));
+        }
+        let layout = FragmentLayout {
+            counts: counts,
+            size: data.value().len() as u64,
+            fragment_size: fragments.first().map_or(0, |fragment| fragment.value().len() as u64),
+            names: fragments.iter().map(ImmutableData::name).collect(),
+            indices: HashMap::new(),
+        };
+        Ok((layout, fragments))
+    }
+
+    // Splits `data` again, checking that it gives the same fragments as when it was first stored.
+    pub fn fragments(&self, data: &ImmutableData) -> Result<Vec<ImmutableData>, InternalError> {
+        let (layout, fragments) = try!(Self::split(data, self.counts));
+        if layout.names != self.names {
+            return Err(InternalError::InvalidFragments);
         }
+        Ok(fragments)
+    }
+
+    pub fn fragment(&self,
+                    data: &ImmutableData,
+                    index: usize)
+                    -> Result<ImmutableData, InternalError> {
+        try!(self.fragments(data)).into_iter().nth(index).ok_or(InternalError::InvalidFragments)
+    }
+
+    // The name of the fragment sent to `pmid_node`.
+    pub fn fragment_name(&self, pmid_node: &XorName) -> Option<XorName> {
+        self.indices.get(pmid_node).and_then(|&index| self.names.get(index)).cloned()
+    }
+
+    // Rebuilds the chunk from at least `counts.data` of its fragments, keyed by index.
+    pub fn join(&self,
+                fragments: &BTreeMap<usize, Vec<u8>>)
+                -> Result<ImmutableData, InternalError> {
+        let value = try!(erasure_coding::decode(fragments, self.counts, self.size as usize));
+        Ok(ImmutableData::new(ImmutableDataType::Normal, value))
     }
 }
 
@@ -108,40 +326,60 @@ struct MetadataForGetRequest {
     pub message_id: MessageId,
     pub requests: Vec<(MessageId, RequestMessage)>,
     pub pmid_nodes: Vec<DataHolder>,
+    // Good holders which haven't been asked for the data yet.
+    pub unqueried: Vec<XorName>,
+    // When each queried holder was asked, to measure how long it takes to respond.
+    pub sent_at: HashMap<XorName, SteadyTime>,
     pub creation_timestamp: SteadyTime,
     pub data: Option<ImmutableData>,
     pub requested_data_type: ImmutableDataType,
     pub secondary_location_failed: bool,
+    // How the chunk was split if it's erasure-coded, and the fragments received so far by index.
+    pub layout: Option<FragmentLayout>,
+    pub fragments: BTreeMap<usize, Vec<u8>>,
+    // Whether the Get was started by the repair pass.
+    pub repair: bool,
 }
 
 impl MetadataForGetRequest {
-    pub fn new(message_id: &MessageId, account: &Account) -> MetadataForGetRequest {
-        Self::construct(message_id, vec![], account)
+    pub fn new(message_id: &MessageId,
+               account: &Account,
+               creation_timestamp: SteadyTime)
+               -> MetadataForGetRequest {
+        Self::construct(message_id, vec![], account, creation_timestamp)
     }
 
     pub fn with_message(message_id: &MessageId,
                         request: &RequestMessage,
-                        account: &Account)
+                        account: &Account,
+                        creation_timestamp: SteadyTime)
                         -> MetadataForGetRequest {
         Self::construct(message_id,
                         vec![(message_id.clone(), request.clone()); 1],
-                        account)
+                        account,
+                        creation_timestamp)
     }
 
-    pub fn send_get_requests(&self,
+    // Asks the first `holder_count` unqueried holders for the data.  If there are no holders left
+    // at all, asks the managers of the other types of the chunk instead.
+    pub fn send_get_requests(&mut self,
                              routing_node: &RoutingNode,
                              data_name: &XorName,
-                             message_id: MessageId) {
+                             message_id: MessageId,
+                             holder_count: usize,
+                             now: SteadyTime) {
         let src = Authority::NaeManager(*data_name);
         let log = |data_type: &ImmutableDataType, data_name: &XorName, dst: &Authority| {
-            trace!("ImmutableDataManager {} sending get {:?}({}) to {:?}",
-                   unwrap_result!(routing_node.name()),
-                   data_type,
-                   data_name,
-                   dst);
+            if let Ok(our_name) = routing_node.name() {
+                trace!("ImmutableDataManager {} sending get {:?}({}) to {:?}",
+                       our_name,
+                       data_type,
+                       data_name,
+                       dst);
+            }
         };
 
-        if self.pmid_nodes.is_empty() {
+        if self.pmid_nodes.is_empty() && self.unqueried.is_empty() {
             // There are no "Good" holders for this type, so send Get to other types' DMs
             let mut msg_id = message_id;
             let (normal_name, backup_name, sacrificial_name) = match self.requested_data_type {
@@ -186,20 +424,87 @@ impl MetadataForGetRequest {
                 let _ = routing_node.send_get_request(src.clone(), dst, data_request, msg_id);
             }
         } else {
-            // Send to pmid_nodes (which should be "Good" holders)
-            for good_node in &self.pmid_nodes {
-                let dst = Authority::ManagedNode(*good_node.name());
-                let data_request = DataRequest::Immutable(*data_name,
+            // Send to the chosen unqueried holders (which should be "Good" holders)
+            let count = cmp::min(holder_count, self.unqueried.len());
+            let chosen = self.unqueried.drain(..count).collect::<Vec<_>>();
+            for good_node in chosen {
+                let dst = Authority::ManagedNode(good_node);
+                // Holders of an erasure-coded chunk are asked for their fragment of it
+                let requested_name = self.layout
+                                         .as_ref()
+                                         .and_then(|layout| layout.fragment_name(&good_node))
+                                         .unwrap_or(*data_name);
+                let data_request = DataRequest::Immutable(requested_name,
                                                           self.requested_data_type.clone());
-                log(&self.requested_data_type, data_name, &dst);
+                log(&self.requested_data_type, &requested_name, &dst);
                 let _ = routing_node.send_get_request(src.clone(), dst, data_request, message_id);
+                self.pmid_nodes.push(DataHolder::Pending(good_node));
+                let _ = self.sent_at.insert(good_node, now);
+            }
+        }
+    }
+
+    // The number of holders the chunk should have.
+    pub fn required_holders(&self) -> usize {
+        self.layout.as_ref().map_or(REPLICANTS, |layout| layout.counts.total())
+    }
+
+    // The number of further holders to ask for the data: one, or for an erasure-coded chunk,
+    // enough to make up the fragments still needed to rebuild it.
+    pub fn holders_needed(&self) -> usize {
+        let layout = if let Some(ref layout) = self.layout {
+            layout
+        } else {
+            return 1;
+        };
+        let pending_count = self.pmid_nodes
+                                .iter()
+                                .filter(|pmid_node| {
+                                    match **pmid_node {
+                                        DataHolder::Pending(_) => true,
+                                        _ => false,
+                                    }
+                                })
+                                .count();
+        cmp::max(1,
+                 layout.counts.data.saturating_sub(self.fragments.len() + pending_count))
+    }
+
+    // Takes the data sent by the holder `pmid_node`.  For an erasure-coded chunk this is one of its
+    // fragments, and the chunk is returned once enough of them have arrived to rebuild it.
+    pub fn add_from_holder(&mut self,
+                           pmid_node: &XorName,
+                           data: ImmutableData)
+                           -> Result<Option<ImmutableData>, InternalError> {
+        let layout = if let Some(ref layout) = self.layout {
+            layout
+        } else {
+            return Ok(Some(data));
+        };
+        let index = match layout.indices.get(pmid_node) {
+            Some(&index) if layout.names.get(index) == Some(&data.name()) => index,
+            _ => {
+                warn!("{} sent {} which isn't the fragment it was given.",
+                      pmid_node,
+                      data.name());
+                return Err(InternalError::InvalidResponse);
             }
+        };
+        if self.data.is_some() {
+            return Ok(self.data.clone());
+        }
+        let fragment = try!(Fragment::from_data(&data));
+        let _ = self.fragments.insert(index, fragment.value);
+        if self.fragments.len() < layout.counts.data {
+            return Ok(None);
         }
+        layout.join(&self.fragments).map(Some)
     }
 
     fn construct(message_id: &MessageId,
                  requests: Vec<(MessageId, RequestMessage)>,
-                 account: &Account)
+                 account: &Account,
+                 creation_timestamp: SteadyTime)
                  -> MetadataForGetRequest {
         // We only want to try and get data from "good" holders
         let good_nodes = account.pmid_nodes()
@@ -207,9 +512,7 @@ impl MetadataForGetRequest {
                                 .cloned()
                                 .filter_map(|pmid_node| {
                                     match pmid_node {
-                                        DataHolder::Good(pmid_node) => {
-                                            Some(DataHolder::Pending(pmid_node))
-                                        }
+                                        DataHolder::Good(pmid_node) => Some(pmid_node),
                                         DataHolder::Failed(_) |
                                         DataHolder::Pending(_) => None,
                                     }
@@ -219,11 +522,16 @@ impl MetadataForGetRequest {
         MetadataForGetRequest {
             message_id: message_id.clone(),
             requests: requests,
-            pmid_nodes: good_nodes,
-            creation_timestamp: SteadyTime::now(),
+            pmid_nodes: vec![],
+            unqueried: good_nodes,
+            sent_at: HashMap::new(),
+            creation_timestamp: creation_timestamp,
             data: None,
             requested_data_type: account.data_type(),
             secondary_location_failed: false,
+            layout: account.layout().cloned(),
+            fragments: BTreeMap::new(),
+            repair: false,
         }
     }
 }
@@ -232,19 +540,93 @@ impl MetadataForGetRequest {
 
 pub struct ImmutableDataManager {
     // <Data name, PmidNodes holding a copy of the data>
-    accounts: HashMap<XorName, Account>,
+    accounts: XorMap<Account>,
     // key is chunk_name
     ongoing_gets: TimedBuffer<XorName, MetadataForGetRequest>,
-    ongoing_puts: HashSet<(ImmutableData, MessageId)>,
+    ongoing_puts: OngoingPuts,
+    // Recently retrieved chunks, served directly to further Gets
+    data_cache: DataCache,
+    // How each PmidNode has responded to our Gets, used to decide how long to wait for it
+    holder_history: PmidNodeHistory,
+    // Reputations shared by the PmidManagers of nodes which have lost or failed to store chunks,
+    // or refreshed along with the accounts of chunks they hold.  Nodes not listed are assumed to
+    // have a clean record.
+    reputations: HashMap<XorName, u8>,
+    // A cache of PmidNodes' free space in bytes as reported by their managers, or `None` while
+    // waiting for an answer.  Placement never waits on it: nodes are only passed over once their
+    // report is in, so a query sent while storing one chunk only informs the choice for later ones.
+    free_space: TimedBuffer<XorName, Option<u64>>,
+    // The PmidNodes which handed off chunks now being stored on new holders, along with the chunk
+    // the copy is of, by the ID of the Put
+    hand_offs: TimedBuffer<MessageId, (XorName, XorName)>,
+    // The longest to wait for a holder before also asking the next closest one.  Zero asks all
+    // holders at once.
+    hedge_delay: Duration,
+    // When to next ask another holder for each chunk still being fetched
+    hedges: HashMap<XorName, SteadyTime>,
+    // How "Normal" chunks are split into fragments, or `None` to store whole copies of them
+    fragment_counts: Option<FragmentCounts>,
+    // Copies being stored on new holders after churn or a failed Get
+    replications: ReplicationQueue,
+    // Accounts still to be checked after churn, with the ID of the latest churn event to affect
+    // each of them
+    churn_queue: ChurnQueue<XorName, MessageId>,
+    // The wall-clock repair interval the latest repair pass ran in, which numbers the pass alike
+    // across the group, and the last chunk it started repairing
+    repair_epoch: u64,
+    repair_cursor: Option<XorName>,
+    repair_stats: RepairStats,
+    // Success responses sent since the registry last took them
+    successes: Vec<Success>,
+    clock: Clock,
 }
 
 impl ImmutableDataManager {
-    pub fn new() -> ImmutableDataManager {
-        ImmutableDataManager {
-            accounts: HashMap::new(),
-            ongoing_gets: TimedBuffer::new(Duration::minutes(5)),
-            ongoing_puts: HashSet::new(),
-        }
+    pub fn new(memory_budget: u64,
+               disk_budget: u64,
+               cache_budget: u64,
+               hedge_delay: Duration,
+               fragment_counts: Option<FragmentCounts>)
+               -> Result<ImmutableDataManager, InternalError> {
+        Self::with_clock(memory_budget,
+                         disk_budget,
+                         cache_budget,
+                         hedge_delay,
+                         fragment_counts,
+                         Clock::system())
+    }
+
+    pub fn with_clock(memory_budget: u64,
+                      disk_budget: u64,
+                      cache_budget: u64,
+                      hedge_delay: Duration,
+                      fragment_counts: Option<FragmentCounts>,
+                      clock: Clock)
+                      -> Result<ImmutableDataManager, InternalError> {
+        Ok(ImmutableDataManager {
+            accounts: XorMap::new(),
+            ongoing_gets: TimedBuffer::with_capacity(Duration::minutes(5),
+                                                     MAX_ONGOING_GETS,
+                                                     EvictionPolicy::RejectNew,
+                                                     clock.clone()),
+            ongoing_puts: try!(OngoingPuts::new(memory_budget, disk_budget, clock.clone())),
+            data_cache: DataCache::new(cache_budget),
+            holder_history: PmidNodeHistory::new(),
+            reputations: HashMap::new(),
+            free_space: TimedBuffer::with_clock(Duration::seconds(CAPACITY_REPORT_TTL_SECS),
+                                                clock.clone()),
+            hand_offs: TimedBuffer::with_clock(Duration::seconds(HAND_OFF_TTL_SECS), clock.clone()),
+            hedge_delay: hedge_delay,
+            hedges: HashMap::new(),
+            fragment_counts: fragment_counts,
+            replications: ReplicationQueue::new(),
+            churn_queue: ChurnQueue::new(),
+            repair_epoch: Self::repair_epoch(&clock),
+            repair_cursor: None,
+            repair_stats: RepairStats::default(),
+            successes: Vec::new(),
+            clock: clock,
+        })
     }
 
     pub fn handle_get(&mut self,
@@ -256,7 +638,7 @@ impl ImmutableDataManager {
                    request.content {
                 (data_name, message_id)
             } else {
-                unreachable!("Error in vault demuxing")
+                return Err(InternalError::InvalidMessage);
             };
 
         // If the data doesn't exist, respond with GetFailure
@@ -276,14 +658,23 @@ impl ImmutableDataManager {
         };
 
         // If there's an ongoing Put operation, get the data from the cached copy there and return
-        if let Some(&(ref immutable_data, _)) = self.ongoing_puts
-                                                    .iter()
-                                                    .find(|&entry| entry.0.name() == *data_name) {
+        if let Some(immutable_data) = self.ongoing_puts.get(data_name) {
+            let src = request.dst.clone();
+            let dst = request.src.clone();
+            let _ = routing_node.send_get_success(src,
+                                                  dst,
+                                                  Data::Immutable(immutable_data),
+                                                  *message_id);
+            return Ok(());
+        }
+
+        // If the data has been retrieved recently, serve it from the cache
+        if let Some(immutable_data) = self.data_cache.get(data_name) {
             let src = request.dst.clone();
             let dst = request.src.clone();
             let _ = routing_node.send_get_success(src,
                                                   dst,
-                                                  Data::Immutable(immutable_data.clone()),
+                                                  Data::Immutable(immutable_data),
                                                   *message_id);
             return Ok(());
         }
@@ -299,9 +690,25 @@ impl ImmutableDataManager {
         }
 
         // This is new cache entry
-        let entry = MetadataForGetRequest::with_message(message_id, request, pmid_nodes);
-        entry.send_get_requests(routing_node, &data_name, *message_id);
-        let _ = self.ongoing_gets.insert(*data_name, entry);
+        let entry = MetadataForGetRequest::with_message(message_id,
+                                                        request,
+                                                        pmid_nodes,
+                                                        self.clock.now());
+        if self.ongoing_gets.insert(*data_name, entry).is_some() {
+            // We're already handling as many Gets as we can, so fail this one straight away rather
+            // than leave the client waiting.
+            let src = request.dst.clone();
+            let dst = request.src.clone();
+            let error = GetError::NetworkOther("Too many ongoing Get requests".to_owned());
+            let external_error_indicator = try!(serialisation::serialise(&error));
+            let _ = routing_node.send_get_failure(src,
+                                                  dst,
+                                                  request.clone(),
+                                                  external_error_indicator,
+                                                  *message_id);
+            return Err(From::from(error));
+        }
+        self.ask_holders(routing_node, data_name, message_id);
         Ok(())
     }
 
@@ -314,7 +721,7 @@ impl ImmutableDataManager {
                                                             ref message_id) = request.content {
             (data, message_id)
         } else {
-            unreachable!("Error in vault demuxing");
+            return Err(InternalError::InvalidMessage);
         };
 
         let send_success = || {
@@ -322,42 +729,89 @@ impl ImmutableDataManager {
             if let Authority::ClientManager(_) = request.src {
                 let src = request.dst.clone();
                 let dst = request.src.clone();
-                let _ = routing_node.send_put_success(src, dst, data.name(), *message_id);
+                Some(Success::new(SuccessKind::Put, src, dst, data.name(), *message_id)
+                         .send(routing_node))
+            } else {
+                None
             }
         };
 
         // If the data already exists, send success and finish.
         let data_name = data.name();
         if self.accounts.contains_key(&data_name) {
-            return Ok(send_success());
+            self.successes.extend(send_success());
+            return Ok(());
         }
 
-        // Choose the PmidNodes to store the data on, and add them in a new database entry.
-        let target_pmid_nodes = match self.choose_initial_pmid_nodes(routing_node,
-                                                                     full_pmid_nodes,
-                                                                     &data_name) {
-            Ok(pmid_nodes) => {
-                // Send success since we found enough non-full Pmid Nodes
-                send_success();
-                pmid_nodes
-            }
-            Err(error) => return Err(error),
+        // Choose the PmidNodes to store the data on, and add them in a new database entry.  A
+        // "Normal" chunk is split into fragments if configured to be, as long as there are enough
+        // PmidNodes to hold one each.
+        let fragment_counts = match *data.get_type_tag() {
+            ImmutableDataType::Normal => self.fragment_counts,
+            _ => None,
         };
+        let holder_count = fragment_counts.map_or(REPLICANTS, |counts| counts.total());
+        let data_size = data.value().len() as u64;
+        let copy_size = fragment_counts.map_or(data_size, |counts| {
+            let data_fragments = counts.data as u64;
+            (data_size + data_fragments - 1) / data_fragments
+        });
+        self.query_free_space(routing_node, &data_name);
+        let mut target_pmid_nodes = try!(self.choose_initial_pmid_nodes(routing_node,
+                                                                        full_pmid_nodes,
+                                                                        &data_name,
+                                                                        holder_count,
+                                                                        copy_size));
+        let fragment_counts = fragment_counts.and_then(|counts| {
+            if target_pmid_nodes.len() < counts.total() {
+                None
+            } else {
+                Some(counts)
+            }
+        });
+        if fragment_counts.is_none() {
+            target_pmid_nodes.truncate(REPLICANTS);
+        }
+
+        // Hold on to the data until it's stored, pushing back on the sender if we can't.
+        if let Err(error) = self.ongoing_puts.insert(data, *message_id) {
+            if let InternalError::ClientMutation(ref mutation_error) = error {
+                if let Authority::ClientManager(_) = request.src {
+                    let external_error_indicator = try!(serialisation::serialise(mutation_error));
+                    let _ = routing_node.send_put_failure(request.dst.clone(),
+                                                          request.src.clone(),
+                                                          request.clone(),
+                                                          external_error_indicator,
+                                                          *message_id);
+                }
+            }
+            return Err(error);
+        }
+
+        // Send success since we found enough non-full Pmid Nodes
+        self.successes.extend(send_success());
         trace!("ImmutableDataManager chosen {:?} as pmid_nodes for chunk {:?}",
                target_pmid_nodes,
                data);
-        let _ = self.accounts.insert(data_name,
-                                     Account::new(data.get_type_tag(), target_pmid_nodes.clone()));
-        let _ = self.ongoing_puts.insert((data.clone(), *message_id));
+        let holders = target_pmid_nodes.iter()
+                                       .cloned()
+                                       .map(DataHolder::Pending)
+                                       .collect::<HashSet<_>>();
+        let (account, copies) = if let Some(counts) = fragment_counts {
+            let (mut layout, fragments) = try!(FragmentLayout::split(data, counts));
+            layout.indices = target_pmid_nodes.iter().cloned().zip(0..).collect();
+            (Account::Fragmented(holders, layout), fragments)
+        } else {
+            (Account::new(data.get_type_tag(), holders, data.value().len() as u64),
+             vec![data.clone(); target_pmid_nodes.len()])
+        };
+        let _ = self.accounts.insert(data_name, account);
 
         // Send the message on to the PmidNodes' managers.
-        for pmid_node in target_pmid_nodes {
+        for (pmid_node, copy) in target_pmid_nodes.into_iter().zip(copies) {
             let src = Authority::NaeManager(data_name);
-            let dst = Authority::NodeManager(*pmid_node.name());
-            let _ = routing_node.send_put_request(src,
-                                                  dst,
-                                                  Data::Immutable(data.clone()),
-                                                  *message_id);
+            let dst = Authority::NodeManager(pmid_node);
+            let _ = routing_node.send_put_request(src, dst, Data::Immutable(copy), *message_id);
         }
 
         // If this is a "Normal" copy, we need to Put the "Backup" and "Sacrificial" copies too.
@@ -384,26 +838,40 @@ impl ImmutableDataManager {
                               routing_node: &RoutingNode,
                               response: &ResponseMessage)
                               -> Result<(), InternalError> {
+        let now = self.clock.now();
         let data_name;
         let message_id;
+        let cached_data;
+        let mut latency = None;
         {
-            let (data, metadata) = try!(self.find_ongoing_get_after_success(response));
-            data_name = data.name();
+            let (found_name, data, metadata) = try!(self.find_ongoing_get_after_success(response));
+            data_name = found_name;
             message_id = metadata.message_id;
+            // A holder of an erasure-coded chunk only sends a fragment of it
+            let data = if let Authority::ManagedNode(ref pmid_node) = response.src {
+                try!(metadata.add_from_holder(pmid_node, data))
+            } else {
+                Some(data)
+            };
 
             // Reply to any unanswered requests
-            while let Some((original_message_id, request)) = metadata.requests.pop() {
-                let src = request.dst.clone();
-                let dst = request.src;
-                trace!("Sending GetSuccess back to {:?}", dst);
-                let _ = routing_node.send_get_success(src,
-                                                      dst,
-                                                      Data::Immutable(data.clone()),
-                                                      original_message_id);
+            if let Some(ref data) = data {
+                while let Some((original_message_id, request)) = metadata.requests.pop() {
+                    let src = request.dst.clone();
+                    let dst = request.src;
+                    trace!("Sending GetSuccess back to {:?}", dst);
+                    let _ = routing_node.send_get_success(src,
+                                                          dst,
+                                                          Data::Immutable(data.clone()),
+                                                          original_message_id);
+                }
             }
 
             // If the src is a PmidNode, mark the responder as "good"
             if let Authority::ManagedNode(_) = response.src {
+                latency = metadata.sent_at
+                                  .remove(response.src.name())
+                                  .map(|sent_at| now - sent_at);
                 let predicate = |elt: &DataHolder| {
                     match *elt {
                         DataHolder::Pending(ref name) => name == response.src.name(),
@@ -420,10 +888,18 @@ impl ImmutableDataManager {
 
             // Keep the data with the cached metadata in case further get requests arrive for it
             if metadata.data.is_none() {
-                metadata.data = Some(data);
+                metadata.data = data.clone();
             }
+            cached_data = data;
             trace!("Metadata for Get {} updated to {:?}", data_name, metadata);
         }
+        if let Some(data) = cached_data {
+            self.data_cache.insert(data);
+            let _ = self.hedges.remove(&data_name);
+        }
+        if let Some(latency) = latency {
+            self.holder_history.record_success(response.src.name(), latency);
+        }
 
         self.check_and_replicate_after_get(routing_node, &data_name, &message_id)
     }
@@ -453,27 +929,29 @@ impl ImmutableDataManager {
                                                                 .name());
                 metadata.pmid_nodes.push(failed_holder);
             }
+            let _ = metadata.sent_at.remove(pmid_node);
             trace!("Metadata for Get {} updated to {:?}", data_name, metadata);
             data_name
         } else {
-            if let RequestContent::Get(ref data_request, _) = request.content {
-                data_request.name()
+            if let RequestContent::Get(..) = request.content {
+                *request.src.name()
             } else {
                 return Err(InternalError::InvalidResponse);
             }
         };
+        self.holder_history.record_failure(pmid_node);
 
         // Mark the responder as "failed" in the account if it was previously marked "good"
         if let Some(account) = self.accounts.get_mut(&data_name) {
             if account.pmid_nodes_mut().remove(&DataHolder::Good(*pmid_node)) {
-                // Notify the failed PN's managers
-                let src = Authority::NaeManager(data_name);
-                let dst = Authority::NodeManager(*pmid_node);
-                let _ = routing_node.send_post_request(src,
-                                                       dst,
-                                                       Data::Plain(PlainData::new(data_name,
-                                                                                  vec![])),
-                                                       *message_id);
+                // Notify the failed PN's managers, so they can charge it for what it lost
+                let message = VaultMessage::ChunkLost { size: account.copy_size() };
+                if let Ok(serialised_message) = serialisation::serialise(&message) {
+                    let src = Authority::NaeManager(data_name);
+                    let dst = Authority::NodeManager(*pmid_node);
+                    let data = Data::Plain(PlainData::new(data_name, serialised_message));
+                    let _ = routing_node.send_post_request(src, dst, data, *message_id);
+                }
                 account.pmid_nodes_mut().insert(DataHolder::Failed(*pmid_node));
             }
             trace!("Account for {} updated to {:?}", data_name, account);
@@ -511,6 +989,8 @@ impl ImmutableDataManager {
         }
 
         if let Some(data_name) = to_remove {
+            error!("Chunk lost - no holder or other location could provide {}", data_name);
+            self.repair_stats.unrecoverable += 1;
             let _ = self.ongoing_gets.remove(&data_name);
         }
         Ok(())
@@ -518,26 +998,32 @@ impl ImmutableDataManager {
 
     pub fn handle_put_success(&mut self,
                               pmid_node: &XorName,
-                              _data_name: &XorName,
+                              data_name: &XorName,
                               message_id: &MessageId)
                               -> Result<(), InternalError> {
         let mut replicants_stored = 0;
+        let required_holders;
 
-        let entry = if let Some(entry) = self.ongoing_puts
-                                             .iter()
-                                             .find(|&entry| entry.1 == *message_id) {
+        // For an erasure-coded chunk, `data_name` is the name of the fragment stored.
+        let stored = if let Some(replication) = self.replications.remove(data_name, pmid_node) {
+            Some(replication.chunk_name)
+        } else {
+            self.ongoing_puts.data_name(message_id)
+        };
+        let data_name = if let Some(data_name) = stored {
             // TODO: Check that the data_name is correct.
-            if let Some(account) = self.accounts.get_mut(&entry.0.name()) {
+            if let Some(account) = self.accounts.get_mut(&data_name) {
                 if !account.pmid_nodes_mut().remove(&DataHolder::Pending(*pmid_node)) {
                     return Err(InternalError::InvalidResponse);
                 }
                 account.pmid_nodes_mut().insert(DataHolder::Good(*pmid_node));
+                required_holders = account.required_holders();
                 for node in account.pmid_nodes().iter() {
                     if let DataHolder::Good(_) = *node {
                         replicants_stored += 1;
                     }
                 }
-                entry.clone()
+                data_name
             } else {
                 return Err(InternalError::InvalidResponse);
             }
@@ -545,8 +1031,8 @@ impl ImmutableDataManager {
             return Err(InternalError::FailedToFindCachedRequest(*message_id));
         };
 
-        if replicants_stored >= REPLICANTS {
-            let _ = self.ongoing_puts.remove(&entry);
+        if replicants_stored >= required_holders {
+            self.ongoing_puts.remove(&data_name);
         }
 
         Ok(())
@@ -555,11 +1041,19 @@ impl ImmutableDataManager {
     pub fn handle_put_failure(&mut self,
                               routing_node: &RoutingNode,
                               pmid_node: &XorName,
+                              data_name: &XorName,
                               message_id: &MessageId)
                               -> Result<(), InternalError> {
-        if let Some(&(ref immutable_data, _)) = self.ongoing_puts
-                                                    .iter()
-                                                    .find(|&entry| entry.1 == *message_id) {
+        if let Some(replication) = self.replications.remove(data_name, pmid_node) {
+            self.retry_replication(routing_node, replication);
+            return Ok(());
+        }
+
+        let immutable_data = match self.ongoing_puts.data_name(message_id) {
+            Some(data_name) => self.ongoing_puts.get(&data_name),
+            None => None,
+        };
+        if let Some(immutable_data) = immutable_data {
             if let Some(account) = self.accounts.get_mut(&immutable_data.name()) {
                 // Mark the holder as Failed
                 if !account.pmid_nodes_mut().remove(&DataHolder::Pending(*pmid_node)) {
@@ -575,11 +1069,14 @@ impl ImmutableDataManager {
                             !account.pmid_nodes().iter().any(|exclude| elt == exclude.name())
                         });
                         if let Some(new_holder) = target_pmid_nodes.iter().next() {
+                            // The new holder takes over the failed one's fragment, if any
+                            let index = account.fragment_index(pmid_node);
+                            let copy = try!(account.copy_for(&immutable_data, index));
                             let src = Authority::NaeManager(immutable_data.name());
                             let dst = Authority::NodeManager(*new_holder);
-                            let data = Data::Immutable(immutable_data.clone());
+                            let data = Data::Immutable(copy);
                             let _ = routing_node.send_put_request(src, dst, data, *message_id);
-                            account.pmid_nodes_mut().insert(DataHolder::Pending(*new_holder));
+                            account.add_pending(*new_holder, index);
                         } else {
                             warn!("Failed to find a new storage node for {}.", data_name);
                             return Err(InternalError::UnableToAllocateNewPmidNode);
@@ -597,7 +1094,288 @@ impl ImmutableDataManager {
         Ok(())
     }
 
+    // A PmidNode which is reducing its storage allowance, or is no longer in the chunk's close
+    // group, hands its chunks back to us.  Stop treating it as a holder and store the copy it sent
+    // on a new one, then tell it once the copy is stored so it can delete its own.  A holder of an
+    // erasure-coded chunk hands back its fragment, which is stored like any other replication.
+    pub fn handle_post(&mut self,
+                       routing_node: &RoutingNode,
+                       request: &RequestMessage)
+                       -> Result<(), InternalError> {
+        let (data, message_id) = if let RequestContent::Post(Data::Immutable(ref data),
+                                                             ref message_id) = request.content {
+            (data, message_id)
+        } else {
+            return Err(InternalError::InvalidMessage);
+        };
+        let pmid_node = *request.src.name();
+        let data_name = *request.dst.name();
+        let (new_holder, index) = {
+            let account = if let Some(account) = self.accounts.get_mut(&data_name) {
+                account
+            } else {
+                return Err(InternalError::NotInCloseGroup);
+            };
+            let index = try!(account.copy_index(&data_name, &data.name()));
+            if account.fragment_index(&pmid_node) != index ||
+               (!account.pmid_nodes_mut().remove(&DataHolder::Good(pmid_node)) &&
+                !account.pmid_nodes_mut().remove(&DataHolder::Pending(pmid_node))) {
+                trace!("{} handed off {} but isn't one of its holders.",
+                       pmid_node,
+                       data.name());
+                if Self::new_replicants_count(account) == 0 {
+                    Self::confirm_hand_off(routing_node, &pmid_node, &data_name, &data.name());
+                }
+                return Ok(());
+            }
+            account.pmid_nodes_mut().insert(DataHolder::Failed(pmid_node));
+            if Self::new_replicants_count(account) == 0 {
+                Self::confirm_hand_off(routing_node, &pmid_node, &data_name, &data.name());
+                return Ok(());
+            }
+
+            let close_group = if let Some(group) = try!(routing_node.close_group(data_name)) {
+                group
+            } else {
+                return Err(InternalError::NotInCloseGroup);
+            };
+            let new_holder = close_group.into_iter().find(|group_member| {
+                !account.pmid_nodes().iter().any(|holder| holder.name() == group_member)
+            });
+            if let Some(new_holder) = new_holder {
+                (new_holder, index)
+            } else {
+                warn!("Failed to find a new storage node for {}.", data_name);
+                return Err(InternalError::UnableToAllocateNewPmidNode);
+            }
+        };
+
+        trace!("ImmutableDataManager replacing {} with {} as holder of {}",
+               pmid_node,
+               new_holder,
+               data.name());
+        if index.is_some() {
+            // A fragment isn't the chunk itself, so is replicated rather than held as an ongoing
+            // put.  If the queue is full, the fragment is repaired once the departing holder has
+            // deleted it.
+            let now = self.clock.now();
+            if !self.replications.push(routing_node,
+                                       data_name,
+                                       data.clone(),
+                                       new_holder,
+                                       *message_id,
+                                       now) {
+                warn!("Replication queue full - {} will be repaired once {} has gone",
+                      data.name(),
+                      pmid_node);
+                return Ok(());
+            }
+        } else {
+            // Hold on to the data until the new holder has it.  If that's over budget, the put
+            // isn't sent at all; the departing holder keeps its copy for a while, and the chunk
+            // is repaired once that's gone.
+            try!(self.ongoing_puts.insert(data, *message_id));
+            let src = Authority::NaeManager(data_name);
+            let dst = Authority::NodeManager(new_holder);
+            let _ = routing_node.send_put_request(src,
+                                                  dst,
+                                                  Data::Immutable(data.clone()),
+                                                  *message_id);
+        }
+        if let Some(account) = self.accounts.get_mut(&data_name) {
+            account.add_pending(new_holder, index);
+        }
+        let _ = self.hand_offs.insert(*message_id, (pmid_node, data_name));
+        Ok(())
+    }
+
+    // Once a handed off chunk, or its fragment named `name`, is stored on its new holder, tells the
+    // PmidNode which handed it off.
+    pub fn handle_hand_off_stored(&mut self,
+                                  routing_node: &RoutingNode,
+                                  name: &XorName,
+                                  message_id: &MessageId) {
+        if let Some((pmid_node, data_name)) = self.hand_offs.remove(message_id) {
+            Self::confirm_hand_off(routing_node, &pmid_node, &data_name, name);
+        }
+    }
+
+    // A PmidNode which has (re)joined the network announces that it still holds the chunk
+    // `data_name`, or its fragment named `name`.  If that is short of good holders and the node is
+    // in the chunk's close group, count it as a good holder again; if there are already enough,
+    // tell the node to delete its copy.  Pending holders don't count, as they may yet fail.
+    pub fn handle_chunk_held(&mut self,
+                             routing_node: &RoutingNode,
+                             pmid_node: XorName,
+                             data_name: XorName,
+                             name: XorName) {
+        let in_close_group = match routing_node.close_group(data_name) {
+            Ok(Some(close_group)) => close_group.contains(&pmid_node),
+            _ => false,
+        };
+        let not_needed = if let Some(account) = self.accounts.get_mut(&data_name) {
+            let index = if let Ok(index) = account.copy_index(&data_name, &name) {
+                index
+            } else {
+                trace!("{} announced {} which isn't a copy of {}.",
+                       pmid_node,
+                       name,
+                       data_name);
+                return;
+            };
+            if account.pmid_nodes().contains(&DataHolder::Good(pmid_node)) ||
+               account.pmid_nodes().contains(&DataHolder::Pending(pmid_node)) {
+                return;
+            }
+            let good_holders = account.pmid_nodes()
+                                      .iter()
+                                      .filter_map(|holder| {
+                                          match *holder {
+                                              DataHolder::Good(holder_name) => Some(holder_name),
+                                              _ => None,
+                                          }
+                                      })
+                                      .collect::<Vec<_>>();
+            let needed = match index {
+                Some(index) => {
+                    good_holders.iter().all(|holder| account.fragment_index(holder) != Some(index))
+                }
+                None => good_holders.len() < REPLICANTS,
+            };
+            if !needed {
+                true
+            } else if in_close_group {
+                trace!("ImmutableDataManager restoring {} as holder of {}",
+                       pmid_node,
+                       name);
+                let _ = account.pmid_nodes_mut().remove(&DataHolder::Failed(pmid_node));
+                account.add_holder(DataHolder::Good(pmid_node), index);
+                false
+            } else {
+                false
+            }
+        } else {
+            trace!("{} announced {} which has no account here.", pmid_node, data_name);
+            return;
+        };
+        if not_needed {
+            Self::post_to_holder(routing_node,
+                                 &pmid_node,
+                                 &data_name,
+                                 &name,
+                                 &VaultMessage::ChunkNotNeeded);
+        }
+    }
+
+    // Posts from a PmidManager report a failed storage challenge, or share the PmidNode's
+    // reputation or capacity.
+    pub fn handle_pmid_manager_post(&mut self,
+                                    routing_node: &RoutingNode,
+                                    request: &RequestMessage)
+                                    -> Result<(), InternalError> {
+        let (data, message_id) = if let RequestContent::Post(Data::Plain(ref data),
+                                                             ref message_id) = request.content {
+            (data, message_id)
+        } else {
+            return Err(InternalError::InvalidMessage);
+        };
+        let pmid_node = *request.src.name();
+        match try!(serialisation::deserialise(data.value())) {
+            VaultMessage::FailedStorageChallenge => {
+                self.handle_failed_challenge(routing_node, pmid_node, data.name(), message_id)
+            }
+            VaultMessage::PmidNodeReputation(reputation) => {
+                Ok(self.handle_reputation(pmid_node, reputation))
+            }
+            VaultMessage::CapacityReport { free, .. } => {
+                let _ = self.free_space.insert(pmid_node, Some(free));
+                Ok(())
+            }
+            _ => Err(InternalError::InvalidMessage),
+        }
+    }
+
+    // A PmidManager reports that a holder failed to prove it still holds the chunk.  Stop treating
+    // it as a holder, and fetch the chunk from the others to store it on a new one.
+    pub fn handle_failed_challenge(&mut self,
+                                   routing_node: &RoutingNode,
+                                   pmid_node: XorName,
+                                   data_name: XorName,
+                                   message_id: &MessageId)
+                                   -> Result<(), InternalError> {
+        let entry = {
+            let account = if let Some(account) = self.accounts.get_mut(&data_name) {
+                account
+            } else {
+                return Err(InternalError::NotInCloseGroup);
+            };
+            if !account.pmid_nodes_mut().remove(&DataHolder::Good(pmid_node)) &&
+               !account.pmid_nodes_mut().remove(&DataHolder::Pending(pmid_node)) {
+                trace!("{} failed a storage challenge for {} but isn't one of its holders.",
+                       pmid_node,
+                       data_name);
+                return Ok(());
+            }
+            account.pmid_nodes_mut().insert(DataHolder::Failed(pmid_node));
+            if Self::new_replicants_count(account) == 0 || self.ongoing_puts.contains(&data_name) {
+                return Ok(());
+            }
+            MetadataForGetRequest::new(message_id, account, self.clock.now())
+        };
+
+        if let Some(metadata) = self.ongoing_gets.get_mut(&data_name) {
+            // The Get already under way will replicate the chunk if need be, as long as it doesn't
+            // count the failed holder as good.
+            metadata.unqueried.retain(|holder| *holder != pmid_node);
+            for holder in &mut metadata.pmid_nodes {
+                if *holder.name() == pmid_node {
+                    *holder = DataHolder::Failed(pmid_node);
+                }
+            }
+            return Ok(());
+        }
+        if self.ongoing_gets.insert(data_name, entry).is_some() {
+            warn!("Too many ongoing Gets to replace {} as holder of {}",
+                  pmid_node,
+                  data_name);
+            return Ok(());
+        }
+        self.ask_holders(routing_node, &data_name, message_id);
+        // Keep the failed holder from being chosen again when the chunk is replicated.
+        if let Some(metadata) = self.ongoing_gets.get_mut(&data_name) {
+            metadata.pmid_nodes.push(DataHolder::Failed(pmid_node));
+        }
+        Ok(())
+    }
+
+    pub fn handle_reputation(&mut self, pmid_node: XorName, reputation: u8) {
+        if reputation < MISBEHAVING_REPUTATION {
+            trace!("Won't store new chunks on misbehaving PmidNode {}.", pmid_node);
+        }
+        let _ = self.reputations.insert(pmid_node, cmp::min(reputation, MAX_REPUTATION));
+    }
+
     pub fn check_timeout(&mut self, routing_node: &RoutingNode) {
+        for data_name in self.ongoing_puts.remove_expired() {
+            warn!("Gave up waiting for enough holders to store {}.", data_name);
+        }
+        for pmid_node in self.free_space.get_expired() {
+            let _ = self.free_space.remove(&pmid_node);
+        }
+        for message_id in self.hand_offs.get_expired() {
+            let _ = self.hand_offs.remove(&message_id);
+        }
+        trace!("ImmutableDataManager cache: {:?}", self.data_cache.stats());
+        trace!("ImmutableDataManager repairs: {:?}", self.repair_stats);
+        let now = self.clock.now();
+        for replication in self.replications.remove_timed_out(now) {
+            warn!("PmidNode {} failed to reply to Put request for {}.",
+                  replication.pmid_node,
+                  replication.chunk_name);
+            self.retry_replication(routing_node, replication);
+        }
+        self.replications.send_due(routing_node, now);
+        self.send_hedged_gets(routing_node);
         for data_name in &self.ongoing_gets.get_expired() {
             let message_id;
             {
@@ -613,6 +1391,7 @@ impl ImmutableDataManager {
                               data_name);
                         // Mark it as failed in the cache
                         *pmid_node = DataHolder::Failed(name);
+                        self.holder_history.record_failure(&name);
                         // Mark it as "failed" in the account if it was previously marked "good"
                         if let Some(account) = self.accounts.get_mut(data_name) {
                             if account.pmid_nodes_mut().remove(&DataHolder::Good(name)) {
@@ -626,6 +1405,7 @@ impl ImmutableDataManager {
             // let _ = self.ongoing_gets.insert(*data_name, metadata);
             let _ = self.check_and_replicate_after_get(routing_node, data_name, &message_id);
         }
+        self.repair(routing_node);
     }
 
     pub fn handle_refresh(&mut self, data_name: XorName, account: Account) {
@@ -633,11 +1413,14 @@ impl ImmutableDataManager {
     }
 
     pub fn handle_node_added(&mut self, routing_node: &RoutingNode, node_added: &XorName) {
-        self.handle_churn(routing_node, MessageId::from_added_node(*node_added));
+        self.handle_churn(routing_node, node_added, MessageId::from_added_node(*node_added));
     }
 
     pub fn handle_node_lost(&mut self, routing_node: &RoutingNode, node_lost: &XorName) {
-        self.handle_churn(routing_node, MessageId::from_lost_node(*node_lost));
+        self.holder_history.forget(node_lost);
+        let _ = self.reputations.remove(node_lost);
+        let _ = self.free_space.remove(node_lost);
+        self.handle_churn(routing_node, node_lost, MessageId::from_lost_node(*node_lost));
     }
 
     // This is used when handling Get responses since we don't know the data name of the original
@@ -654,13 +1437,13 @@ impl ImmutableDataManager {
     fn find_ongoing_get_after_success
         (&mut self,
          response: &ResponseMessage)
-         -> Result<(ImmutableData, &mut MetadataForGetRequest), InternalError> {
+         -> Result<(XorName, ImmutableData, &mut MetadataForGetRequest), InternalError> {
         let (data, message_id) = if let ResponseContent::GetSuccess(Data::Immutable(ref data),
                                                                     ref message_id) =
                                         response.content {
             (data, message_id)
         } else {
-            unreachable!("Error in vault demuxing")
+            return Err(InternalError::InvalidMessage);
         };
         let data_name = data.name();
         if let Authority::NaeManager(_) = response.src {
@@ -705,11 +1488,13 @@ impl ImmutableDataManager {
             }
             if let Some((found_name, converted_data)) = found {
                 let metadata = self.ongoing_gets.get_mut(&found_name).expect("Must exist");
-                return Ok((converted_data, metadata));
+                return Ok((found_name, converted_data, metadata));
             }
         } else {
-            if let Some(metadata) = self.ongoing_gets.get_mut(&data_name) {
-                return Ok((data.clone(), metadata));
+            // The data may be a fragment of the chunk, so look up the chunk we asked for instead.
+            let chunk_name = *response.dst.name();
+            if let Some(metadata) = self.ongoing_gets.get_mut(&chunk_name) {
+                return Ok((chunk_name, data.clone(), metadata));
             }
         }
         warn!("Failed to find metadata for Get response of {} with msg ID {:?}",
@@ -751,7 +1536,11 @@ impl ImmutableDataManager {
                      Some(routing::sacrificial_to_backup(data_name)),
                      None)
                 }
-                _ => unreachable!(),  // Safe to use this here since response is from a group
+                _ => {
+                    warn!("Request type doesn't correspond to response type: {:?}",
+                          request);
+                    return Err(InternalError::InvalidResponse);
+                }
             };
             if let Some(normal_name) = normal_name {
                 if self.ongoing_gets.contains_key(&normal_name) {
@@ -773,8 +1562,10 @@ impl ImmutableDataManager {
                 return Ok((found_name, metadata));
             }
         } else {
-            if let Some(metadata) = self.ongoing_gets.get_mut(&data_request.name()) {
-                return Ok((data_request.name(), metadata));
+            // We may have asked for a fragment of the chunk, so look up the chunk instead.
+            let chunk_name = *request.src.name();
+            if let Some(metadata) = self.ongoing_gets.get_mut(&chunk_name) {
+                return Ok((chunk_name, metadata));
             }
         }
         warn!("Failed to find metadata for Get response of {} with msg ID {:?}",
@@ -783,17 +1574,39 @@ impl ImmutableDataManager {
         Err(InternalError::FailedToFindCachedRequest(*message_id))
     }
 
-    fn handle_churn(&mut self, routing_node: &RoutingNode, message_id: MessageId) {
-        // Only retain accounts for which we're still in the close group
-        let accounts = mem::replace(&mut self.accounts, HashMap::new());
-        self.accounts = accounts.into_iter()
-                                .filter_map(|(data_name, mut account)| {
-                                    self.handle_churn_for_account(routing_node,
-                                                                  &data_name,
-                                                                  &message_id,
-                                                                  &mut account)
-                                })
-                                .collect();
+    // Queues the accounts whose close group could have gained or lost `node_changed`; the others
+    // are unaffected.
+    fn handle_churn(&mut self,
+                    routing_node: &RoutingNode,
+                    node_changed: &XorName,
+                    message_id: MessageId) {
+        let neighbourhood = Neighbourhood::new(routing_node, node_changed);
+        for data_name in self.accounts.names_in(&neighbourhood) {
+            self.churn_queue.push(data_name, message_id);
+        }
+        let _ = self.process_churn(routing_node);
+    }
+
+    // Checks the next slice of accounts queued by churn, only retaining those for which we're still
+    // in the close group.  Returns whether any remain to be checked.
+    fn process_churn(&mut self, routing_node: &RoutingNode) -> bool {
+        for _ in 0..CHURN_SLICE_SIZE {
+            let (data_name, message_id) = match self.churn_queue.pop() {
+                Some(entry) => entry,
+                None => return false,
+            };
+            let mut account = match self.accounts.remove(&data_name) {
+                Some(account) => account,
+                None => continue,
+            };
+            if let Some((data_name, account)) = self.handle_churn_for_account(routing_node,
+                                                                              &data_name,
+                                                                              &message_id,
+                                                                              &mut account) {
+                let _ = self.accounts.insert(data_name, account);
+            }
+        }
+        !self.churn_queue.is_empty()
     }
 
     fn handle_churn_for_account(&mut self,
@@ -810,10 +1623,8 @@ impl ImmutableDataManager {
         } else {
             trace!("no longer part of the IDM group");
             // Remove entry from `ongoing_puts`, as we're not part of the IDM group any more
-            let ongoing_puts = mem::replace(&mut self.ongoing_puts, HashSet::new());
-            self.ongoing_puts = ongoing_puts.into_iter()
-                                            .filter(|&(ref data, _)| data.name() != *data_name)
-                                            .collect();
+            self.ongoing_puts.remove(data_name);
+            self.replications.remove_data(data_name);
             return None;
         };
 
@@ -824,10 +1635,11 @@ impl ImmutableDataManager {
                                            })
                                            .cloned()
                                            .collect();
+        account.forget_departed_holders();
         trace!("Churning for {} - holders after: {:?}", data_name, account);
         if account.pmid_nodes().is_empty() {
-            error!("Chunk lost - No valid nodes left to retrieve chunk");
-            return None;
+            warn!("No holders left for {} - recovering it from the other locations",
+                  data_name);
         }
 
         // Check to see if the chunk should be replicated
@@ -840,14 +1652,16 @@ impl ImmutableDataManager {
                                                    data_name,
                                                    message_id,
                                                    account,
-                                                   &close_group,
-                                                   new_replicants_count) &&
+                                                   &close_group) &&
                !self.handle_churn_for_ongoing_gets(data_name, &close_group) {
                 // Create a new entry and send Get requests to each of the current holders
-                let entry = MetadataForGetRequest::new(message_id, &account);
+                let entry = MetadataForGetRequest::new(message_id, &account, self.clock.now());
                 trace!("Created ongoing get entry for {} - {:?}", data_name, entry);
-                entry.send_get_requests(routing_node, data_name, *message_id);
-                let _ = self.ongoing_gets.insert(*data_name, entry);
+                if self.ongoing_gets.insert(*data_name, entry).is_some() {
+                    warn!("Too many ongoing Gets to check the holders of {}", data_name);
+                } else {
+                    self.ask_holders(routing_node, data_name, message_id);
+                }
             }
         }
 
@@ -873,6 +1687,9 @@ impl ImmutableDataManager {
     }
 
     fn new_replicants_count(account: &Account) -> usize {
+        if account.layout().is_some() {
+            return account.missing_fragments().len();
+        }
         let mut holder_count = 0;
         for pmid_node in account.pmid_nodes() {
             match *pmid_node {
@@ -893,46 +1710,78 @@ impl ImmutableDataManager {
                                      data_name: &XorName,
                                      message_id: &MessageId,
                                      account: &mut Account,
-                                     close_group: &[XorName],
-                                     mut new_replicants_count: usize)
+                                     close_group: &[XorName])
                                      -> bool {
-        let entries = self.ongoing_puts
-                          .iter()
-                          .filter(|&entry| entry.0.name() == *data_name)
-                          .cloned()
-                          .collect::<Vec<_>>();
-        if entries.is_empty() {
+        if !self.ongoing_puts.contains(data_name) {
             return false;
         }
+        let data = if let Some(data) = self.ongoing_puts.get(data_name) {
+            data
+        } else {
+            // The data couldn't be read back, so fall back to getting it from the holders
+            return false;
+        };
 
-        for entry in &entries {
-            // We have an entry in the `ongoing_puts`, so replicate to new peers
-            for group_member in close_group {
-                if account.pmid_nodes().iter().any(|&pmid_node| pmid_node.name() == group_member) {
-                    // This is already a holder - skip
-                    continue;
-                }
-                trace!("Replicating {} - sending Put to {}",
-                       data_name,
-                       group_member);
-                let src = Authority::NaeManager(*data_name);
-                let dst = Authority::NodeManager(*group_member);
-                let _ = routing_node.send_put_request(src,
-                                                      dst,
-                                                      Data::Immutable(entry.0.clone()),
-                                                      *message_id);
-                account.pmid_nodes_mut().insert(DataHolder::Pending(*group_member));
-                new_replicants_count -= 1;
-                if new_replicants_count == 0 {
-                    return true;
-                }
+        // We have an entry in the `ongoing_puts`, so replicate to new peers
+        let copies = match Self::copies_needed(account, &data) {
+            Ok(copies) => copies,
+            Err(error) => {
+                warn!("Failed to split {} into fragments: {:?}", data_name, error);
+                return true;
+            }
+        };
+        let mut copies = copies.into_iter();
+        let now = self.clock.now();
+        for group_member in close_group {
+            if account.pmid_nodes().iter().any(|&pmid_node| pmid_node.name() == group_member) {
+                // This is already a holder - skip
+                continue;
+            }
+            let (index, copy) = if let Some(copy) = copies.next() {
+                copy
+            } else {
+                return true;
+            };
+            let queued = self.replications.push(routing_node,
+                                                *data_name,
+                                                copy,
+                                                *group_member,
+                                                *message_id,
+                                                now);
+            if !queued {
+                // Rather than dropping the copies still needed, leave the caller to fetch the
+                // chunk and replicate it once the queue has room.
+                warn!("Replication queue full - {} will be replicated after fetching it",
+                      data_name);
+                return false;
             }
+            account.add_pending(*group_member, index);
+        }
+        if copies.next().is_some() {
             warn!("Failed to find a new close group member to replicate {} to",
                   data_name);
         }
         true
     }
 
+    // The copies needed to bring the chunk back up to strength, each with the index of the
+    // fragment it is if the chunk is erasure-coded.  Otherwise they're all whole copies.
+    fn copies_needed(account: &Account,
+                     data: &ImmutableData)
+                     -> Result<Vec<(Option<usize>, ImmutableData)>, InternalError> {
+        if let Some(layout) = account.layout() {
+            let fragments = try!(layout.fragments(data));
+            Ok(account.missing_fragments()
+                      .into_iter()
+                      .filter_map(|index| {
+                          fragments.get(index).map(|fragment| (Some(index), fragment.clone()))
+                      })
+                      .collect())
+        } else {
+            Ok(vec![(None, data.clone()); Self::new_replicants_count(account)])
+        }
+    }
+
     fn handle_churn_for_ongoing_gets(&mut self,
                                      data_name: &XorName,
                                      close_group: &[XorName])
@@ -942,6 +1791,7 @@ impl ImmutableDataManager {
             // Remove any holders which no longer belong in the cache entry
             metadata.pmid_nodes
                     .retain(|pmid_node| close_group.contains(pmid_node.name()));
+            metadata.unqueried.retain(|pmid_node| close_group.contains(pmid_node));
             trace!("Updated ongoing get for {} to {:?}", data_name, metadata);
             true
         } else {
@@ -949,6 +1799,9 @@ impl ImmutableDataManager {
         }
     }
 
+    // Refreshes the account, along with the reputations of any of its holders without a clean
+    // record.  Those are sent separately, since members who have heard from their PmidManagers at
+    // different times can disagree on them, which mustn't stop the account itself accumulating.
     fn send_refresh(&self,
                     routing_node: &RoutingNode,
                     data_name: &XorName,
@@ -965,10 +1818,167 @@ impl ImmutableDataManager {
                                                       serialised_refresh,
                                                       *message_id);
         }
+        let serialised_id = if let Ok(serialised_id) = serialisation::serialise(message_id) {
+            serialised_id
+        } else {
+            return;
+        };
+        for holder in account.pmid_nodes() {
+            let reputation = self.reputation(holder.name());
+            if reputation == MAX_REPUTATION {
+                continue;
+            }
+            let refresh = Refresh::new(data_name,
+                                       RefreshValue::PmidNodeReputation(*holder.name(),
+                                                                        reputation));
+            let reputation_id = utils::message_id(&[&serialised_id[..],
+                                                    &holder.name().0[..],
+                                                    &[reputation][..]]);
+            if let Ok(serialised_refresh) = serialisation::serialise(&refresh) {
+                let _ = routing_node.send_refresh_request(src.clone(),
+                                                          src.clone(),
+                                                          serialised_refresh,
+                                                          reputation_id);
+            }
+        }
     }
 
-    fn reply_with_data_else_cache_request(routing_node: &RoutingNode,
-                                          request: &RequestMessage,
+    // Asks the closest to the data of the holders not yet queried for it, or all of them if hedging
+    // is disabled, and schedules asking the next one if it doesn't answer in time.  Every member of
+    // our group asks the holders in the same order, so that their Gets accumulate; only how long
+    // each member waits for a holder depends on its own history of them.
+    fn ask_holders(&mut self,
+                   routing_node: &RoutingNode,
+                   data_name: &XorName,
+                   message_id: &MessageId) {
+        let now = self.clock.now();
+        let hedging = self.hedge_delay > Duration::zero();
+        let mut patience = Duration::zero();
+        let more_to_ask = if let Some(metadata) = self.ongoing_gets.get_mut(data_name) {
+            metadata.unqueried
+                    .sort_by(|lhs, rhs| neighbourhood::cmp_distance(data_name, lhs, rhs));
+            let holder_count = if hedging {
+                metadata.holders_needed()
+            } else {
+                metadata.unqueried.len()
+            };
+            for pmid_node in metadata.unqueried.iter().take(holder_count) {
+                patience = cmp::max(patience,
+                                    self.holder_history.patience(pmid_node, self.hedge_delay));
+            }
+            metadata.send_get_requests(routing_node, data_name, *message_id, holder_count, now);
+            metadata.data.is_none() && !metadata.unqueried.is_empty()
+        } else {
+            false
+        };
+        if hedging && more_to_ask {
+            let _ = self.hedges.insert(*data_name, now + patience);
+        } else {
+            let _ = self.hedges.remove(data_name);
+        }
+    }
+
+    // Once per wall-clock repair interval, starts retrieving the chunks which have fewer than
+    // `REPLICANTS` holders not known to have failed, so that they can be stored on new ones.
+    // Chunks with no good holders left are recovered from their other locations.  Each pass
+    // carries on in name order from where the last one stopped, so that every chunk gets its turn.
+    fn repair(&mut self, routing_node: &RoutingNode) {
+        let epoch = Self::repair_epoch(&self.clock);
+        if epoch == self.repair_epoch {
+            return;
+        }
+        self.repair_epoch = epoch;
+        let now = self.clock.now();
+        let mut candidates = self.accounts
+                                 .iter()
+                                 .filter(|&(data_name, account)| {
+                                     Self::new_replicants_count(account) > 0 &&
+                                     !self.ongoing_puts.contains(data_name) &&
+                                     !self.ongoing_gets.contains_key(data_name)
+                                 })
+                                 .map(|(data_name, _)| *data_name)
+                                 .collect::<Vec<_>>();
+        candidates.sort();
+        let start = match self.repair_cursor {
+            Some(ref cursor) => candidates.iter().position(|name| name > cursor).unwrap_or(0),
+            None => 0,
+        };
+        let under_replicated = candidates[start..]
+                                   .iter()
+                                   .chain(candidates[..start].iter())
+                                   .take(MAX_REPAIRS_PER_PASS)
+                                   .cloned()
+                                   .collect::<Vec<_>>();
+        let pass = utils::u64_bytes(epoch);
+        for data_name in under_replicated {
+            // The whole group runs the pass in the same interval, so derives the same ID for the
+            // Get and its requests accumulate.
+            let message_id = utils::message_id(&[&data_name.0[..], &pass[..]]);
+            let (entry, failed_holders) = match self.accounts.get(&data_name) {
+                Some(account) => {
+                    let failed_holders = account.pmid_nodes()
+                                                .iter()
+                                                .filter(|holder| {
+                                                    match **holder {
+                                                        DataHolder::Failed(_) => true,
+                                                        _ => false,
+                                                    }
+                                                })
+                                                .cloned()
+                                                .collect::<Vec<_>>();
+                    (MetadataForGetRequest::new(&message_id, account, now), failed_holders)
+                }
+                None => continue,
+            };
+            if self.ongoing_gets.insert(data_name, entry).is_some() {
+                warn!("Too many ongoing Gets to repair {}", data_name);
+                break;
+            }
+            trace!("Repairing {} - failed holders: {:?}", data_name, failed_holders);
+            self.repair_cursor = Some(data_name);
+            if let Some(metadata) = self.ongoing_gets.get_mut(&data_name) {
+                metadata.repair = true;
+            }
+            self.ask_holders(routing_node, &data_name, &message_id);
+            // Keep the failed holders from being chosen again when the chunk is replicated.
+            if let Some(metadata) = self.ongoing_gets.get_mut(&data_name) {
+                metadata.pmid_nodes.extend(failed_holders);
+            }
+        }
+    }
+
+    // Which of the wall-clock repair intervals since the epoch it is.  Unlike a count of passes,
+    // every member of the group agrees on it whenever it joined.
+    fn repair_epoch(clock: &Clock) -> u64 {
+        Self::wall_clock_interval(clock, REPAIR_INTERVAL_SECS)
+    }
+
+    // Which of the wall-clock intervals of `interval_secs` since the epoch it is.
+    fn wall_clock_interval(clock: &Clock, interval_secs: i64) -> u64 {
+        cmp::max(clock.wall_time().sec, 0) as u64 / interval_secs as u64
+    }
+
+    // Asks another holder for each chunk whose current holders have been slow to respond.
+    fn send_hedged_gets(&mut self, routing_node: &RoutingNode) {
+        let now = self.clock.now();
+        let due = self.hedges
+                      .iter()
+                      .filter(|&(_, hedge_time)| *hedge_time <= now)
+                      .map(|(data_name, _)| *data_name)
+                      .collect::<Vec<_>>();
+        for data_name in due {
+            let _ = self.hedges.remove(&data_name);
+            let message_id = match self.ongoing_gets.get_mut(&data_name) {
+                Some(ref metadata) if metadata.data.is_none() => metadata.message_id,
+                _ => continue,
+            };
+            trace!("Hedging Get for {}", data_name);
+            self.ask_holders(routing_node, &data_name, &message_id);
+        }
+    }
+
+    fn reply_with_data_else_cache_request(routing_node: &RoutingNode,
+                                          request: &RequestMessage,
                                           message_id: &MessageId,
                                           metadata: &mut MetadataForGetRequest) {
         // If we've already received the chunk, send it to the new requester.  Otherwise add the
@@ -990,12 +2000,16 @@ impl ImmutableDataManager {
                                      data_name: &XorName,
                                      message_id: &MessageId)
                                      -> Result<(), InternalError> {
+        let now = self.clock.now();
         let mut finished = false;
-        let mut new_pmid_nodes = HashSet::<DataHolder>::new();
+        let mut ask_next_holder = false;
+        let mut replication = None;
+        let mut repairing = false;
         if let Some(metadata) = self.ongoing_gets.get_mut(&data_name) {
+            repairing = metadata.repair;
             // Count the good holders, but just return from this function if any queried holders
-            // haven't responded yet
-            let mut good_holder_count = 0;
+            // haven't responded yet.  Holders we didn't need to ask are still deemed good.
+            let mut good_holder_count = metadata.unqueried.len();
             for queried_pmid_node in &metadata.pmid_nodes {
                 match *queried_pmid_node {
                     DataHolder::Pending(_) => return Ok(()),
@@ -1005,40 +2019,86 @@ impl ImmutableDataManager {
             }
             trace!("Have {} good holders for {}", good_holder_count, data_name);
 
-            if good_holder_count >= REPLICANTS {
+            if metadata.data.is_none() && !metadata.unqueried.is_empty() {
+                // Every holder asked so far has failed, so try the next closest one
+                ask_next_holder = true;
+            } else if good_holder_count >= metadata.required_holders() {
                 // We can now delete this cached get request with no need for further action
                 finished = true;
             } else if let Some(ref data) = metadata.data {
-                assert_eq!(*data_name, data.name());
-                // Put to new close peers and delete this cached get request
-                new_pmid_nodes = try!(Self::replicate_after_get(routing_node,
-                                                                data,
-                                                                &metadata.pmid_nodes,
-                                                                message_id));
+                if *data_name != data.name() {
+                    return Err(InternalError::InvalidResponse);
+                }
+                // Put to new close peers and delete this cached get request.  The holders for an
+                // erasure-coded chunk's missing fragments are chosen from its account below.
+                let new_holders = if metadata.layout.is_some() {
+                    None
+                } else {
+                    Some(try!(Self::choose_new_holders(routing_node,
+                                                       data,
+                                                       &metadata.pmid_nodes,
+                                                       &metadata.unqueried)))
+                };
+                replication = Some((data.clone(), new_holders));
                 finished = true;
             } else {
                 // Recover the data from backup and/or sacrificial locations
-                Self::recover_from_other_locations(routing_node, metadata, data_name, message_id);
+                Self::recover_from_other_locations(routing_node,
+                                                   metadata,
+                                                   data_name,
+                                                   message_id,
+                                                   now);
             }
         } else {
             warn!("Failed to find metadata for check_and_replicate_after_get of {}",
                   data_name);
         }
 
+        if ask_next_holder {
+            self.ask_holders(routing_node, data_name, message_id);
+            return Ok(());
+        }
+
         if finished {
             let _ = self.ongoing_gets.remove(data_name);
         }
 
+        let copies = match replication {
+            Some((data, Some(new_holders))) => {
+                new_holders.into_iter()
+                           .map(|new_holder| (new_holder, None, data.clone()))
+                           .collect::<Vec<_>>()
+            }
+            Some((data, None)) => {
+                try!(self.choose_fragment_holders(routing_node, data_name, &data))
+            }
+            None => vec![],
+        };
+        let mut new_pmid_nodes = vec![];
+        for (new_holder, index, copy) in copies {
+            let queued = self.replications
+                             .push(routing_node, *data_name, copy, new_holder, *message_id, now);
+            if queued {
+                new_pmid_nodes.push((new_holder, index));
+            } else {
+                warn!("Too many replications queued to store {} on {}",
+                      data_name,
+                      new_holder);
+            }
+        }
+
         if !new_pmid_nodes.is_empty() {
             trace!("Replicating {} - new holders: {:?}",
                    data_name,
                    new_pmid_nodes);
+            if repairing {
+                self.repair_stats.repaired += 1;
+            }
             if let Some(account) = self.accounts.get_mut(data_name) {
                 trace!("Replicating {} - account before: {:?}", data_name, account);
-                *account.pmid_nodes_mut() = account.pmid_nodes()
-                                                   .union(&new_pmid_nodes)
-                                                   .cloned()
-                                                   .collect();
+                for (new_holder, index) in new_pmid_nodes {
+                    account.add_pending(new_holder, index);
+                }
                 trace!("Replicating {} - account after:  {:?}", data_name, account);
             }
         }
@@ -1046,14 +2106,17 @@ impl ImmutableDataManager {
         Ok(())
     }
 
-    fn replicate_after_get(routing_node: &RoutingNode,
-                           data: &ImmutableData,
-                           queried_pmid_nodes: &[DataHolder],
-                           message_id: &MessageId)
-                           -> Result<HashSet<DataHolder>, InternalError> {
-        let mut good_nodes = HashSet::<DataHolder>::new();
+    // Chooses the close group members to store the data on after a Get, besides its good holders.
+    fn choose_new_holders(routing_node: &RoutingNode,
+                          data: &ImmutableData,
+                          queried_pmid_nodes: &[DataHolder],
+                          unqueried_pmid_nodes: &[XorName])
+                          -> Result<Vec<XorName>, InternalError> {
+        let mut good_nodes = unqueried_pmid_nodes.iter()
+                                                 .cloned()
+                                                 .map(DataHolder::Good)
+                                                 .collect::<HashSet<DataHolder>>();
         let mut nodes_to_exclude = vec![];
-        let mut new_pmid_nodes = HashSet::<DataHolder>::new();
         for queried_pmid_node in queried_pmid_nodes {
             match *queried_pmid_node {
                 DataHolder::Good(ref name) => {
@@ -1086,26 +2149,90 @@ impl ImmutableDataManager {
         trace!("Replicating {} - target nodes: {:?}",
                data_name,
                target_pmid_nodes);
-        for new_pmid_node in target_pmid_nodes.difference(&good_nodes).into_iter() {
-            trace!("Replicating {} - sending Put to {}",
+        Ok(target_pmid_nodes.difference(&good_nodes)
+                            .map(|new_pmid_node| *new_pmid_node.name())
+                            .collect())
+    }
+
+    // Chooses close group members other than the chunk's current and failed holders to store the
+    // missing fragments of an erasure-coded chunk on, and rebuilds those fragments from `data`.
+    fn choose_fragment_holders(&self,
+                               routing_node: &RoutingNode,
+                               data_name: &XorName,
+                               data: &ImmutableData)
+                               -> Result<Vec<(XorName, Option<usize>, ImmutableData)>,
+                                         InternalError> {
+        let account = if let Some(account) = self.accounts.get(data_name) {
+            account
+        } else {
+            return Ok(vec![]);
+        };
+        let close_group = if let Some(group) = try!(routing_node.close_group(*data_name)) {
+            group
+        } else {
+            return Err(InternalError::NotInCloseGroup);
+        };
+        let copies = try!(Self::copies_needed(account, data));
+        let new_holders = close_group.into_iter().filter(|group_member| {
+            !account.pmid_nodes().iter().any(|holder| holder.name() == group_member)
+        });
+        Ok(new_holders.zip(copies)
+                      .map(|(new_holder, (index, copy))| (new_holder, index, copy))
+                      .collect())
+    }
+
+    // Marks the target of a failed replication as failed, and retries the replication on another
+    // close group member unless it has already been tried too often.
+    fn retry_replication(&mut self, routing_node: &RoutingNode, replication: Replication) {
+        let data_name = replication.chunk_name;
+        let close_group = if let Some(group) = self.close_group_to(routing_node, &data_name) {
+            group
+        } else {
+            return;
+        };
+        let account = if let Some(account) = self.accounts.get_mut(&data_name) {
+            account
+        } else {
+            return;
+        };
+        if account.pmid_nodes_mut().remove(&DataHolder::Pending(replication.pmid_node)) {
+            account.pmid_nodes_mut().insert(DataHolder::Failed(replication.pmid_node));
+        }
+        // The new holder takes over the failed one's fragment, if any
+        let index = account.fragment_index(&replication.pmid_node);
+        if replication.attempts() >= MAX_REPLICATION_ATTEMPTS {
+            warn!("Gave up replicating {} after {} attempts.",
+                  data_name,
+                  replication.attempts());
+            return;
+        }
+        let new_holder = close_group.into_iter().find(|group_member| {
+            !account.pmid_nodes().iter().any(|holder| holder.name() == group_member)
+        });
+        if let Some(new_holder) = new_holder {
+            trace!("Retrying replication of {} on {} instead of {}",
                    data_name,
-                   new_pmid_node.name());
-            let src = Authority::NaeManager(data_name);
-            let dst = Authority::NodeManager(*new_pmid_node.name());
-            new_pmid_nodes.insert(*new_pmid_node);
-            let _ = routing_node.send_put_request(src,
-                                                  dst,
-                                                  Data::Immutable(data.clone()),
-                                                  *message_id);
+                   new_holder,
+                   replication.pmid_node);
+            if self.replications.retry(replication, new_holder, self.clock.now()) {
+                account.add_pending(new_holder, index);
+            } else {
+                warn!("Too many replications queued to retry storing {} on {}",
+                      data_name,
+                      new_holder);
+            }
+        } else {
+            warn!("Failed to find a new storage node for {}.", data_name);
         }
-        Ok(new_pmid_nodes)
     }
 
     fn recover_from_other_locations(routing_node: &RoutingNode,
                                     metadata: &mut MetadataForGetRequest,
                                     data_name: &XorName,
-                                    message_id: &MessageId) {
+                                    message_id: &MessageId,
+                                    now: SteadyTime) {
         metadata.pmid_nodes.clear();
+        metadata.unqueried.clear();
         // If this Vault is a Backup or Sacrificial manager just return failure to any requesters
         // waiting for responses.
         match metadata.requested_data_type {
@@ -1115,7 +2242,7 @@ impl ImmutableDataManager {
             }
             _ => (),
         }
-        metadata.send_get_requests(routing_node, data_name, *message_id);
+        metadata.send_get_requests(routing_node, data_name, *message_id, 0, now);
     }
 
     fn send_get_failures(routing_node: &RoutingNode, metadata: &mut MetadataForGetRequest) {
@@ -1134,27 +2261,236 @@ impl ImmutableDataManager {
         }
     }
 
+    fn reputation(&self, pmid_node: &XorName) -> u8 {
+        self.reputations.get(pmid_node).cloned().unwrap_or(MAX_REPUTATION)
+    }
+
+    // Asks the managers of the chunk's close group members whose free space isn't cached yet, so
+    // that they can be passed over for later chunks they don't have room for.  Every member of our
+    // group queries the same nodes for the same chunk, so the ID is derived from both names and the
+    // wall-clock interval of the reports' lifetime.  A node is only queried again once its report
+    // has expired, which is always in a later interval, so a re-query never reuses an ID.
+    fn query_free_space(&mut self, routing_node: &RoutingNode, data_name: &XorName) {
+        let close_group = match routing_node.close_group(*data_name) {
+            Ok(Some(close_group)) => close_group,
+            _ => return,
+        };
+        let serialised_message = match serialisation::serialise(&VaultMessage::CapacityQuery) {
+            Ok(serialised_message) => serialised_message,
+            Err(_) => return,
+        };
+        let interval = utils::u64_bytes(Self::wall_clock_interval(&self.clock,
+                                                                  CAPACITY_REPORT_TTL_SECS));
+        for pmid_node in close_group {
+            if self.free_space.contains_key(&pmid_node) {
+                continue;
+            }
+            let src = Authority::NaeManager(*data_name);
+            let dst = Authority::NodeManager(pmid_node);
+            let data = Data::Plain(PlainData::new(*data_name, serialised_message.clone()));
+            let message_id = utils::message_id(&[&data_name.0[..],
+                                                 &pmid_node.0[..],
+                                                 &interval[..]]);
+            let _ = routing_node.send_post_request(src, dst, data, message_id);
+            let _ = self.free_space.insert(pmid_node, None);
+        }
+    }
+
+    // Tells `pmid_node` that the copy it handed off of the chunk `data_name`, named `name`, is
+    // stored elsewhere.
+    fn confirm_hand_off(routing_node: &RoutingNode,
+                        pmid_node: &XorName,
+                        data_name: &XorName,
+                        name: &XorName) {
+        Self::post_to_holder(routing_node,
+                             pmid_node,
+                             data_name,
+                             name,
+                             &VaultMessage::HandOffComplete)
+    }
+
+    // Sends `message` about the copy of the chunk `data_name` named `name` to its holder
+    // `pmid_node`.  The ID is derived from the names and message so that our group's posts
+    // accumulate.
+    fn post_to_holder(routing_node: &RoutingNode,
+                      pmid_node: &XorName,
+                      data_name: &XorName,
+                      name: &XorName,
+                      message: &VaultMessage) {
+        if let Ok(serialised_message) = serialisation::serialise(message) {
+            let src = Authority::NaeManager(*data_name);
+            let dst = Authority::ManagedNode(*pmid_node);
+            let message_id = utils::message_id(&[&pmid_node.0[..],
+                                                 &name.0[..],
+                                                 &serialised_message[..]]);
+            let data = Data::Plain(PlainData::new(*name, serialised_message));
+            let _ = routing_node.send_post_request(src, dst, data, message_id);
+        }
+    }
+
+    fn has_room(&self, pmid_node: &XorName, size: u64) -> bool {
+        match self.free_space.get(pmid_node) {
+            Some(&Some(free)) => free >= size,
+            _ => true,
+        }
+    }
+
     fn choose_initial_pmid_nodes(&self,
                                  routing_node: &RoutingNode,
                                  full_pmid_nodes: &HashSet<XorName>,
-                                 data_name: &XorName)
-                                 -> Result<HashSet<DataHolder>, InternalError> {
+                                 data_name: &XorName,
+                                 count: usize,
+                                 size: u64)
+                                 -> Result<Vec<XorName>, InternalError> {
         match try!(routing_node.close_group(*data_name)) {
             Some(mut target_pmid_nodes) => {
-                target_pmid_nodes.retain(|target| !full_pmid_nodes.contains(target));
-                target_pmid_nodes.truncate(REPLICANTS);
-                Ok(target_pmid_nodes.into_iter()
-                                    .map(DataHolder::Pending)
-                                    .collect::<HashSet<DataHolder>>())
+                target_pmid_nodes.retain(|target| {
+                    !full_pmid_nodes.contains(target) && self.has_room(target, size) &&
+                    self.reputation(target) >= MISBEHAVING_REPUTATION
+                });
+                // Prefer the most reputable nodes, and the closest among equally reputable ones.
+                target_pmid_nodes.sort_by(|lhs, rhs| {
+                    self.reputation(rhs).cmp(&self.reputation(lhs))
+                });
+                target_pmid_nodes.truncate(count);
+                Ok(target_pmid_nodes)
             }
             None => Err(InternalError::NotInCloseGroup),
         }
     }
 }
 
-impl Default for ImmutableDataManager {
-    fn default() -> ImmutableDataManager {
-        ImmutableDataManager::new()
+impl Persona for ImmutableDataManager {
+    fn routes(&self) -> Vec<Route> {
+        use personas::AuthorityKind::{Client, ClientManager, ManagedNode, NaeManager, NodeManager};
+        use personas::DataKind::{Immutable, Plain};
+        use personas::MessageKind::{Get, GetFailure, GetSuccess, Post, Put, PutFailure,
+                                    PutSuccess, Refresh};
+        use personas::RefreshKind::{ImmutableDataManagerAccount, PmidNodeReputation};
+        vec![Route(Client, NaeManager, Get(Immutable)),
+             Route(NaeManager, NaeManager, Get(Immutable)),
+             Route(ClientManager, NaeManager, Put(Immutable)),
+             Route(NaeManager, NaeManager, Put(Immutable)),
+             Route(ManagedNode, NaeManager, Post(Immutable)),
+             Route(ManagedNode, NaeManager, Post(Plain)),
+             Route(NodeManager, NaeManager, Post(Plain)),
+             Route(ManagedNode, NaeManager, GetSuccess(Immutable)),
+             Route(NaeManager, NaeManager, GetSuccess(Immutable)),
+             Route(ManagedNode, NaeManager, GetFailure(Immutable)),
+             Route(NaeManager, NaeManager, GetFailure(Immutable)),
+             Route(NodeManager, NaeManager, PutSuccess),
+             Route(NodeManager, NaeManager, PutFailure(Immutable)),
+             Route(NaeManager, NaeManager, Refresh(ImmutableDataManagerAccount)),
+             Route(NaeManager, NaeManager, Refresh(PmidNodeReputation))]
+    }
+
+    fn on_request(&mut self,
+                  context: &mut Context,
+                  request: &RequestMessage)
+                  -> Result<(), InternalError> {
+        match request.content {
+            RequestContent::Get(..) => {
+                try!(context.charge_client(request));
+                self.handle_get(context.routing_node, request)
+            }
+            RequestContent::Put(..) => {
+                let full_pmid_nodes = unwrap_result!(context.full_pmid_nodes.read());
+                self.handle_put(context.routing_node, &full_pmid_nodes, request)
+            }
+            RequestContent::Post(Data::Plain(ref data), _) => {
+                if let Authority::ManagedNode(pmid_node) = request.src {
+                    match try!(serialisation::deserialise(data.value())) {
+                        VaultMessage::ChunkHeld => {
+                            Ok(self.handle_chunk_held(context.routing_node,
+                                                      pmid_node,
+                                                      *request.dst.name(),
+                                                      data.name()))
+                        }
+                        _ => Err(InternalError::InvalidMessage),
+                    }
+                } else {
+                    self.handle_pmid_manager_post(context.routing_node, request)
+                }
+            }
+            RequestContent::Post(..) => self.handle_post(context.routing_node, request),
+            _ => Err(InternalError::InvalidMessage),
+        }
+    }
+
+    fn on_response(&mut self,
+                   context: &mut Context,
+                   response: &ResponseMessage)
+                   -> Result<(), InternalError> {
+        match (&response.src, &response.content) {
+            (_, &ResponseContent::GetSuccess(..)) => {
+                self.handle_get_success(context.routing_node, response)
+            }
+            (&Authority::ManagedNode(ref pmid_node),
+             &ResponseContent::GetFailure { ref id,
+                                            ref request,
+                                            ref external_error_indicator }) => {
+                self.handle_get_failure(context.routing_node,
+                                        pmid_node,
+                                        id,
+                                        request,
+                                        external_error_indicator)
+            }
+            (&Authority::NaeManager(_), &ResponseContent::GetFailure { ref request, .. }) => {
+                self.handle_get_from_other_location_failure(context.routing_node, request)
+            }
+            (&Authority::NodeManager(ref pmid_node),
+             &ResponseContent::PutSuccess(ref name, ref message_id)) => {
+                try!(self.handle_put_success(pmid_node, name, message_id));
+                self.handle_hand_off_stored(context.routing_node, name, message_id);
+                Ok(())
+            }
+            (&Authority::NodeManager(ref pmid_node),
+             &ResponseContent::PutFailure { ref id, ref request, .. }) => {
+                let _ = unwrap_result!(context.full_pmid_nodes.write()).insert(*pmid_node);
+                let data_name = if let RequestContent::Put(ref data, _) = request.content {
+                    data.name()
+                } else {
+                    return Err(InternalError::InvalidResponse);
+                };
+                self.handle_put_failure(context.routing_node, pmid_node, &data_name, id)
+            }
+            _ => Err(InternalError::InvalidResponse),
+        }
+    }
+
+    fn on_refresh(&mut self,
+                  _context: &mut Context,
+                  refresh: &Refresh)
+                  -> Result<(), InternalError> {
+        match refresh.value {
+            RefreshValue::ImmutableDataManagerAccount(ref account) => {
+                Ok(self.handle_refresh(refresh.name, account.clone()))
+            }
+            RefreshValue::PmidNodeReputation(pmid_node, reputation) => {
+                Ok(self.handle_reputation(pmid_node, reputation))
+            }
+            _ => Err(InternalError::InvalidMessage),
+        }
+    }
+
+    fn on_node_added(&mut self, context: &mut Context, node_added: &XorName) {
+        self.handle_node_added(context.routing_node, node_added)
+    }
+
+    fn on_node_lost(&mut self, context: &mut Context, node_lost: &XorName) {
+        self.handle_node_lost(context.routing_node, node_lost)
+    }
+
+    fn continue_churn(&mut self, context: &mut Context) -> bool {
+        self.process_churn(context.routing_node)
+    }
+
+    fn on_tick(&mut self, context: &mut Context) {
+        self.check_timeout(context.routing_node)
+    }
+
+    fn take_successes(&mut self) -> Vec<Success> {
+        self.successes.drain(..).collect()
     }
 }
 
@@ -1170,15 +2506,23 @@ mod test {
     use std::mem;
     use std::sync::mpsc;
 
+    use churn_queue::CHURN_SLICE_SIZE;
+    use clock::Clock;
+    use erasure_coding::{self, FragmentCounts};
+    use error::InternalError;
     use maidsafe_utilities::{log, serialisation};
+    use neighbourhood;
+    use personas::ongoing_puts::OngoingPuts;
     use rand::distributions::{IndependentSample, Range};
     use rand::{random, thread_rng};
-    use routing::{Authority, Data, DataRequest, ImmutableData, ImmutableDataType, MessageId,
-                  RequestContent, RequestMessage, ResponseContent, ResponseMessage};
-    use safe_network_common::client_errors::GetError;
+    use routing::{self, Authority, Data, DataRequest, ImmutableData, ImmutableDataType,
+                  MessageId, PlainData, RequestContent, RequestMessage, ResponseContent,
+                  ResponseMessage};
+    use safe_network_common::client_errors::{GetError, MutationError};
     use sodiumoxide::crypto::sign;
-    use types::{Refresh, RefreshValue};
-    use utils::generate_random_vec_u8;
+    use time::Duration;
+    use types::{Fragment, Refresh, RefreshValue, VaultMessage};
+    use utils::{self, generate_random_vec_u8};
     use vault::RoutingNode;
     use xor_name::XorName;
 
@@ -1199,19 +2543,46 @@ mod test {
 
     struct Environment {
         pub routing: RoutingNode,
+        pub clock: Clock,
         pub immutable_data_manager: ImmutableDataManager,
     }
 
     impl Environment {
         pub fn new() -> Environment {
+            Self::with_hedge_delay(Duration::zero())
+        }
+
+        pub fn with_hedge_delay(hedge_delay: Duration) -> Environment {
             let _ = log::init(false);
+            let clock = Clock::manual();
             let env = Environment {
                 routing: unwrap_result!(RoutingNode::new(mpsc::channel().0, false)),
-                immutable_data_manager: ImmutableDataManager::new(),
+                clock: clock.clone(),
+                immutable_data_manager: unwrap_result!(ImmutableDataManager::with_clock(1 << 20,
+                                                                                        1 << 20,
+                                                                                        1 << 20,
+                                                                                        hedge_delay,
+                                                                                        None,
+                                                                                        clock)),
             };
             env
         }
 
+        // Erasure-codes "Normal" chunks, with hedged Gets.
+        pub fn with_fragment_counts(data: usize, parity: usize) -> Environment {
+            let mut env = Self::new();
+            let fragment_counts = FragmentCounts::new(data, parity);
+            assert!(fragment_counts.is_some());
+            env.immutable_data_manager =
+                unwrap_result!(ImmutableDataManager::with_clock(1 << 20,
+                                                                1 << 20,
+                                                                1 << 20,
+                                                                Duration::seconds(2),
+                                                                fragment_counts,
+                                                                env.clock.clone()));
+            env
+        }
+
         pub fn get_close_data(&self) -> ImmutableData {
             loop {
                 let im_data = ImmutableData::new(ImmutableDataType::Normal,
@@ -1284,6 +2655,82 @@ mod test {
             }
         }
 
+        // Puts the data and has all of its initial holders store it, returning the holders' names.
+        pub fn put_and_store_im_data(&mut self) -> (PutEnvironment, Vec<XorName>) {
+            let put_env = self.put_im_data();
+            let holders = put_env.initial_holders
+                                 .iter()
+                                 .map(|holder| *holder.name())
+                                 .collect::<Vec<_>>();
+            for holder in &holders {
+                unwrap_result!(self.immutable_data_manager
+                                   .handle_put_success(holder,
+                                                       &put_env.im_data.name(),
+                                                       &put_env.message_id));
+            }
+            (put_env, holders)
+        }
+
+        // Puts erasure-coded data and has each holder store its fragment, returning the data and
+        // the Puts of its fragments in order.
+        pub fn put_and_store_fragmented_im_data(&mut self) -> (ImmutableData, Vec<RequestMessage>) {
+            let im_data = self.get_close_data();
+            let message_id = MessageId::new();
+            let client_request = RequestMessage {
+                src: Authority::ClientManager(random()),
+                dst: Authority::NaeManager(im_data.name()),
+                content: RequestContent::Put(Data::Immutable(im_data.clone()), message_id),
+            };
+            unwrap_result!(self.immutable_data_manager
+                               .handle_put(&self.routing, &HashSet::new(), &client_request));
+            let fragment_count = unwrap_option!(self.immutable_data_manager.fragment_counts, "")
+                                     .total();
+            let mut fragment_puts = self.routing.put_requests_given();
+            fragment_puts.truncate(fragment_count);
+            for put_request in &fragment_puts {
+                let fragment = fragment_sent_to(&fragment_puts, put_request.dst.name());
+                unwrap_result!(self.immutable_data_manager
+                                   .handle_put_success(put_request.dst.name(),
+                                                       &fragment.name(),
+                                                       &message_id));
+            }
+            (im_data, fragment_puts)
+        }
+
+        pub fn fail_holder(&mut self, data_name: &XorName, pmid_node: &XorName) {
+            let account = unwrap_option!(self.immutable_data_manager.accounts.get_mut(data_name),
+                                         "");
+            assert!(account.pmid_nodes_mut().remove(&DataHolder::Good(*pmid_node)));
+            account.pmid_nodes_mut().insert(DataHolder::Failed(*pmid_node));
+        }
+
+        // Fails one of the holders of newly stored data and has the repair pass replicate it,
+        // returning the first replication Put sent.
+        pub fn replicate_im_data(&mut self) -> (PutEnvironment, Vec<XorName>, RequestMessage) {
+            let (put_env, holders) = self.put_and_store_im_data();
+            let data_name = put_env.im_data.name();
+            self.fail_holder(&data_name, &holders[0]);
+            self.clock.advance(Duration::seconds(REPAIR_INTERVAL_SECS));
+            self.immutable_data_manager.check_timeout(&self.routing);
+            let get_request = unwrap_option!(self.routing.get_requests_given().pop(), "");
+            let message_id = if let RequestContent::Get(_, message_id) = get_request.content {
+                message_id
+            } else {
+                panic!("Received unexpected request {:?}", get_request);
+            };
+            let response = ResponseMessage {
+                src: get_request.dst.clone(),
+                dst: get_request.src.clone(),
+                content: ResponseContent::GetSuccess(Data::Immutable(put_env.im_data.clone()),
+                                                     message_id),
+            };
+            unwrap_result!(self.immutable_data_manager.handle_get_success(&self.routing,
+                                                                          &response));
+            let put_requests = self.routing.put_requests_given();
+            let replication = unwrap_option!(put_requests.get(REPLICANTS + 2), "").clone();
+            (put_env, holders, replication)
+        }
+
         pub fn get_im_data(&mut self, data_name: XorName) -> GetEnvironment {
             let message_id = MessageId::new();
             let content = RequestContent::Get(DataRequest::Immutable(data_name.clone(),
@@ -1349,6 +2796,162 @@ mod test {
                    put_successes[0].src);
     }
 
+    #[test]
+    fn put_avoids_unreliable_pmid_nodes() {
+        let mut env = Environment::new();
+        let im_data = env.get_close_data();
+        let close_group = unwrap_option!(unwrap_result!(env.routing.close_group(im_data.name())),
+                                         "");
+        // The closest node is misbehaving and the next closest is merely unreliable.
+        for (pmid_node, reputation) in close_group.iter().zip(vec![40, 80]) {
+            let message = VaultMessage::PmidNodeReputation(reputation);
+            let serialised_message = unwrap_result!(serialisation::serialise(&message));
+            let request = RequestMessage {
+                src: Authority::NodeManager(*pmid_node),
+                dst: Authority::NaeManager(im_data.name()),
+                content: RequestContent::Post(Data::Plain(PlainData::new(im_data.name(),
+                                                                         serialised_message)),
+                                              MessageId::new()),
+            };
+            unwrap_result!(env.immutable_data_manager
+                              .handle_pmid_manager_post(&env.routing, &request));
+        }
+
+        let request = RequestMessage {
+            src: Authority::ClientManager(random()),
+            dst: Authority::NaeManager(im_data.name()),
+            content: RequestContent::Put(Data::Immutable(im_data.clone()), MessageId::new()),
+        };
+        unwrap_result!(env.immutable_data_manager
+                          .handle_put(&env.routing, &HashSet::new(), &request));
+        let put_requests = env.routing.put_requests_given();
+        let holders = put_requests.iter()
+                                  .take(REPLICANTS)
+                                  .map(|put_request| *put_request.dst.name())
+                                  .collect::<Vec<_>>();
+        assert_eq!(holders, close_group[2..REPLICANTS + 2].to_vec());
+    }
+
+    #[test]
+    fn put_avoids_pmid_nodes_without_room() {
+        let mut env = Environment::new();
+        let im_data = env.get_close_data();
+        let close_group = unwrap_option!(unwrap_result!(env.routing.close_group(im_data.name())),
+                                         "");
+        let message = VaultMessage::CapacityReport {
+            used: 1 << 20,
+            free: im_data.value().len() as u64 - 1,
+        };
+        let serialised_message = unwrap_result!(serialisation::serialise(&message));
+        let report = RequestMessage {
+            src: Authority::NodeManager(close_group[0]),
+            dst: Authority::NaeManager(im_data.name()),
+            content: RequestContent::Post(Data::Plain(PlainData::new(im_data.name(),
+                                                                     serialised_message)),
+                                          MessageId::new()),
+        };
+        unwrap_result!(env.immutable_data_manager.handle_pmid_manager_post(&env.routing, &report));
+
+        let request = RequestMessage {
+            src: Authority::ClientManager(random()),
+            dst: Authority::NaeManager(im_data.name()),
+            content: RequestContent::Put(Data::Immutable(im_data.clone()), MessageId::new()),
+        };
+        unwrap_result!(env.immutable_data_manager
+                          .handle_put(&env.routing, &HashSet::new(), &request));
+        let put_requests = env.routing.put_requests_given();
+        let holders = put_requests.iter()
+                                  .take(REPLICANTS)
+                                  .map(|put_request| *put_request.dst.name())
+                                  .collect::<Vec<_>>();
+        assert_eq!(holders, close_group[1..REPLICANTS + 1].to_vec());
+
+        // The managers of the other close group members are asked for their free space, under IDs
+        // every member of our group derives alike.
+        let post_requests = env.routing.post_requests_given();
+        let queried = post_requests.iter()
+                                   .map(|post_request| *post_request.dst.name())
+                                   .collect::<Vec<_>>();
+        assert_eq!(queried, close_group[1..].to_vec());
+        let assert_query_ids = |post_requests: &[RequestMessage], interval: u64| {
+            let interval = utils::u64_bytes(interval);
+            for (post_request, pmid_node) in post_requests.iter().zip(&close_group[1..]) {
+                let expected_id = utils::message_id(&[&im_data.name().0[..],
+                                                      &pmid_node.0[..],
+                                                      &interval[..]]);
+                if let RequestContent::Post(_, ref id) = post_request.content {
+                    assert_eq!(*id, expected_id);
+                } else {
+                    panic!("Received unexpected request {:?}", post_request);
+                }
+            }
+        };
+        assert_query_ids(&post_requests, 0);
+
+        // Once the reports expire, putting the same chunk again re-queries the same nodes under new
+        // IDs, so the queries aren't dropped as duplicates of the first ones.
+        env.clock.advance(Duration::seconds(CAPACITY_REPORT_TTL_SECS));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        env.immutable_data_manager.accounts.clear();
+        unwrap_result!(env.immutable_data_manager
+                          .handle_put(&env.routing, &HashSet::new(), &request));
+        let requeries = env.routing
+                           .post_requests_given()
+                           .into_iter()
+                           .skip(post_requests.len())
+                           .filter(|post_request| {
+                               if let RequestContent::Post(Data::Plain(ref data), _) =
+                                      post_request.content {
+                                   serialisation::deserialise(data.value()).ok() ==
+                                   Some(VaultMessage::CapacityQuery)
+                               } else {
+                                   false
+                               }
+                           })
+                           .collect::<Vec<_>>();
+        assert_eq!(requeries.len(), close_group.len());
+        assert_query_ids(&requeries[1..], 1);
+    }
+
+    #[test]
+    fn put_with_no_room() {
+        let mut env = Environment::new();
+        env.immutable_data_manager =
+            unwrap_result!(ImmutableDataManager::with_clock(0,
+                                                            0,
+                                                            0,
+                                                            Duration::zero(),
+                                                            None,
+                                                            env.clock.clone()));
+        let im_data = env.get_close_data();
+        let message_id = MessageId::new();
+        let client_manager = Authority::ClientManager(random());
+        let request = RequestMessage {
+            src: client_manager.clone(),
+            dst: Authority::NaeManager(im_data.name()),
+            content: RequestContent::Put(Data::Immutable(im_data.clone()), message_id),
+        };
+        match env.immutable_data_manager.handle_put(&env.routing, &HashSet::new(), &request) {
+            Err(InternalError::ClientMutation(MutationError::NetworkFull)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        assert!(env.routing.put_requests_given().is_empty());
+        assert!(env.routing.put_successes_given().is_empty());
+        let put_failures = env.routing.put_failures_given();
+        assert_eq!(put_failures.len(), 1);
+        assert_eq!(put_failures[0].dst, client_manager);
+        if let ResponseContent::PutFailure { ref id, ref external_error_indicator, .. } =
+               put_failures[0].content {
+            assert_eq!(*id, message_id);
+            let error = unwrap_result!(serialisation::deserialise(external_error_indicator));
+            assert_eq!(MutationError::NetworkFull, error);
+        } else {
+            unreachable!()
+        }
+        assert!(!env.immutable_data_manager.accounts.contains_key(&im_data.name()));
+    }
+
     #[test]
     fn get_non_existing_data() {
         let mut env = Environment::new();
@@ -1419,6 +3022,51 @@ mod test {
         }
     }
 
+    #[test]
+    fn get_timeout() {
+        let mut env = Environment::new();
+        let put_env = env.put_im_data();
+        for data_holder in &put_env.initial_holders {
+            let _ = env.immutable_data_manager
+                       .handle_put_success(data_holder.name(),
+                                           &put_env.im_data.name(),
+                                           &put_env.message_id);
+        }
+
+        let get_env = env.get_im_data(put_env.im_data.name());
+        assert_eq!(env.routing.get_requests_given().len(), REPLICANTS);
+
+        // Nothing should happen until the holders have had five minutes to respond
+        env.clock.advance(Duration::minutes(5));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        assert_eq!(env.routing.get_requests_given().len(), REPLICANTS);
+
+        // Once they've timed out, they should be marked as failed and the data retrieved from the
+        // backup and sacrificial locations instead
+        env.clock.advance(Duration::milliseconds(1));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        let data_name = put_env.im_data.name();
+        let account = unwrap_option!(env.immutable_data_manager.accounts.get(&data_name), "");
+        for data_holder in &put_env.initial_holders {
+            assert!(account.pmid_nodes().contains(&DataHolder::Failed(*data_holder.name())));
+        }
+
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), REPLICANTS + 2);
+        let expected_dsts = vec![Authority::NaeManager(routing::normal_to_backup(&data_name)),
+                                 Authority::NaeManager(routing::normal_to_sacrificial(&data_name))];
+        for get_request in &get_requests[REPLICANTS..] {
+            assert_eq!(Authority::NaeManager(data_name), get_request.src);
+            assert!(expected_dsts.contains(&get_request.dst));
+        }
+        assert!(env.routing.get_failures_given().is_empty());
+
+        // The client's request should still be waiting on the retry
+        let metadata = unwrap_option!(env.immutable_data_manager.ongoing_gets.get_mut(&data_name),
+                                      "");
+        assert_eq!(metadata.requests, vec![(get_env.message_id, get_env.request)]);
+    }
+
     #[test]
     fn handle_put_failure() {
         let mut env = Environment::new();
@@ -1426,8 +3074,10 @@ mod test {
         let mut current_put_request_count = put_env.outgoing_requests.len();
         let mut current_holders = put_env.initial_holders.clone();
         for data_holder in &put_env.initial_holders {
-            let _ = env.immutable_data_manager
-                       .handle_put_failure(&env.routing, data_holder.name(), &put_env.message_id);
+            let _ = env.immutable_data_manager.handle_put_failure(&env.routing,
+                                                                  data_holder.name(),
+                                                                  &put_env.im_data.name(),
+                                                                  &put_env.message_id);
             let put_requests = env.routing.put_requests_given();
             let last_put_request = unwrap_option!(put_requests.last(), "");
             assert_eq!(put_requests.len(), current_put_request_count + 1);
@@ -1443,6 +3093,192 @@ mod test {
         }
     }
 
+    #[test]
+    fn handle_hand_off() {
+        let mut env = Environment::new();
+        let put_env = env.put_im_data();
+        for data_holder in &put_env.initial_holders {
+            let _ = env.immutable_data_manager
+                       .handle_put_success(data_holder.name(),
+                                           &put_env.im_data.name(),
+                                           &put_env.message_id);
+        }
+
+        let departing_holder = *unwrap_option!(put_env.initial_holders.iter().next(), "")
+                                    .name();
+        let message_id = MessageId::new();
+        let request = RequestMessage {
+            src: Authority::ManagedNode(departing_holder),
+            dst: Authority::NaeManager(put_env.im_data.name()),
+            content: RequestContent::Post(Data::Immutable(put_env.im_data.clone()), message_id),
+        };
+        unwrap_result!(env.immutable_data_manager.handle_post(&env.routing, &request));
+
+        let put_requests = env.routing.put_requests_given();
+        assert_eq!(put_requests.len(), put_env.outgoing_requests.len() + 1);
+        let last_put_request = unwrap_option!(put_requests.last(), "");
+        assert_eq!(last_put_request.src,
+                   Authority::NaeManager(put_env.im_data.name()));
+        assert_eq!(last_put_request.content,
+                   RequestContent::Put(Data::Immutable(put_env.im_data.clone()), message_id));
+        let new_holder = *last_put_request.dst.name();
+        assert!(!put_env.initial_holders.iter().any(|holder| *holder.name() == new_holder));
+
+        let account = unwrap_option!(env.immutable_data_manager
+                                        .accounts
+                                        .get(&put_env.im_data.name()),
+                                     "");
+        assert!(account.pmid_nodes().contains(&DataHolder::Failed(departing_holder)));
+        assert!(account.pmid_nodes().contains(&DataHolder::Pending(new_holder)));
+
+        // Once the new holder has stored the chunk, it's counted as a good holder again
+        unwrap_result!(env.immutable_data_manager
+                          .handle_put_success(&new_holder, &put_env.im_data.name(), &message_id));
+        let account = unwrap_option!(env.immutable_data_manager
+                                        .accounts
+                                        .get(&put_env.im_data.name()),
+                                     "");
+        assert!(account.pmid_nodes().contains(&DataHolder::Good(new_holder)));
+
+        // and the departing holder is told it can delete its copy.
+        let posts_sent = env.routing.post_requests_given().len();
+        env.immutable_data_manager
+           .handle_hand_off_stored(&env.routing, &put_env.im_data.name(), &message_id);
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), posts_sent + 1);
+        let confirmation = unwrap_option!(post_requests.last(), "");
+        assert_eq!(confirmation.src, Authority::NaeManager(put_env.im_data.name()));
+        assert_eq!(confirmation.dst, Authority::ManagedNode(departing_holder));
+        if let RequestContent::Post(Data::Plain(ref data), ref id) = confirmation.content {
+            assert_eq!(data.name(), put_env.im_data.name());
+            assert_eq!(unwrap_result!(serialisation::deserialise::<VaultMessage>(data.value())),
+                       VaultMessage::HandOffComplete);
+            assert_eq!(*id,
+                       utils::message_id(&[&departing_holder.0[..],
+                                           &put_env.im_data.name().0[..],
+                                           &data.value()[..]]));
+        } else {
+            panic!("Received unexpected request {:?}", confirmation);
+        }
+
+        // It's only told once.
+        env.immutable_data_manager
+           .handle_hand_off_stored(&env.routing, &put_env.im_data.name(), &message_id);
+        assert_eq!(env.routing.post_requests_given().len(), posts_sent + 1);
+    }
+
+    #[test]
+    fn hand_off_over_budget() {
+        let mut env = Environment::new();
+        let put_env = env.put_im_data();
+        for data_holder in &put_env.initial_holders {
+            let _ = env.immutable_data_manager
+                       .handle_put_success(data_holder.name(),
+                                           &put_env.im_data.name(),
+                                           &put_env.message_id);
+        }
+        env.immutable_data_manager.ongoing_puts =
+            unwrap_result!(OngoingPuts::new(0, 0, env.clock.clone()));
+
+        let departing_holder = *unwrap_option!(put_env.initial_holders.iter().next(), "")
+                                    .name();
+        let request = RequestMessage {
+            src: Authority::ManagedNode(departing_holder),
+            dst: Authority::NaeManager(put_env.im_data.name()),
+            content: RequestContent::Post(Data::Immutable(put_env.im_data.clone()),
+                                          MessageId::new()),
+        };
+        match env.immutable_data_manager.handle_post(&env.routing, &request) {
+            Err(InternalError::ClientMutation(MutationError::NetworkFull)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // No put is sent which couldn't be tracked, nor is a new holder expected to store it.
+        assert_eq!(env.routing.put_requests_given().len(),
+                   put_env.outgoing_requests.len());
+        let account = unwrap_option!(env.immutable_data_manager
+                                        .accounts
+                                        .get(&put_env.im_data.name()),
+                                     "");
+        assert!(!account.pmid_nodes().iter().any(|holder| {
+            if let DataHolder::Pending(_) = *holder {
+                true
+            } else {
+                false
+            }
+        }));
+    }
+
+    #[test]
+    fn handle_chunk_held() {
+        let mut env = Environment::new();
+        let put_env = env.put_im_data();
+        for data_holder in &put_env.initial_holders {
+            let _ = env.immutable_data_manager
+                       .handle_put_success(data_holder.name(),
+                                           &put_env.im_data.name(),
+                                           &put_env.message_id);
+        }
+        let data_name = put_env.im_data.name();
+
+        // A holder which rejoins while the chunk is short of holders is counted again.
+        let rejoined_holder = *unwrap_option!(put_env.initial_holders.iter().next(), "").name();
+        {
+            let account = unwrap_option!(env.immutable_data_manager.accounts.get_mut(&data_name),
+                                         "");
+            assert!(account.pmid_nodes_mut().remove(&DataHolder::Good(rejoined_holder)));
+            account.pmid_nodes_mut().insert(DataHolder::Failed(rejoined_holder));
+        }
+        let posts_sent = env.routing.post_requests_given().len();
+        env.immutable_data_manager
+           .handle_chunk_held(&env.routing, rejoined_holder, data_name, data_name);
+        assert_eq!(env.routing.post_requests_given().len(), posts_sent);
+        {
+            let account = unwrap_option!(env.immutable_data_manager.accounts.get(&data_name), "");
+            assert!(account.pmid_nodes().contains(&DataHolder::Good(rejoined_holder)));
+            assert!(!account.pmid_nodes().contains(&DataHolder::Failed(rejoined_holder)));
+        }
+
+        // While one of its holders is still pending, another node announcing it keeps its copy.
+        let other_node = random::<XorName>();
+        let pending_holder = rejoined_holder;
+        {
+            let account = unwrap_option!(env.immutable_data_manager.accounts.get_mut(&data_name),
+                                         "");
+            assert!(account.pmid_nodes_mut().remove(&DataHolder::Good(pending_holder)));
+            account.pmid_nodes_mut().insert(DataHolder::Pending(pending_holder));
+        }
+        env.immutable_data_manager
+           .handle_chunk_held(&env.routing, other_node, data_name, data_name);
+        assert_eq!(env.routing.post_requests_given().len(), posts_sent);
+
+        // Once it has enough good holders, the node is told to delete its copy.
+        {
+            let account = unwrap_option!(env.immutable_data_manager.accounts.get_mut(&data_name),
+                                         "");
+            assert!(account.pmid_nodes_mut().remove(&DataHolder::Pending(pending_holder)));
+            account.pmid_nodes_mut().insert(DataHolder::Good(pending_holder));
+        }
+        env.immutable_data_manager
+           .handle_chunk_held(&env.routing, other_node, data_name, data_name);
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), posts_sent + 1);
+        let reply = unwrap_option!(post_requests.last(), "");
+        assert_eq!(reply.src, Authority::NaeManager(data_name));
+        assert_eq!(reply.dst, Authority::ManagedNode(other_node));
+        if let RequestContent::Post(Data::Plain(ref data), ref id) = reply.content {
+            assert_eq!(data.name(), data_name);
+            assert_eq!(unwrap_result!(serialisation::deserialise::<VaultMessage>(data.value())),
+                       VaultMessage::ChunkNotNeeded);
+            assert_eq!(*id,
+                       utils::message_id(&[&other_node.0[..],
+                                           &data_name.0[..],
+                                           &data.value()[..]]));
+        } else {
+            panic!("Received unexpected request {:?}", reply);
+        }
+    }
+
     #[test]
     fn handle_get_failure() {
         let mut env = Environment::new();
@@ -1470,6 +3306,20 @@ mod test {
             assert_eq!(env.routing.get_requests_given().len(), REPLICANTS);
             assert!(env.routing.get_successes_given().is_empty());
             assert!(env.routing.get_failures_given().is_empty());
+
+            // Its managers are told the size of the chunk it lost
+            let post_requests = env.routing.post_requests_given();
+            let loss = unwrap_option!(post_requests.last(), "");
+            assert_eq!(loss.dst, Authority::NodeManager(*get_request.dst.name()));
+            if let RequestContent::Post(Data::Plain(ref data), _) = loss.content {
+                let expected = VaultMessage::ChunkLost {
+                    size: put_env.im_data.value().len() as u64,
+                };
+                assert_eq!(unwrap_result!(serialisation::deserialise::<VaultMessage>(data.value())),
+                           expected);
+            } else {
+                panic!("Received unexpected request {:?}", loss);
+            }
         }
 
         // The second holder responds with failure - should trigger Gets from Backup and Sacrificial
@@ -1590,6 +3440,683 @@ mod test {
         }
     }
 
+    #[test]
+    fn get_served_from_cache() {
+        let mut env = Environment::new();
+        let put_env = env.put_im_data();
+        for data_holder in &put_env.initial_holders {
+            let _ = env.immutable_data_manager
+                       .handle_put_success(data_holder.name(),
+                                           &put_env.im_data.name(),
+                                           &put_env.message_id);
+        }
+
+        let first_get_env = env.get_im_data(put_env.im_data.name());
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), REPLICANTS);
+        let response = ResponseMessage {
+            src: get_requests[0].dst.clone(),
+            dst: get_requests[0].src.clone(),
+            content: ResponseContent::GetSuccess(Data::Immutable(put_env.im_data.clone()),
+                                                 first_get_env.message_id),
+        };
+        let _ = env.immutable_data_manager.handle_get_success(&env.routing, &response);
+        assert_eq!(env.routing.get_successes_given().len(), 1);
+
+        // A further Get is answered straight away, without asking the PmidNodes again.
+        let second_get_env = env.get_im_data(put_env.im_data.name());
+        assert_eq!(env.routing.get_requests_given().len(), REPLICANTS);
+        let get_successes = env.routing.get_successes_given();
+        assert_eq!(get_successes.len(), 2);
+        if let ResponseContent::GetSuccess(ref response_data, ref id) = get_successes[1].content {
+            assert_eq!(Data::Immutable(put_env.im_data.clone()), *response_data);
+            assert_eq!(second_get_env.message_id, *id);
+        } else {
+            panic!("Received unexpected response {:?}", get_successes[1]);
+        }
+        assert_eq!(second_get_env.client, get_successes[1].dst);
+
+        let stats = env.immutable_data_manager.data_cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.entries, 1);
+    }
+
+    #[test]
+    fn hedged_get() {
+        let mut env = Environment::with_hedge_delay(Duration::seconds(2));
+        let put_env = env.put_im_data();
+        for data_holder in &put_env.initial_holders {
+            let _ = env.immutable_data_manager
+                       .handle_put_success(data_holder.name(),
+                                           &put_env.im_data.name(),
+                                           &put_env.message_id);
+        }
+
+        // Only the closest holder is asked at first
+        let get_env = env.get_im_data(put_env.im_data.name());
+        assert_eq!(env.routing.get_requests_given().len(), 1);
+        env.clock.advance(Duration::seconds(1));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        assert_eq!(env.routing.get_requests_given().len(), 1);
+
+        // Once the hedge delay has passed without a response, the next holder is asked too
+        env.clock.advance(Duration::seconds(1));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), REPLICANTS);
+        assert!(get_requests[0].dst != get_requests[1].dst);
+
+        // The second holder answers first, and its latency is recorded, so it will be waited for
+        // less than the first
+        let response = ResponseMessage {
+            src: get_requests[1].dst.clone(),
+            dst: get_requests[1].src.clone(),
+            content: ResponseContent::GetSuccess(Data::Immutable(put_env.im_data.clone()),
+                                                 get_env.message_id),
+        };
+        unwrap_result!(env.immutable_data_manager.handle_get_success(&env.routing, &response));
+        assert_eq!(env.routing.get_successes_given().len(), 1);
+        let history = &env.immutable_data_manager.holder_history;
+        let hedge_delay = env.immutable_data_manager.hedge_delay;
+        assert!(history.patience(get_requests[1].dst.name(), hedge_delay) <
+                history.patience(get_requests[0].dst.name(), hedge_delay));
+
+        // No more holders are asked once the data has arrived
+        env.clock.advance(Duration::seconds(10));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        assert_eq!(env.routing.get_requests_given().len(), REPLICANTS);
+    }
+
+    #[test]
+    fn get_failure_asks_next_holder() {
+        let mut env = Environment::with_hedge_delay(Duration::seconds(2));
+        let put_env = env.put_im_data();
+        for data_holder in &put_env.initial_holders {
+            let _ = env.immutable_data_manager
+                       .handle_put_success(data_holder.name(),
+                                           &put_env.im_data.name(),
+                                           &put_env.message_id);
+        }
+
+        let get_env = env.get_im_data(put_env.im_data.name());
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), 1);
+        unwrap_result!(env.immutable_data_manager.handle_get_failure(&env.routing,
+                                                                     get_requests[0].dst.name(),
+                                                                     &get_env.message_id,
+                                                                     &get_requests[0],
+                                                                     &[]));
+
+        // The next holder is asked straight away, without waiting for the hedge delay
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), REPLICANTS);
+        assert!(get_requests[0].dst != get_requests[1].dst);
+        assert!(env.routing.get_failures_given().is_empty());
+    }
+
+    #[test]
+    fn closest_holder_asked_first() {
+        let mut env = Environment::with_hedge_delay(Duration::seconds(2));
+        let put_env = env.put_im_data();
+        let data_name = put_env.im_data.name();
+        for data_holder in &put_env.initial_holders {
+            let _ = env.immutable_data_manager
+                       .handle_put_success(data_holder.name(), &data_name, &put_env.message_id);
+        }
+        let mut holders = put_env.initial_holders
+                                 .iter()
+                                 .map(|holder| *holder.name())
+                                 .collect::<Vec<_>>();
+        holders.sort_by(|lhs, rhs| neighbourhood::cmp_distance(&data_name, lhs, rhs));
+
+        // However quickly the others have answered before, the holder closest to the data is asked
+        // first, as it is by the rest of our group.
+        for holder in &holders[1..] {
+            env.immutable_data_manager
+               .holder_history
+               .record_success(holder, Duration::milliseconds(10));
+        }
+        env.immutable_data_manager
+           .holder_history
+           .record_success(&holders[0], Duration::milliseconds(500));
+        let _ = env.get_im_data(data_name);
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), 1);
+        assert_eq!(*get_requests[0].dst.name(), holders[0]);
+
+        // It's only waited for as long as it has taken before, rather than the whole hedge delay,
+        // before the next closest is asked.
+        let patience = env.immutable_data_manager
+                          .holder_history
+                          .patience(&holders[0], Duration::seconds(2));
+        assert!(patience < Duration::seconds(2));
+        env.clock.advance(patience - Duration::milliseconds(1));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        assert_eq!(env.routing.get_requests_given().len(), 1);
+        env.clock.advance(Duration::milliseconds(1));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), 2);
+        assert_eq!(*get_requests[1].dst.name(), holders[1]);
+    }
+
+    #[test]
+    fn repair_under_replicated() {
+        let mut env = Environment::new();
+        let (put_env, holders) = env.put_and_store_im_data();
+        let data_name = put_env.im_data.name();
+        env.fail_holder(&data_name, &holders[0]);
+
+        // Nothing happens until the repair interval has passed
+        env.clock.advance(Duration::seconds(59));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        assert!(env.routing.get_requests_given().is_empty());
+
+        // The remaining good holder is asked for the data
+        env.clock.advance(Duration::seconds(1));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), 1);
+        assert_eq!(get_requests[0].dst, Authority::ManagedNode(holders[1]));
+        let message_id = if let RequestContent::Get(_, message_id) = get_requests[0].content {
+            message_id
+        } else {
+            panic!("Received unexpected request {:?}", get_requests[0]);
+        };
+
+        // Once it answers, the data is stored on new holders other than the failed one
+        let response = ResponseMessage {
+            src: get_requests[0].dst.clone(),
+            dst: get_requests[0].src.clone(),
+            content: ResponseContent::GetSuccess(Data::Immutable(put_env.im_data.clone()),
+                                                 message_id),
+        };
+        unwrap_result!(env.immutable_data_manager.handle_get_success(&env.routing, &response));
+        let put_requests = env.routing.put_requests_given();
+        assert!(put_requests.len() > REPLICANTS + 2);
+        for put_request in &put_requests[REPLICANTS + 2..] {
+            assert!(put_request.dst != Authority::NodeManager(holders[0]));
+            assert_eq!(put_request.content,
+                       RequestContent::Put(Data::Immutable(put_env.im_data.clone()), message_id));
+        }
+        assert_eq!(env.immutable_data_manager.repair_stats.repaired, 1);
+        assert!(env.routing.get_successes_given().is_empty());
+
+        // The chunk now has enough holders, so isn't repaired again
+        env.clock.advance(Duration::seconds(REPAIR_INTERVAL_SECS));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        assert_eq!(env.routing.get_requests_given().len(), 1);
+    }
+
+    #[test]
+    fn repair_from_other_locations() {
+        let mut env = Environment::new();
+        let (put_env, holders) = env.put_and_store_im_data();
+        let data_name = put_env.im_data.name();
+        for holder in &holders {
+            env.fail_holder(&data_name, holder);
+        }
+
+        // With no good holders left, the Backup and Sacrificial managers are asked instead
+        env.clock.advance(Duration::seconds(REPAIR_INTERVAL_SECS));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), 2);
+        let backup = ImmutableData::new(ImmutableDataType::Backup, put_env.im_data.value().clone());
+        assert_eq!(get_requests[0].dst, Authority::NaeManager(backup.name()));
+        let message_id = if let RequestContent::Get(_, message_id) = get_requests[0].content {
+            message_id
+        } else {
+            panic!("Received unexpected request {:?}", get_requests[0]);
+        };
+
+        let response = ResponseMessage {
+            src: get_requests[0].dst.clone(),
+            dst: get_requests[0].src.clone(),
+            content: ResponseContent::GetSuccess(Data::Immutable(backup), message_id),
+        };
+        unwrap_result!(env.immutable_data_manager.handle_get_success(&env.routing, &response));
+        let put_requests = env.routing.put_requests_given();
+        assert_eq!(put_requests.len(), 2 * REPLICANTS + 2);
+        for put_request in &put_requests[REPLICANTS + 2..] {
+            assert!(!holders.contains(put_request.dst.name()));
+            if let RequestContent::Put(Data::Immutable(ref data), _) = put_request.content {
+                assert_eq!(*data, put_env.im_data);
+            } else {
+                panic!("Received unexpected request {:?}", put_request);
+            }
+        }
+        assert_eq!(env.immutable_data_manager.repair_stats.repaired, 1);
+        assert!(env.immutable_data_manager.ongoing_gets.get(&data_name).is_none());
+    }
+
+    #[test]
+    fn repair_rotates() {
+        let mut env = Environment::new();
+        let chunk_count = MAX_REPAIRS_PER_PASS + 1;
+        for _ in 0..chunk_count {
+            let holders = vec![DataHolder::Good(random())].into_iter().collect();
+            let _ = env.immutable_data_manager
+                       .accounts
+                       .insert(random(), Account::Normal(holders, 0));
+        }
+        // Each pass's Gets have IDs derived from the wall-clock interval it runs in.
+        let repaired_names = |env: &Environment, skip: usize| {
+            let pass = utils::u64_bytes(ImmutableDataManager::repair_epoch(&env.clock));
+            env.routing
+               .get_requests_given()
+               .iter()
+               .skip(skip)
+               .map(|get_request| {
+                   if let RequestContent::Get(DataRequest::Immutable(name, _), id) =
+                          get_request.content {
+                       assert_eq!(id, utils::message_id(&[&name.0[..], &pass[..]]));
+                       name
+                   } else {
+                       panic!("Received unexpected request {:?}", get_request);
+                   }
+               })
+               .collect::<Vec<_>>()
+        };
+
+        env.clock.advance(Duration::seconds(REPAIR_INTERVAL_SECS));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        let first_pass = repaired_names(&env, 0);
+        assert_eq!(first_pass.len(), MAX_REPAIRS_PER_PASS);
+
+        // Once the first pass's Gets are over, the next pass starts with the chunk it missed.
+        for data_name in &first_pass {
+            let _ = env.immutable_data_manager.ongoing_gets.remove(data_name);
+        }
+        env.clock.advance(Duration::seconds(REPAIR_INTERVAL_SECS));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        let second_pass = repaired_names(&env, MAX_REPAIRS_PER_PASS);
+        assert_eq!(second_pass.len(), MAX_REPAIRS_PER_PASS);
+        assert!(!first_pass.contains(&second_pass[0]));
+        assert_eq!(second_pass[1..], first_pass[..MAX_REPAIRS_PER_PASS - 1]);
+    }
+
+    #[test]
+    fn repair_ids_agreed() {
+        let mut env = Environment::new();
+        let (put_env, holders) = env.put_and_store_im_data();
+        let data_name = put_env.im_data.name();
+        env.fail_holder(&data_name, &holders[0]);
+
+        // A member which joined the group later repairs the chunk in the same pass, with the same
+        // ID for its Get.
+        env.clock.advance(Duration::seconds(REPAIR_INTERVAL_SECS / 2));
+        let mut joined_manager = unwrap_result!(ImmutableDataManager::with_clock(0,
+                                                                                 0,
+                                                                                 0,
+                                                                                 Duration::zero(),
+                                                                                 None,
+                                                                                 env.clock
+                                                                                    .clone()));
+        let account = unwrap_option!(env.immutable_data_manager.accounts.get(&data_name), "");
+        let _ = joined_manager.accounts.insert(data_name, account.clone());
+        env.clock.advance(Duration::seconds(REPAIR_INTERVAL_SECS));
+        let gets_sent = env.routing.get_requests_given().len();
+        env.immutable_data_manager.check_timeout(&env.routing);
+        joined_manager.check_timeout(&env.routing);
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), gets_sent + 2);
+        assert_eq!(get_requests[gets_sent], get_requests[gets_sent + 1]);
+    }
+
+    #[test]
+    fn unrecoverable_chunk() {
+        let mut env = Environment::new();
+        let (put_env, holders) = env.put_and_store_im_data();
+        let data_name = put_env.im_data.name();
+        for holder in &holders {
+            env.fail_holder(&data_name, holder);
+        }
+
+        env.clock.advance(Duration::seconds(REPAIR_INTERVAL_SECS));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), 2);
+        for get_request in &get_requests {
+            unwrap_result!(env.immutable_data_manager
+                              .handle_get_from_other_location_failure(&env.routing,
+                                                                      get_request));
+        }
+        assert_eq!(env.immutable_data_manager.repair_stats.unrecoverable, 1);
+        assert_eq!(env.immutable_data_manager.repair_stats.repaired, 0);
+        assert!(env.immutable_data_manager.ongoing_gets.get(&data_name).is_none());
+        assert_eq!(env.routing.put_requests_given().len(), REPLICANTS + 2);
+
+        // The account is kept, so the chunk is tried again on the next pass
+        assert!(env.immutable_data_manager.accounts.contains_key(&data_name));
+        env.clock.advance(Duration::seconds(REPAIR_INTERVAL_SECS));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        assert_eq!(env.routing.get_requests_given().len(), 4);
+    }
+
+    // Checks that the latest Put is a retry of `replication` on a new holder, and returns it.
+    fn assert_replication_retried(env: &Environment,
+                                  holders: &[XorName],
+                                  replication: &RequestMessage)
+                                  -> XorName {
+        let put_requests = env.routing.put_requests_given();
+        let retry = unwrap_option!(put_requests.last(), "");
+        assert!(retry.dst != replication.dst);
+        assert!(!holders.contains(retry.dst.name()));
+        assert_eq!(retry.src, replication.src);
+        if let (&RequestContent::Put(ref retried_data, _), &RequestContent::Put(ref data, _)) =
+               (&retry.content, &replication.content) {
+            assert_eq!(retried_data, data);
+        } else {
+            panic!("Received unexpected request {:?}", retry);
+        }
+        let data_name = replication.src.name();
+        let account = unwrap_option!(env.immutable_data_manager.accounts.get(data_name), "");
+        assert!(account.pmid_nodes().contains(&DataHolder::Failed(*replication.dst.name())));
+        assert!(account.pmid_nodes().contains(&DataHolder::Pending(*retry.dst.name())));
+        *retry.dst.name()
+    }
+
+    #[test]
+    fn replication_retried_after_failure() {
+        let mut env = Environment::new();
+        let (put_env, holders, replication) = env.replicate_im_data();
+        let data_name = put_env.im_data.name();
+        let put_count = env.routing.put_requests_given().len();
+        let message_id = if let RequestContent::Put(_, message_id) = replication.content {
+            message_id
+        } else {
+            panic!("Received unexpected request {:?}", replication);
+        };
+
+        // The first attempt fails, and is retried on another holder once the backoff has elapsed
+        unwrap_result!(env.immutable_data_manager.handle_put_failure(&env.routing,
+                                                                     replication.dst.name(),
+                                                                     &data_name,
+                                                                     &message_id));
+        env.clock.advance(Duration::seconds(4));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        assert_eq!(env.routing.put_requests_given().len(), put_count);
+        env.clock.advance(Duration::seconds(1));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        assert_eq!(env.routing.put_requests_given().len(), put_count + 1);
+        let new_holder = assert_replication_retried(&env, &holders, &replication);
+
+        // The retry succeeds
+        let put_requests = env.routing.put_requests_given();
+        let retry_message_id = match unwrap_option!(put_requests.last(), "").content {
+            RequestContent::Put(_, message_id) => message_id,
+            ref content => panic!("Received unexpected content {:?}", content),
+        };
+        unwrap_result!(env.immutable_data_manager
+                          .handle_put_success(&new_holder, &data_name, &retry_message_id));
+        let account = unwrap_option!(env.immutable_data_manager.accounts.get(&data_name), "");
+        assert!(account.pmid_nodes().contains(&DataHolder::Good(new_holder)));
+    }
+
+    #[test]
+    fn replication_retried_after_timeout() {
+        let mut env = Environment::new();
+        let (_, holders, replication) = env.replicate_im_data();
+        let put_count = env.routing.put_requests_given().len();
+
+        // The first attempt is never answered
+        env.clock.advance(Duration::minutes(2));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        assert_eq!(env.routing.put_requests_given().len(), put_count);
+        env.clock.advance(Duration::milliseconds(1));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        env.clock.advance(Duration::seconds(5));
+        env.immutable_data_manager.check_timeout(&env.routing);
+        assert_eq!(env.routing.put_requests_given().len(), put_count + 1);
+        let _ = assert_replication_retried(&env, &holders, &replication);
+    }
+
+    // The fragment which was Put to `pmid_node`.
+    fn fragment_sent_to(fragment_puts: &[RequestMessage], pmid_node: &XorName) -> ImmutableData {
+        let put_request = unwrap_option!(fragment_puts.iter()
+                                                      .find(|put_request| {
+                                                          put_request.dst.name() == pmid_node
+                                                      }),
+                                         "");
+        if let RequestContent::Put(Data::Immutable(ref fragment), _) = put_request.content {
+            fragment.clone()
+        } else {
+            panic!("Received unexpected request {:?}", put_request);
+        }
+    }
+
+    #[test]
+    fn erasure_coded_put() {
+        let mut env = Environment::with_fragment_counts(4, 2);
+        let counts = unwrap_option!(FragmentCounts::new(4, 2), "");
+        let (im_data, fragment_puts) = env.put_and_store_fragmented_im_data();
+        let data_name = im_data.name();
+
+        // Each holder is sent a different fragment of the chunk, which names the chunk it's from
+        let fragments = erasure_coding::encode(im_data.value(), counts);
+        assert_eq!(fragment_puts.len(), counts.total());
+        let mut fragment_holders = HashSet::new();
+        for (index, (put_request, value)) in fragment_puts.iter().zip(fragments).enumerate() {
+            assert_eq!(put_request.src, Authority::NaeManager(data_name));
+            assert!(fragment_holders.insert(*put_request.dst.name()));
+            let sent = fragment_sent_to(&fragment_puts, put_request.dst.name());
+            let fragment = Fragment {
+                chunk_name: data_name,
+                index: index,
+                value: value,
+            };
+            assert_eq!(sent, unwrap_result!(fragment.to_data()));
+            assert_eq!(Fragment::managers_name(&sent), data_name);
+        }
+
+        // The Backup and Sacrificial copies are still stored whole
+        let put_requests = env.routing.put_requests_given();
+        assert_eq!(put_requests.len(), counts.total() + 2);
+        let backup = ImmutableData::new(ImmutableDataType::Backup, im_data.value().clone());
+        assert_eq!(put_requests[counts.total()].dst,
+                   Authority::NaeManager(backup.name()));
+
+        // Once every fragment is stored, the chunk itself is no longer kept
+        {
+            let account = unwrap_option!(env.immutable_data_manager.accounts.get(&data_name), "");
+            assert_eq!(account.pmid_nodes().len(), counts.total());
+            for holder in &fragment_holders {
+                assert!(account.pmid_nodes().contains(&DataHolder::Good(*holder)));
+            }
+            assert!(account.missing_fragments().is_empty());
+        }
+        assert!(!env.immutable_data_manager.ongoing_puts.contains(&data_name));
+
+        // Without enough PmidNodes to hold a fragment each, whole copies are stored instead
+        let mut env = Environment::with_fragment_counts(8, 8);
+        let put_env = env.put_im_data();
+        let account = unwrap_option!(env.immutable_data_manager
+                                        .accounts
+                                        .get(&put_env.im_data.name()),
+                                     "");
+        assert!(account.layout().is_none());
+    }
+
+    #[test]
+    fn erasure_coded_get() {
+        let mut env = Environment::with_fragment_counts(4, 2);
+        let (im_data, fragment_puts) = env.put_and_store_fragmented_im_data();
+        let data_name = im_data.name();
+        let put_count = env.routing.put_requests_given().len();
+
+        // Only as many holders as there are data fragments are asked at first, each for its own
+        let get_env = env.get_im_data(data_name);
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), 4);
+        for get_request in &get_requests {
+            let fragment = fragment_sent_to(&fragment_puts, get_request.dst.name());
+            assert_eq!(get_request.content,
+                       RequestContent::Get(DataRequest::Immutable(fragment.name(),
+                                                                  ImmutableDataType::Normal),
+                                           get_env.message_id));
+        }
+
+        // One of them fails, so another holder is asked in its place
+        let failed_holder = *get_requests[0].dst.name();
+        unwrap_result!(env.immutable_data_manager.handle_get_failure(&env.routing,
+                                                                     &failed_holder,
+                                                                     &get_env.message_id,
+                                                                     &get_requests[0],
+                                                                     &[]));
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), 5);
+
+        // The chunk is rebuilt and sent to the client once four fragments have arrived
+        for get_request in &get_requests[1..] {
+            assert!(env.routing.get_successes_given().is_empty());
+            let fragment = fragment_sent_to(&fragment_puts, get_request.dst.name());
+            let response = ResponseMessage {
+                src: get_request.dst.clone(),
+                dst: get_request.src.clone(),
+                content: ResponseContent::GetSuccess(Data::Immutable(fragment),
+                                                     get_env.message_id),
+            };
+            unwrap_result!(env.immutable_data_manager.handle_get_success(&env.routing,
+                                                                         &response));
+        }
+        let get_successes = env.routing.get_successes_given();
+        assert_eq!(get_successes.len(), 1);
+        assert_eq!(get_successes[0].dst, get_env.client);
+        assert_eq!(get_successes[0].content,
+                   ResponseContent::GetSuccess(Data::Immutable(im_data), get_env.message_id));
+
+        // The failed holder's fragment is stored on a new holder
+        let put_requests = env.routing.put_requests_given();
+        assert_eq!(put_requests.len(), put_count + 1);
+        let replication = unwrap_option!(put_requests.last(), "");
+        assert_eq!(replication.src, Authority::NaeManager(data_name));
+        let failed_fragment = fragment_sent_to(&fragment_puts, &failed_holder);
+        assert_eq!(replication.content,
+                   RequestContent::Put(Data::Immutable(failed_fragment), get_env.message_id));
+        assert!(!fragment_puts.iter().any(|put_request| put_request.dst == replication.dst));
+        let new_holder = *replication.dst.name();
+        let account = unwrap_option!(env.immutable_data_manager.accounts.get(&data_name), "");
+        assert!(account.pmid_nodes().contains(&DataHolder::Failed(failed_holder)));
+        assert!(account.pmid_nodes().contains(&DataHolder::Pending(new_holder)));
+        assert_eq!(account.fragment_index(&new_holder),
+                   account.fragment_index(&failed_holder));
+        // That's replication after a client's Get rather than a repair.
+        assert_eq!(env.immutable_data_manager.repair_stats.repaired, 0);
+    }
+
+    #[test]
+    fn erasure_coded_churn() {
+        let mut env = Environment::with_fragment_counts(4, 2);
+        let (im_data, fragment_puts) = env.put_and_store_fragmented_im_data();
+        let data_name = im_data.name();
+        let put_count = env.routing.put_requests_given().len();
+        let our_name = unwrap_result!(env.routing.name());
+        let lost_index = unwrap_option!(fragment_puts.iter()
+                                                     .position(|put_request| {
+                                                         *put_request.dst.name() != our_name
+                                                     }),
+                                        "");
+        let lost_node = *fragment_puts[lost_index].dst.name();
+
+        // Losing a holder leaves its fragment missing, so the others are asked for theirs
+        env.routing.remove_node_from_routing_table(&lost_node);
+        env.immutable_data_manager.handle_node_lost(&env.routing, &lost_node);
+        let get_requests = env.routing.get_requests_given();
+        assert_eq!(get_requests.len(), 4);
+        let message_id = MessageId::from_lost_node(lost_node);
+        for get_request in &get_requests {
+            assert!(*get_request.dst.name() != lost_node);
+            let fragment = fragment_sent_to(&fragment_puts, get_request.dst.name());
+            let response = ResponseMessage {
+                src: get_request.dst.clone(),
+                dst: get_request.src.clone(),
+                content: ResponseContent::GetSuccess(Data::Immutable(fragment), message_id),
+            };
+            unwrap_result!(env.immutable_data_manager.handle_get_success(&env.routing,
+                                                                         &response));
+        }
+
+        // The missing fragment is rebuilt and stored on a new holder
+        let put_requests = env.routing.put_requests_given();
+        assert_eq!(put_requests.len(), put_count + 1);
+        let replication = unwrap_option!(put_requests.last(), "");
+        let lost_fragment = fragment_sent_to(&fragment_puts, &lost_node);
+        assert_eq!(replication.content,
+                   RequestContent::Put(Data::Immutable(lost_fragment), message_id));
+        assert!(!fragment_puts.iter().any(|put_request| put_request.dst == replication.dst));
+        let account = unwrap_option!(env.immutable_data_manager.accounts.get(&data_name), "");
+        assert_eq!(account.fragment_index(replication.dst.name()), Some(lost_index));
+        assert!(account.fragment_index(&lost_node).is_none());
+    }
+
+    #[test]
+    fn erasure_coded_hand_off() {
+        let mut env = Environment::with_fragment_counts(4, 2);
+        let (im_data, fragment_puts) = env.put_and_store_fragmented_im_data();
+        let data_name = im_data.name();
+        let put_count = env.routing.put_requests_given().len();
+        let departing_holder = *fragment_puts[0].dst.name();
+        let fragment = fragment_sent_to(&fragment_puts, &departing_holder);
+
+        // A holder hands its fragment back to the chunk's managers, who store it on a new holder
+        let message_id = MessageId::new();
+        let request = RequestMessage {
+            src: Authority::ManagedNode(departing_holder),
+            dst: Authority::NaeManager(data_name),
+            content: RequestContent::Post(Data::Immutable(fragment.clone()), message_id),
+        };
+        unwrap_result!(env.immutable_data_manager.handle_post(&env.routing, &request));
+        let put_requests = env.routing.put_requests_given();
+        assert_eq!(put_requests.len(), put_count + 1);
+        let replication = unwrap_option!(put_requests.last(), "");
+        assert_eq!(replication.src, Authority::NaeManager(data_name));
+        assert_eq!(replication.content,
+                   RequestContent::Put(Data::Immutable(fragment.clone()), message_id));
+        let new_holder = *replication.dst.name();
+        {
+            let account = unwrap_option!(env.immutable_data_manager.accounts.get(&data_name), "");
+            assert!(account.pmid_nodes().contains(&DataHolder::Failed(departing_holder)));
+            assert!(account.pmid_nodes().contains(&DataHolder::Pending(new_holder)));
+            assert_eq!(account.fragment_index(&new_holder), Some(0));
+        }
+
+        // Once it's stored, the departing holder is told it can delete its fragment
+        let posts_sent = env.routing.post_requests_given().len();
+        unwrap_result!(env.immutable_data_manager
+                          .handle_put_success(&new_holder, &fragment.name(), &message_id));
+        env.immutable_data_manager
+           .handle_hand_off_stored(&env.routing, &fragment.name(), &message_id);
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), posts_sent + 1);
+        let confirmation = unwrap_option!(post_requests.last(), "");
+        assert_eq!(confirmation.src, Authority::NaeManager(data_name));
+        assert_eq!(confirmation.dst, Authority::ManagedNode(departing_holder));
+        if let RequestContent::Post(Data::Plain(ref data), _) = confirmation.content {
+            assert_eq!(data.name(), fragment.name());
+            assert_eq!(unwrap_result!(serialisation::deserialise::<VaultMessage>(data.value())),
+                       VaultMessage::HandOffComplete);
+        } else {
+            panic!("Received unexpected request {:?}", confirmation);
+        }
+
+        // Announcing the fragment again now that it has a new holder, it's told it isn't needed
+        env.immutable_data_manager
+           .handle_chunk_held(&env.routing, departing_holder, data_name, fragment.name());
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), posts_sent + 2);
+        let reply = unwrap_option!(post_requests.last(), "");
+        assert_eq!(reply.src, Authority::NaeManager(data_name));
+        if let RequestContent::Post(Data::Plain(ref data), _) = reply.content {
+            assert_eq!(data.name(), fragment.name());
+            assert_eq!(unwrap_result!(serialisation::deserialise::<VaultMessage>(data.value())),
+                       VaultMessage::ChunkNotNeeded);
+        } else {
+            panic!("Received unexpected request {:?}", reply);
+        }
+    }
+
     #[test]
     fn handle_refresh() {
         let mut env = Environment::new();
@@ -1600,7 +4127,8 @@ mod test {
         }
         let _ = env.immutable_data_manager.handle_refresh(data.name(),
                                                           Account::new(&ImmutableDataType::Normal,
-                                                                       data_holders.clone()));
+                                                                       data_holders.clone(),
+                                                                       0));
         let _get_env = env.get_im_data(data.name());
         let get_requests = env.routing.get_requests_given();
         assert_eq!(get_requests.len(), REPLICANTS);
@@ -1616,7 +4144,10 @@ mod test {
     fn churn_during_put() {
         let mut env = Environment::new();
         let put_env = env.put_im_data();
-        let mut account = Account::new(&ImmutableDataType::Normal, put_env.initial_holders.clone());
+        let data_size = put_env.im_data.value().len() as u64;
+        let mut account = Account::new(&ImmutableDataType::Normal,
+                                       put_env.initial_holders.clone(),
+                                       data_size);
         let mut churn_count = 0;
         let mut replicants = REPLICANTS;
         let mut put_request_len = REPLICANTS + 2;
@@ -1633,7 +4164,8 @@ mod test {
                 let _ = env.immutable_data_manager.handle_node_lost(&env.routing, &lost_node);
                 let temp_account = mem::replace(&mut account,
                                                 Account::new(&ImmutableDataType::Normal,
-                                                             HashSet::new()));
+                                                             HashSet::new(),
+                                                             data_size));
                 *account.pmid_nodes_mut() =
                     temp_account.pmid_nodes()
                                 .into_iter()
@@ -1655,6 +4187,7 @@ mod test {
                 let new_node = env.get_close_node();
                 let _ = env.immutable_data_manager.handle_put_failure(&env.routing,
                                                                       data_holder.name(),
+                                                                      &put_env.im_data.name(),
                                                                       &put_env.message_id);
                 env.routing.add_node_into_routing_table(&new_node);
                 let _ = env.immutable_data_manager.handle_node_added(&env.routing, &new_node);
@@ -1667,7 +4200,8 @@ mod test {
 
                 let temp_account = mem::replace(&mut account,
                                                 Account::new(&ImmutableDataType::Normal,
-                                                             HashSet::new()));
+                                                             HashSet::new(),
+                                                             data_size));
                 *account.pmid_nodes_mut() =
                     temp_account.pmid_nodes()
                                 .into_iter()
@@ -1724,7 +4258,8 @@ mod test {
             good_holders.insert(DataHolder::Good(*data_holder.name()));
         }
 
-        let mut account = Account::new(&ImmutableDataType::Normal, good_holders.clone());
+        let data_size = put_env.im_data.value().len() as u64;
+        let mut account = Account::new(&ImmutableDataType::Normal, good_holders.clone(), data_size);
         let mut churn_count = 0;
         let mut get_message_id: MessageId;
         let mut get_requests_len = 0;
@@ -1739,7 +4274,8 @@ mod test {
 
                 let temp_account = mem::replace(&mut account,
                                                 Account::new(&ImmutableDataType::Normal,
-                                                             HashSet::new()));
+                                                             HashSet::new(),
+                                                             data_size));
                 *account.pmid_nodes_mut() = temp_account.pmid_nodes()
                                                         .into_iter()
                                                         .filter_map(|holder| {
@@ -1811,7 +4347,8 @@ mod test {
         let get_env = env.get_im_data(put_env.im_data.name());
         let get_requests = env.routing.get_requests_given();
 
-        let mut account = Account::new(&ImmutableDataType::Normal, good_holders.clone());
+        let data_size = put_env.im_data.value().len() as u64;
+        let mut account = Account::new(&ImmutableDataType::Normal, good_holders.clone(), data_size);
         let mut churn_count = 0;
         let mut get_response_len = 0;
         for get_request in &get_requests {
@@ -1829,7 +4366,8 @@ mod test {
                 let _ = env.immutable_data_manager.handle_node_lost(&env.routing, &lost_node);
                 let temp_account = mem::replace(&mut account,
                                                 Account::new(&ImmutableDataType::Normal,
-                                                             HashSet::new()));
+                                                             HashSet::new(),
+                                                             data_size));
                 *account.pmid_nodes_mut() = temp_account.pmid_nodes()
                                                         .into_iter()
                                                         .filter_map(|holder| {
@@ -1859,7 +4397,8 @@ mod test {
 
                 let temp_account = mem::replace(&mut account,
                                                 Account::new(&ImmutableDataType::Normal,
-                                                             HashSet::new()));
+                                                             HashSet::new(),
+                                                             data_size));
                 *account.pmid_nodes_mut() =
                     temp_account.pmid_nodes()
                                 .into_iter()
@@ -1913,4 +4452,168 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn churn_refreshes_reputations() {
+        let mut env = Environment::new();
+        let (put_env, holders) = env.put_and_store_im_data();
+        let data_name = put_env.im_data.name();
+        env.immutable_data_manager.handle_reputation(holders[0], 40);
+
+        // Along with the account, the group is sent the reputation of its unreliable holder.
+        let mut node_added = data_name;
+        node_added.0[63] ^= 1;
+        env.routing.add_node_into_routing_table(&node_added);
+        let refresh_count = env.routing.refresh_requests_given().len();
+        env.immutable_data_manager.handle_node_added(&env.routing, &node_added);
+        while env.immutable_data_manager.process_churn(&env.routing) {}
+        let refreshs = env.routing.refresh_requests_given();
+        let reputations = refreshs[refresh_count..]
+                              .iter()
+                              .filter_map(|refresh| {
+                                  if let RequestContent::Refresh(ref serialised_refresh, _) =
+                                         refresh.content {
+                                      let parsed = unwrap_result!(serialisation::deserialise::<
+                                          Refresh>(&serialised_refresh[..]));
+                                      if let RefreshValue::PmidNodeReputation(pmid_node,
+                                                                              reputation) =
+                                             parsed.value {
+                                          return Some((refresh.src.clone(),
+                                                       pmid_node,
+                                                       reputation));
+                                      }
+                                  }
+                                  None
+                              })
+                              .collect::<Vec<_>>();
+        assert_eq!(reputations,
+                   vec![(Authority::NaeManager(data_name), holders[0], 40)]);
+    }
+
+    #[test]
+    fn churn_refreshes_match_full_scan() {
+        let mut env = Environment::new();
+        let clock = env.clock.clone();
+        let no_hedging = Duration::zero();
+        let mut full_scan_manager = unwrap_result!(ImmutableDataManager::with_clock(1 << 20,
+                                                                                    1 << 20,
+                                                                                    1 << 20,
+                                                                                    no_hedging,
+                                                                                    None,
+                                                                                    clock));
+        let our_name = unwrap_result!(env.routing.name());
+        for _ in 0..100 {
+            let data_name = env.get_close_data().name();
+            let close_group = unwrap_option!(unwrap_result!(env.routing.close_group(data_name)),
+                                             "");
+            let holders = close_group.into_iter()
+                                     .filter(|name| *name != our_name)
+                                     .take(REPLICANTS + 1)
+                                     .map(DataHolder::Good)
+                                     .collect();
+            let account = Account::new(&ImmutableDataType::Normal, holders, 0);
+            env.immutable_data_manager.handle_refresh(data_name, account.clone());
+            full_scan_manager.handle_refresh(data_name, account);
+        }
+
+        let parse = |refreshs: &[RequestMessage]| -> Vec<(XorName, RefreshValue)> {
+            refreshs.iter()
+                    .map(|refresh| {
+                        if let RequestContent::Refresh(ref serialised_refresh, _) =
+                               refresh.content {
+                            let parsed = unwrap_result!(serialisation::deserialise::<Refresh>(
+                                    &serialised_refresh[..]));
+                            (parsed.name, parsed.value)
+                        } else {
+                            panic!("Received unexpected refresh {:?}", refresh);
+                        }
+                    })
+                    .collect()
+        };
+
+        let mut far_node = our_name;
+        far_node.0[0] ^= 0b1000_0000;
+        for node_added in &[env.get_close_node(), far_node] {
+            env.routing.add_node_into_routing_table(node_added);
+            let refresh_count = env.routing.refresh_requests_given().len();
+            env.immutable_data_manager.handle_node_added(&env.routing, node_added);
+            while env.immutable_data_manager.process_churn(&env.routing) {}
+            let refreshs = env.routing.refresh_requests_given();
+            let restricted = parse(&refreshs[refresh_count..]);
+
+            let message_id = MessageId::from_added_node(*node_added);
+            let data_names = full_scan_manager.accounts.keys().cloned().collect::<Vec<_>>();
+            for data_name in data_names {
+                full_scan_manager.churn_queue.push(data_name, message_id);
+            }
+            while full_scan_manager.process_churn(&env.routing) {}
+            let refreshs = env.routing.refresh_requests_given();
+            let full_scan = parse(&refreshs[(refresh_count + restricted.len())..]);
+
+            // Every refresh sent is one the full scan also sends, and the full scan's are only
+            // missing for accounts whose close group doesn't include the new node.
+            for refresh in &restricted {
+                assert!(full_scan.contains(refresh));
+            }
+            for refresh in &full_scan {
+                let close_group = unwrap_option!(unwrap_result!(env.routing
+                                                                   .close_group(refresh.0)),
+                                                 "");
+                if close_group.contains(node_added) {
+                    assert!(restricted.contains(refresh));
+                }
+            }
+            if *node_added == far_node {
+                assert!(restricted.is_empty());
+            }
+            assert_eq!(env.immutable_data_manager.accounts.keys().count(),
+                       full_scan_manager.accounts.keys().count());
+        }
+    }
+
+    #[test]
+    fn churn_spread_across_ticks() {
+        let mut env = Environment::new();
+        let our_name = unwrap_result!(env.routing.name());
+        // Names this close to ours are all managed by us, and all affected by a node joining
+        // right next to us.
+        let account_count = 2 * CHURN_SLICE_SIZE + 1;
+        for index in 0..account_count {
+            let mut data_name = our_name;
+            data_name.0[62] = (index >> 8) as u8;
+            data_name.0[63] = index as u8;
+            let close_group = unwrap_option!(unwrap_result!(env.routing.close_group(data_name)),
+                                             "");
+            let holders = close_group.into_iter()
+                                     .filter(|name| *name != our_name)
+                                     .take(REPLICANTS)
+                                     .map(DataHolder::Good)
+                                     .collect();
+            let account = Account::new(&ImmutableDataType::Normal, holders, 0);
+            env.immutable_data_manager.handle_refresh(data_name, account);
+        }
+
+        let mut node_added = our_name;
+        node_added.0[60] ^= 1;
+        env.routing.add_node_into_routing_table(&node_added);
+        env.immutable_data_manager.handle_node_added(&env.routing, &node_added);
+
+        // Only one slice of the accounts is refreshed by the churn event itself, and each later
+        // tick refreshes at most one more.
+        assert_eq!(env.routing.refresh_requests_given().len(), CHURN_SLICE_SIZE);
+        assert_eq!(env.immutable_data_manager.churn_queue.len(),
+                   account_count - CHURN_SLICE_SIZE);
+        assert!(env.immutable_data_manager.process_churn(&env.routing));
+        assert_eq!(env.routing.refresh_requests_given().len(), 2 * CHURN_SLICE_SIZE);
+        assert_eq!(env.immutable_data_manager.churn_queue.len(), 1);
+
+        // Churn overlapping the queued work doesn't queue the remaining account twice.
+        env.immutable_data_manager.handle_node_added(&env.routing, &node_added);
+        assert_eq!(env.routing.refresh_requests_given().len(), 3 * CHURN_SLICE_SIZE);
+        assert_eq!(env.immutable_data_manager.churn_queue.len(),
+                   account_count - CHURN_SLICE_SIZE);
+        while env.immutable_data_manager.process_churn(&env.routing) {}
+        assert_eq!(env.routing.refresh_requests_given().len(), 2 * account_count - 1);
+        assert_eq!(env.immutable_data_manager.accounts.keys().count(), account_count);
+    }
 }
@@ -19,10 +19,16 @@ use std::mem;
 use std::convert::From;
 use std::collections::{HashMap, HashSet};
 
-use error::InternalError;
+use churn_queue::{CHURN_SLICE_SIZE, ChurnQueue};
+use clock::Clock;
+use error::{InternalError, Refusal};
+use neighbourhood::{Neighbourhood, XorMap};
+use personas::{Context, Persona, Route, Success, SuccessKind};
+use personas::rate_limit::{Allowances, DEFAULT_PUT_RATE_LIMIT, RateLimit};
 use safe_network_common::client_errors::MutationError;
 use maidsafe_utilities::serialisation;
-use routing::{Authority, Data, ImmutableDataType, MessageId, RequestContent, RequestMessage};
+use routing::{Authority, Data, ImmutableDataType, MessageId, RequestContent, RequestMessage,
+              ResponseContent, ResponseMessage};
 use types::{Refresh, RefreshValue};
 use utils;
 use vault::RoutingNode;
@@ -31,12 +37,25 @@ use xor_name::XorName;
 // It has now been decided that the charge will be by unit
 // i.e. each chunk incurs a default charge of one unit, no matter of the data size
 const DEFAULT_ACCOUNT_SIZE: u64 = 1024;  // 1024 units, max 1GB for immutable_data (1MB per chunk)
+// The same 1GB, for accounts limited by the bytes they store instead
+const DEFAULT_ACCOUNT_BYTES: u64 = 1_073_741_824;
 const MAX_FULL_RATIO: f32 = 0.5;
 
+/// What client accounts' storage limit is measured in.  Both are always tracked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaUnit {
+    /// One unit per chunk, whatever its size.
+    Chunks,
+    /// The size of the data stored.
+    Bytes,
+}
+
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug, Clone)]
 pub struct Account {
     data_stored: u64,
     space_available: u64,
+    bytes_stored: u64,
+    bytes_available: u64,
 }
 
 impl Default for Account {
@@ -44,38 +63,70 @@ impl Default for Account {
         Account {
             data_stored: 0,
             space_available: DEFAULT_ACCOUNT_SIZE,
+            bytes_stored: 0,
+            bytes_available: DEFAULT_ACCOUNT_BYTES,
         }
     }
 }
 
 impl Account {
-    fn put_data(&mut self) -> Result<(), MutationError> {
-        if self.space_available < 1 {
+    // Charges the account for `size` bytes of data, as long as there's room for it in whichever
+    // of the unit and byte totals limits the account.
+    fn put_data(&mut self, size: u64, quota_unit: QuotaUnit) -> Result<(), MutationError> {
+        let has_room = match quota_unit {
+            QuotaUnit::Chunks => self.space_available >= 1,
+            QuotaUnit::Bytes => self.bytes_available >= size,
+        };
+        if !has_room {
             return Err(MutationError::LowBalance);
         }
         self.data_stored += 1;
-        self.space_available -= 1;
+        self.space_available = self.space_available.saturating_sub(1);
+        self.bytes_stored = self.bytes_stored.saturating_add(size);
+        self.bytes_available = self.bytes_available.saturating_sub(size);
         Ok(())
     }
 
-    fn delete_data(&mut self) {
-        self.data_stored -= 1;
-        self.space_available += 1;
+    fn delete_data(&mut self, size: u64) {
+        self.data_stored = self.data_stored.saturating_sub(1);
+        self.space_available = self.space_available.saturating_add(1);
+        self.bytes_stored = self.bytes_stored.saturating_sub(size);
+        self.bytes_available = self.bytes_available.saturating_add(size);
     }
 }
 
 
 
 pub struct MaidManager {
-    accounts: HashMap<XorName, Account>,
+    accounts: XorMap<Account>,
+    // Kept out of the accounts, as members of the group handle a client's puts moments apart so
+    // rarely agree on the allowance exactly.  It's refreshed separately, in whole puts.
+    put_allowances: Allowances,
     request_cache: HashMap<MessageId, RequestMessage>,
+    quota_unit: QuotaUnit,
+    churn_queue: ChurnQueue<XorName, XorName>,
+    // Success responses sent since the registry last took them
+    successes: Vec<Success>,
+    clock: Clock,
 }
 
 impl MaidManager {
-    pub fn new() -> MaidManager {
+    pub fn new(put_rate_limit: RateLimit, quota_unit: QuotaUnit) -> MaidManager {
+        Self::with_clock(put_rate_limit, quota_unit, Clock::system())
+    }
+
+    fn with_clock(put_rate_limit: RateLimit,
+                  quota_unit: QuotaUnit,
+                  clock: Clock)
+                  -> MaidManager {
         MaidManager {
-            accounts: HashMap::new(),
+            accounts: XorMap::new(),
+            put_allowances: Allowances::new(put_rate_limit),
             request_cache: HashMap::new(),
+            quota_unit: quota_unit,
+            churn_queue: ChurnQueue::new(),
+            successes: Vec::new(),
+            clock: clock,
         }
     }
 
@@ -91,7 +142,7 @@ impl MaidManager {
             RequestContent::Put(Data::Structured(_), _) => {
                 self.handle_put_structured_data(routing_node, request)
             }
-            _ => unreachable!("Error in vault demuxing"),
+            _ => Err(InternalError::InvalidMessage),
         }
     }
 
@@ -105,7 +156,8 @@ impl MaidManager {
                 // Send success response back to client
                 let src = client_request.dst;
                 let dst = client_request.src;
-                let _ = routing_node.send_put_success(src, dst, *data_name, *message_id);
+                let success = Success::new(SuccessKind::Put, src, dst, *data_name, *message_id);
+                self.successes.push(success.send(routing_node));
                 Ok(())
             }
             None => Err(InternalError::FailedToFindCachedRequest(*message_id)),
@@ -120,8 +172,8 @@ impl MaidManager {
         match self.request_cache.remove(message_id) {
             Some(client_request) => {
                 // Refund account
-                match self.accounts.get_mut(&utils::client_name(&client_request.src)) {
-                    Some(account) => account.delete_data(),
+                match self.accounts.get_mut(&try!(utils::client_name(&client_request.src))) {
+                    Some(account) => account.delete_data(utils::put_size(&client_request)),
                     None => return Ok(()),
                 }
                 // Send failure response back to client
@@ -137,55 +189,88 @@ impl MaidManager {
         let _ = self.accounts.insert(name, account);
     }
 
+    pub fn handle_put_allowance_refresh(&mut self, name: XorName, puts: u64) {
+        self.put_allowances.limit_to(name, puts, self.clock.now());
+    }
+
+    // Queues the accounts whose close group could have gained or lost `node_changed`.
     pub fn handle_churn(&mut self, routing_node: &RoutingNode, node_changed: &XorName) {
-        // Only retain accounts for which we're still in the close group
-        let accounts = mem::replace(&mut self.accounts, HashMap::new());
-        self.accounts = accounts.into_iter()
-                                .filter(|&(ref maid_name, ref account)| {
-                                    match routing_node.close_group(*maid_name) {
-                                        Ok(None) => {
-                                            trace!("No longer a MM for {}", maid_name);
-                                            let requests = mem::replace(&mut self.request_cache,
-                                                                        HashMap::new());
-                                            self.request_cache =
-                                                requests.into_iter()
-                                                        .filter(|&(_, ref r)| {
-                                                            utils::client_name(&r.src) != *maid_name
-                                                        })
-                                                        .collect();
-                                            false
-                                        }
-                                        Ok(Some(_)) => {
-                                            self.send_refresh(routing_node,
-                                                              maid_name,
-                                                              account,
-                                                              node_changed);
-                                            true
-                                        }
-                                        Err(error) => {
-                                            error!("Failed to get close group: {:?} for {}",
-                                                   error,
-                                                   maid_name);
-                                            false
-                                        }
-                                    }
-                                })
-                                .collect();
+        let neighbourhood = Neighbourhood::new(routing_node, node_changed);
+        for maid_name in self.accounts.names_in(&neighbourhood) {
+            self.churn_queue.push(maid_name, *node_changed);
+        }
+        let _ = self.process_churn(routing_node);
+    }
+
+    // Refreshes the next slice of accounts queued by churn, only retaining those for which we're
+    // still in the close group.  Returns whether any remain to be checked.
+    fn process_churn(&mut self, routing_node: &RoutingNode) -> bool {
+        for _ in 0..CHURN_SLICE_SIZE {
+            let (maid_name, node_changed) = match self.churn_queue.pop() {
+                Some(entry) => entry,
+                None => return false,
+            };
+            match routing_node.close_group(maid_name) {
+                Ok(None) => {
+                    trace!("No longer a MM for {}", maid_name);
+                    let _ = self.accounts.remove(&maid_name);
+                    self.put_allowances.remove(&maid_name);
+                    let requests = mem::replace(&mut self.request_cache, HashMap::new());
+                    self.request_cache = requests.into_iter()
+                                                 .filter(|&(_, ref r)| {
+                                                     utils::client_name(&r.src).ok() !=
+                                                     Some(maid_name)
+                                                 })
+                                                 .collect();
+                }
+                Ok(Some(_)) => {
+                    if let Some(account) = self.accounts.get(&maid_name) {
+                        self.send_refresh(routing_node, &maid_name, account, &node_changed);
+                    }
+                }
+                Err(error) => {
+                    error!("Failed to get close group: {:?} for {}", error, maid_name);
+                    let _ = self.accounts.remove(&maid_name);
+                    self.put_allowances.remove(&maid_name);
+                }
+            }
+        }
+        !self.churn_queue.is_empty()
     }
 
+    // Refreshes the account, along with the client's put allowance if it has used any.  That's
+    // sent separately, so that members who disagree on it still agree on the account.
     fn send_refresh(&self,
                     routing_node: &RoutingNode,
                     maid_name: &XorName,
                     account: &Account,
                     node_changed: &XorName) {
         let src = Authority::ClientManager(*maid_name);
+        let message_id = MessageId::from_lost_node(*node_changed);
         let refresh = Refresh::new(maid_name, RefreshValue::MaidManagerAccount(account.clone()));
         if let Ok(serialised_refresh) = serialisation::serialise(&refresh) {
             trace!("MM sending refresh for account {}", src.name());
             let _ = routing_node.send_refresh_request(src.clone(),
                                                       src.clone(),
                                                       serialised_refresh,
-                                                      MessageId::from_lost_node(*node_changed));
+                                                      message_id);
+        }
+        let puts = self.put_allowances.whole_requests(maid_name, self.clock.now());
+        if puts >= self.put_allowances.burst() {
+            return;
+        }
+        let serialised_id = if let Ok(serialised_id) = serialisation::serialise(&message_id) {
+            serialised_id
+        } else {
+            return;
+        };
+        let refresh = Refresh::new(maid_name, RefreshValue::MaidManagerPutAllowance(puts));
+        let allowance_id = utils::message_id(&[&serialised_id[..], &utils::u64_bytes(puts)[..]]);
+        if let Ok(serialised_refresh) = serialisation::serialise(&refresh) {
+            let _ = routing_node.send_refresh_request(src.clone(),
+                                                      src,
+                                                      serialised_refresh,
+                                                      allowance_id);
         }
     }
 
@@ -202,7 +287,8 @@ impl MaidManager {
                                                    &MutationError::InvalidOperation);
             }
 
-            match routing_node.close_group(utils::client_name(&request.src)) {
+            let client_name = try!(utils::client_name(&request.src));
+            match routing_node.close_group(client_name) {
                 Ok(Some(ref close_group)) => {
                     if full_pmid_nodes.intersection(&close_group.iter()
                                                                 .cloned()
@@ -222,12 +308,12 @@ impl MaidManager {
             }
 
             self.forward_put_request(routing_node,
-                                     utils::client_name(&request.src),
+                                     client_name,
                                      Data::Immutable(data.clone()),
                                      message_id,
                                      request)
         } else {
-            unreachable!("Logic error")
+            Err(InternalError::InvalidMessage)
         }
     }
 
@@ -240,11 +326,11 @@ impl MaidManager {
                                                   request.content {
             (Data::Structured(data.clone()), data.get_type_tag(), message_id)
         } else {
-            unreachable!("Logic error")
+            return Err(InternalError::InvalidMessage);
         };
 
         // If the type_tag is 0, the account must not exist, else it must exist.
-        let client_name = utils::client_name(&request.src);
+        let client_name = try!(utils::client_name(&request.src));
         if type_tag == 0 {
             if self.accounts.contains_key(&client_name) {
                 let error = MutationError::AccountExists;
@@ -268,11 +354,19 @@ impl MaidManager {
                            message_id: MessageId,
                            request: &RequestMessage)
                            -> Result<(), InternalError> {
-        // Account must already exist to Put Data.
-        let result = self.accounts
-                         .get_mut(&client_name)
-                         .ok_or(MutationError::NoSuchAccount)
-                         .and_then(|account| account.put_data());
+        // The client mustn't be over its rate limit, and its account must already exist to Put
+        // Data.
+        if let Err(refusal) = self.charge_put_allowance(&client_name) {
+            trace!("MM refuses put of data {}, as {:?}", data.name(), refusal);
+            try!(utils::refuse(routing_node, request, refusal));
+            return Err(From::from(refusal));
+        }
+        let size = utils::put_size(request);
+        let quota_unit = self.quota_unit;
+        let result = match self.accounts.get_mut(&client_name) {
+            Some(account) => account.put_data(size, quota_unit),
+            None => Err(MutationError::NoSuchAccount),
+        };
         if let Err(error) = result {
             trace!("MM responds put_failure of data {}, due to error {:?}",
                    data.name(),
@@ -297,6 +391,15 @@ impl MaidManager {
         Ok(())
     }
 
+    // Takes one put from the client's allowance, after topping it up for the time since it was
+    // last refilled.  A client without an account has no allowance to take from.
+    fn charge_put_allowance(&mut self, client_name: &XorName) -> Result<(), Refusal> {
+        if !self.accounts.contains_key(client_name) {
+            return Ok(());
+        }
+        self.put_allowances.take(*client_name, self.clock.now())
+    }
+
     fn reply_with_put_failure(&self,
                               routing_node: &RoutingNode,
                               request: RequestMessage,
@@ -315,9 +418,75 @@ impl MaidManager {
     }
 }
 
+impl Persona for MaidManager {
+    fn routes(&self) -> Vec<Route> {
+        use personas::AuthorityKind::{Client, ClientManager, NaeManager};
+        use personas::DataKind::{Immutable, Structured};
+        use personas::MessageKind::{Put, PutFailure, PutSuccess, Refresh};
+        use personas::RefreshKind::{MaidManagerAccount, MaidManagerPutAllowance};
+        vec![Route(Client, ClientManager, Put(Immutable)),
+             Route(Client, ClientManager, Put(Structured)),
+             Route(NaeManager, ClientManager, PutSuccess),
+             Route(NaeManager, ClientManager, PutFailure(Immutable)),
+             Route(NaeManager, ClientManager, PutFailure(Structured)),
+             Route(ClientManager, ClientManager, Refresh(MaidManagerAccount)),
+             Route(ClientManager, ClientManager, Refresh(MaidManagerPutAllowance))]
+    }
+
+    fn on_request(&mut self,
+                  context: &mut Context,
+                  request: &RequestMessage)
+                  -> Result<(), InternalError> {
+        let full_pmid_nodes = unwrap_result!(context.full_pmid_nodes.read());
+        self.handle_put(context.routing_node, &full_pmid_nodes, request)
+    }
+
+    fn on_response(&mut self,
+                   context: &mut Context,
+                   response: &ResponseMessage)
+                   -> Result<(), InternalError> {
+        match response.content {
+            ResponseContent::PutSuccess(ref name, ref message_id) => {
+                self.handle_put_success(context.routing_node, name, message_id)
+            }
+            ResponseContent::PutFailure { ref id, ref external_error_indicator, .. } => {
+                self.handle_put_failure(context.routing_node, id, external_error_indicator)
+            }
+            _ => Err(InternalError::InvalidResponse),
+        }
+    }
+
+    fn on_refresh(&mut self,
+                  _context: &mut Context,
+                  refresh: &Refresh)
+                  -> Result<(), InternalError> {
+        match refresh.value {
+            RefreshValue::MaidManagerAccount(ref account) => {
+                Ok(self.handle_refresh(refresh.name, account.clone()))
+            }
+            RefreshValue::MaidManagerPutAllowance(puts) => {
+                Ok(self.handle_put_allowance_refresh(refresh.name, puts))
+            }
+            _ => Err(InternalError::InvalidMessage),
+        }
+    }
+
+    fn on_churn(&mut self, context: &mut Context, node_changed: &XorName) {
+        self.handle_churn(context.routing_node, node_changed)
+    }
+
+    fn continue_churn(&mut self, context: &mut Context) -> bool {
+        self.process_churn(context.routing_node)
+    }
+
+    fn take_successes(&mut self) -> Vec<Success> {
+        self.successes.drain(..).collect()
+    }
+}
+
 impl Default for MaidManager {
     fn default() -> MaidManager {
-        MaidManager::new()
+        MaidManager::new(DEFAULT_PUT_RATE_LIMIT, QuotaUnit::Chunks)
     }
 }
 
@@ -328,7 +497,10 @@ impl Default for MaidManager {
 mod test {
     use super::*;
     use std::collections::HashSet;
-    use error::InternalError;
+    use churn_queue::CHURN_SLICE_SIZE;
+    use clock::Clock;
+    use error::{InternalError, Refusal};
+    use personas::rate_limit::{DEFAULT_PUT_RATE_LIMIT, RateLimit};
     use safe_network_common::client_errors::MutationError;
     use maidsafe_utilities::serialisation;
     use rand::{thread_rng, random};
@@ -338,7 +510,8 @@ mod test {
     use sodiumoxide::crypto::hash::sha512;
     use sodiumoxide::crypto::sign;
     use std::sync::mpsc;
-    use types::Refresh;
+    use time::Duration;
+    use types::{Refresh, RefreshValue};
     use utils;
     use utils::generate_random_vec_u8;
     use vault::RoutingNode;
@@ -351,13 +524,13 @@ mod test {
         assert_eq!(0, account.data_stored);
         assert_eq!(super::DEFAULT_ACCOUNT_SIZE, account.space_available);
         for _ in 0..super::DEFAULT_ACCOUNT_SIZE {
-            assert!(account.put_data().is_ok());
+            assert!(account.put_data(1, QuotaUnit::Chunks).is_ok());
         }
         assert_eq!(super::DEFAULT_ACCOUNT_SIZE, account.data_stored);
         assert_eq!(0, account.space_available);
 
         for _ in 0..super::DEFAULT_ACCOUNT_SIZE {
-            account.delete_data();
+            account.delete_data(1);
         }
         assert_eq!(0, account.data_stored);
         assert_eq!(super::DEFAULT_ACCOUNT_SIZE, account.space_available);
@@ -370,15 +543,38 @@ mod test {
         assert_eq!(0, account.data_stored);
         assert_eq!(super::DEFAULT_ACCOUNT_SIZE, account.space_available);
         for _ in 0..super::DEFAULT_ACCOUNT_SIZE {
-            assert!(account.put_data().is_ok());
+            assert!(account.put_data(1, QuotaUnit::Chunks).is_ok());
         }
         assert_eq!(super::DEFAULT_ACCOUNT_SIZE, account.data_stored);
         assert_eq!(0, account.space_available);
-        assert!(account.put_data().is_err());
+        assert!(account.put_data(1, QuotaUnit::Chunks).is_err());
         assert_eq!(super::DEFAULT_ACCOUNT_SIZE, account.data_stored);
         assert_eq!(0, account.space_available);
     }
 
+    #[test]
+    fn account_bytes_quota() {
+        let mut account = Account::default();
+
+        // Limited by bytes, one chunk can use the whole account...
+        assert!(account.put_data(super::DEFAULT_ACCOUNT_BYTES, QuotaUnit::Bytes).is_ok());
+        assert_eq!(1, account.data_stored);
+        assert_eq!(super::DEFAULT_ACCOUNT_BYTES, account.bytes_stored);
+        assert_eq!(0, account.bytes_available);
+        match account.put_data(1, QuotaUnit::Bytes) {
+            Err(MutationError::LowBalance) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        // ...whereas limited by units, the bytes are only tracked.
+        assert!(account.put_data(1, QuotaUnit::Chunks).is_ok());
+        assert_eq!(2, account.data_stored);
+        assert_eq!(super::DEFAULT_ACCOUNT_BYTES + 1, account.bytes_stored);
+
+        account.delete_data(super::DEFAULT_ACCOUNT_BYTES);
+        assert_eq!(1, account.data_stored);
+        assert_eq!(1, account.bytes_stored);
+        assert_eq!(super::DEFAULT_ACCOUNT_BYTES, account.bytes_available);
+    }
 
     struct Environment {
         our_authority: Authority,
@@ -388,6 +584,10 @@ mod test {
     }
 
     fn environment_setup() -> Environment {
+        environment_with(MaidManager::default())
+    }
+
+    fn environment_with(maid_manager: MaidManager) -> Environment {
         let routing = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
         let from = random::<XorName>();
         let client;
@@ -405,46 +605,40 @@ mod test {
             }
         }
 
+        let client_name = unwrap_result!(utils::client_name(&client));
         Environment {
-            our_authority: Authority::ClientManager(utils::client_name(&client)),
+            our_authority: Authority::ClientManager(client_name),
             client: client,
             routing: routing,
-            maid_manager: MaidManager::new(),
+            maid_manager: maid_manager,
         }
     }
 
     fn create_account(env: &mut Environment) {
-        if let Authority::Client { client_key, .. } = env.client {
-            let identifier = random::<XorName>();
-            let sd = unwrap_result!(StructuredData::new(0,
-                                                        identifier,
-                                                        0,
-                                                        vec![],
-                                                        vec![client_key],
-                                                        vec![],
-                                                        None));
-            let message_id = MessageId::new();
-            let request = RequestMessage {
-                src: env.client.clone(),
-                dst: env.our_authority.clone(),
-                content: RequestContent::Put(Data::Structured(sd), message_id),
-            };
-
-            assert!(env.maid_manager
-                       .handle_put(&env.routing, &HashSet::<XorName>::new(), &request)
-                       .is_ok());
-        };
+        let request = account_creation(env);
+        assert!(env.maid_manager
+                   .handle_put(&env.routing, &HashSet::<XorName>::new(), &request)
+                   .is_ok());
     }
 
-    fn get_close_node(env: &Environment) -> XorName {
-        let mut name = random::<XorName>();
-
-        loop {
-            if let Ok(Some(_)) = env.routing.close_group(name) {
-                return name;
-            } else {
-                name = random::<XorName>();
-            }
+    fn account_creation(env: &Environment) -> RequestMessage {
+        let client_key = if let Authority::Client { client_key, .. } = env.client {
+            client_key
+        } else {
+            unreachable!()
+        };
+        let identifier = random::<XorName>();
+        let sd = unwrap_result!(StructuredData::new(0,
+                                                    identifier,
+                                                    0,
+                                                    vec![],
+                                                    vec![client_key],
+                                                    vec![],
+                                                    None));
+        RequestMessage {
+            src: env.client.clone(),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Put(Data::Structured(sd), MessageId::new()),
         }
     }
 
@@ -805,7 +999,8 @@ mod test {
 
         let mut full_pmid_nodes = HashSet::new();
 
-        if let Ok(Some(close_group)) = env.routing.close_group(utils::client_name(&env.client)) {
+        let client_name = unwrap_result!(utils::client_name(&env.client));
+        if let Ok(Some(close_group)) = env.routing.close_group(client_name) {
             full_pmid_nodes = close_group.iter()
                                          .take(close_group.len() / 2)
                                          .cloned()
@@ -838,17 +1033,25 @@ mod test {
 
     #[test]
     fn churn_refresh() {
-        let mut env = environment_setup();
+        // The clock is left alone, so that the client's allowance doesn't refill between churns.
+        let mut env = environment_with(MaidManager::with_clock(DEFAULT_PUT_RATE_LIMIT,
+                                                               QuotaUnit::Chunks,
+                                                               Clock::manual()));
         create_account(&mut env);
+        let client_name = unwrap_result!(utils::client_name(&env.client));
 
-        env.routing.node_added_event(get_close_node(&env));
-        env.maid_manager.handle_churn(&env.routing, &random::<XorName>());
+        // A node joining right next to the account
+        let mut node_added = client_name;
+        node_added.0[63] ^= 1;
+        env.routing.node_added_event(node_added);
+        env.maid_manager.handle_churn(&env.routing, &node_added);
 
         let mut refresh_count = 0;
         let mut refresh_requests = env.routing.refresh_requests_given();
 
-        if let Ok(Some(_)) = env.routing.close_group(utils::client_name(&env.client)) {
-            assert_eq!(refresh_requests.len(), 1);
+        // Creating the account used one of the client's puts, so its allowance is refreshed too.
+        if let Ok(Some(_)) = env.routing.close_group(client_name) {
+            assert_eq!(refresh_requests.len(), 2);
             assert_eq!(refresh_requests[0].src, env.our_authority);
             assert_eq!(refresh_requests[0].dst, env.our_authority);
 
@@ -856,25 +1059,26 @@ mod test {
                                                                             .content {
                 if let Ok(refresh) = serialisation::deserialise(&serialised_refresh) {
                     let refresh: Refresh = refresh;
-                    assert_eq!(refresh.name, utils::client_name(&env.client));
+                    assert_eq!(refresh.name, client_name);
                 } else {
                     unreachable!()
                 }
             } else {
                 unreachable!()
             }
-            refresh_count += 1;
+            refresh_count += 2;
         } else {
             assert!(refresh_requests.is_empty());
         }
 
-        env.routing.node_lost_event(lose_close_node(&env));
-        env.maid_manager.handle_churn(&env.routing, &random::<XorName>());
+        let node_lost = lose_close_node(&env);
+        env.routing.node_lost_event(node_lost);
+        env.maid_manager.handle_churn(&env.routing, &node_lost);
 
         refresh_requests = env.routing.refresh_requests_given();
 
-        if let Ok(Some(_)) = env.routing.close_group(utils::client_name(&env.client)) {
-            assert_eq!(refresh_requests.len(), refresh_count + 1);
+        if let Ok(Some(_)) = env.routing.close_group(client_name) {
+            assert_eq!(refresh_requests.len(), refresh_count + 2);
             assert_eq!(refresh_requests[refresh_count].src, env.our_authority);
             assert_eq!(refresh_requests[refresh_count].dst, env.our_authority);
 
@@ -882,7 +1086,7 @@ mod test {
                    refresh_requests[refresh_count].content {
                 if let Ok(refresh) = serialisation::deserialise(&serialised_refresh) {
                     let refresh: Refresh = refresh;
-                    assert_eq!(refresh.name, utils::client_name(&env.client));
+                    assert_eq!(refresh.name, client_name);
                 } else {
                     unreachable!()
                 }
@@ -894,4 +1098,164 @@ mod test {
             assert_eq!(refresh_requests.len(), refresh_count);
         }
     }
+
+    fn immutable_put(env: &Environment) -> RequestMessage {
+        let immutable_data = ImmutableData::new(ImmutableDataType::Normal,
+                                                generate_random_vec_u8(1024));
+        RequestMessage {
+            src: env.client.clone(),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Put(Data::Immutable(immutable_data), MessageId::new()),
+        }
+    }
+
+    fn assert_rate_limited(env: &mut Environment) {
+        let limited_request = immutable_put(env);
+        match env.maid_manager
+                 .handle_put(&env.routing, &HashSet::<XorName>::new(), &limited_request) {
+            Err(InternalError::Refused(Refusal::RateLimited)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let put_failures = env.routing.put_failures_given();
+        let put_failure = unwrap_option!(put_failures.last(), "");
+        if let ResponseContent::PutFailure { ref request, ref external_error_indicator, .. } =
+               put_failure.content {
+            assert_eq!(*request, limited_request);
+            match unwrap_result!(serialisation::deserialise(external_error_indicator)) {
+                MutationError::NetworkOther(ref reason) => {
+                    assert_eq!(Some(Refusal::RateLimited), Refusal::from_description(reason))
+                }
+                error => panic!("Unexpected error {:?}", error),
+            }
+        } else {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn churn_spread_across_ticks() {
+        let mut env = environment_setup();
+        let our_name = unwrap_result!(env.routing.name());
+        // Names this close to ours are all managed by us, and all affected by a node joining
+        // right next to us.
+        let account_count = 2 * CHURN_SLICE_SIZE + 1;
+        for index in 0..account_count {
+            let mut maid_name = our_name;
+            maid_name.0[62] = (index >> 8) as u8;
+            maid_name.0[63] = index as u8;
+            env.maid_manager.handle_refresh(maid_name, Account::default());
+        }
+
+        let mut node_added = our_name;
+        node_added.0[60] ^= 1;
+        env.routing.add_node_into_routing_table(&node_added);
+        env.maid_manager.handle_churn(&env.routing, &node_added);
+        assert_eq!(env.routing.refresh_requests_given().len(), CHURN_SLICE_SIZE);
+        assert!(env.maid_manager.process_churn(&env.routing));
+        assert_eq!(env.routing.refresh_requests_given().len(), 2 * CHURN_SLICE_SIZE);
+        assert!(!env.maid_manager.process_churn(&env.routing));
+        assert_eq!(env.routing.refresh_requests_given().len(), account_count);
+        assert!(!env.maid_manager.process_churn(&env.routing));
+        assert_eq!(env.routing.refresh_requests_given().len(), account_count);
+    }
+
+    #[test]
+    fn rate_limited_put() {
+        let clock = Clock::manual();
+        let put_rate_limit = RateLimit {
+            per_second: 1,
+            burst: 2,
+        };
+        let mut env = environment_with(MaidManager::with_clock(put_rate_limit,
+                                                               QuotaUnit::Chunks,
+                                                               clock.clone()));
+        // Creating the account uses the first put.
+        create_account(&mut env);
+
+        let request = immutable_put(&env);
+        assert!(env.maid_manager
+                   .handle_put(&env.routing, &HashSet::<XorName>::new(), &request)
+                   .is_ok());
+        assert_rate_limited(&mut env);
+        assert_eq!(2, env.routing.put_requests_given().len());
+
+        clock.advance(Duration::seconds(1));
+        let request = immutable_put(&env);
+        assert!(env.maid_manager
+                   .handle_put(&env.routing, &HashSet::<XorName>::new(), &request)
+                   .is_ok());
+        assert_eq!(3, env.routing.put_requests_given().len());
+        assert_eq!(1, env.routing.put_failures_given().len());
+    }
+
+    #[test]
+    fn rate_limit_carried_in_refresh() {
+        let put_rate_limit = RateLimit {
+            per_second: 1,
+            burst: 5,
+        };
+        let mut env = environment_with(MaidManager::with_clock(put_rate_limit,
+                                                               QuotaUnit::Chunks,
+                                                               Clock::manual()));
+        let client_name = unwrap_result!(utils::client_name(&env.client));
+        let requests = vec![account_creation(&env), immutable_put(&env), immutable_put(&env)];
+        let mut node_added = client_name;
+        node_added.0[63] ^= 1;
+        env.routing.node_added_event(node_added);
+
+        // Each member of the group handles the client's puts, 400ms apart, and the churn a second
+        // after the last of them, all a few milliseconds later than the others.
+        let lags_ms = [[0, 7, 31], [12, 0, 3], [45, 26, 0]];
+        let mut member_refreshes = Vec::new();
+        for member_lags_ms in &lags_ms {
+            let clock = Clock::manual();
+            let mut member = MaidManager::with_clock(put_rate_limit,
+                                                     QuotaUnit::Chunks,
+                                                     clock.clone());
+            let mut elapsed_ms = 0;
+            for (index, request) in requests.iter().enumerate() {
+                let due_ms = 400 * index as i64 + member_lags_ms[index];
+                clock.advance(Duration::milliseconds(due_ms - elapsed_ms));
+                elapsed_ms = due_ms;
+                assert!(member.handle_put(&env.routing, &HashSet::<XorName>::new(), request)
+                              .is_ok());
+            }
+            clock.advance(Duration::milliseconds(1000 + member_lags_ms[0]));
+            let given = env.routing.refresh_requests_given().len();
+            member.handle_churn(&env.routing, &node_added);
+            member_refreshes.push(env.routing.refresh_requests_given().split_off(given));
+        }
+
+        // Their refreshes of both the account and the put allowance are the same, so accumulate.
+        assert_eq!(2, member_refreshes[0].len());
+        assert!(member_refreshes.iter().all(|refreshes| *refreshes == member_refreshes[0]));
+
+        // A manager which takes over the account inherits what's left of the allowance: with
+        // 3 puts used and about 1.8 refilled, that's 3.
+        for refresh_request in &member_refreshes[0] {
+            let refresh = if let RequestContent::Refresh(ref serialised_refresh, _) =
+                                 refresh_request.content {
+                unwrap_result!(serialisation::deserialise::<Refresh>(serialised_refresh))
+            } else {
+                unreachable!()
+            };
+            match refresh.value {
+                RefreshValue::MaidManagerAccount(account) => {
+                    env.maid_manager.handle_refresh(refresh.name, account)
+                }
+                RefreshValue::MaidManagerPutAllowance(puts) => {
+                    assert_eq!(3, puts);
+                    env.maid_manager.handle_put_allowance_refresh(refresh.name, puts)
+                }
+                value => panic!("Unexpected refresh {:?}", value),
+            }
+        }
+        for _ in 0..3 {
+            let request = immutable_put(&env);
+            assert!(env.maid_manager
+                       .handle_put(&env.routing, &HashSet::<XorName>::new(), &request)
+                       .is_ok());
+        }
+        assert_rate_limited(&mut env);
+    }
 }
@@ -15,9 +15,516 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+mod chunk_io;
+mod data_cache;
 pub mod immutable_data_manager;
 pub mod maid_manager;
 pub mod mpid_manager;
+mod ongoing_puts;
 pub mod pmid_manager;
 pub mod pmid_node;
+mod pmid_node_history;
+pub mod rate_limit;
+mod replication_queue;
 pub mod structured_data_manager;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+
+use error::InternalError;
+use personas::rate_limit::ClientRateLimits;
+use routing::{Authority, Data, DataRequest, MessageId, RequestContent, RequestMessage,
+              ResponseContent, ResponseMessage, RoutingMessage};
+use types::{Refresh, RefreshValue};
+use utils;
+use vault::RoutingNode;
+use xor_name::XorName;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AuthorityKind {
+    Client,
+    ClientManager,
+    NaeManager,
+    NodeManager,
+    ManagedNode,
+}
+
+impl<'a> From<&'a Authority> for AuthorityKind {
+    fn from(authority: &'a Authority) -> AuthorityKind {
+        match *authority {
+            Authority::Client { .. } => AuthorityKind::Client,
+            Authority::ClientManager(_) => AuthorityKind::ClientManager,
+            Authority::NaeManager(_) => AuthorityKind::NaeManager,
+            Authority::NodeManager(_) => AuthorityKind::NodeManager,
+            Authority::ManagedNode(_) => AuthorityKind::ManagedNode,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DataKind {
+    Immutable,
+    Structured,
+    Plain,
+}
+
+impl<'a> From<&'a Data> for DataKind {
+    fn from(data: &'a Data) -> DataKind {
+        match *data {
+            Data::Immutable(_) => DataKind::Immutable,
+            Data::Structured(_) => DataKind::Structured,
+            Data::Plain(_) => DataKind::Plain,
+        }
+    }
+}
+
+impl<'a> From<&'a DataRequest> for DataKind {
+    fn from(data_request: &'a DataRequest) -> DataKind {
+        match *data_request {
+            DataRequest::Immutable(..) => DataKind::Immutable,
+            DataRequest::Structured(..) => DataKind::Structured,
+            DataRequest::Plain(_) => DataKind::Plain,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RefreshKind {
+    MaidManagerAccount,
+    MaidManagerPutAllowance,
+    ImmutableDataManagerAccount,
+    StructuredDataManager,
+    PmidManagerAccount,
+    MpidManagerAccount,
+    PmidNodeReputation,
+    ClientAllowances,
+}
+
+impl<'a> From<&'a RefreshValue> for RefreshKind {
+    fn from(value: &'a RefreshValue) -> RefreshKind {
+        match *value {
+            RefreshValue::MaidManagerAccount(_) => RefreshKind::MaidManagerAccount,
+            RefreshValue::MaidManagerPutAllowance(_) => RefreshKind::MaidManagerPutAllowance,
+            RefreshValue::ImmutableDataManagerAccount(_) => {
+                RefreshKind::ImmutableDataManagerAccount
+            }
+            RefreshValue::StructuredDataManager(_) => RefreshKind::StructuredDataManager,
+            RefreshValue::PmidManagerAccount(_) => RefreshKind::PmidManagerAccount,
+            RefreshValue::MpidManagerAccount(..) => RefreshKind::MpidManagerAccount,
+            RefreshValue::PmidNodeReputation(..) => RefreshKind::PmidNodeReputation,
+            RefreshValue::ClientAllowances(_) => RefreshKind::ClientAllowances,
+        }
+    }
+}
+
+// The content of a message as far as dispatching is concerned.  Failure responses are classified
+// by the data in the request they carry.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MessageKind {
+    Get(DataKind),
+    Put(DataKind),
+    Post(DataKind),
+    Delete(DataKind),
+    Refresh(RefreshKind),
+    GetSuccess(DataKind),
+    GetFailure(DataKind),
+    PutSuccess,
+    PutFailure(DataKind),
+}
+
+// A (source, destination, content) combination which a persona serves.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Route(pub AuthorityKind, pub AuthorityKind, pub MessageKind);
+
+impl Route {
+    // Refresh requests aren't covered here since their kind is only known once deserialised.
+    fn of_request(request: &RequestMessage) -> Option<Route> {
+        let message = match request.content {
+            RequestContent::Get(ref data_request, _) => MessageKind::Get(data_request.into()),
+            RequestContent::Put(ref data, _) => MessageKind::Put(data.into()),
+            RequestContent::Post(ref data, _) => MessageKind::Post(data.into()),
+            RequestContent::Delete(ref data, _) => MessageKind::Delete(data.into()),
+            _ => return None,
+        };
+        Some(Route((&request.src).into(), (&request.dst).into(), message))
+    }
+
+    fn of_response(response: &ResponseMessage) -> Option<Route> {
+        let message = match response.content {
+            ResponseContent::GetSuccess(ref data, _) => MessageKind::GetSuccess(data.into()),
+            ResponseContent::GetFailure { ref request, .. } => {
+                if let RequestContent::Get(ref data_request, _) = request.content {
+                    MessageKind::GetFailure(data_request.into())
+                } else {
+                    return None;
+                }
+            }
+            ResponseContent::PutSuccess(..) => MessageKind::PutSuccess,
+            ResponseContent::PutFailure { ref request, .. } => {
+                if let RequestContent::Put(ref data, _) = request.content {
+                    MessageKind::PutFailure(data.into())
+                } else {
+                    return None;
+                }
+            }
+            _ => return None,
+        };
+        Some(Route((&response.src).into(), (&response.dst).into(), message))
+    }
+
+    fn of_refresh(src: &Authority, dst: &Authority, refresh: &Refresh) -> Route {
+        Route(src.into(),
+              dst.into(),
+              MessageKind::Refresh((&refresh.value).into()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SuccessKind {
+    Put,
+    Post,
+    Delete,
+}
+
+// A success response to a put, post or delete.  Once sent, it's kept so that a retransmission of
+// the request it answers can be sent it again.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Success {
+    pub kind: SuccessKind,
+    pub src: Authority,
+    pub dst: Authority,
+    pub name: XorName,
+    pub message_id: MessageId,
+}
+
+impl Success {
+    pub fn new(kind: SuccessKind,
+               src: Authority,
+               dst: Authority,
+               name: XorName,
+               message_id: MessageId)
+               -> Success {
+        Success {
+            kind: kind,
+            src: src,
+            dst: dst,
+            name: name,
+            message_id: message_id,
+        }
+    }
+
+    // Sends the response, returning it to be recorded.
+    pub fn send(self, routing_node: &RoutingNode) -> Success {
+        let (src, dst) = (self.src.clone(), self.dst.clone());
+        let _ = match self.kind {
+            SuccessKind::Put => routing_node.send_put_success(src, dst, self.name, self.message_id),
+            SuccessKind::Post => {
+                routing_node.send_post_success(src, dst, self.name, self.message_id)
+            }
+            SuccessKind::Delete => {
+                routing_node.send_delete_success(src, dst, self.name, self.message_id)
+            }
+        };
+        self
+    }
+
+    // The source, destination and message ID of the request it answers.
+    pub fn request_key(&self) -> (Authority, Authority, MessageId) {
+        (self.dst.clone(), self.src.clone(), self.message_id)
+    }
+}
+
+// State owned by the vault which personas need while handling a message.
+pub struct Context<'a> {
+    pub routing_node: &'a RoutingNode,
+    // Shared by all the vault's shards.
+    pub full_pmid_nodes: &'a RwLock<HashSet<XorName>>,
+    // The shard's own, as they're kept per name.
+    pub client_rate_limits: &'a Mutex<ClientRateLimits>,
+    // The success responses sent while handling the message, or on a tick, for requests whose
+    // handling finished since.
+    pub successes: Vec<Success>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(routing_node: &'a RoutingNode,
+               full_pmid_nodes: &'a RwLock<HashSet<XorName>>,
+               client_rate_limits: &'a Mutex<ClientRateLimits>)
+               -> Context<'a> {
+        Context {
+            routing_node: routing_node,
+            full_pmid_nodes: full_pmid_nodes,
+            client_rate_limits: client_rate_limits,
+            successes: Vec::new(),
+        }
+    }
+
+    // Charges a client's post, delete or get against its rate limit, refusing the request if the
+    // client is over it.
+    pub fn charge_client(&self, request: &RequestMessage) -> Result<(), InternalError> {
+        let result = unwrap_result!(self.client_rate_limits.lock()).charge(request);
+        if let Err(refusal) = result {
+            trace!("Refusing {:?} as {:?}", request, refusal);
+            try!(utils::refuse(self.routing_node, request, refusal));
+            return Err(InternalError::Refused(refusal));
+        }
+        Ok(())
+    }
+}
+
+// Common interface of all personas.  Each persona declares the routes it serves and is only passed
+// messages matching one of them.  Personas are owned by a vault shard's thread.
+pub trait Persona: Send {
+    fn routes(&self) -> Vec<Route>;
+
+    fn on_request(&mut self,
+                  _context: &mut Context,
+                  request: &RequestMessage)
+                  -> Result<(), InternalError> {
+        Err(InternalError::UnknownMessageType(RoutingMessage::Request(request.clone())))
+    }
+
+    fn on_response(&mut self,
+                   _context: &mut Context,
+                   response: &ResponseMessage)
+                   -> Result<(), InternalError> {
+        Err(InternalError::UnknownMessageType(RoutingMessage::Response(response.clone())))
+    }
+
+    fn on_refresh(&mut self,
+                  _context: &mut Context,
+                  _refresh: &Refresh)
+                  -> Result<(), InternalError> {
+        Err(InternalError::InvalidMessage)
+    }
+
+    fn on_node_added(&mut self, context: &mut Context, node_added: &XorName) {
+        self.on_churn(context, node_added)
+    }
+
+    fn on_node_lost(&mut self, context: &mut Context, node_lost: &XorName) {
+        self.on_churn(context, node_lost)
+    }
+
+    // Called for both added and lost nodes unless `on_node_added` or `on_node_lost` is overridden.
+    fn on_churn(&mut self, _context: &mut Context, _node_changed: &XorName) {}
+
+    // Called between other events to carry on with work queued by churn which didn't fit in the
+    // first slice.  Returns whether any such work remains.
+    fn continue_churn(&mut self, _context: &mut Context) -> bool {
+        false
+    }
+
+    // Called periodically, whether or not there is any traffic, to expire timed-out operations.
+    fn on_tick(&mut self, _context: &mut Context) {}
+
+    // Called once the vault has joined the network.
+    fn on_connected(&mut self, _context: &mut Context) {}
+
+    // Only called for personas registered with a non-zero storage allowance.
+    fn on_capacity_changed(&mut self,
+                           _context: &mut Context,
+                           _capacity: u64)
+                           -> Result<(), InternalError> {
+        Ok(())
+    }
+
+    // Returns the success responses sent since last called.
+    fn take_successes(&mut self) -> Vec<Success> {
+        Vec::new()
+    }
+
+    #[cfg(feature = "use-mock-crust")]
+    fn stored_names(&self) -> Vec<XorName> {
+        Vec::new()
+    }
+}
+
+struct Entry {
+    persona: Box<Persona>,
+    // The share of the vault's storage capacity given to this persona.
+    allowance: f64,
+}
+
+// Holds the vault's personas and dispatches messages to them according to their declared routes.
+pub struct Registry {
+    entries: Vec<Entry>,
+    routes: HashMap<Route, usize>,
+    churn_pending: bool,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            entries: Vec::new(),
+            routes: HashMap::new(),
+            churn_pending: false,
+        }
+    }
+
+    // Adds a persona, failing if any of its routes is already served by another one.
+    pub fn register(&mut self,
+                    persona: Box<Persona>,
+                    allowance: f64)
+                    -> Result<(), InternalError> {
+        let routes = persona.routes();
+        if let Some(route) = routes.iter().find(|route| self.routes.contains_key(*route)) {
+            return Err(InternalError::DuplicateRoute(*route));
+        }
+        let index = self.entries.len();
+        self.routes.extend(routes.into_iter().map(|route| (route, index)));
+        self.entries.push(Entry {
+            persona: persona,
+            allowance: allowance,
+        });
+        Ok(())
+    }
+
+    pub fn handle_request(&mut self,
+                          context: &mut Context,
+                          request: &RequestMessage)
+                          -> Result<(), InternalError> {
+        match self.persona_for(Route::of_request(request)) {
+            Some(persona) => {
+                let result = persona.on_request(context, request);
+                context.successes.extend(persona.take_successes());
+                result
+            }
+            None => {
+                Err(InternalError::UnknownMessageType(RoutingMessage::Request(request.clone())))
+            }
+        }
+    }
+
+    pub fn handle_response(&mut self,
+                           context: &mut Context,
+                           response: &ResponseMessage)
+                           -> Result<(), InternalError> {
+        match self.persona_for(Route::of_response(response)) {
+            Some(persona) => {
+                let result = persona.on_response(context, response);
+                context.successes.extend(persona.take_successes());
+                result
+            }
+            None => {
+                Err(InternalError::UnknownMessageType(RoutingMessage::Response(response.clone())))
+            }
+        }
+    }
+
+    pub fn handle_refresh(&mut self,
+                          context: &mut Context,
+                          src: &Authority,
+                          dst: &Authority,
+                          refresh: &Refresh)
+                          -> Result<(), InternalError> {
+        match self.persona_for(Some(Route::of_refresh(src, dst, refresh))) {
+            Some(persona) => persona.on_refresh(context, refresh),
+            None => {
+                Err(InternalError::UnknownRefreshType(src.clone(), dst.clone(), refresh.clone()))
+            }
+        }
+    }
+
+    pub fn handle_node_added(&mut self, context: &mut Context, node_added: &XorName) {
+        for entry in &mut self.entries {
+            entry.persona.on_node_added(context, node_added);
+        }
+        self.churn_pending = true;
+    }
+
+    pub fn handle_node_lost(&mut self, context: &mut Context, node_lost: &XorName) {
+        for entry in &mut self.entries {
+            entry.persona.on_node_lost(context, node_lost);
+        }
+        self.churn_pending = true;
+    }
+
+    // Gives each persona with outstanding churn work the chance to do another slice of it.
+    // Returns whether any remains.
+    pub fn continue_churn(&mut self, context: &mut Context) -> bool {
+        if !self.churn_pending {
+            return false;
+        }
+        let mut churn_pending = false;
+        for entry in &mut self.entries {
+            if entry.persona.continue_churn(context) {
+                churn_pending = true;
+            }
+        }
+        self.churn_pending = churn_pending;
+        churn_pending
+    }
+
+    pub fn has_pending_churn(&self) -> bool {
+        self.churn_pending
+    }
+
+    pub fn handle_tick(&mut self, context: &mut Context) {
+        for entry in &mut self.entries {
+            entry.persona.on_tick(context);
+            context.successes.extend(entry.persona.take_successes());
+        }
+    }
+
+    pub fn handle_connected(&mut self, context: &mut Context) {
+        for entry in &mut self.entries {
+            entry.persona.on_connected(context);
+        }
+    }
+
+    // Splits `max_capacity` between the personas according to their allowances.
+    pub fn set_max_capacity(&mut self,
+                            context: &mut Context,
+                            max_capacity: u64)
+                            -> Result<(), InternalError> {
+        for entry in &mut self.entries {
+            if entry.allowance > 0.0 {
+                let capacity = capacity_share(max_capacity, entry.allowance);
+                try!(entry.persona.on_capacity_changed(context, capacity));
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "use-mock-crust")]
+    pub fn stored_names(&self) -> Vec<XorName> {
+        self.entries.iter().flat_map(|entry| entry.persona.stored_names()).collect()
+    }
+
+    fn persona_for(&mut self, route: Option<Route>) -> Option<&mut Box<Persona>> {
+        let index = match route.and_then(|route| self.routes.get(&route).cloned()) {
+            Some(index) => index,
+            None => return None,
+        };
+        self.entries.get_mut(index).map(|entry| &mut entry.persona)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+pub fn capacity_share(max_capacity: u64, allowance: f64) -> u64 {
+    (max_capacity as f64 * allowance) as u64
+}
+
+#[cfg(test)]
+#[cfg(not(feature="use-mock-crust"))]
+mod test {
+    use super::*;
+    use error::InternalError;
+    use personas::maid_manager::MaidManager;
+
+    #[test]
+    fn duplicate_route() {
+        let mut registry = Registry::new();
+        unwrap_result!(registry.register(Box::new(MaidManager::default()), 0.0));
+        match registry.register(Box::new(MaidManager::default()), 0.0) {
+            Err(InternalError::DuplicateRoute(_)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
@@ -15,16 +15,20 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use std::cmp;
 use std::convert::From;
 use std::collections::HashMap;
 
 use chunk_store::ChunkStore;
 use error::InternalError;
+use neighbourhood::{Neighbourhood, XorMap};
+use personas::{Context, Persona, Route, Success, SuccessKind};
 use safe_network_common::client_errors::MutationError;
 use maidsafe_utilities::serialisation::{deserialise, serialise};
 use safe_network_common::messaging::{MAX_INBOX_SIZE, MAX_OUTBOX_SIZE, MpidHeader, MpidMessage,
                                      MpidMessageWrapper};
-use routing::{Authority, Data, MessageId, PlainData, RequestContent, RequestMessage};
+use routing::{Authority, Data, MessageId, PlainData, RequestContent, RequestMessage,
+              ResponseContent, ResponseMessage};
 use sodiumoxide::crypto::sign::PublicKey;
 use types::{Refresh, RefreshValue};
 use utils;
@@ -68,8 +72,8 @@ impl MailBox {
     fn remove(&mut self, size: u64, entry: &XorName) -> bool {
         match self.mail_box.remove(entry) {
             Some(_) => {
-                self.used_space -= size;
-                self.space_available += size;
+                self.used_space = self.used_space.saturating_sub(size);
+                self.space_available = self.space_available.saturating_add(size);
                 true
             }
             None => false,
@@ -160,17 +164,20 @@ impl Account {
 }
 
 pub struct MpidManager {
-    accounts: HashMap<XorName, Account>,
+    accounts: XorMap<Account>,
     chunk_store_inbox: ChunkStore,
     chunk_store_outbox: ChunkStore,
+    // Success responses sent since the registry last took them
+    successes: Vec<Success>,
 }
 
 impl MpidManager {
     pub fn new(capacity: u64) -> Result<MpidManager, InternalError> {
         Ok(MpidManager {
-            accounts: HashMap::new(),
+            accounts: XorMap::new(),
             chunk_store_inbox: try!(ChunkStore::new(CHUNK_STORE_PREFIX, capacity / 2)),
             chunk_store_outbox: try!(ChunkStore::new(CHUNK_STORE_PREFIX, capacity / 2)),
+            successes: Vec::new(),
         })
     }
 
@@ -185,17 +192,16 @@ impl MpidManager {
                                                             ref message_id) = request.content {
             (data, message_id)
         } else {
-            unreachable!("Error in vault demuxing")
+            return Err(InternalError::InvalidMessage);
         };
-        let mpid_message_wrapper: MpidMessageWrapper = try!(deserialise(&data.value()));
-        match mpid_message_wrapper {
-            MpidMessageWrapper::PutHeader(mpid_header) => {
+        match deserialise::<MpidMessageWrapper>(&data.value()) {
+            Ok(MpidMessageWrapper::PutHeader(mpid_header)) => {
                 self.handle_put_for_header(routing_node, request, mpid_header, data, message_id)
             }
-            MpidMessageWrapper::PutMessage(mpid_message) => {
+            Ok(MpidMessageWrapper::PutMessage(mpid_message)) => {
                 self.handle_put_for_message(routing_node, request, mpid_message, data, message_id)
             }
-            _ => unreachable!("Error in vault demuxing"),
+            _ => Self::reply_with_invalid_message(routing_node, request, message_id),
         }
     }
 
@@ -211,13 +217,13 @@ impl MpidManager {
                                                             ref message_id) = request.content {
             (data.clone(), message_id)
         } else {
-            unreachable!("Error in vault demuxing")
+            return Err(InternalError::InvalidResponse);
         };
         let wrapper: MpidMessageWrapper = try!(deserialise(&data.value()));
         let mpid_header = if let MpidMessageWrapper::PutHeader(mpid_header) = wrapper {
             mpid_header
         } else {
-            unreachable!("Error in vault demuxing")
+            return Err(InternalError::InvalidMessage);
         };
 
         if mpid_header.sender() != request.src.name() {
@@ -256,26 +262,25 @@ impl MpidManager {
                                                              ref message_id) = request.content {
             (data, message_id)
         } else {
-            unreachable!("Error in vault demuxing")
+            return Err(InternalError::InvalidMessage);
         };
-        let mpid_message_wrapper: MpidMessageWrapper = try!(deserialise(&data.value()));
-        match mpid_message_wrapper {
-            MpidMessageWrapper::Online => {
+        match deserialise::<MpidMessageWrapper>(&data.value()) {
+            Ok(MpidMessageWrapper::Online) => {
                 self.handle_post_for_online(routing_node, request, message_id)
             }
-            MpidMessageWrapper::GetMessage(header) => {
+            Ok(MpidMessageWrapper::GetMessage(header)) => {
                 self.handle_post_for_get_message(routing_node, request, header, message_id)
             }
-            MpidMessageWrapper::PutMessage(message) => {
+            Ok(MpidMessageWrapper::PutMessage(message)) => {
                 self.handle_post_for_put_message(routing_node, request, message, data, message_id)
             }
-            MpidMessageWrapper::OutboxHas(header_names) => {
+            Ok(MpidMessageWrapper::OutboxHas(header_names)) => {
                 self.handle_post_for_outbox_has(routing_node, request, header_names, message_id)
             }
-            MpidMessageWrapper::GetOutboxHeaders => {
+            Ok(MpidMessageWrapper::GetOutboxHeaders) => {
                 self.handle_post_for_get_outbox_headers(routing_node, request, message_id)
             }
-            _ => unreachable!("Error in vault demuxing"),
+            _ => Self::reply_with_invalid_message(routing_node, request, message_id),
         }
     }
 
@@ -287,17 +292,16 @@ impl MpidManager {
                                                                ref message_id) = request.content {
             (data, message_id)
         } else {
-            unreachable!("Error in vault demuxing")
+            return Err(InternalError::InvalidMessage);
         };
-        let mpid_message_wrapper: MpidMessageWrapper = try!(deserialise(&data.value()));
-        match mpid_message_wrapper {
-            MpidMessageWrapper::DeleteHeader(header_name) => {
+        match deserialise::<MpidMessageWrapper>(&data.value()) {
+            Ok(MpidMessageWrapper::DeleteHeader(header_name)) => {
                 self.handle_delete_for_header(routing_node, request, header_name, message_id)
             }
-            MpidMessageWrapper::DeleteMessage(message_name) => {
+            Ok(MpidMessageWrapper::DeleteMessage(message_name)) => {
                 self.handle_delete_for_message(routing_node, request, message_name, message_id)
             }
-            _ => unreachable!("Error in vault demuxing"),
+            _ => Self::reply_with_invalid_message(routing_node, request, message_id),
         }
     }
 
@@ -316,16 +320,22 @@ impl MpidManager {
         }
     }
 
+    // Refreshes the accounts whose close group could have gained or lost `node_changed`.
     pub fn handle_churn(&mut self, routing_node: &RoutingNode, node_changed: &XorName) {
         let message_id = MessageId::from_lost_node(*node_changed);
-        for (mpid_name, account) in &self.accounts {
+        let neighbourhood = Neighbourhood::new(routing_node, node_changed);
+        for mpid_name in self.accounts.names_in(&neighbourhood) {
+            let account = match self.accounts.get(&mpid_name) {
+                Some(account) => account,
+                None => continue,
+            };
             let received_headers = Self::fetch_chunks(&self.chunk_store_inbox,
                                                       &account.received_headers());
             let stored_messages = Self::fetch_chunks(&self.chunk_store_outbox,
                                                      &account.stored_messages());
 
-            let src = Authority::ClientManager(*mpid_name);
-            let refresh = Refresh::new(mpid_name,
+            let src = Authority::ClientManager(mpid_name);
+            let refresh = Refresh::new(&mpid_name,
                                        RefreshValue::MpidManagerAccount(account.clone(),
                                                                         stored_messages,
                                                                         received_headers));
@@ -339,6 +349,21 @@ impl MpidManager {
         }
     }
 
+    // Changes the storage allowance, split evenly between inbox and outbox.  Messages can't be
+    // handed off elsewhere, so neither store is reduced below the space it already uses.
+    pub fn set_capacity(&mut self, capacity: u64) -> Result<(), InternalError> {
+        for chunk_store in &mut [&mut self.chunk_store_inbox, &mut self.chunk_store_outbox] {
+            let used_space = chunk_store.used_space();
+            if used_space > capacity / 2 {
+                warn!("Can't reduce MpidManager capacity to {} bytes as {} are in use.",
+                      capacity / 2,
+                      used_space);
+            }
+            try!(utils::resize_chunk_store(chunk_store, cmp::max(capacity / 2, used_space)));
+        }
+        Ok(())
+    }
+
     fn handle_put_for_header(&mut self,
                              routing_node: &RoutingNode,
                              request: &RequestMessage,
@@ -377,8 +402,7 @@ impl MpidManager {
         }
 
         if self.accounts
-               .entry(*request.dst.name())
-               .or_insert_with(Account::default)
+               .get_or_insert_with(*request.dst.name(), Account::default)
                .put_into_inbox(serialised_header.len() as u64, &data.name(), &None) {
             try!(self.chunk_store_inbox.put(&data.name(), &serialised_header[..]));
         } else {
@@ -426,7 +450,8 @@ impl MpidManager {
             try!(routing_node.send_put_request(src.clone(), dst, notification, message_id.clone()));
             // Send put success to Client.
             dst = request.src.clone();
-            let _ = routing_node.send_put_success(src, dst, data.name(), *message_id);
+            let success = Success::new(SuccessKind::Put, src, dst, data.name(), *message_id);
+            self.successes.push(success.send(routing_node));
         } else {
             // Client not registered online.
             try!(routing_node.send_put_failure(request.dst.clone(),
@@ -443,15 +468,14 @@ impl MpidManager {
                               request: &RequestMessage,
                               message_id: &MessageId)
                               -> Result<(), InternalError> {
-        let account = self.accounts
-                          .entry(*request.dst.name())
-                          .or_insert_with(Account::default);
+        let account = self.accounts.get_or_insert_with(*request.dst.name(), Account::default);
         account.register_online(&request.src);
         // Send post success to client.
         let src = request.dst.clone();
         let dst = request.src.clone();
         // TODO: Check whether the request argument is needed.
-        let _ = routing_node.send_post_success(src, dst, *request.dst.name(), *message_id);
+        let success = Success::new(SuccessKind::Post, src, dst, *request.dst.name(), *message_id);
+        self.successes.push(success.send(routing_node));
         // For each received header in the inbox, fetch the full message from the sender
         let received_headers = account.received_headers();
         for header in &received_headers {
@@ -642,7 +666,7 @@ impl MpidManager {
             if let Ok(data) = self.chunk_store_outbox.get(&message_name) {
                 if !registered {
                     let mpid_message: MpidMessage = try!(deserialise(&data));
-                    if *mpid_message.recipient() != utils::client_name(&request.src) {
+                    if *mpid_message.recipient() != try!(utils::client_name(&request.src)) {
                         return Ok(()); // !
                     }
                 }
@@ -664,6 +688,43 @@ impl MpidManager {
         Ok(())
     }
 
+    // Used when the request's content isn't a wrapper which is valid for that request type.
+    fn reply_with_invalid_message(routing_node: &RoutingNode,
+                                  request: &RequestMessage,
+                                  message_id: &MessageId)
+                                  -> Result<(), InternalError> {
+        warn!("MpidManager received invalid message wrapper in {:?}", request);
+        let src = request.dst.clone();
+        let dst = request.src.clone();
+        let error = MutationError::InvalidOperation;
+        let external_error_indicator = try!(serialise(&error));
+        let _ = match request.content {
+            RequestContent::Put(..) => {
+                routing_node.send_put_failure(src,
+                                              dst,
+                                              request.clone(),
+                                              external_error_indicator,
+                                              *message_id)
+            }
+            RequestContent::Post(..) => {
+                routing_node.send_post_failure(src,
+                                               dst,
+                                               request.clone(),
+                                               external_error_indicator,
+                                               *message_id)
+            }
+            RequestContent::Delete(..) => {
+                routing_node.send_delete_failure(src,
+                                                 dst,
+                                                 request.clone(),
+                                                 external_error_indicator,
+                                                 *message_id)
+            }
+            _ => return Err(InternalError::InvalidMessage),
+        };
+        Err(InternalError::InvalidMessage)
+    }
+
     fn fetch_chunks(storage: &ChunkStore, names: &[XorName]) -> Vec<PlainData> {
         let mut datas = Vec::new();
         for name in names.iter() {
@@ -683,6 +744,73 @@ impl MpidManager {
     }
 }
 
+impl Persona for MpidManager {
+    fn routes(&self) -> Vec<Route> {
+        use personas::AuthorityKind::{Client, ClientManager};
+        use personas::DataKind::Plain;
+        use personas::MessageKind::{Delete, Post, Put, PutFailure, Refresh};
+        use personas::RefreshKind::MpidManagerAccount;
+        vec![Route(Client, ClientManager, Put(Plain)),
+             Route(ClientManager, ClientManager, Put(Plain)),
+             Route(Client, ClientManager, Post(Plain)),
+             Route(ClientManager, ClientManager, Post(Plain)),
+             Route(Client, ClientManager, Delete(Plain)),
+             Route(ClientManager, ClientManager, PutFailure(Plain)),
+             Route(ClientManager, ClientManager, Refresh(MpidManagerAccount))]
+    }
+
+    fn on_request(&mut self,
+                  context: &mut Context,
+                  request: &RequestMessage)
+                  -> Result<(), InternalError> {
+        match request.content {
+            RequestContent::Put(..) => self.handle_put(context.routing_node, request),
+            RequestContent::Post(..) => self.handle_post(context.routing_node, request),
+            RequestContent::Delete(..) => self.handle_delete(context.routing_node, request),
+            _ => Err(InternalError::InvalidMessage),
+        }
+    }
+
+    fn on_response(&mut self,
+                   context: &mut Context,
+                   response: &ResponseMessage)
+                   -> Result<(), InternalError> {
+        if let ResponseContent::PutFailure { ref request, .. } = response.content {
+            self.handle_put_failure(context.routing_node, request)
+        } else {
+            Err(InternalError::InvalidResponse)
+        }
+    }
+
+    fn on_refresh(&mut self,
+                  _context: &mut Context,
+                  refresh: &Refresh)
+                  -> Result<(), InternalError> {
+        if let RefreshValue::MpidManagerAccount(ref account,
+                                                ref stored_messages,
+                                                ref received_headers) = refresh.value {
+            Ok(self.handle_refresh(refresh.name, account, stored_messages, received_headers))
+        } else {
+            Err(InternalError::InvalidMessage)
+        }
+    }
+
+    fn on_churn(&mut self, context: &mut Context, node_changed: &XorName) {
+        self.handle_churn(context.routing_node, node_changed)
+    }
+
+    fn on_capacity_changed(&mut self,
+                           _context: &mut Context,
+                           capacity: u64)
+                           -> Result<(), InternalError> {
+        self.set_capacity(capacity)
+    }
+
+    fn take_successes(&mut self) -> Vec<Success> {
+        self.successes.drain(..).collect()
+    }
+}
+
 
 
 #[cfg(test)]
@@ -695,7 +823,7 @@ mod test {
     use maidsafe_utilities::serialisation;
     use rand;
     use routing::{Authority, Data, MessageId, PlainData, RequestContent, RequestMessage,
-                  ResponseContent};
+                  ResponseContent, ResponseMessage};
     use sodiumoxide::crypto::sign;
     use std::sync::mpsc;
     use utils::generate_random_vec_u8;
@@ -1508,4 +1636,151 @@ mod test {
             unreachable!()
         }
     }
+
+    // Returns one of each variant of `MpidMessageWrapper`.
+    fn all_wrappers() -> Vec<MpidMessageWrapper> {
+        let (_public_key, secret_key) = sign::gen_keypair();
+        let mpid_message = unwrap_result!(MpidMessage::new(rand::random::<XorName>(),
+                                                           generate_random_vec_u8(128),
+                                                           rand::random::<XorName>(),
+                                                           generate_random_vec_u8(128),
+                                                           &secret_key));
+        let mpid_header = mpid_message.header().clone();
+        vec![MpidMessageWrapper::Online,
+             MpidMessageWrapper::PutHeader(mpid_header.clone()),
+             MpidMessageWrapper::PutMessage(mpid_message),
+             MpidMessageWrapper::GetMessage(mpid_header.clone()),
+             MpidMessageWrapper::OutboxHas(vec![rand::random::<XorName>()]),
+             MpidMessageWrapper::OutboxHasResponse(vec![mpid_header.clone()]),
+             MpidMessageWrapper::GetOutboxHeaders,
+             MpidMessageWrapper::GetOutboxHeadersResponse(vec![mpid_header]),
+             MpidMessageWrapper::DeleteHeader(rand::random::<XorName>()),
+             MpidMessageWrapper::DeleteMessage(rand::random::<XorName>())]
+    }
+
+    fn invalid_wrapper_request(env: &Environment,
+                               wrapper: &MpidMessageWrapper,
+                               content: fn(Data, MessageId) -> RequestContent)
+                               -> RequestMessage {
+        let value = unwrap_result!(serialisation::serialise(wrapper));
+        let plain_data = PlainData::new(rand::random::<XorName>(), value);
+        RequestMessage {
+            src: env.client.clone(),
+            dst: env.our_authority.clone(),
+            content: content(Data::Plain(plain_data), MessageId::new()),
+        }
+    }
+
+    fn assert_rejected(responses: &[ResponseMessage], request: &RequestMessage) {
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].src, request.dst);
+        assert_eq!(responses[0].dst, request.src);
+        match responses[0].content {
+            ResponseContent::PutFailure { request: ref failed_request,
+                                          ref external_error_indicator,
+                                          .. } |
+            ResponseContent::PostFailure { request: ref failed_request,
+                                           ref external_error_indicator,
+                                           .. } |
+            ResponseContent::DeleteFailure { request: ref failed_request,
+                                             ref external_error_indicator,
+                                             .. } => {
+                assert_eq!(*failed_request, *request);
+                let error = unwrap_result!(serialisation::deserialise(external_error_indicator));
+                assert_eq!(MutationError::InvalidOperation, error);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn put_invalid_wrapper() {
+        for wrapper in &all_wrappers() {
+            match *wrapper {
+                MpidMessageWrapper::PutHeader(_) |
+                MpidMessageWrapper::PutMessage(_) => continue,
+                _ => (),
+            }
+            let mut env = environment_setup();
+            let request = invalid_wrapper_request(&env, wrapper, RequestContent::Put);
+            match env.mpid_manager.handle_put(&env.routing, &request) {
+                Err(InternalError::InvalidMessage) => (),
+                result => panic!("Unexpected result for {:?}: {:?}", wrapper, result),
+            }
+            assert_rejected(&env.routing.put_failures_given(), &request);
+            assert!(env.routing.put_requests_given().is_empty());
+        }
+    }
+
+    #[test]
+    fn post_invalid_wrapper() {
+        for wrapper in &all_wrappers() {
+            match *wrapper {
+                MpidMessageWrapper::Online |
+                MpidMessageWrapper::GetMessage(_) |
+                MpidMessageWrapper::PutMessage(_) |
+                MpidMessageWrapper::OutboxHas(_) |
+                MpidMessageWrapper::GetOutboxHeaders => continue,
+                _ => (),
+            }
+            let mut env = environment_setup();
+            let request = invalid_wrapper_request(&env, wrapper, RequestContent::Post);
+            match env.mpid_manager.handle_post(&env.routing, &request) {
+                Err(InternalError::InvalidMessage) => (),
+                result => panic!("Unexpected result for {:?}: {:?}", wrapper, result),
+            }
+            assert_rejected(&env.routing.post_failures_given(), &request);
+            assert!(env.routing.post_requests_given().is_empty());
+        }
+    }
+
+    #[test]
+    fn delete_invalid_wrapper() {
+        for wrapper in &all_wrappers() {
+            match *wrapper {
+                MpidMessageWrapper::DeleteHeader(_) |
+                MpidMessageWrapper::DeleteMessage(_) => continue,
+                _ => (),
+            }
+            let mut env = environment_setup();
+            let request = invalid_wrapper_request(&env, wrapper, RequestContent::Delete);
+            match env.mpid_manager.handle_delete(&env.routing, &request) {
+                Err(InternalError::InvalidMessage) => (),
+                result => panic!("Unexpected result for {:?}: {:?}", wrapper, result),
+            }
+            assert_rejected(&env.routing.delete_failures_given(), &request);
+        }
+    }
+
+    #[test]
+    fn undecodable_wrapper() {
+        let mut env = environment_setup();
+        let plain_data = PlainData::new(rand::random::<XorName>(), generate_random_vec_u8(64));
+        let request = RequestMessage {
+            src: env.client.clone(),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Put(Data::Plain(plain_data), MessageId::new()),
+        };
+        match env.mpid_manager.handle_put(&env.routing, &request) {
+            Err(InternalError::InvalidMessage) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert_rejected(&env.routing.put_failures_given(), &request);
+    }
+
+    #[test]
+    fn put_failure_with_invalid_wrapper() {
+        let mut env = environment_setup();
+        for wrapper in &all_wrappers() {
+            if let MpidMessageWrapper::PutHeader(_) = *wrapper {
+                continue;
+            }
+            let request = invalid_wrapper_request(&env, wrapper, RequestContent::Put);
+            match env.mpid_manager.handle_put_failure(&env.routing, &request) {
+                Err(InternalError::InvalidMessage) => (),
+                result => panic!("Unexpected result for {:?}: {:?}", wrapper, result),
+            }
+        }
+        assert!(env.routing.put_failures_given().is_empty());
+    }
 }
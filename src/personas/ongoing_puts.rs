@@ -0,0 +1,234 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::collections::HashMap;
+
+use chunk_store::ChunkStore;
+use clock::Clock;
+use error::InternalError;
+use maidsafe_utilities::serialisation;
+use routing::{ImmutableData, MessageId};
+use safe_network_common::client_errors::MutationError;
+use time::Duration;
+use timed_buffer::TimedBuffer;
+use vault::CHUNK_STORE_PREFIX;
+use xor_name::XorName;
+
+#[derive(Clone)]
+struct Entry {
+    message_ids: Vec<MessageId>,
+    // `None` if the payload has been moved to disk.
+    data: Option<ImmutableData>,
+    size: u64,
+}
+
+// The chunks an ImmutableDataManager is in the process of storing, kept so that they can be
+// served to Gets and re-sent to new holders until enough holders have confirmed.  Payloads are
+// held in memory up to `memory_budget` bytes, then on disk up to the disk budget.  Once both are
+// used up, new chunks are refused with `NetworkFull`.
+pub struct OngoingPuts {
+    entries: TimedBuffer<XorName, Entry>,
+    data_names: HashMap<MessageId, XorName>,
+    memory_budget: u64,
+    memory_used: u64,
+    spilled: ChunkStore,
+}
+
+impl OngoingPuts {
+    pub fn new(memory_budget: u64,
+               disk_budget: u64,
+               clock: Clock)
+               -> Result<OngoingPuts, InternalError> {
+        Ok(OngoingPuts {
+            entries: TimedBuffer::with_clock(Duration::minutes(5), clock),
+            data_names: HashMap::new(),
+            memory_budget: memory_budget,
+            memory_used: 0,
+            spilled: try!(ChunkStore::new(CHUNK_STORE_PREFIX, disk_budget)),
+        })
+    }
+
+    pub fn insert(&mut self,
+                  data: &ImmutableData,
+                  message_id: MessageId)
+                  -> Result<(), InternalError> {
+        let data_name = data.name();
+        if let Some(entry) = self.entries.get_mut(&data_name) {
+            if !entry.message_ids.contains(&message_id) {
+                entry.message_ids.push(message_id);
+            }
+            let _ = self.data_names.insert(message_id, data_name);
+            return Ok(());
+        }
+
+        let size = data.value().len() as u64;
+        let in_memory = if self.memory_used + size <= self.memory_budget {
+            self.memory_used += size;
+            Some(data.clone())
+        } else {
+            let serialised_data = try!(serialisation::serialise(data));
+            if !self.spilled.has_space(serialised_data.len() as u64) {
+                return Err(InternalError::ClientMutation(MutationError::NetworkFull));
+            }
+            try!(self.spilled.put(&data_name, &serialised_data));
+            None
+        };
+        let entry = Entry {
+            message_ids: vec![message_id],
+            data: in_memory,
+            size: size,
+        };
+        let _ = self.entries.insert(data_name, entry);
+        let _ = self.data_names.insert(message_id, data_name);
+        Ok(())
+    }
+
+    pub fn contains(&self, data_name: &XorName) -> bool {
+        self.entries.contains_key(data_name)
+    }
+
+    // Returns the name of the chunk being stored under `message_id`.
+    pub fn data_name(&self, message_id: &MessageId) -> Option<XorName> {
+        self.data_names.get(message_id).cloned()
+    }
+
+    pub fn get(&mut self, data_name: &XorName) -> Option<ImmutableData> {
+        let in_memory = match self.entries.get_mut(data_name) {
+            Some(entry) => entry.data.clone(),
+            None => return None,
+        };
+        if in_memory.is_some() {
+            return in_memory;
+        }
+        match self.read_from_disk(data_name) {
+            Ok(data) => Some(data),
+            Err(error) => {
+                error!("Failed to read ongoing put of {} from disk: {:?}",
+                       data_name,
+                       error);
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, data_name: &XorName) {
+        if let Some(entry) = self.entries.remove(data_name) {
+            for message_id in &entry.message_ids {
+                let _ = self.data_names.remove(message_id);
+            }
+            if entry.data.is_some() {
+                self.memory_used -= entry.size;
+            } else if let Err(error) = self.spilled.delete(data_name) {
+                warn!("Failed to delete ongoing put of {} from disk: {:?}",
+                      data_name,
+                      error);
+            }
+        }
+    }
+
+    // Drops any chunks which haven't been touched within the expiry period, returning their names.
+    pub fn remove_expired(&mut self) -> Vec<XorName> {
+        let expired = self.entries.get_expired();
+        for data_name in &expired {
+            self.remove(data_name);
+        }
+        expired
+    }
+
+    #[cfg(all(test, not(feature = "use-mock-crust")))]
+    pub fn memory_used(&self) -> u64 {
+        self.memory_used
+    }
+
+    fn read_from_disk(&self, data_name: &XorName) -> Result<ImmutableData, InternalError> {
+        let serialised_data = try!(self.spilled.get(data_name));
+        Ok(try!(serialisation::deserialise(&serialised_data)))
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature="use-mock-crust"))]
+mod test {
+    use super::*;
+    use clock::Clock;
+    use error::InternalError;
+    use routing::{ImmutableData, ImmutableDataType, MessageId};
+    use safe_network_common::client_errors::MutationError;
+    use time::Duration;
+    use utils::generate_random_vec_u8;
+
+    fn random_data(size: usize) -> ImmutableData {
+        ImmutableData::new(ImmutableDataType::Normal, generate_random_vec_u8(size))
+    }
+
+    #[test]
+    fn spill_to_disk() {
+        let mut ongoing_puts = unwrap_result!(OngoingPuts::new(1024, 4096, Clock::manual()));
+        let in_memory = random_data(1000);
+        let on_disk = random_data(1000);
+        let message_id = MessageId::new();
+        unwrap_result!(ongoing_puts.insert(&in_memory, message_id));
+        unwrap_result!(ongoing_puts.insert(&on_disk, MessageId::new()));
+        assert_eq!(ongoing_puts.memory_used(), 1000);
+
+        assert_eq!(ongoing_puts.get(&in_memory.name()), Some(in_memory.clone()));
+        assert_eq!(ongoing_puts.get(&on_disk.name()), Some(on_disk.clone()));
+        assert_eq!(ongoing_puts.data_name(&message_id), Some(in_memory.name()));
+
+        ongoing_puts.remove(&in_memory.name());
+        assert_eq!(ongoing_puts.memory_used(), 0);
+        assert!(!ongoing_puts.contains(&in_memory.name()));
+        assert!(ongoing_puts.data_name(&message_id).is_none());
+        ongoing_puts.remove(&on_disk.name());
+        assert!(ongoing_puts.get(&on_disk.name()).is_none());
+    }
+
+    #[test]
+    fn budget_exhausted() {
+        let mut ongoing_puts = unwrap_result!(OngoingPuts::new(1024, 512, Clock::manual()));
+        let first = random_data(1000);
+        let second = random_data(1000);
+        unwrap_result!(ongoing_puts.insert(&first, MessageId::new()));
+        match ongoing_puts.insert(&second, MessageId::new()) {
+            Err(InternalError::ClientMutation(MutationError::NetworkFull)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // Chunks we already hold are still accepted.
+        unwrap_result!(ongoing_puts.insert(&first, MessageId::new()));
+
+        ongoing_puts.remove(&first.name());
+        unwrap_result!(ongoing_puts.insert(&second, MessageId::new()));
+    }
+
+    #[test]
+    fn expiry() {
+        let clock = Clock::manual();
+        let mut ongoing_puts = unwrap_result!(OngoingPuts::new(1024, 1024, clock.clone()));
+        let data = random_data(100);
+        let message_id = MessageId::new();
+        unwrap_result!(ongoing_puts.insert(&data, message_id));
+
+        clock.advance(Duration::minutes(5));
+        assert!(ongoing_puts.remove_expired().is_empty());
+        clock.advance(Duration::milliseconds(1));
+        assert_eq!(ongoing_puts.remove_expired(), vec![data.name()]);
+        assert!(!ongoing_puts.contains(&data.name()));
+        assert!(ongoing_puts.data_name(&message_id).is_none());
+        assert_eq!(ongoing_puts.memory_used(), 0);
+    }
+}
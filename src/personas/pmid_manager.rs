@@ -15,56 +15,188 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use std::collections::HashMap;
-use std::mem;
-
+use clock::Clock;
 use error::InternalError;
 use maidsafe_utilities::serialisation;
-use routing::{Authority, Data, MessageId, RequestContent, RequestMessage};
-use time::Duration;
-use timed_buffer::TimedBuffer;
-use types::{Refresh, RefreshValue};
+use neighbourhood::{Neighbourhood, XorMap};
+use personas::{Context, Persona, Route, Success, SuccessKind};
+use routing::{Authority, Data, ImmutableData, MessageId, PlainData, RequestContent,
+              RequestMessage, ResponseContent, ResponseMessage};
+use sodiumoxide::crypto::hash::sha512;
+use sodiumoxide::randombytes;
+use std::collections::HashMap;
+use time::{Duration, SteadyTime};
+use timed_buffer::{EvictionPolicy, TimedBuffer};
+use types::{Refresh, RefreshValue, VaultMessage};
+use utils;
 use vault::RoutingNode;
 use xor_name::XorName;
 
+// The most Puts which can be awaiting a response from the PmidNodes at any one time.
+const MAX_ONGOING_PUTS: usize = 10_000;
+// How long a PmidNode has to answer a storage challenge.
+const CHALLENGE_TIMEOUT_SECS: i64 = 60;
+// The most storage challenges kept ready for each PmidNode.
+const MAX_CHALLENGES_PER_NODE: usize = 64;
+const CHALLENGE_NONCE_SIZE: usize = 32;
+// A PmidNode's reputation ranges from zero, if it has lost or failed to store every chunk sent to
+// it, to this if it hasn't let any down.
+pub const MAX_REPUTATION: u8 = 100;
+// PmidNodes with a lower reputation are reported as misbehaving and aren't sent new chunks.
+pub const MISBEHAVING_REPUTATION: u8 = 50;
+// How many chunks a PmidNode must have been sent before its reputation is judged.
+const MIN_REPUTATION_SAMPLE: u64 = 10;
+
 // TODO: Account Creation process required https://maidsafe.atlassian.net/browse/MAID-1191
 #[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug, Default, Clone)]
 pub struct Account {
     // It is now decided the chunk is measured by unit instead of size
     stored_total: u64,
     lost_total: u64,
+    // Puts which the PmidNode failed or didn't answer in time
+    failed_puts: u64,
+    // The sizes of the chunks counted by `stored_total` and `lost_total`
+    stored_bytes: u64,
+    lost_bytes: u64,
+    // As last reported by the PmidNode, or `None` if it hasn't reported yet
+    used_space: u64,
+    free_space: Option<u64>,
+    // Storage challenges not sent yet.  They're kept in the account so that every member of the
+    // group holds the same ones, and sends the same challenge.
+    challenges: Vec<Challenge>,
+    // How many challenges have been prepared for the PmidNode, which numbers each one's round.
+    challenge_rounds: u64,
+    // Mixed into every challenge's nonce, so that the PmidNode can't work the nonces out when it's
+    // sent the chunks.  It's never sent to the PmidNode, and is `None` until the group has agreed
+    // on one.
+    challenge_salt: Option<Vec<u8>>,
 }
 
 impl Account {
     // Always return true to allow pmid_node carry out removal of Sacrificial copies
     // Otherwise Account need to remember storage info of Primary, Backup and Sacrificial
     // copies separately to trigger an early alert
-    fn put_data(&mut self) {
+    fn put_data(&mut self, size: u64) {
         self.stored_total = self.stored_total.saturating_add(1);
+        self.stored_bytes = self.stored_bytes.saturating_add(size);
     }
 
-    fn delete_data(&mut self) {
+    fn delete_data(&mut self, size: u64) {
         self.stored_total = self.stored_total.saturating_sub(1);
+        self.stored_bytes = self.stored_bytes.saturating_sub(size);
     }
 
-    fn lost_data(&mut self) {
-        self.stored_total = self.stored_total.saturating_sub(1);
+    fn lost_data(&mut self, size: u64) {
+        self.delete_data(size);
         self.lost_total = self.lost_total.saturating_add(1);
+        self.lost_bytes = self.lost_bytes.saturating_add(size);
+    }
+
+    fn failed_put(&mut self, size: u64) {
+        self.delete_data(size);
+        self.failed_puts = self.failed_puts.saturating_add(1);
+    }
+
+    // The share of the chunks sent to the PmidNode which it still holds, out of `MAX_REPUTATION`.
+    fn reputation(&self) -> u8 {
+        let faults = self.lost_total.saturating_add(self.failed_puts);
+        let total = self.stored_total.saturating_add(faults);
+        if total < MIN_REPUTATION_SAMPLE {
+            return MAX_REPUTATION;
+        }
+        (self.stored_total.saturating_mul(MAX_REPUTATION as u64) / total) as u8
+    }
+}
+
+// A storage challenge prepared while the chunk passes through us on its way to the PmidNode, so
+// that the answer can be checked later without us holding the chunk.  Its nonce is derived from
+// the account's salt, the chunk's name and the challenge's round, so that the whole group prepares
+// the same one.
+#[derive(RustcEncodable, RustcDecodable, Clone, PartialEq, Eq, Debug)]
+struct Challenge {
+    chunk_name: XorName,
+    // The ImmutableDataManagers which sent the chunk.  For a fragment of an erasure-coded chunk,
+    // these are named after the whole chunk rather than the fragment.
+    data_manager: XorName,
+    size: u64,
+    round: u64,
+    nonce: Vec<u8>,
+    proof: Vec<u8>,
+}
+
+impl Challenge {
+    fn new(data: &ImmutableData,
+           data_manager: &XorName,
+           round: u64,
+           salt: &[u8])
+           -> Result<Challenge, InternalError> {
+        let chunk_name = data.name();
+        let mut input = salt.to_vec();
+        input.extend_from_slice(&chunk_name.0);
+        input.extend_from_slice(&utils::u64_bytes(round));
+        let nonce = sha512::hash(&input).0[..CHALLENGE_NONCE_SIZE].to_vec();
+        let serialised_data = try!(serialisation::serialise(data));
+        let proof = utils::storage_proof(&nonce, &serialised_data);
+        Ok(Challenge {
+            chunk_name: chunk_name,
+            data_manager: *data_manager,
+            size: data.value().len() as u64,
+            round: round,
+            nonce: nonce,
+            proof: proof,
+        })
+    }
+
+    // The ID the group sends the challenge with, which each member derives alike.
+    fn message_id(&self) -> MessageId {
+        utils::message_id(&[&self.chunk_name.0[..], &utils::u64_bytes(self.round)[..]])
+    }
+
+    // The ID the group reports the PmidNode's failure to answer with.
+    fn failure_message_id(&self, pmid_node: &XorName) -> MessageId {
+        utils::message_id(&[&pmid_node.0[..],
+                            &self.chunk_name.0[..],
+                            &utils::u64_bytes(self.round)[..]])
     }
 }
 
 
 pub struct PmidManager {
-    accounts: HashMap<XorName, Account>,
+    accounts: XorMap<Account>,
     // key -- (message_id, targeted pmid_node)
     ongoing_puts: TimedBuffer<(MessageId, XorName), RequestMessage>,
+    // Challenges awaiting an answer, by PmidNode
+    ongoing_challenges: TimedBuffer<XorName, Challenge>,
+    // The lowest challenge salt proposed by a member of the group, by PmidNode, for accounts whose
+    // salt isn't agreed yet.  The next refresh of the account carries it.
+    salt_proposals: HashMap<XorName, Vec<u8>>,
+    // How often each PmidNode is challenged.  Zero disables challenges.
+    challenge_interval: Duration,
+    last_challenged: SteadyTime,
+    // Success responses sent since the registry last took them
+    successes: Vec<Success>,
+    clock: Clock,
 }
 
 impl PmidManager {
-    pub fn new() -> PmidManager {
+    pub fn new(challenge_interval: Duration) -> PmidManager {
+        Self::with_clock(challenge_interval, Clock::system())
+    }
+
+    pub fn with_clock(challenge_interval: Duration, clock: Clock) -> PmidManager {
         PmidManager {
-            accounts: HashMap::new(),
-            ongoing_puts: TimedBuffer::new(Duration::minutes(1)),
+            accounts: XorMap::new(),
+            ongoing_puts: TimedBuffer::with_capacity(Duration::minutes(1),
+                                                     MAX_ONGOING_PUTS,
+                                                     EvictionPolicy::EvictOldest,
+                                                     clock.clone()),
+            ongoing_challenges: TimedBuffer::with_clock(Duration::seconds(CHALLENGE_TIMEOUT_SECS),
+                                                        clock.clone()),
+            salt_proposals: HashMap::new(),
+            challenge_interval: challenge_interval,
+            last_challenged: clock.now(),
+            successes: Vec::new(),
+            clock: clock,
         }
     }
 
@@ -76,21 +208,31 @@ impl PmidManager {
                                                             ref message_id) = request.content {
             (data.clone(), message_id)
         } else {
-            unreachable!("Error in vault demuxing")
+            return Err(InternalError::InvalidMessage);
         };
         // Put data always being allowed, i.e. no early alert
         self.accounts
-            .entry(*request.dst.name())
-            .or_insert_with(Account::default)
-            .put_data();
+            .get_or_insert_with(*request.dst.name(), Account::default)
+            .put_data(data.value().len() as u64);
         let src = Authority::NodeManager(*request.dst.name());
         let dst = Authority::ManagedNode(*request.dst.name());
         trace!("PM forwarding put request of data {} targeting PN {}",
                data.name(),
                dst.name());
-        let _ = self.ongoing_puts.insert((*message_id, *request.dst.name()), request.clone());
+        let key = (*message_id, *request.dst.name());
+        if let Some((evicted_key, evicted_request)) = self.ongoing_puts
+                                                          .insert(key, request.clone()) {
+            // Too many Puts are awaiting a response, so treat the oldest as having timed out.
+            if evicted_key != key {
+                let _ = self.notify_put_failure(routing_node, &evicted_request);
+                // That isn't the PmidNode's fault, so don't hold it against its reputation.
+                if let Some(account) = self.accounts.get_mut(evicted_request.dst.name()) {
+                    account.delete_data(utils::put_size(&evicted_request));
+                }
+            }
+        }
         let _ = routing_node.send_put_request(src, dst, Data::Immutable(data.clone()), *message_id);
-        Ok(())
+        self.prepare_challenge(routing_node, request.dst.name(), &data, request.src.name())
     }
 
     pub fn check_timeout(&mut self, routing_node: &RoutingNode) {
@@ -108,11 +250,25 @@ impl PmidManager {
                                    .ok()
                                    .is_some() {
                         let _ = self.notify_put_failure(routing_node, &request);
+                        let size = utils::put_size(&request);
+                        self.record_fault(routing_node,
+                                          request.dst.name(),
+                                          request.src.name(),
+                                          |account| account.failed_put(size));
                     }
                 }
                 None => continue,
             }
         }
+        for pmid_node in &self.ongoing_challenges.get_expired() {
+            if let Some(challenge) = self.ongoing_challenges.remove(pmid_node) {
+                warn!("PmidNode {} failed to answer storage challenge for {}.",
+                      pmid_node,
+                      challenge.chunk_name);
+                self.fail_challenge(routing_node, pmid_node, &challenge);
+            }
+        }
+        self.send_challenges(routing_node);
     }
 
     pub fn handle_put_success(&mut self,
@@ -122,17 +278,25 @@ impl PmidManager {
                               message_id: &MessageId)
                               -> Result<(), InternalError> {
         if let Some(request) = self.ongoing_puts.remove(&(*message_id, *pmid_node)) {
-            if request.src.name() != data_name {
+            // This isn't necessarily the name of the chunk in `src`, as it may be a fragment of it
+            let stored_name = if let RequestContent::Put(ref data, _) = request.content {
+                data.name()
+            } else {
+                return Err(InternalError::InvalidResponse);
+            };
+            if stored_name != *data_name {
                 error!("Got PutSuccess for {:?} with data name {:?} instead of {:?}.",
                        message_id,
                        data_name,
-                       request.src.name());
+                       stored_name);
                 return Err(InternalError::InvalidResponse);
             }
             let src = request.dst.clone();
             let dst = request.src.clone();
             trace!("As {:?} sending put success to {:?}", src, dst);
-            let _ = routing_node.send_put_success(src, dst, *data_name, *message_id);
+            let success = Success::new(SuccessKind::Put, src, dst, *data_name, *message_id);
+            self.successes.push(success.send(routing_node));
+            self.share_reputation(routing_node, pmid_node, request.src.name());
         }
         // Otherwise we are probably a new member of this `PmidManager` group.
         Ok(())
@@ -147,52 +311,187 @@ impl PmidManager {
         let message_id = if let RequestContent::Put(_, ref message_id) = request.content {
             message_id
         } else {
-            unreachable!("Error in vault demuxing")
+            warn!("Request type doesn't correspond to response type: {:?}",
+                  request);
+            return Err(InternalError::InvalidResponse);
         };
         let _ = self.ongoing_puts.remove(&(*message_id, *request.dst.name()));
-        self.notify_put_failure(routing_node, request)
+        try!(self.notify_put_failure(routing_node, request));
+        let size = utils::put_size(request);
+        self.record_fault(routing_node,
+                          request.dst.name(),
+                          request.src.name(),
+                          |account| account.failed_put(size));
+        Ok(())
     }
 
-    // Posting from DM to PM is only used to notify a get_failure
-    // the encapulated data can be any, as the chunk is now meansured by unit instead of size
-    pub fn handle_post(&mut self, request: &RequestMessage) -> Result<(), InternalError> {
-        if let Some(account) = self.accounts.get_mut(request.dst.name()) {
-            account.lost_data();
+    // Posting from DM to PM is used to ask for the PmidNode's capacity, or to notify a
+    // get_failure, in which case the PmidNode is charged for the size of the chunk it lost.
+    pub fn handle_post(&mut self,
+                       routing_node: &RoutingNode,
+                       request: &RequestMessage)
+                       -> Result<(), InternalError> {
+        let data = if let RequestContent::Post(Data::Plain(ref data), _) = request.content {
+            data
+        } else {
+            return Err(InternalError::InvalidMessage);
+        };
+        match try!(serialisation::deserialise(data.value())) {
+            VaultMessage::CapacityQuery => self.handle_capacity_query(routing_node, request),
+            VaultMessage::ChunkLost { size } => {
+                self.record_fault(routing_node,
+                                  request.dst.name(),
+                                  request.src.name(),
+                                  |account| account.lost_data(size));
+                Ok(())
+            }
+            _ => Err(InternalError::InvalidMessage),
+        }
+    }
+
+    // Posts from a PmidNode either answer our storage challenge or report its capacity.  Another
+    // member of the group may also propose a challenge salt for the PmidNode's account.
+    pub fn handle_pmid_node_post(&mut self,
+                                 routing_node: &RoutingNode,
+                                 request: &RequestMessage)
+                                 -> Result<(), InternalError> {
+        let data = if let RequestContent::Post(Data::Plain(ref data), _) = request.content {
+            data
+        } else {
+            return Err(InternalError::InvalidMessage);
+        };
+        let pmid_node = *request.src.name();
+        match try!(serialisation::deserialise(data.value())) {
+            VaultMessage::StorageProof { nonce, proof } => {
+                Ok(self.handle_storage_proof(routing_node, pmid_node, data.name(), nonce, proof))
+            }
+            VaultMessage::CapacityReport { used, free } => {
+                Ok(self.handle_capacity_report(pmid_node, used, free))
+            }
+            VaultMessage::ChallengeSalt(salt) => {
+                self.handle_salt_proposal(routing_node, request.dst.name(), &pmid_node, salt)
+            }
+            _ => Err(InternalError::InvalidMessage),
+        }
+    }
+
+    // Keeps the lowest salt proposed for the PmidNode's account by a member of its group.  The
+    // PmidNode itself can't propose one, as it mustn't know the salt.
+    pub fn handle_salt_proposal(&mut self,
+                                routing_node: &RoutingNode,
+                                pmid_node: &XorName,
+                                proposer: &XorName,
+                                salt: Vec<u8>)
+                                -> Result<(), InternalError> {
+        if proposer == pmid_node {
+            return Err(InternalError::InvalidMessage);
+        }
+        match try!(routing_node.close_group(*pmid_node)) {
+            Some(ref close_group) if close_group.contains(proposer) => (),
+            _ => return Err(InternalError::InvalidMessage),
         }
+        if self.accounts.get(pmid_node).map_or(false, |account| account.challenge_salt.is_some()) {
+            return Ok(());
+        }
+        let lowest = self.salt_proposals.entry(*pmid_node).or_insert_with(|| salt.clone());
+        if salt < *lowest {
+            *lowest = salt;
+        }
+        Ok(())
+    }
+
+    // Checks a PmidNode's answer to our storage challenge.
+    pub fn handle_storage_proof(&mut self,
+                                routing_node: &RoutingNode,
+                                pmid_node: XorName,
+                                chunk_name: XorName,
+                                nonce: Vec<u8>,
+                                proof: Vec<u8>) {
+        let expected = match self.ongoing_challenges.get(&pmid_node) {
+            Some(challenge) => challenge.chunk_name == chunk_name && challenge.nonce == nonce,
+            None => false,
+        };
+        if !expected {
+            return;
+        }
+        if let Some(challenge) = self.ongoing_challenges.remove(&pmid_node) {
+            if challenge.proof == proof {
+                trace!("PmidNode {} proved it holds {}.", pmid_node, challenge.chunk_name);
+            } else {
+                warn!("PmidNode {} failed storage challenge for {}.",
+                      pmid_node,
+                      challenge.chunk_name);
+                self.fail_challenge(routing_node, &pmid_node, &challenge);
+            }
+        }
+    }
+
+    pub fn handle_capacity_report(&mut self, pmid_node: XorName, used: u64, free: u64) {
+        let account = self.accounts.get_or_insert_with(pmid_node, Account::default);
+        account.used_space = used;
+        account.free_space = Some(free);
+    }
+
+    // Answers ImmutableDataManagers asking whether the PmidNode has room for their chunks with its
+    // latest report.  If it hasn't reported yet, they're left to find out by trying.
+    pub fn handle_capacity_query(&self,
+                                 routing_node: &RoutingNode,
+                                 request: &RequestMessage)
+                                 -> Result<(), InternalError> {
+        let message_id = if let RequestContent::Post(_, ref message_id) = request.content {
+            message_id
+        } else {
+            return Err(InternalError::InvalidMessage);
+        };
+        let (used, free) = match self.accounts.get(request.dst.name()) {
+            Some(&Account { used_space, free_space: Some(free_space), .. }) => {
+                (used_space, free_space)
+            }
+            _ => return Ok(()),
+        };
+        let message = VaultMessage::CapacityReport {
+            used: used,
+            free: free,
+        };
+        let serialised_message = try!(serialisation::serialise(&message));
+        let src = request.dst.clone();
+        let dst = request.src.clone();
+        let data = Data::Plain(PlainData::new(*dst.name(), serialised_message));
+        let _ = routing_node.send_post_request(src, dst, data, *message_id);
         Ok(())
     }
 
     pub fn handle_refresh(&mut self, name: XorName, account: Account) {
+        if account.challenge_salt.is_some() {
+            let _ = self.salt_proposals.remove(&name);
+        }
         let _ = self.accounts.insert(name, account);
     }
 
+    // Refreshes the accounts whose close group could have gained or lost `node_changed`, only
+    // retaining those for which we're still in the close group.
     pub fn handle_churn(&mut self, routing_node: &RoutingNode, node_changed: &XorName) {
-        // Only retain accounts for which we're still in the close group
-        let accounts = mem::replace(&mut self.accounts, HashMap::new());
-        self.accounts = accounts.into_iter()
-                                .filter(|&(ref pmid_node, ref account)| {
-                                    match routing_node.close_group(*pmid_node) {
-                                        Ok(None) => {
-                                            trace!("No longer a PM for {}", pmid_node);
-                                            false
-                                        }
-                                        Ok(Some(_)) => {
-                                            self.send_refresh(routing_node,
-                                                              pmid_node,
-                                                              account,
-                                                              &MessageId::from_lost_node(
-                                                                    *node_changed));
-                                            true
-                                        }
-                                        Err(error) => {
-                                            error!("Failed to get close group: {:?} for {}",
-                                                   error,
-                                                   pmid_node);
-                                            false
-                                        }
-                                    }
-                                })
-                                .collect();
+        let neighbourhood = Neighbourhood::new(routing_node, node_changed);
+        for pmid_node in self.accounts.names_in(&neighbourhood) {
+            match routing_node.close_group(pmid_node) {
+                Ok(None) => {
+                    trace!("No longer a PM for {}", pmid_node);
+                    self.remove_account(&pmid_node);
+                }
+                Ok(Some(_)) => {
+                    if let Some(account) = self.accounts.get(&pmid_node) {
+                        self.send_refresh(routing_node,
+                                          &pmid_node,
+                                          account,
+                                          &MessageId::from_lost_node(*node_changed));
+                    }
+                }
+                Err(error) => {
+                    error!("Failed to get close group: {:?} for {}", error, pmid_node);
+                    self.remove_account(&pmid_node);
+                }
+            }
+        }
     }
 
     // The `request` is the original request from NAE to PM
@@ -204,7 +503,7 @@ impl PmidManager {
                                                             ref message_id) = request.content {
             (data.clone(), message_id)
         } else {
-            unreachable!("Error in vault demuxing")
+            return Err(InternalError::InvalidResponse);
         };
 
         let src = request.dst.clone();
@@ -216,19 +515,207 @@ impl PmidManager {
         let _ = routing_node.send_put_failure(src, dst, request.clone(), vec![], *message_id);
 
         if let Some(account) = self.accounts.get_mut(request.dst.name()) {
-            account.delete_data();
+            account.challenges.retain(|challenge| challenge.chunk_name != data.name());
         }
 
         Ok(())
     }
 
+    fn prepare_challenge(&mut self,
+                         routing_node: &RoutingNode,
+                         pmid_node: &XorName,
+                         data: &ImmutableData,
+                         data_manager: &XorName)
+                         -> Result<(), InternalError> {
+        if self.challenge_interval <= Duration::zero() {
+            return Ok(());
+        }
+        let account = match self.accounts.get_mut(pmid_node) {
+            Some(account) => account,
+            None => return Ok(()),
+        };
+        let challenge = match account.challenge_salt {
+            Some(ref salt) => {
+                try!(Challenge::new(data, data_manager, account.challenge_rounds, salt))
+            }
+            None => {
+                // The group can only challenge chunks stored once it has agreed on a salt.
+                if !self.salt_proposals.contains_key(pmid_node) {
+                    Self::propose_salt(routing_node, pmid_node, &mut self.salt_proposals);
+                }
+                return Ok(());
+            }
+        };
+        account.challenge_rounds = account.challenge_rounds.wrapping_add(1);
+        let challenges = &mut account.challenges;
+        if challenges.len() < MAX_CHALLENGES_PER_NODE {
+            challenges.push(challenge);
+        } else {
+            // Replace the one the nonce picks, so that older chunks keep a chance of being
+            // challenged.
+            let index = challenge.nonce.first().map_or(0, |&byte| byte as usize) % challenges.len();
+            if let Some(slot) = challenges.get_mut(index) {
+                *slot = challenge;
+            }
+        }
+        Ok(())
+    }
+
+    // Once per challenge interval, challenges each PmidNode which isn't still answering the last
+    // one.
+    fn send_challenges(&mut self, routing_node: &RoutingNode) {
+        let now = self.clock.now();
+        if self.challenge_interval <= Duration::zero() ||
+           now - self.last_challenged < self.challenge_interval {
+            return;
+        }
+        self.last_challenged = now;
+        let pmid_nodes = self.accounts.keys().cloned().collect::<Vec<_>>();
+        for pmid_node in &pmid_nodes {
+            if self.ongoing_challenges.contains_key(pmid_node) {
+                continue;
+            }
+            let challenge = match self.accounts
+                                      .get_mut(pmid_node)
+                                      .and_then(|account| account.challenges.pop()) {
+                Some(challenge) => challenge,
+                None => continue,
+            };
+            let message = VaultMessage::StorageChallenge { nonce: challenge.nonce.clone() };
+            if let Ok(serialised_message) = serialisation::serialise(&message) {
+                let src = Authority::NodeManager(*pmid_node);
+                let dst = Authority::ManagedNode(*pmid_node);
+                trace!("PM challenging {} to prove it holds {}",
+                       pmid_node,
+                       challenge.chunk_name);
+                let data = Data::Plain(PlainData::new(challenge.chunk_name, serialised_message));
+                let _ = routing_node.send_post_request(src, dst, data, challenge.message_id());
+                let _ = self.ongoing_challenges.insert(*pmid_node, challenge);
+            }
+        }
+    }
+
+    // Proposes a random challenge salt for the PmidNode's account to the rest of its group, and
+    // counts it among the proposals ourselves.
+    fn propose_salt(routing_node: &RoutingNode,
+                    pmid_node: &XorName,
+                    salt_proposals: &mut HashMap<XorName, Vec<u8>>) {
+        let our_name = match routing_node.name() {
+            Ok(name) => name,
+            Err(error) => {
+                error!("Failed to get our name: {:?}", error);
+                return;
+            }
+        };
+        let salt = randombytes::randombytes(CHALLENGE_NONCE_SIZE);
+        let message = VaultMessage::ChallengeSalt(salt.clone());
+        if let Ok(serialised_message) = serialisation::serialise(&message) {
+            let src = Authority::ManagedNode(our_name);
+            let dst = Authority::NodeManager(*pmid_node);
+            let data = Data::Plain(PlainData::new(*pmid_node, serialised_message));
+            let _ = routing_node.send_post_request(src, dst, data, MessageId::new());
+        }
+        let _ = salt_proposals.insert(*pmid_node, salt);
+    }
+
+    // Records the loss against the PmidNode's account and asks the chunk's ImmutableDataManagers to
+    // find it a new holder.
+    fn fail_challenge(&mut self,
+                      routing_node: &RoutingNode,
+                      pmid_node: &XorName,
+                      challenge: &Challenge) {
+        let message = VaultMessage::FailedStorageChallenge;
+        if let Ok(serialised_message) = serialisation::serialise(&message) {
+            let src = Authority::NodeManager(*pmid_node);
+            let dst = Authority::NaeManager(challenge.data_manager);
+            let data = Data::Plain(PlainData::new(challenge.data_manager, serialised_message));
+            let _ = routing_node.send_post_request(src,
+                                                   dst,
+                                                   data,
+                                                   challenge.failure_message_id(pmid_node));
+        }
+        let size = challenge.size;
+        self.record_fault(routing_node,
+                          pmid_node,
+                          &challenge.data_manager,
+                          |account| account.lost_data(size));
+    }
+
+    // Records a lost chunk or failed Put against the PmidNode's account, reporting the node as
+    // misbehaving if that drops its reputation too low, and tells the chunk's ImmutableDataManagers
+    // its new reputation.
+    fn record_fault<F: FnOnce(&mut Account)>(&mut self,
+                                             routing_node: &RoutingNode,
+                                             pmid_node: &XorName,
+                                             data_manager: &XorName,
+                                             fault: F) {
+        if let Some(account) = self.accounts.get_mut(pmid_node) {
+            let previous_reputation = account.reputation();
+            fault(account);
+            let reputation = account.reputation();
+            if reputation < MISBEHAVING_REPUTATION &&
+               previous_reputation >= MISBEHAVING_REPUTATION {
+                warn!("PmidNode {} is misbehaving: {} chunks lost and {} Puts failed.",
+                      pmid_node,
+                      account.lost_total,
+                      account.failed_puts);
+            }
+        } else {
+            return;
+        }
+        self.share_reputation(routing_node, pmid_node, data_manager);
+    }
+
+    // Tells the ImmutableDataManagers named `data_manager` the PmidNode's reputation.  Nothing is
+    // sent for a PmidNode with a clean record, as that's what they assume of nodes they haven't
+    // heard about.  The ID is derived from the counts the reputation is worked out from, so that
+    // the whole group sends the same message.
+    fn share_reputation(&self,
+                        routing_node: &RoutingNode,
+                        pmid_node: &XorName,
+                        data_manager: &XorName) {
+        let account = if let Some(account) = self.accounts.get(pmid_node) {
+            account
+        } else {
+            return;
+        };
+        let reputation = account.reputation();
+        if reputation == MAX_REPUTATION {
+            return;
+        }
+        let message = VaultMessage::PmidNodeReputation(reputation);
+        if let Ok(serialised_message) = serialisation::serialise(&message) {
+            let src = Authority::NodeManager(*pmid_node);
+            let dst = Authority::NaeManager(*data_manager);
+            let data = Data::Plain(PlainData::new(*data_manager, serialised_message));
+            let message_id = utils::message_id(&[&pmid_node.0[..],
+                                                 &data_manager.0[..],
+                                                 &utils::u64_bytes(account.stored_total)[..],
+                                                 &utils::u64_bytes(account.lost_total)[..],
+                                                 &utils::u64_bytes(account.failed_puts)[..]]);
+            let _ = routing_node.send_post_request(src, dst, data, message_id);
+        }
+    }
+
+    fn remove_account(&mut self, pmid_node: &XorName) {
+        let _ = self.accounts.remove(pmid_node);
+        let _ = self.ongoing_challenges.remove(pmid_node);
+        let _ = self.salt_proposals.remove(pmid_node);
+    }
+
+    // Until the group has agreed on the account's challenge salt, the refresh carries the lowest
+    // one proposed, which every member picks alike once the proposals have reached them all.
     fn send_refresh(&self,
                     routing_node: &RoutingNode,
                     pmid_node: &XorName,
                     account: &Account,
                     message_id: &MessageId) {
         let src = Authority::NodeManager(*pmid_node);
-        let refresh = Refresh::new(pmid_node, RefreshValue::PmidManagerAccount(account.clone()));
+        let mut account = account.clone();
+        if account.challenge_salt.is_none() {
+            account.challenge_salt = self.salt_proposals.get(pmid_node).cloned();
+        }
+        let refresh = Refresh::new(pmid_node, RefreshValue::PmidManagerAccount(account));
         if let Ok(serialised_refresh) = serialisation::serialise(&refresh) {
             trace!("PM sending refresh for account {}", src.name());
             let _ = routing_node.send_refresh_request(src.clone(),
@@ -239,9 +726,76 @@ impl PmidManager {
     }
 }
 
-impl Default for PmidManager {
-    fn default() -> PmidManager {
-        PmidManager::new()
+impl Persona for PmidManager {
+    fn routes(&self) -> Vec<Route> {
+        use personas::AuthorityKind::{ManagedNode, NaeManager, NodeManager};
+        use personas::DataKind::{Immutable, Plain};
+        use personas::MessageKind::{Post, Put, PutFailure, PutSuccess, Refresh};
+        use personas::RefreshKind::PmidManagerAccount;
+        vec![Route(NaeManager, NodeManager, Put(Immutable)),
+             Route(NaeManager, NodeManager, Post(Plain)),
+             Route(ManagedNode, NodeManager, Post(Plain)),
+             Route(ManagedNode, NodeManager, PutSuccess),
+             Route(ManagedNode, NodeManager, PutFailure(Immutable)),
+             Route(NodeManager, NodeManager, Refresh(PmidManagerAccount))]
+    }
+
+    fn on_request(&mut self,
+                  context: &mut Context,
+                  request: &RequestMessage)
+                  -> Result<(), InternalError> {
+        match request.content {
+            RequestContent::Put(..) => self.handle_put(context.routing_node, request),
+            RequestContent::Post(..) => {
+                if let Authority::ManagedNode(_) = request.src {
+                    self.handle_pmid_node_post(context.routing_node, request)
+                } else {
+                    self.handle_post(context.routing_node, request)
+                }
+            }
+            _ => Err(InternalError::InvalidMessage),
+        }
+    }
+
+    fn on_response(&mut self,
+                   context: &mut Context,
+                   response: &ResponseMessage)
+                   -> Result<(), InternalError> {
+        match response.content {
+            ResponseContent::PutSuccess(ref name, ref message_id) => {
+                self.handle_put_success(context.routing_node,
+                                        response.src.name(),
+                                        name,
+                                        message_id)
+            }
+            ResponseContent::PutFailure { ref request, .. } => {
+                self.handle_put_failure(context.routing_node, request)
+            }
+            _ => Err(InternalError::InvalidResponse),
+        }
+    }
+
+    fn on_refresh(&mut self,
+                  _context: &mut Context,
+                  refresh: &Refresh)
+                  -> Result<(), InternalError> {
+        if let RefreshValue::PmidManagerAccount(ref account) = refresh.value {
+            Ok(self.handle_refresh(refresh.name, account.clone()))
+        } else {
+            Err(InternalError::InvalidMessage)
+        }
+    }
+
+    fn on_churn(&mut self, context: &mut Context, node_changed: &XorName) {
+        self.handle_churn(context.routing_node, node_changed)
+    }
+
+    fn on_tick(&mut self, context: &mut Context) {
+        self.check_timeout(context.routing_node)
+    }
+
+    fn take_successes(&mut self) -> Vec<Success> {
+        self.successes.drain(..).collect()
     }
 }
 
@@ -251,17 +805,18 @@ impl Default for PmidManager {
 #[cfg(not(feature="use-mock-crust"))]
 mod test {
     use super::*;
+    use clock::Clock;
+    use error::InternalError;
     use maidsafe_utilities::serialisation;
     use rand::{thread_rng, random};
     use rand::distributions::{IndependentSample, Range};
-    use routing::{Authority, Data, ImmutableData, ImmutableDataType, MessageId, RequestContent,
-                  RequestMessage, ResponseContent};
+    use routing::{Authority, Data, ImmutableData, ImmutableDataType, MessageId, PlainData,
+                  RequestContent, RequestMessage, ResponseContent};
+    use sodiumoxide::crypto::hash::sha512;
     use std::sync::mpsc;
-    use std::thread::sleep;
     use time::Duration;
-    use timed_buffer::TimedBuffer;
-    use types::Refresh;
-    use utils::generate_random_vec_u8;
+    use types::{Refresh, RefreshValue, VaultMessage};
+    use utils::{self, generate_random_vec_u8};
     use vault::RoutingNode;
     use xor_name::XorName;
 
@@ -270,6 +825,7 @@ mod test {
         our_authority: Authority,
         from_authority: Authority,
         routing: RoutingNode,
+        clock: Clock,
         pmid_manager: PmidManager,
     }
 
@@ -294,11 +850,13 @@ mod test {
             }
         }
 
+        let clock = Clock::manual();
         Environment {
             our_authority: Authority::NodeManager(our_name),
             from_authority: Authority::NaeManager(from_name),
             routing: routing,
-            pmid_manager: PmidManager::default(),
+            clock: clock.clone(),
+            pmid_manager: PmidManager::with_clock(Duration::minutes(10), clock),
         }
     }
 
@@ -346,6 +904,276 @@ mod test {
         }
     }
 
+    // An account for which the group has agreed on `salt`.
+    fn salted_account(salt: Vec<u8>) -> Account {
+        Account { challenge_salt: Some(salt), ..Account::default() }
+    }
+
+    // Stores a chunk on our PmidNode and has us challenge it, returning the chunk and the
+    // challenge's nonce.
+    fn challenge_for_new_chunk(env: &mut Environment) -> (ImmutableData, Vec<u8>) {
+        env.pmid_manager.handle_refresh(*env.our_authority.name(), salted_account(vec![1; 32]));
+        let immutable_data = get_close_data(env);
+        let message_id = MessageId::new();
+        let request = RequestMessage {
+            src: Authority::NaeManager(immutable_data.name()),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Put(Data::Immutable(immutable_data.clone()), message_id),
+        };
+        unwrap_result!(env.pmid_manager.handle_put(&env.routing, &request));
+        unwrap_result!(env.pmid_manager.handle_put_success(&env.routing,
+                                                           env.our_authority.name(),
+                                                           &immutable_data.name(),
+                                                           &message_id));
+        assert!(env.routing.post_requests_given().is_empty());
+
+        env.clock.advance(Duration::minutes(10));
+        env.pmid_manager.check_timeout(&env.routing);
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), 1);
+        assert_eq!(post_requests[0].src, env.our_authority);
+        assert_eq!(post_requests[0].dst,
+                   Authority::ManagedNode(*env.our_authority.name()));
+        let nonce = if let RequestContent::Post(Data::Plain(ref data), _) = post_requests[0]
+                                                                                .content {
+            assert_eq!(data.name(), immutable_data.name());
+            match unwrap_result!(serialisation::deserialise(data.value())) {
+                VaultMessage::StorageChallenge { nonce } => nonce,
+                message => panic!("Unexpected message {:?}", message),
+            }
+        } else {
+            panic!("Unexpected request {:?}", post_requests[0]);
+        };
+        (immutable_data, nonce)
+    }
+
+    fn storage_proof_request(env: &Environment,
+                             chunk_name: XorName,
+                             nonce: Vec<u8>,
+                             proof: Vec<u8>)
+                             -> RequestMessage {
+        let message = VaultMessage::StorageProof {
+            nonce: nonce,
+            proof: proof,
+        };
+        let serialised_message = unwrap_result!(serialisation::serialise(&message));
+        RequestMessage {
+            src: Authority::ManagedNode(*env.our_authority.name()),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Post(Data::Plain(PlainData::new(chunk_name,
+                                                                     serialised_message)),
+                                          MessageId::new()),
+        }
+    }
+
+    fn assert_challenge_failure_reported(env: &Environment, chunk_name: XorName) {
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), 2);
+        assert_eq!(post_requests[1].src, env.our_authority);
+        assert_eq!(post_requests[1].dst, Authority::NaeManager(chunk_name));
+        if let RequestContent::Post(Data::Plain(ref data), ref id) = post_requests[1].content {
+            assert_eq!(unwrap_result!(serialisation::deserialise::<VaultMessage>(data.value())),
+                       VaultMessage::FailedStorageChallenge);
+            // The whole group reports it under the same ID.
+            assert_eq!(*id,
+                       utils::message_id(&[&env.our_authority.name().0[..],
+                                           &chunk_name.0[..],
+                                           &utils::u64_bytes(0)[..]]));
+        } else {
+            panic!("Unexpected request {:?}", post_requests[1]);
+        }
+        let account = unwrap_option!(env.pmid_manager.accounts.get(env.our_authority.name()), "");
+        assert_eq!(account.lost_total, 1);
+        assert_eq!(account.lost_bytes, 1024);
+    }
+
+    #[test]
+    fn storage_challenge_passed() {
+        let mut env = environment_setup();
+        let (immutable_data, nonce) = challenge_for_new_chunk(&mut env);
+        let serialised_data = unwrap_result!(serialisation::serialise(&immutable_data));
+        let proof = utils::storage_proof(&nonce, &serialised_data);
+
+        // An answer to some other challenge is ignored.
+        let other_answer = storage_proof_request(&env, immutable_data.name(), vec![0; 32], vec![]);
+        unwrap_result!(env.pmid_manager.handle_pmid_node_post(&env.routing, &other_answer));
+        let answer = storage_proof_request(&env, immutable_data.name(), nonce, proof);
+        unwrap_result!(env.pmid_manager.handle_pmid_node_post(&env.routing, &answer));
+
+        env.clock.advance(Duration::minutes(1));
+        env.pmid_manager.check_timeout(&env.routing);
+        assert_eq!(env.routing.post_requests_given().len(), 1);
+        let account = unwrap_option!(env.pmid_manager.accounts.get(env.our_authority.name()), "");
+        assert_eq!(account.lost_total, 0);
+    }
+
+    #[test]
+    fn storage_challenge_failed() {
+        let mut env = environment_setup();
+        let (immutable_data, nonce) = challenge_for_new_chunk(&mut env);
+        let answer = storage_proof_request(&env, immutable_data.name(), nonce, vec![]);
+        unwrap_result!(env.pmid_manager.handle_pmid_node_post(&env.routing, &answer));
+        assert_challenge_failure_reported(&env, immutable_data.name());
+    }
+
+    #[test]
+    fn storage_challenge_precomputed() {
+        let mut env = environment_setup();
+        let (immutable_data, nonce) = challenge_for_new_chunk(&mut env);
+
+        // Knowing the chunk's name and how many were stored before it, the PmidNode can't work out
+        // the nonce when it stores the chunk, so a proof it computed then and kept instead of the
+        // chunk doesn't answer the challenge.
+        let mut input = immutable_data.name().0.to_vec();
+        input.extend_from_slice(&utils::u64_bytes(0));
+        let guessed_nonce = sha512::hash(&input).0[..CHALLENGE_NONCE_SIZE].to_vec();
+        assert!(guessed_nonce != nonce);
+        let serialised_data = unwrap_result!(serialisation::serialise(&immutable_data));
+        let precomputed_proof = utils::storage_proof(&guessed_nonce, &serialised_data);
+        let answer = storage_proof_request(&env, immutable_data.name(), nonce, precomputed_proof);
+        unwrap_result!(env.pmid_manager.handle_pmid_node_post(&env.routing, &answer));
+        assert_challenge_failure_reported(&env, immutable_data.name());
+    }
+
+    #[test]
+    fn storage_challenge_timeout() {
+        let mut env = environment_setup();
+        let (immutable_data, _) = challenge_for_new_chunk(&mut env);
+        env.clock.advance(Duration::seconds(60));
+        env.pmid_manager.check_timeout(&env.routing);
+        assert_eq!(env.routing.post_requests_given().len(), 1);
+        env.clock.advance(Duration::milliseconds(1));
+        env.pmid_manager.check_timeout(&env.routing);
+        assert_challenge_failure_reported(&env, immutable_data.name());
+    }
+
+    #[test]
+    fn storage_challenge_agreed() {
+        let mut env = environment_setup();
+        let (immutable_data, nonce) = challenge_for_new_chunk(&mut env);
+        let challenge = env.routing.post_requests_given()[0].content.clone();
+
+        // Another member of the group, and one which joins it afterwards, send the same
+        // challenge with the same ID, so that the group's challenges accumulate.
+        let mut other_manager = PmidManager::with_clock(Duration::minutes(10), env.clock.clone());
+        other_manager.handle_refresh(*env.our_authority.name(), salted_account(vec![1; 32]));
+        let request = RequestMessage {
+            src: Authority::NaeManager(immutable_data.name()),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Put(Data::Immutable(immutable_data.clone()),
+                                         MessageId::new()),
+        };
+        unwrap_result!(other_manager.handle_put(&env.routing, &request));
+        let mut joining_manager = PmidManager::with_clock(Duration::minutes(10),
+                                                          env.clock.clone());
+        let account = unwrap_option!(other_manager.accounts.get(env.our_authority.name()), "");
+        joining_manager.handle_refresh(*env.our_authority.name(), account.clone());
+
+        env.clock.advance(Duration::minutes(10));
+        other_manager.check_timeout(&env.routing);
+        joining_manager.check_timeout(&env.routing);
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), 3);
+        for post_request in &post_requests[1..] {
+            assert_eq!(post_request.content, challenge);
+        }
+        if let RequestContent::Post(Data::Plain(ref data), _) = challenge {
+            assert_eq!(unwrap_result!(serialisation::deserialise::<VaultMessage>(data.value())),
+                       VaultMessage::StorageChallenge { nonce: nonce });
+        } else {
+            panic!("Unexpected request {:?}", challenge);
+        }
+    }
+
+    #[test]
+    fn challenge_salt_agreed() {
+        let mut env = environment_setup();
+        let pmid_node = *env.our_authority.name();
+        let our_name = unwrap_result!(env.routing.name());
+        let immutable_data = get_close_data(&env);
+        let request = RequestMessage {
+            src: Authority::NaeManager(immutable_data.name()),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Put(Data::Immutable(immutable_data), MessageId::new()),
+        };
+
+        // Until the group has agreed on a salt, chunks aren't challenged, and we propose one to
+        // the rest of the group.
+        unwrap_result!(env.pmid_manager.handle_put(&env.routing, &request));
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), 1);
+        assert_eq!(post_requests[0].src, Authority::ManagedNode(our_name));
+        assert_eq!(post_requests[0].dst, env.our_authority);
+        let our_salt = if let RequestContent::Post(Data::Plain(ref data), _) = post_requests[0]
+                                                                                  .content {
+            match unwrap_result!(serialisation::deserialise(data.value())) {
+                VaultMessage::ChallengeSalt(salt) => salt,
+                message => panic!("Unexpected message {:?}", message),
+            }
+        } else {
+            panic!("Unexpected request {:?}", post_requests[0]);
+        };
+        let account = unwrap_option!(env.pmid_manager.accounts.get(&pmid_node), "");
+        assert!(account.challenges.is_empty());
+        assert_eq!(account.challenge_salt, None);
+
+        // The PmidNode itself can't propose one, but the lowest proposed by the group is kept.
+        let our_authority = env.our_authority.clone();
+        let proposal = |proposer: XorName, salt: Vec<u8>| {
+            let message = VaultMessage::ChallengeSalt(salt);
+            let serialised_message = unwrap_result!(serialisation::serialise(&message));
+            RequestMessage {
+                src: Authority::ManagedNode(proposer),
+                dst: our_authority.clone(),
+                content: RequestContent::Post(Data::Plain(PlainData::new(pmid_node,
+                                                                         serialised_message)),
+                                              MessageId::new()),
+            }
+        };
+        let lowest_salt = vec![0; 32];
+        match env.pmid_manager
+                 .handle_pmid_node_post(&env.routing, &proposal(pmid_node, lowest_salt.clone())) {
+            Err(InternalError::InvalidMessage) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert_eq!(env.pmid_manager.salt_proposals.get(&pmid_node), Some(&our_salt));
+        let close_group = unwrap_option!(unwrap_result!(env.routing.close_group(pmid_node)), "");
+        let member = *unwrap_option!(close_group.iter().find(|name| **name != pmid_node), "");
+        unwrap_result!(env.pmid_manager
+                          .handle_pmid_node_post(&env.routing,
+                                                 &proposal(member, lowest_salt.clone())));
+        unwrap_result!(env.pmid_manager
+                          .handle_pmid_node_post(&env.routing, &proposal(member, vec![255; 32])));
+        assert_eq!(env.pmid_manager.salt_proposals.get(&pmid_node), Some(&lowest_salt));
+
+        // The account's refresh carries it, and once that's agreed the group challenges chunks.
+        let account = unwrap_option!(env.pmid_manager.accounts.get(&pmid_node), "").clone();
+        env.pmid_manager.send_refresh(&env.routing, &pmid_node, &account, &MessageId::new());
+        let refresh_requests = env.routing.refresh_requests_given();
+        assert_eq!(refresh_requests.len(), 1);
+        let refreshed_account = if let RequestContent::Refresh(ref serialised_refresh, _) =
+                                       refresh_requests[0].content {
+            match unwrap_result!(serialisation::deserialise::<Refresh>(serialised_refresh)).value {
+                RefreshValue::PmidManagerAccount(account) => account,
+                value => panic!("Unexpected refresh {:?}", value),
+            }
+        } else {
+            panic!("Unexpected request {:?}", refresh_requests[0]);
+        };
+        assert_eq!(refreshed_account.challenge_salt, Some(lowest_salt.clone()));
+        env.pmid_manager.handle_refresh(pmid_node, refreshed_account);
+        assert!(env.pmid_manager.salt_proposals.is_empty());
+        let request = RequestMessage {
+            src: Authority::NaeManager(random()),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Put(Data::Immutable(get_close_data(&env)), MessageId::new()),
+        };
+        unwrap_result!(env.pmid_manager.handle_put(&env.routing, &request));
+        assert_eq!(env.routing.post_requests_given().len(), 1);
+        let account = unwrap_option!(env.pmid_manager.accounts.get(&pmid_node), "");
+        assert_eq!(account.challenges.len(), 1);
+    }
+
     #[test]
     fn handle_put() {
         let mut env = environment_setup();
@@ -379,8 +1207,6 @@ mod test {
     #[test]
     fn check_timeout() {
         let mut env = environment_setup();
-        // Reduce the timeout to speed up the test
-        env.pmid_manager.ongoing_puts = TimedBuffer::new(Duration::milliseconds(500));
 
         let immutable_data = get_close_data(&env);
         let message_id = MessageId::new();
@@ -408,7 +1234,11 @@ mod test {
             unreachable!()
         }
 
-        sleep(::std::time::Duration::from_secs(1));
+        env.clock.advance(Duration::minutes(1));
+        env.pmid_manager.check_timeout(&env.routing);
+        assert!(env.routing.put_failures_given().is_empty());
+
+        env.clock.advance(Duration::milliseconds(1));
         env.pmid_manager.check_timeout(&env.routing);
 
         let put_failures = env.routing.put_failures_given();
@@ -544,6 +1374,129 @@ mod test {
         }
     }
 
+    #[test]
+    fn capacity_query() {
+        let mut env = environment_setup();
+        let query_message = VaultMessage::CapacityQuery;
+        let serialised_query = unwrap_result!(serialisation::serialise(&query_message));
+        let query = RequestMessage {
+            src: env.from_authority.clone(),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Post(Data::Plain(PlainData::new(*env.from_authority.name(),
+                                                                     serialised_query)),
+                                          MessageId::new()),
+        };
+        // Nothing is known until the PmidNode reports.
+        unwrap_result!(env.pmid_manager.handle_post(&env.routing, &query));
+        assert!(env.routing.post_requests_given().is_empty());
+
+        let report = VaultMessage::CapacityReport {
+            used: 1000,
+            free: 24,
+        };
+        let serialised_report = unwrap_result!(serialisation::serialise(&report));
+        let report_request = RequestMessage {
+            src: Authority::ManagedNode(*env.our_authority.name()),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Post(Data::Plain(PlainData::new(*env.our_authority.name(),
+                                                                     serialised_report)),
+                                          MessageId::new()),
+        };
+        unwrap_result!(env.pmid_manager.handle_pmid_node_post(&env.routing, &report_request));
+        unwrap_result!(env.pmid_manager.handle_post(&env.routing, &query));
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), 1);
+        assert_eq!(post_requests[0].src, env.our_authority);
+        assert_eq!(post_requests[0].dst, env.from_authority);
+        if let RequestContent::Post(Data::Plain(ref data), _) = post_requests[0].content {
+            assert_eq!(unwrap_result!(serialisation::deserialise::<VaultMessage>(data.value())),
+                       report);
+        } else {
+            panic!("Unexpected request {:?}", post_requests[0]);
+        }
+        // The query isn't mistaken for a lost chunk.
+        let account = unwrap_option!(env.pmid_manager.accounts.get(env.our_authority.name()), "");
+        assert_eq!(account.lost_total, 0);
+    }
+
+    #[test]
+    fn chunk_lost() {
+        let mut env = environment_setup();
+        for _ in 0..2 {
+            let immutable_data = get_close_data(&env);
+            let request = RequestMessage {
+                src: Authority::NaeManager(immutable_data.name()),
+                dst: env.our_authority.clone(),
+                content: RequestContent::Put(Data::Immutable(immutable_data), MessageId::new()),
+            };
+            unwrap_result!(env.pmid_manager.handle_put(&env.routing, &request));
+        }
+
+        // The PmidNode is charged for exactly the size it's reported to have lost.
+        let loss = VaultMessage::ChunkLost { size: 100 };
+        let serialised_loss = unwrap_result!(serialisation::serialise(&loss));
+        let data_name = random::<XorName>();
+        let request = RequestMessage {
+            src: Authority::NaeManager(data_name),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Post(Data::Plain(PlainData::new(data_name, serialised_loss)),
+                                          MessageId::new()),
+        };
+        unwrap_result!(env.pmid_manager.handle_post(&env.routing, &request));
+        let account = unwrap_option!(env.pmid_manager.accounts.get(env.our_authority.name()), "");
+        assert_eq!(account.lost_total, 1);
+        assert_eq!(account.lost_bytes, 100);
+        assert_eq!(account.stored_total, 1);
+        assert_eq!(account.stored_bytes, 2 * 1024 - 100);
+    }
+
+    #[test]
+    fn reputation() {
+        let mut env = environment_setup();
+        // Without challenges, no salt is proposed, so the only posts are the reputations shared.
+        env.pmid_manager = PmidManager::with_clock(Duration::zero(), env.clock.clone());
+        let mut requests = vec![];
+        for _ in 0..10 {
+            let immutable_data = get_close_data(&env);
+            let request = RequestMessage {
+                src: Authority::NaeManager(immutable_data.name()),
+                dst: env.our_authority.clone(),
+                content: RequestContent::Put(Data::Immutable(immutable_data), MessageId::new()),
+            };
+            unwrap_result!(env.pmid_manager.handle_put(&env.routing, &request));
+            requests.push(request);
+        }
+
+        // Each failure is held against the PmidNode and shared with the chunk's managers, under an
+        // ID derived from the PmidNode's counts.
+        for (count, request) in requests.iter().take(6).enumerate() {
+            unwrap_result!(env.pmid_manager.handle_put_failure(&env.routing, request));
+            let post_requests = env.routing.post_requests_given();
+            assert_eq!(post_requests.len(), count + 1);
+            assert_eq!(post_requests[count].src, env.our_authority);
+            assert_eq!(post_requests[count].dst, request.src);
+            if let RequestContent::Post(Data::Plain(ref data), ref message_id) =
+                   post_requests[count].content {
+                let expected = VaultMessage::PmidNodeReputation(90 - 10 * count as u8);
+                assert_eq!(unwrap_result!(serialisation::deserialise::<VaultMessage>(data.value())),
+                           expected);
+                let failed_puts = count as u64 + 1;
+                let expected_id = utils::message_id(&[&env.our_authority.name().0[..],
+                                                      &request.src.name().0[..],
+                                                      &utils::u64_bytes(10 - failed_puts)[..],
+                                                      &utils::u64_bytes(0)[..],
+                                                      &utils::u64_bytes(failed_puts)[..]]);
+                assert_eq!(*message_id, expected_id);
+            } else {
+                panic!("Unexpected request {:?}", post_requests[count]);
+            }
+        }
+        let account = unwrap_option!(env.pmid_manager.accounts.get(env.our_authority.name()), "");
+        assert_eq!(account.failed_puts, 6);
+        assert_eq!(account.stored_bytes, 4 * 1024);
+        assert!(account.reputation() < MISBEHAVING_REPUTATION);
+    }
+
     #[test]
     fn churn_refresh() {
         let mut env = environment_setup();
@@ -573,8 +1526,11 @@ mod test {
             unreachable!()
         }
 
-        env.routing.node_added_event(get_close_node(&env));
-        env.pmid_manager.handle_churn(&env.routing, &random::<XorName>());
+        // A node joining right next to the account
+        let mut node_added = *env.our_authority.name();
+        node_added.0[63] ^= 1;
+        env.routing.node_added_event(node_added);
+        env.pmid_manager.handle_churn(&env.routing, &node_added);
 
         let mut refresh_count = 0;
         let mut refresh_requests = env.routing.refresh_requests_given();
@@ -600,8 +1556,9 @@ mod test {
             assert!(refresh_requests.is_empty());
         }
 
-        env.routing.node_lost_event(lose_close_node(&env));
-        env.pmid_manager.handle_churn(&env.routing, &random::<XorName>());
+        let node_lost = lose_close_node(&env);
+        env.routing.node_lost_event(node_lost);
+        env.pmid_manager.handle_churn(&env.routing, &node_lost);
 
         refresh_requests = env.routing.refresh_requests_given();
 
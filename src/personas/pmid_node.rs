@@ -15,21 +15,80 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use chunk_store::ChunkStore;
+use std::cmp;
+use std::collections::HashMap;
+
+use churn_queue::{CHURN_SLICE_SIZE, ChurnQueue};
+use clock::Clock;
 use error::InternalError;
-use safe_network_common::client_errors::GetError;
 use maidsafe_utilities::serialisation;
-use routing::{Data, DataRequest, ImmutableData, MessageId, RequestContent, RequestMessage};
-use vault::{CHUNK_STORE_PREFIX, RoutingNode};
+use neighbourhood::{Neighbourhood, XorMap};
+use personas::{Context, Persona, Route, Success, SuccessKind};
+use personas::chunk_io::{self, ChunkIo, Stored};
+use routing::{Authority, Data, DataRequest, ImmutableData, ImmutableDataType, MessageId,
+              PlainData, RequestContent, RequestMessage};
+use time::{Duration, SteadyTime};
+use timed_buffer::{EvictionPolicy, TimedBuffer};
+use types::{Fragment, VaultMessage};
+use vault::RoutingNode;
 use xor_name::XorName;
 
+// How often we tell our PmidManagers how much space we have used and free.
+const CAPACITY_REPORT_INTERVAL_SECS: i64 = 60;
+// How long chunks we're no longer responsible for are kept while their new holders are found.
+const DEPARTING_GRACE_SECS: i64 = 600;
+// The most such chunks kept.  Beyond this, the oldest are deleted early.
+const MAX_DEPARTING_CHUNKS: usize = 1000;
+// The most chunks announced to their ImmutableDataManagers on each tick after joining.
+const MAX_ANNOUNCEMENTS_PER_TICK: usize = 100;
+
 pub struct PmidNode {
-    chunk_store: ChunkStore,
+    // The chunks' disk IO, done on a pool of worker threads.
+    chunk_io: ChunkIo,
+    capacity: u64,
+    // The names of the chunks held, kept in step with `chunk_io`, by the name of their
+    // ImmutableDataManagers.  That's the chunk's own name, or for a fragment of an erasure-coded
+    // chunk, the name of the chunk it's from.
+    managed_chunks: XorMap<Vec<XorName>>,
+    // <chunk name, name of its ImmutableDataManagers>
+    managers: HashMap<XorName, XorName>,
+    // The ImmutableDataManagers whose chunks are still to be checked after churn
+    churn_queue: ChurnQueue<XorName, ()>,
+    // Chunks we're no longer responsible for, still held and served until their
+    // ImmutableDataManagers confirm they're stored elsewhere
+    departing: TimedBuffer<XorName, ()>,
+    // Chunks still to be announced to their ImmutableDataManagers after joining the network
+    announcements: Vec<XorName>,
+    last_capacity_report: SteadyTime,
+    // Success responses sent since the registry last took them
+    successes: Vec<Success>,
+    clock: Clock,
 }
 
 impl PmidNode {
-    pub fn new(capacity: u64) -> Result<PmidNode, InternalError> {
-        Ok(PmidNode { chunk_store: try!(ChunkStore::new(CHUNK_STORE_PREFIX, capacity)) })
+    pub fn new(capacity: u64, io_thread_count: usize) -> Result<PmidNode, InternalError> {
+        Self::with_clock(capacity, io_thread_count, Clock::system())
+    }
+
+    pub fn with_clock(capacity: u64,
+                      io_thread_count: usize,
+                      clock: Clock)
+                      -> Result<PmidNode, InternalError> {
+        Ok(PmidNode {
+            chunk_io: try!(ChunkIo::new(io_thread_count)),
+            capacity: capacity,
+            managed_chunks: XorMap::new(),
+            managers: HashMap::new(),
+            churn_queue: ChurnQueue::new(),
+            departing: TimedBuffer::with_capacity(Duration::seconds(DEPARTING_GRACE_SECS),
+                                                  MAX_DEPARTING_CHUNKS,
+                                                  EvictionPolicy::EvictOldest,
+                                                  clock.clone()),
+            announcements: Vec::new(),
+            last_capacity_report: clock.now(),
+            successes: Vec::new(),
+            clock: clock,
+        })
     }
 
     pub fn handle_get(&mut self,
@@ -37,38 +96,13 @@ impl PmidNode {
                       request: &RequestMessage)
                       -> Result<(), InternalError> {
         let (data_name, message_id) =
-            if let RequestContent::Get(DataRequest::Immutable(ref name, _), ref message_id) =
+            if let RequestContent::Get(DataRequest::Immutable(name, _), message_id) =
                    request.content {
                 (name, message_id)
             } else {
-                unreachable!("Error in vault demuxing")
+                return Err(InternalError::InvalidMessage);
             };
-
-        if let Ok(data) = self.chunk_store.get(data_name) {
-            if let Ok(decoded) = serialisation::deserialise::<ImmutableData>(&data) {
-                let immutable_data = Data::Immutable(decoded);
-                trace!("As {:?} sending data {:?} to {:?}",
-                       request.dst,
-                       immutable_data,
-                       request.src);
-                let _ = routing_node.send_get_success(request.dst.clone(),
-                                                      request.src.clone(),
-                                                      immutable_data,
-                                                      *message_id);
-                return Ok(());
-            }
-        }
-        let error = GetError::NoSuchData;
-        let external_error_indicator = try!(serialisation::serialise(&error));
-        trace!("As {:?} sending get failure of data {} to {:?}",
-               request.dst,
-               data_name,
-               request.src);
-        let _ = routing_node.send_get_failure(request.dst.clone(),
-                                              request.src.clone(),
-                                              request.clone(),
-                                              external_error_indicator,
-                                              *message_id);
+        self.chunk_io.answer_get(routing_node, data_name, message_id, request);
         Ok(())
     }
 
@@ -80,75 +114,339 @@ impl PmidNode {
                                                             ref message_id) = request.content {
             (data.clone(), message_id)
         } else {
-            unreachable!("Error in vault demuxing")
+            return Err(InternalError::InvalidMessage);
         };
         let data_name = data.name();
         info!("pmid_node {:?} storing {:?}", request.dst.name(), data_name);
         let serialised_data = try!(serialisation::serialise(&data));
-        if self.chunk_store.has_space(serialised_data.len() as u64) {
-            if let Ok(_) = self.chunk_store.put(&data_name, &serialised_data) {
-                let _ = self.notify_managers_of_success(routing_node,
-                                                        &data_name,
-                                                        &message_id,
-                                                        request);
-                return Ok(());
-            }
+        if self.has_space(serialised_data.len() as u64) {
+            // The chunk is held from now on, though it's only confirmed to our managers once
+            // it's been written.
+            self.add_chunk(data_name, Fragment::managers_name(&data));
+            let src = request.dst.clone();
+            let dst = request.src.clone();
+            trace!("As {:?} storing data {} for {:?}", src, data_name, dst);
+            let success = Success::new(SuccessKind::Put, src, dst, data_name, *message_id);
+            self.chunk_io.store(routing_node, data_name, serialised_data, request, success);
+        } else {
+            try!(chunk_io::send_put_failure(routing_node, &data_name, request));
+            // Our managers' idea of our free space is evidently out of date.
+            self.report_capacity(routing_node);
         }
+        Ok(())
+    }
 
-        let src = request.dst.clone();
-        let dst = request.src.clone();
-        trace!("As {:?} sending Put failure of data {} to {:?} ",
-               src,
-               data_name,
-               dst);
-        let external_error_indicator = try!(serialisation::serialise(&Vec::<u8>::new()));
-        let _ = routing_node.send_put_failure(src,
-                                              dst,
-                                              request.clone(),
-                                              external_error_indicator,
-                                              *message_id);
+    // Answers a PmidManager's storage challenge with proof that we hold the chunk.  If we don't,
+    // the proof is left empty so that the challenge fails straight away rather than timing out.
+    // Other posts come from a chunk's ImmutableDataManagers, either confirming that a chunk we
+    // handed off has been stored elsewhere, or telling us a chunk we announced isn't needed.
+    pub fn handle_post(&mut self,
+                       routing_node: &RoutingNode,
+                       request: &RequestMessage)
+                       -> Result<(), InternalError> {
+        let (data, message_id) = if let RequestContent::Post(Data::Plain(ref data),
+                                                             ref message_id) = request.content {
+            (data, message_id)
+        } else {
+            return Err(InternalError::InvalidMessage);
+        };
+        let chunk_name = data.name();
+        match try!(serialisation::deserialise(data.value())) {
+            VaultMessage::StorageChallenge { nonce } => {
+                self.chunk_io
+                    .answer_challenge(routing_node, chunk_name, nonce, *message_id, request)
+            }
+            VaultMessage::HandOffComplete => {
+                self.handle_hand_off_complete(routing_node, &chunk_name)
+            }
+            VaultMessage::ChunkNotNeeded => self.handle_chunk_not_needed(routing_node, &chunk_name),
+            _ => return Err(InternalError::InvalidMessage),
+        }
         Ok(())
     }
 
-    pub fn handle_churn(&mut self, routing_node: &RoutingNode) {
-        // Only retain chunks for which we're still in the close group
-        let chunk_names = self.chunk_store.names();
-        for chunk_name in chunk_names {
-            match routing_node.close_group(chunk_name) {
-                Ok(None) => {
-                    trace!("No longer a PN for {}", chunk_name);
-                    let _ = self.chunk_store.delete(&chunk_name);
+    // Queues the chunks whose ImmutableDataManagers' close group could have gained or lost
+    // `node_changed`.
+    pub fn handle_churn(&mut self, routing_node: &RoutingNode, node_changed: &XorName) {
+        let neighbourhood = Neighbourhood::new(routing_node, node_changed);
+        for managers_name in self.managed_chunks.names_in(&neighbourhood) {
+            self.churn_queue.push(managers_name, ());
+        }
+        let _ = self.process_churn(routing_node);
+    }
+
+    // Checks the chunks of the next slice of ImmutableDataManagers queued by churn, handing off
+    // those for which we're no longer in the close group.  Returns whether any remain to be
+    // checked.
+    fn process_churn(&mut self, routing_node: &RoutingNode) -> bool {
+        for _ in 0..CHURN_SLICE_SIZE {
+            let managers_name = match self.churn_queue.pop() {
+                Some((managers_name, ())) => managers_name,
+                None => return false,
+            };
+            let chunk_names = match self.managed_chunks.get(&managers_name) {
+                Some(chunk_names) => chunk_names.clone(),
+                None => continue,
+            };
+            let close_group = routing_node.close_group(managers_name);
+            for chunk_name in chunk_names {
+                match close_group {
+                    Ok(None) => {
+                        trace!("No longer a PN for {}", chunk_name);
+                        let _ = self.depart(routing_node, &chunk_name);
+                    }
+                    Ok(Some(_)) => {
+                        // Responsible for it again, so it mustn't be deleted when the grace period
+                        // ends.
+                        let _ = self.departing.remove(&chunk_name);
+                    }
+                    Err(ref error) => {
+                        error!("Failed to get close group: {:?} for {}", error, chunk_name);
+                        let _ = self.depart(routing_node, &chunk_name);
+                    }
                 }
-                Ok(Some(_)) => (),
-                Err(error) => {
-                    error!("Failed to get close group: {:?} for {}", error, chunk_name);
-                    let _ = self.chunk_store.delete(&chunk_name);
+            }
+        }
+        !self.churn_queue.is_empty()
+    }
+
+    // Changes the storage allowance.  If the chunks already held exceed the new allowance, the
+    // excess is handed back to the chunks' ImmutableDataManagers to be stored elsewhere, and only
+    // deleted here once they confirm it has been.
+    pub fn set_capacity(&mut self,
+                        routing_node: &RoutingNode,
+                        capacity: u64)
+                        -> Result<(), InternalError> {
+        let used_space = self.chunk_io.used_space();
+        if used_space > capacity {
+            try!(self.hand_off_chunks(routing_node, used_space - capacity));
+        }
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    // Our ImmutableDataManagers only know of the holders they chose, so after (re)joining the
+    // network we announce every chunk we still hold to them.  After a restart, the chunks' managers
+    // are only known from the chunks themselves, so they're read back to find them.
+    pub fn handle_connected(&mut self, routing_node: &RoutingNode) {
+        self.announcements = self.chunk_io.names();
+        for chunk_name in self.announcements.clone() {
+            if self.managers.contains_key(&chunk_name) {
+                continue;
+            }
+            match self.chunk_io
+                      .get(routing_node, &chunk_name)
+                      .ok()
+                      .and_then(|data| serialisation::deserialise::<ImmutableData>(&data).ok()) {
+                Some(data) => self.add_chunk(chunk_name, Fragment::managers_name(&data)),
+                None => error!("Failed to read back stored chunk {}", chunk_name),
+            }
+        }
+        self.send_announcements(routing_node);
+    }
+
+    // Announces the next batch of chunks queued by `handle_connected`.
+    pub fn send_announcements(&mut self, routing_node: &RoutingNode) {
+        if self.announcements.is_empty() {
+            return;
+        }
+        let our_name = match routing_node.name() {
+            Ok(our_name) => our_name,
+            Err(_) => return,
+        };
+        let serialised_message = match serialisation::serialise(&VaultMessage::ChunkHeld) {
+            Ok(serialised_message) => serialised_message,
+            Err(_) => return,
+        };
+        let count = cmp::min(MAX_ANNOUNCEMENTS_PER_TICK, self.announcements.len());
+        let remaining = self.announcements.len() - count;
+        for chunk_name in self.announcements.drain(remaining..) {
+            if !self.chunk_io.has_chunk(&chunk_name) {
+                continue;
+            }
+            let src = Authority::ManagedNode(our_name);
+            let dst = Authority::NaeManager(self.managers_name(&chunk_name));
+            trace!("As {:?} announcing {} to {:?}", src, chunk_name, dst);
+            let data = Data::Plain(PlainData::new(chunk_name, serialised_message.clone()));
+            let _ = routing_node.send_post_request(src, dst, data, MessageId::new());
+        }
+    }
+
+    // Deletes departing chunks whose grace period has ended, whether or not their new holders have
+    // confirmed.
+    pub fn check_timeout(&mut self, routing_node: &RoutingNode) {
+        for chunk_name in self.departing.get_expired() {
+            let _ = self.departing.remove(&chunk_name);
+            trace!("Grace period ended for {}", chunk_name);
+            let _ = self.delete(routing_node, &chunk_name);
+        }
+    }
+
+    // Takes the outcomes of the chunks written since last checked, keeping the success responses
+    // sent for them.  Chunks which couldn't be written are no longer held, and as our managers
+    // thought we had space for them, they're sent a fresh capacity report.
+    pub fn check_stored(&mut self, routing_node: &RoutingNode) {
+        let mut failed = false;
+        for stored in self.chunk_io.take_stored() {
+            match stored {
+                Stored::Written(success) => self.successes.push(success),
+                Stored::Failed(chunk_name) => {
+                    if let Some(managers_name) = self.managers.remove(&chunk_name) {
+                        self.remove_managed_chunk(&managers_name, &chunk_name);
+                    }
+                    failed = true;
                 }
             }
         }
+        if failed {
+            self.report_capacity(routing_node);
+        }
+    }
+
+    // Reports our used and free space to our PmidManagers once per report interval.
+    pub fn check_capacity_report(&mut self, routing_node: &RoutingNode) {
+        if self.clock.now() - self.last_capacity_report >=
+           Duration::seconds(CAPACITY_REPORT_INTERVAL_SECS) {
+            self.report_capacity(routing_node);
+        }
+    }
+
+    // The vault runs a single PmidNode holding all its chunks, so this reports the vault's whole
+    // storage allowance for them.
+    fn report_capacity(&mut self, routing_node: &RoutingNode) {
+        self.last_capacity_report = self.clock.now();
+        let our_name = match routing_node.name() {
+            Ok(name) => name,
+            Err(error) => {
+                error!("Failed to get our name: {:?}", error);
+                return;
+            }
+        };
+        let used = self.chunk_io.used_space();
+        let message = VaultMessage::CapacityReport {
+            used: used,
+            free: self.capacity.saturating_sub(used),
+        };
+        if let Ok(serialised_message) = serialisation::serialise(&message) {
+            let src = Authority::ManagedNode(our_name);
+            let dst = Authority::NodeManager(our_name);
+            trace!("As {:?} reporting {:?} to {:?}", src, message, dst);
+            let data = Data::Plain(PlainData::new(our_name, serialised_message));
+            let _ = routing_node.send_post_request(src, dst, data, MessageId::new());
+        }
     }
 
     #[cfg(feature = "use-mock-crust")]
     pub fn get_stored_names(&self) -> Vec<XorName> {
-        self.chunk_store.names()
-    }
-
-    fn notify_managers_of_success(&mut self,
-                                  routing_node: &RoutingNode,
-                                  data_name: &XorName,
-                                  message_id: &MessageId,
-                                  request: &RequestMessage)
-                                  -> Result<(), InternalError> {
-        let src = request.dst.clone();
-        let dst = request.src.clone();
-        trace!("As {:?} sending put success of data {} to {:?}",
-               src,
-               data_name,
-               dst);
-        let _ = routing_node.send_put_success(src, dst, *data_name, *message_id);
+        self.chunk_io.names()
+    }
+
+    // Hands off enough chunks to free at least `excess` bytes, giving up sacrificial copies first,
+    // then backup and finally normal ones.  Chunks which are already departing count towards it.
+    fn hand_off_chunks(&mut self,
+                       routing_node: &RoutingNode,
+                       mut excess: u64)
+                       -> Result<(), InternalError> {
+        let mut chunks = vec![];
+        for chunk_name in self.chunk_io.names() {
+            let serialised_data = try!(self.chunk_io.get(routing_node, &chunk_name));
+            if self.departing.contains_key(&chunk_name) {
+                excess = excess.saturating_sub(serialised_data.len() as u64);
+                continue;
+            }
+            let data = try!(serialisation::deserialise::<ImmutableData>(&serialised_data));
+            let priority = match *data.get_type_tag() {
+                ImmutableDataType::Sacrificial => 0,
+                ImmutableDataType::Backup => 1,
+                ImmutableDataType::Normal => 2,
+            };
+            chunks.push((priority, chunk_name, serialised_data.len() as u64));
+        }
+        chunks.sort_by_key(|&(priority, _, _)| priority);
+
+        for (_, chunk_name, size) in chunks {
+            if excess == 0 {
+                break;
+            }
+            try!(self.depart(routing_node, &chunk_name));
+            excess = excess.saturating_sub(size);
+        }
+        Ok(())
+    }
+
+    // Offers the chunk to its ImmutableDataManagers to be stored elsewhere, keeping it until
+    // they confirm or the grace period ends.  If that's too many departing chunks, the oldest is
+    // deleted straight away.
+    fn depart(&mut self,
+              routing_node: &RoutingNode,
+              chunk_name: &XorName)
+              -> Result<(), InternalError> {
+        if self.departing.contains_key(chunk_name) {
+            return Ok(());
+        }
+        if let Some((evicted_name, ())) = self.departing.insert(*chunk_name, ()) {
+            warn!("Too many departing chunks to keep {} for its grace period.",
+                  evicted_name);
+            let _ = self.delete(routing_node, &evicted_name);
+        }
+        let our_name = try!(routing_node.name());
+        let src = Authority::ManagedNode(our_name);
+        let dst = Authority::NaeManager(self.managers_name(chunk_name));
+        self.chunk_io.hand_off(routing_node, *chunk_name, src, dst);
         Ok(())
     }
 
+    fn handle_hand_off_complete(&mut self, routing_node: &RoutingNode, chunk_name: &XorName) {
+        if self.departing.remove(chunk_name).is_some() {
+            trace!("{} is stored elsewhere now", chunk_name);
+            let _ = self.delete(routing_node, chunk_name);
+        }
+    }
+
+    fn handle_chunk_not_needed(&mut self, routing_node: &RoutingNode, chunk_name: &XorName) {
+        let _ = self.departing.remove(chunk_name);
+        if self.delete(routing_node, chunk_name) {
+            trace!("{} has enough holders without us", chunk_name);
+        }
+    }
+
+    // Whether a chunk of the given size fits in our allowance.
+    fn has_space(&self, size: u64) -> bool {
+        self.chunk_io.used_space() + size <= self.capacity
+    }
+
+    // The name of the chunk's ImmutableDataManagers.
+    fn managers_name(&self, chunk_name: &XorName) -> XorName {
+        self.managers.get(chunk_name).cloned().unwrap_or(*chunk_name)
+    }
+
+    fn add_chunk(&mut self, chunk_name: XorName, managers_name: XorName) {
+        if let Some(old_managers_name) = self.managers.insert(chunk_name, managers_name) {
+            self.remove_managed_chunk(&old_managers_name, &chunk_name);
+        }
+        self.managed_chunks.get_or_insert_with(managers_name, Vec::new).push(chunk_name);
+    }
+
+    fn remove_managed_chunk(&mut self, managers_name: &XorName, chunk_name: &XorName) {
+        let now_empty = if let Some(chunk_names) = self.managed_chunks.get_mut(managers_name) {
+            chunk_names.retain(|name| name != chunk_name);
+            chunk_names.is_empty()
+        } else {
+            false
+        };
+        if now_empty {
+            let _ = self.managed_chunks.remove(managers_name);
+        }
+    }
+
+    // Deletes the chunk, returning whether it was held.
+    fn delete(&mut self, routing_node: &RoutingNode, chunk_name: &XorName) -> bool {
+        if let Some(managers_name) = self.managers.remove(chunk_name) {
+            self.remove_managed_chunk(&managers_name, chunk_name);
+        }
+        self.chunk_io.delete(routing_node, chunk_name)
+    }
+
     // fn notify_managers_of_sacrifice(&self,
     //                                 our_authority: &::routing::Authority,
     //                                 data: ImmutableData,
@@ -163,18 +461,82 @@ impl PmidNode {
     // }
 }
 
+impl Persona for PmidNode {
+    fn routes(&self) -> Vec<Route> {
+        use personas::AuthorityKind::{ManagedNode, NaeManager, NodeManager};
+        use personas::DataKind::{Immutable, Plain};
+        use personas::MessageKind::{Get, Post, Put};
+        vec![Route(NaeManager, ManagedNode, Get(Immutable)),
+             Route(NaeManager, ManagedNode, Post(Plain)),
+             Route(NodeManager, ManagedNode, Put(Immutable)),
+             Route(NodeManager, ManagedNode, Post(Plain))]
+    }
+
+    fn on_request(&mut self,
+                  context: &mut Context,
+                  request: &RequestMessage)
+                  -> Result<(), InternalError> {
+        let result = match request.content {
+            RequestContent::Get(..) => self.handle_get(context.routing_node, request),
+            RequestContent::Put(..) => self.handle_put(context.routing_node, request),
+            RequestContent::Post(..) => self.handle_post(context.routing_node, request),
+            _ => Err(InternalError::InvalidMessage),
+        };
+        self.check_stored(context.routing_node);
+        result
+    }
+
+    fn on_churn(&mut self, context: &mut Context, node_changed: &XorName) {
+        self.handle_churn(context.routing_node, node_changed)
+    }
+
+    fn continue_churn(&mut self, context: &mut Context) -> bool {
+        self.process_churn(context.routing_node)
+    }
+
+    fn on_tick(&mut self, context: &mut Context) {
+        self.check_stored(context.routing_node);
+        self.check_timeout(context.routing_node);
+        self.send_announcements(context.routing_node);
+        self.check_capacity_report(context.routing_node)
+    }
+
+    fn on_connected(&mut self, context: &mut Context) {
+        self.handle_connected(context.routing_node)
+    }
+
+    fn on_capacity_changed(&mut self,
+                           context: &mut Context,
+                           capacity: u64)
+                           -> Result<(), InternalError> {
+        self.set_capacity(context.routing_node, capacity)
+    }
+
+    fn take_successes(&mut self) -> Vec<Success> {
+        self.successes.drain(..).collect()
+    }
+
+    #[cfg(feature = "use-mock-crust")]
+    fn stored_names(&self) -> Vec<XorName> {
+        self.get_stored_names()
+    }
+}
+
 
 #[cfg(test)]
 #[cfg(not(feature="use-mock-crust"))]
 mod test {
     use super::*;
+    use clock::Clock;
     use safe_network_common::client_errors::GetError;
     use maidsafe_utilities::serialisation;
     use rand::random;
     use routing::{Authority, Data, DataRequest, ImmutableData, ImmutableDataType, MessageId,
-                  RequestContent, RequestMessage, ResponseContent};
+                  PlainData, RequestContent, RequestMessage, ResponseContent};
     use std::sync::mpsc;
-    use utils::generate_random_vec_u8;
+    use time::Duration;
+    use types::{Fragment, VaultMessage};
+    use utils::{self, generate_random_vec_u8};
     use vault::RoutingNode;
     use xor_name::XorName;
 
@@ -188,7 +550,7 @@ mod test {
     fn environment_setup(capacity: u64) -> Environment {
         let mut name = random::<XorName>();
         let routing = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
-        let pmid_node = unwrap_result!(PmidNode::new(capacity));
+        let pmid_node = unwrap_result!(PmidNode::new(capacity, 0));
 
         loop {
             if let Ok(Some(_)) = routing.close_group(name) {
@@ -254,6 +616,142 @@ mod test {
         }
     }
 
+    #[test]
+    fn storage_challenge() {
+        let mut env = environment_setup(1 << 20);
+        let immutable_data = ImmutableData::new(ImmutableDataType::Normal,
+                                                generate_random_vec_u8(128));
+        let request_msg = RequestMessage {
+            src: env.from_authority.clone(),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Put(Data::Immutable(immutable_data.clone()), MessageId::new()),
+        };
+        unwrap_result!(env.pmid_node.handle_put(&env.routing, &request_msg));
+        let serialised_data = unwrap_result!(serialisation::serialise(&immutable_data));
+
+        // A chunk we hold is proved with the hash of the nonce and chunk, and one we don't hold
+        // with nothing.
+        let missing_name = random::<XorName>();
+        let cases = vec![(immutable_data.name(), utils::storage_proof(b"nonce", &serialised_data)),
+                         (missing_name, vec![])];
+        for (index, (chunk_name, expected_proof)) in cases.into_iter().enumerate() {
+            let message_id = MessageId::new();
+            let challenge = VaultMessage::StorageChallenge { nonce: b"nonce".to_vec() };
+            let serialised_challenge = unwrap_result!(serialisation::serialise(&challenge));
+            let request_msg = RequestMessage {
+                src: env.from_authority.clone(),
+                dst: env.our_authority.clone(),
+                content: RequestContent::Post(Data::Plain(PlainData::new(chunk_name,
+                                                                         serialised_challenge)),
+                                              message_id),
+            };
+            unwrap_result!(env.pmid_node.handle_post(&env.routing, &request_msg));
+
+            let post_requests = env.routing.post_requests_given();
+            assert_eq!(post_requests.len(), index + 1);
+            assert_eq!(post_requests[index].src, env.our_authority);
+            assert_eq!(post_requests[index].dst, env.from_authority);
+            if let RequestContent::Post(Data::Plain(ref data), ref id) = post_requests[index]
+                                                                             .content {
+                assert_eq!(*id, message_id);
+                assert_eq!(data.name(), chunk_name);
+                let expected = VaultMessage::StorageProof {
+                    nonce: b"nonce".to_vec(),
+                    proof: expected_proof,
+                };
+                assert_eq!(unwrap_result!(serialisation::deserialise::<VaultMessage>(data.value())),
+                           expected);
+            } else {
+                panic!("Received unexpected request {:?}", post_requests[index]);
+            }
+        }
+    }
+
+    #[test]
+    fn capacity_report() {
+        let clock = Clock::manual();
+        let mut env = environment_setup(1 << 20);
+        env.pmid_node = unwrap_result!(PmidNode::with_clock(1 << 20, 0, clock.clone()));
+        let immutable_data = ImmutableData::new(ImmutableDataType::Normal,
+                                                generate_random_vec_u8(128));
+        let request_msg = RequestMessage {
+            src: env.from_authority.clone(),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Put(Data::Immutable(immutable_data.clone()), MessageId::new()),
+        };
+        unwrap_result!(env.pmid_node.handle_put(&env.routing, &request_msg));
+        let used = unwrap_result!(serialisation::serialise(&immutable_data)).len() as u64;
+
+        clock.advance(Duration::seconds(59));
+        env.pmid_node.check_capacity_report(&env.routing);
+        assert!(env.routing.post_requests_given().is_empty());
+
+        clock.advance(Duration::seconds(1));
+        env.pmid_node.check_capacity_report(&env.routing);
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), 1);
+        let our_name = unwrap_result!(env.routing.name());
+        assert_eq!(post_requests[0].src, Authority::ManagedNode(our_name));
+        assert_eq!(post_requests[0].dst, Authority::NodeManager(our_name));
+        if let RequestContent::Post(Data::Plain(ref data), _) = post_requests[0].content {
+            let expected = VaultMessage::CapacityReport {
+                used: used,
+                free: (1 << 20) - used,
+            };
+            assert_eq!(unwrap_result!(serialisation::deserialise::<VaultMessage>(data.value())),
+                       expected);
+        } else {
+            panic!("Received unexpected request {:?}", post_requests[0]);
+        }
+    }
+
+    #[test]
+    fn announce_chunks() {
+        let mut env = environment_setup(1 << 20);
+        let our_name = unwrap_result!(env.routing.name());
+        let mut chunk_names = Vec::new();
+        for _ in 0..2 {
+            let immutable_data = ImmutableData::new(ImmutableDataType::Normal,
+                                                    generate_random_vec_u8(128));
+            let request_msg = RequestMessage {
+                src: env.from_authority.clone(),
+                dst: env.our_authority.clone(),
+                content: RequestContent::Put(Data::Immutable(immutable_data.clone()),
+                                             MessageId::new()),
+            };
+            unwrap_result!(env.pmid_node.handle_put(&env.routing, &request_msg));
+            chunk_names.push(immutable_data.name());
+        }
+
+        env.pmid_node.handle_connected(&env.routing);
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), 2);
+        for post_request in &post_requests {
+            assert_eq!(post_request.src, Authority::ManagedNode(our_name));
+            let chunk_name = *post_request.dst.name();
+            assert_eq!(post_request.dst, Authority::NaeManager(chunk_name));
+            assert!(chunk_names.contains(&chunk_name));
+            if let RequestContent::Post(Data::Plain(ref data), _) = post_request.content {
+                assert_eq!(data.name(), chunk_name);
+                assert_eq!(unwrap_result!(serialisation::deserialise::<VaultMessage>(data.value())),
+                           VaultMessage::ChunkHeld);
+            } else {
+                panic!("Received unexpected request {:?}", post_request);
+            }
+        }
+
+        // A chunk which already has enough holders is deleted.
+        let message = unwrap_result!(serialisation::serialise(&VaultMessage::ChunkNotNeeded));
+        let request_msg = RequestMessage {
+            src: Authority::NaeManager(chunk_names[0]),
+            dst: Authority::ManagedNode(our_name),
+            content: RequestContent::Post(Data::Plain(PlainData::new(chunk_names[0], message)),
+                                          MessageId::new()),
+        };
+        unwrap_result!(env.pmid_node.handle_post(&env.routing, &request_msg));
+        assert_eq!(env.pmid_node.chunk_io.names(), vec![chunk_names[1]]);
+    }
+
     #[test]
     fn put_past_capacity() {
         let mut capacity = 0;
@@ -453,7 +951,7 @@ mod test {
         let name = get_close_node(&env);
 
         env.routing.node_added_event(name);
-        env.pmid_node.handle_churn(&env.routing);
+        env.pmid_node.handle_churn(&env.routing, &name);
 
         let message_id = MessageId::new();
         let name = if let Authority::ManagedNode(name) = env.our_authority {
@@ -471,47 +969,228 @@ mod test {
 
         assert!(env.pmid_node.handle_get(&env.routing, &request_msg).is_ok());
 
-        if let Ok(Some(_)) = env.routing.close_group(immutable_data.name().clone()) {
-            let get_failures = env.routing.get_failures_given();
+        // Even if we're no longer responsible for the chunk, it's kept and served until its new
+        // holders have it.
+        let get_failures = env.routing.get_failures_given();
+
+        assert!(get_failures.is_empty());
 
-            assert!(get_failures.is_empty());
+        let get_successes = env.routing.get_successes_given();
 
-            let get_successes = env.routing.get_successes_given();
+        assert_eq!(get_successes.len(), 1);
+        assert_eq!(get_successes[0].src, env.our_authority);
+        assert_eq!(get_successes[0].dst, Authority::NaeManager(name));
 
-            assert_eq!(get_successes.len(), 1);
-            assert_eq!(get_successes[0].src, env.our_authority);
-            assert_eq!(get_successes[0].dst, Authority::NaeManager(name));
+        if let ResponseContent::GetSuccess(ref data, ref id) = get_successes[0].content {
+            assert_eq!(*data, Data::Immutable(immutable_data.clone()));
+            assert_eq!(*id, message_id);
+        } else {
+            unreachable!()
+        }
 
-            if let ResponseContent::GetSuccess(ref data, ref id) = get_successes[0].content {
+        let post_requests = env.routing.post_requests_given();
+        if let Ok(Some(_)) = env.routing.close_group(immutable_data.name().clone()) {
+            assert!(post_requests.is_empty());
+        } else {
+            assert_eq!(post_requests.len(), 1);
+            assert_eq!(post_requests[0].dst,
+                       Authority::NaeManager(immutable_data.name()));
+            if let RequestContent::Post(ref data, _) = post_requests[0].content {
                 assert_eq!(*data, Data::Immutable(immutable_data));
-                assert_eq!(*id, message_id);
             } else {
                 unreachable!()
             }
+        }
+    }
+
+    #[test]
+    fn departing_chunks() {
+        let clock = Clock::manual();
+        let mut env = environment_setup(1 << 20);
+        env.pmid_node = unwrap_result!(PmidNode::with_clock(1 << 20, 0, clock.clone()));
+        let our_name = unwrap_result!(env.routing.name());
+        let mut chunk_names = Vec::new();
+        for _ in 0..2 {
+            let immutable_data = ImmutableData::new(ImmutableDataType::Normal,
+                                                    generate_random_vec_u8(128));
+            let request_msg = RequestMessage {
+                src: env.from_authority.clone(),
+                dst: env.our_authority.clone(),
+                content: RequestContent::Put(Data::Immutable(immutable_data.clone()),
+                                             MessageId::new()),
+            };
+            unwrap_result!(env.pmid_node.handle_put(&env.routing, &request_msg));
+            unwrap_result!(env.pmid_node.depart(&env.routing, &immutable_data.name()));
+            chunk_names.push(immutable_data.name());
+        }
+
+        // Both chunks are offered to their managers, and kept meanwhile.
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), 2);
+        for (post_request, chunk_name) in post_requests.iter().zip(&chunk_names) {
+            assert_eq!(post_request.src, Authority::ManagedNode(our_name));
+            assert_eq!(post_request.dst, Authority::NaeManager(*chunk_name));
+        }
+        assert_eq!(env.pmid_node.chunk_io.names().len(), 2);
+
+        // The first is deleted once its managers confirm it's stored elsewhere.
+        let message = unwrap_result!(serialisation::serialise(&VaultMessage::HandOffComplete));
+        let request_msg = RequestMessage {
+            src: Authority::NaeManager(chunk_names[0]),
+            dst: Authority::ManagedNode(our_name),
+            content: RequestContent::Post(Data::Plain(PlainData::new(chunk_names[0], message)),
+                                          MessageId::new()),
+        };
+        unwrap_result!(env.pmid_node.handle_post(&env.routing, &request_msg));
+        assert_eq!(env.pmid_node.chunk_io.names(), vec![chunk_names[1]]);
+
+        // The second is deleted once the grace period ends.
+        clock.advance(Duration::seconds(DEPARTING_GRACE_SECS - 1));
+        env.pmid_node.check_timeout(&env.routing);
+        assert_eq!(env.pmid_node.chunk_io.names(), vec![chunk_names[1]]);
+        clock.advance(Duration::seconds(1));
+        env.pmid_node.check_timeout(&env.routing);
+        assert!(env.pmid_node.chunk_io.names().is_empty());
+    }
+
+    #[test]
+    fn fragment_managers() {
+        let mut env = environment_setup(1 << 20);
+        let our_name = unwrap_result!(env.routing.name());
+        let chunk_name = random::<XorName>();
+        let fragment = Fragment {
+            chunk_name: chunk_name,
+            index: 0,
+            value: generate_random_vec_u8(128),
+        };
+        let immutable_data = unwrap_result!(fragment.to_data());
+        let request_msg = RequestMessage {
+            src: env.from_authority.clone(),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Put(Data::Immutable(immutable_data.clone()), MessageId::new()),
+        };
+        unwrap_result!(env.pmid_node.handle_put(&env.routing, &request_msg));
+
+        // A fragment is announced and handed off to the managers of the chunk it's from, rather
+        // than to those of its own name.
+        env.pmid_node.handle_connected(&env.routing);
+        unwrap_result!(env.pmid_node.depart(&env.routing, &immutable_data.name()));
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), 2);
+        for post_request in &post_requests {
+            assert_eq!(post_request.src, Authority::ManagedNode(our_name));
+            assert_eq!(post_request.dst, Authority::NaeManager(chunk_name));
+        }
+        if let RequestContent::Post(Data::Plain(ref data), _) = post_requests[0].content {
+            assert_eq!(data.name(), immutable_data.name());
         } else {
-            let get_successes = env.routing.get_successes_given();
+            panic!("Received unexpected request {:?}", post_requests[0]);
+        }
+        if let RequestContent::Post(ref data, _) = post_requests[1].content {
+            assert_eq!(*data, Data::Immutable(immutable_data));
+        } else {
+            panic!("Received unexpected request {:?}", post_requests[1]);
+        }
+    }
 
-            assert!(get_successes.is_empty());
+    #[test]
+    fn restart_over_populated_store() {
+        let mut env = environment_setup(1 << 20);
+        let our_name = unwrap_result!(env.routing.name());
+        let chunk_name = random::<XorName>();
+        let fragment = Fragment {
+            chunk_name: chunk_name,
+            index: 0,
+            value: generate_random_vec_u8(128),
+        };
+        let immutable_data = unwrap_result!(fragment.to_data());
+
+        // A freshly started PmidNode knows nothing of the chunks already in its store.
+        let serialised_data = unwrap_result!(serialisation::serialise(&immutable_data));
+        unwrap_result!(env.pmid_node
+                          .chunk_io
+                          .put_existing(&immutable_data.name(), &serialised_data));
+        assert!(env.pmid_node.managers.is_empty());
+        assert!(env.pmid_node.managed_chunks.get(&chunk_name).is_none());
+
+        // Once connected, it announces the fragment to the managers of the chunk it's from, and
+        // checks it on churn near them.
+        env.pmid_node.handle_connected(&env.routing);
+        let post_requests = env.routing.post_requests_given();
+        assert_eq!(post_requests.len(), 1);
+        assert_eq!(post_requests[0].src, Authority::ManagedNode(our_name));
+        assert_eq!(post_requests[0].dst, Authority::NaeManager(chunk_name));
+        assert_eq!(env.pmid_node.managers.get(&immutable_data.name()), Some(&chunk_name));
+        assert_eq!(env.pmid_node.managed_chunks.get(&chunk_name),
+                   Some(&vec![immutable_data.name()]));
+    }
 
-            let get_failures = env.routing.get_failures_given();
+    #[test]
+    fn shrink_capacity() {
+        let normal_data = ImmutableData::new(ImmutableDataType::Normal,
+                                             generate_random_vec_u8(128));
+        let sacrificial_data = ImmutableData::new(ImmutableDataType::Sacrificial,
+                                                  normal_data.value().clone());
+        let normal_size = unwrap_result!(serialisation::serialise(&normal_data)).len() as u64;
+        let sacrificial_size =
+            unwrap_result!(serialisation::serialise(&sacrificial_data)).len() as u64;
+        let mut env = environment_setup(normal_size + sacrificial_size);
+
+        for data in vec![normal_data.clone(), sacrificial_data.clone()] {
+            let request_msg = RequestMessage {
+                src: env.from_authority.clone(),
+                dst: env.our_authority.clone(),
+                content: RequestContent::Put(Data::Immutable(data), MessageId::new()),
+            };
+            assert!(env.pmid_node.handle_put(&env.routing, &request_msg).is_ok());
+        }
+        assert_eq!(env.routing.put_successes_given().len(), 2);
 
-            assert_eq!(get_failures.len(), 1);
-            assert_eq!(get_failures[0].src, env.our_authority);
-            assert_eq!(get_failures[0].dst, Authority::NaeManager(name));
+        // Shrinking to fit a single chunk should hand off the sacrificial copy only
+        assert!(env.pmid_node.set_capacity(&env.routing, normal_size).is_ok());
 
-            if let ResponseContent::GetFailure { ref id,
-                                                 ref request,
-                                                 ref external_error_indicator } = get_failures[0]
-                                                                                      .content {
-                assert_eq!(*id, message_id);
-                assert_eq!(*request, request_msg);
-                let error = unwrap_result!(serialisation::deserialise(external_error_indicator));
-                if let GetError::NoSuchData = error {} else {
-                    unreachable!()
-                }
-            } else {
-                unreachable!()
-            }
+        let post_requests = env.routing.post_requests_given();
+
+        assert_eq!(post_requests.len(), 1);
+        assert_eq!(post_requests[0].dst,
+                   Authority::NaeManager(sacrificial_data.name()));
+
+        if let RequestContent::Post(ref data, _) = post_requests[0].content {
+            assert_eq!(*data, Data::Immutable(sacrificial_data.clone()));
+        } else {
+            unreachable!()
         }
+
+        // It's kept until its ImmutableDataManagers confirm it's stored elsewhere, and shrinking
+        // again meanwhile doesn't hand off anything more.
+        assert!(env.pmid_node.chunk_io.has_chunk(&sacrificial_data.name()));
+        assert!(env.pmid_node.set_capacity(&env.routing, normal_size).is_ok());
+        assert_eq!(env.routing.post_requests_given().len(), 1);
+
+        let message = unwrap_result!(serialisation::serialise(&VaultMessage::HandOffComplete));
+        let request_msg = RequestMessage {
+            src: Authority::NaeManager(sacrificial_data.name()),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Post(Data::Plain(PlainData::new(sacrificial_data.name(),
+                                                                     message)),
+                                          MessageId::new()),
+        };
+        assert!(env.pmid_node.handle_post(&env.routing, &request_msg).is_ok());
+
+        assert!(!env.pmid_node.chunk_io.has_chunk(&sacrificial_data.name()));
+        assert!(env.pmid_node.chunk_io.has_chunk(&normal_data.name()));
+
+        // Growing again should allow the sacrificial copy to be stored
+        assert!(env.pmid_node.set_capacity(&env.routing, normal_size + sacrificial_size).is_ok());
+        assert_eq!(env.routing.post_requests_given().len(), 1);
+
+        let request_msg = RequestMessage {
+            src: env.from_authority.clone(),
+            dst: env.our_authority.clone(),
+            content: RequestContent::Put(Data::Immutable(sacrificial_data), MessageId::new()),
+        };
+        assert!(env.pmid_node.handle_put(&env.routing, &request_msg).is_ok());
+        assert_eq!(env.routing.put_successes_given().len(), 3);
+        assert!(env.routing.put_failures_given().is_empty());
     }
 }
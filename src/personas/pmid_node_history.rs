@@ -0,0 +1,135 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::cmp;
+use std::collections::HashMap;
+
+use time::Duration;
+use xor_name::XorName;
+
+// Weight given to the latest outcome in each node's running averages.
+const SMOOTHING: f64 = 0.2;
+// Latency assumed for PmidNodes which haven't answered a Get yet.
+const INITIAL_LATENCY_MS: f64 = 1000.0;
+// How many times its usual latency a node is given to answer before it's considered slow.
+const PATIENCE_FACTOR: f64 = 2.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Record {
+    latency_ms: f64,
+    failure_rate: f64,
+}
+
+impl Record {
+    fn new() -> Record {
+        Record {
+            latency_ms: INITIAL_LATENCY_MS,
+            failure_rate: 0.0,
+        }
+    }
+
+    // Time enough for the node to usually have answered, cut short in proportion to how often it
+    // fails to.
+    fn patience_ms(&self) -> f64 {
+        PATIENCE_FACTOR * self.latency_ms * (1.0 - self.failure_rate)
+    }
+}
+
+// How quickly and reliably each PmidNode has answered our Gets, used to decide how long to wait
+// for a holder before also asking the next one.  Which holders are asked, and in what order, is
+// left to state our whole group shares, so that the group's Gets go to the same holders.
+pub struct PmidNodeHistory {
+    records: HashMap<XorName, Record>,
+}
+
+impl PmidNodeHistory {
+    pub fn new() -> PmidNodeHistory {
+        PmidNodeHistory { records: HashMap::new() }
+    }
+
+    pub fn record_success(&mut self, pmid_node: &XorName, latency: Duration) {
+        let latency_ms = latency.num_milliseconds().max(0) as f64;
+        let record = self.records.entry(*pmid_node).or_insert_with(Record::new);
+        record.latency_ms += SMOOTHING * (latency_ms - record.latency_ms);
+        record.failure_rate -= SMOOTHING * record.failure_rate;
+    }
+
+    pub fn record_failure(&mut self, pmid_node: &XorName) {
+        let record = self.records.entry(*pmid_node).or_insert_with(Record::new);
+        record.failure_rate += SMOOTHING * (1.0 - record.failure_rate);
+    }
+
+    pub fn forget(&mut self, pmid_node: &XorName) {
+        let _ = self.records.remove(pmid_node);
+    }
+
+    // How long to wait for `pmid_node` to answer before asking another holder too, at most `max`.
+    pub fn patience(&self, pmid_node: &XorName, max: Duration) -> Duration {
+        let patience_ms = self.records
+                              .get(pmid_node)
+                              .map_or(Record::new().patience_ms(), Record::patience_ms);
+        cmp::min(max, Duration::milliseconds(patience_ms as i64))
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature="use-mock-crust"))]
+mod test {
+    use super::*;
+    use rand::random;
+    use time::Duration;
+    use xor_name::XorName;
+
+    #[test]
+    fn fast_nodes_hedged_sooner() {
+        let mut history = PmidNodeHistory::new();
+        let fast: XorName = random();
+        let slow: XorName = random();
+        let unknown: XorName = random();
+        history.record_success(&fast, Duration::milliseconds(50));
+        history.record_success(&slow, Duration::seconds(10));
+
+        let max = Duration::seconds(5);
+        assert_eq!(history.patience(&unknown, max), Duration::seconds(2));
+        assert!(history.patience(&fast, max) < history.patience(&unknown, max));
+        assert_eq!(history.patience(&slow, max), max);
+    }
+
+    #[test]
+    fn failing_nodes_hedged_sooner() {
+        let mut history = PmidNodeHistory::new();
+        let reliable: XorName = random();
+        let failing: XorName = random();
+        history.record_success(&reliable, Duration::milliseconds(500));
+        history.record_success(&failing, Duration::milliseconds(500));
+        history.record_failure(&failing);
+
+        let max = Duration::seconds(5);
+        assert!(history.patience(&failing, max) < history.patience(&reliable, max));
+
+        // Once it starts answering again, its failures are gradually forgiven.
+        for _ in 0..20 {
+            history.record_success(&failing, Duration::milliseconds(500));
+            history.record_success(&reliable, Duration::milliseconds(500));
+        }
+        let difference = history.patience(&reliable, max) - history.patience(&failing, max);
+        assert!(difference < Duration::milliseconds(10));
+
+        history.forget(&failing);
+        assert_eq!(history.patience(&failing, max), Duration::seconds(2));
+    }
+}
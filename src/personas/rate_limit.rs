@@ -0,0 +1,610 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+use std::cmp;
+use std::collections::HashMap;
+use std::mem;
+
+use churn_queue::{CHURN_SLICE_SIZE, ChurnQueue};
+use clock::Clock;
+use error::{InternalError, Refusal};
+use maidsafe_utilities::serialisation;
+use neighbourhood::{Neighbourhood, XorMap};
+use personas::{Context, Persona, Route};
+use routing::{Authority, MessageId, RequestContent, RequestMessage};
+use time::{Duration, SteadyTime};
+use types::{Refresh, RefreshValue};
+use utils;
+use vault::RoutingNode;
+use xor_name::XorName;
+
+// Allowances are kept in thousandths of a request, so that they refill smoothly.
+pub const ALLOWANCE_PER_REQUEST: u64 = 1000;
+// Once this many clients (or pieces of data) are tracked, those whose allowance has refilled
+// completely are forgotten before another is added.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+pub const DEFAULT_PUT_RATE_LIMIT: RateLimit = RateLimit {
+    per_second: 10,
+    burst: 100,
+};
+pub const DEFAULT_POST_RATE_LIMIT: RateLimit = RateLimit {
+    per_second: 10,
+    burst: 100,
+};
+pub const DEFAULT_DELETE_RATE_LIMIT: RateLimit = RateLimit {
+    per_second: 10,
+    burst: 100,
+};
+pub const DEFAULT_GET_RATE_LIMIT: RateLimit = RateLimit {
+    per_second: 100,
+    burst: 1000,
+};
+
+/// How quickly a client's allowance of one kind of request refills, and the most it can save up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub per_second: u64,
+    pub burst: u64,
+}
+
+impl RateLimit {
+    // An allowance with the whole burst available.
+    pub fn full_allowance(&self) -> u64 {
+        self.burst.saturating_mul(ALLOWANCE_PER_REQUEST)
+    }
+
+    // Tops `allowance` up for `elapsed_ms` having passed since it was last refilled, then takes
+    // one request from it.
+    pub fn take(&self, allowance: &mut u64, elapsed_ms: u64) -> Result<(), Refusal> {
+        *allowance = self.refill(*allowance, elapsed_ms);
+        if *allowance < ALLOWANCE_PER_REQUEST {
+            return Err(Refusal::RateLimited);
+        }
+        *allowance -= ALLOWANCE_PER_REQUEST;
+        Ok(())
+    }
+
+    // `allowance` topped up for `elapsed_ms` having passed since it was last refilled.
+    fn refill(&self, allowance: u64, elapsed_ms: u64) -> u64 {
+        cmp::min(allowance.saturating_add(elapsed_ms.saturating_mul(self.per_second)),
+                 self.full_allowance())
+    }
+}
+
+/// Clients' allowances of one kind of request, and when each was last refilled.  A client which
+/// isn't held has its full allowance.
+pub struct Allowances {
+    limit: RateLimit,
+    clients: HashMap<XorName, (u64, SteadyTime)>,
+}
+
+impl Allowances {
+    /// Constructor.
+    pub fn new(limit: RateLimit) -> Allowances {
+        Allowances {
+            limit: limit,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Takes one request from the client's allowance, after topping it up for the time since it
+    /// was last refilled.
+    pub fn take(&mut self, client_name: XorName, now: SteadyTime) -> Result<(), Refusal> {
+        if self.clients.len() >= PRUNE_THRESHOLD && !self.clients.contains_key(&client_name) {
+            self.prune(now);
+        }
+        let (mut allowance, refilled_at) = self.current(&client_name, now);
+        let result = self.limit.take(&mut allowance, 0);
+        let _ = self.clients.insert(client_name, (allowance, refilled_at));
+        result
+    }
+
+    /// The whole requests left in the client's allowance by `now`.  These are what's refreshed to
+    /// the rest of the group: members handle the same requests moments apart, so would rarely
+    /// agree on the fractions.
+    pub fn whole_requests(&self, client_name: &XorName, now: SteadyTime) -> u64 {
+        self.current(client_name, now).0 / ALLOWANCE_PER_REQUEST
+    }
+
+    /// Lowers the client's allowance to `requests` if it has more than that left, as the rest of
+    /// the group has seen it use more.
+    pub fn limit_to(&mut self, client_name: XorName, requests: u64, now: SteadyTime) {
+        let (allowance, refilled_at) = self.current(&client_name, now);
+        let limited = requests.saturating_mul(ALLOWANCE_PER_REQUEST);
+        if limited < allowance {
+            let _ = self.clients.insert(client_name, (limited, refilled_at));
+        }
+    }
+
+    /// Forgets the client's allowance.
+    pub fn remove(&mut self, client_name: &XorName) {
+        let _ = self.clients.remove(client_name);
+    }
+
+    /// The full allowance, in whole requests.
+    pub fn burst(&self) -> u64 {
+        self.limit.burst
+    }
+
+    // The client's allowance topped up to `now`, and the time it has been refilled up to.
+    fn current(&self, client_name: &XorName, now: SteadyTime) -> (u64, SteadyTime) {
+        match self.clients.get(client_name) {
+            Some(&(allowance, refilled_at)) => {
+                let elapsed_ms = cmp::max((now - refilled_at).num_milliseconds(), 0);
+                (self.limit.refill(allowance, elapsed_ms as u64),
+                 refilled_at + Duration::milliseconds(elapsed_ms))
+            }
+            None => (self.limit.full_allowance(), now),
+        }
+    }
+
+    // Forgets the clients whose allowance is full again, as they're no different from new ones.
+    fn prune(&mut self, now: SteadyTime) {
+        let limit = self.limit;
+        let clients = mem::replace(&mut self.clients, HashMap::new());
+        self.clients = clients.into_iter()
+                              .filter(|&(_, (allowance, refilled_at))| {
+                                  let elapsed_ms = cmp::max((now - refilled_at).num_milliseconds(),
+                                                            0);
+                                  limit.refill(allowance, elapsed_ms as u64) <
+                                  limit.full_allowance()
+                              })
+                              .collect();
+    }
+
+    fn client_names(&self) -> Vec<XorName> {
+        self.clients.keys().cloned().collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+/// A client's allowances of posts, deletes and gets of one piece of data, in whole requests, as
+/// refreshed to the rest of the data's managers.
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug, Clone)]
+pub struct ClientAllowances {
+    client_name: XorName,
+    posts: u64,
+    deletes: u64,
+    gets: u64,
+}
+
+// Clients' allowances for one piece of data.
+struct DataAllowances {
+    posts: Allowances,
+    deletes: Allowances,
+    gets: Allowances,
+}
+
+impl DataAllowances {
+    fn is_empty(&self) -> bool {
+        self.posts.is_empty() && self.deletes.is_empty() && self.gets.is_empty()
+    }
+
+    fn prune(&mut self, now: SteadyTime) {
+        self.posts.prune(now);
+        self.deletes.prune(now);
+        self.gets.prune(now);
+    }
+
+    // The allowances of the clients which have used some of theirs, ordered by client name so
+    // that every member of the group refreshes the same list.
+    fn to_refresh(&self, now: SteadyTime) -> Vec<ClientAllowances> {
+        let mut client_names = self.posts.client_names();
+        client_names.extend(self.deletes.client_names());
+        client_names.extend(self.gets.client_names());
+        client_names.sort();
+        client_names.dedup();
+        client_names.into_iter()
+                    .map(|client_name| {
+                        ClientAllowances {
+                            client_name: client_name,
+                            posts: self.posts.whole_requests(&client_name, now),
+                            deletes: self.deletes.whole_requests(&client_name, now),
+                            gets: self.gets.whole_requests(&client_name, now),
+                        }
+                    })
+                    .filter(|allowances| {
+                        allowances.posts < self.posts.burst() ||
+                        allowances.deletes < self.deletes.burst() ||
+                        allowances.gets < self.gets.burst()
+                    })
+                    .collect()
+    }
+}
+
+/// Rate limits on the requests clients send straight to the managers of the data: posts, deletes
+/// and gets.  Puts are limited by the client's MaidManagers instead.  A client has separate
+/// allowances for each piece of data, since only the data's managers all see the requests for it,
+/// and they refresh those allowances to each other on churn, so that a new member of the group
+/// doesn't start the client off with a full allowance.  Each manager shard holds the allowances
+/// for the names it handles.
+pub struct ClientRateLimits {
+    posts: RateLimit,
+    deletes: RateLimit,
+    gets: RateLimit,
+    data: XorMap<DataAllowances>,
+    churn_queue: ChurnQueue<XorName, XorName>,
+    clock: Clock,
+}
+
+impl ClientRateLimits {
+    /// Constructor.
+    pub fn new(posts: RateLimit, deletes: RateLimit, gets: RateLimit) -> ClientRateLimits {
+        ClientRateLimits::with_clock(posts, deletes, gets, Clock::system())
+    }
+
+    fn with_clock(posts: RateLimit,
+                  deletes: RateLimit,
+                  gets: RateLimit,
+                  clock: Clock)
+                  -> ClientRateLimits {
+        ClientRateLimits {
+            posts: posts,
+            deletes: deletes,
+            gets: gets,
+            data: XorMap::new(),
+            churn_queue: ChurnQueue::new(),
+            clock: clock,
+        }
+    }
+
+    /// Takes `request` from its client's allowance for that kind of request of the data it's
+    /// addressed to.  Requests from other nodes aren't limited.
+    pub fn charge(&mut self, request: &RequestMessage) -> Result<(), Refusal> {
+        let client_name = match utils::client_name(&request.src) {
+            Ok(client_name) => client_name,
+            Err(_) => return Ok(()),
+        };
+        match request.content {
+            RequestContent::Post(..) |
+            RequestContent::Delete(..) |
+            RequestContent::Get(..) => (),
+            _ => return Ok(()),
+        }
+        let now = self.clock.now();
+        let data_name = *request.dst.name();
+        if !self.data.contains_key(&data_name) && self.data.iter().len() >= PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+        let (posts, deletes, gets) = (self.posts, self.deletes, self.gets);
+        let data_allowances = self.data.get_or_insert_with(data_name, || {
+            DataAllowances {
+                posts: Allowances::new(posts),
+                deletes: Allowances::new(deletes),
+                gets: Allowances::new(gets),
+            }
+        });
+        let allowances = match request.content {
+            RequestContent::Post(..) => &mut data_allowances.posts,
+            RequestContent::Delete(..) => &mut data_allowances.deletes,
+            _ => &mut data_allowances.gets,
+        };
+        allowances.take(client_name, now)
+    }
+
+    /// Lowers the allowances held for the data to those refreshed by the rest of its managers.
+    pub fn handle_refresh(&mut self, data_name: XorName, refreshed: &[ClientAllowances]) {
+        let now = self.clock.now();
+        let (posts, deletes, gets) = (self.posts, self.deletes, self.gets);
+        let data_allowances = self.data.get_or_insert_with(data_name, || {
+            DataAllowances {
+                posts: Allowances::new(posts),
+                deletes: Allowances::new(deletes),
+                gets: Allowances::new(gets),
+            }
+        });
+        for allowances in refreshed {
+            data_allowances.posts.limit_to(allowances.client_name, allowances.posts, now);
+            data_allowances.deletes.limit_to(allowances.client_name, allowances.deletes, now);
+            data_allowances.gets.limit_to(allowances.client_name, allowances.gets, now);
+        }
+    }
+
+    /// Queues the names whose close group could have gained or lost `node_changed`.
+    pub fn handle_churn(&mut self, routing_node: &RoutingNode, node_changed: &XorName) {
+        let neighbourhood = Neighbourhood::new(routing_node, node_changed);
+        for data_name in self.data.names_in(&neighbourhood) {
+            self.churn_queue.push(data_name, *node_changed);
+        }
+        let _ = self.process_churn(routing_node);
+    }
+
+    /// Refreshes the next slice of names queued by churn, forgetting those for which we're no
+    /// longer in the close group.  Returns whether any remain to be checked.
+    pub fn process_churn(&mut self, routing_node: &RoutingNode) -> bool {
+        let now = self.clock.now();
+        for _ in 0..CHURN_SLICE_SIZE {
+            let (data_name, node_changed) = match self.churn_queue.pop() {
+                Some(entry) => entry,
+                None => return false,
+            };
+            match routing_node.close_group(data_name) {
+                Ok(Some(_)) => self.send_refresh(routing_node, &data_name, &node_changed, now),
+                Ok(None) => {
+                    let _ = self.data.remove(&data_name);
+                }
+                Err(error) => {
+                    error!("Failed to get close group: {:?} for {}", error, data_name);
+                    let _ = self.data.remove(&data_name);
+                }
+            }
+        }
+        !self.churn_queue.is_empty()
+    }
+
+    fn send_refresh(&self,
+                    routing_node: &RoutingNode,
+                    data_name: &XorName,
+                    node_changed: &XorName,
+                    now: SteadyTime) {
+        let allowances = match self.data.get(data_name) {
+            Some(data_allowances) => data_allowances.to_refresh(now),
+            None => return,
+        };
+        if allowances.is_empty() {
+            return;
+        }
+        let src = Authority::NaeManager(*data_name);
+        let refresh = Refresh::new(data_name, RefreshValue::ClientAllowances(allowances));
+        let churn_id = MessageId::from_lost_node(*node_changed);
+        let serialised_id = match serialisation::serialise(&churn_id) {
+            Ok(serialised_id) => serialised_id,
+            Err(_) => return,
+        };
+        if let Ok(serialised_refresh) = serialisation::serialise(&refresh) {
+            trace!("Sending refresh of client allowances for {}", data_name);
+            // The data's own refresh is sent under the churn's message ID, so this one's is
+            // derived from it.
+            let message_id = utils::message_id(&[&serialised_id[..], &serialised_refresh[..]]);
+            let _ = routing_node.send_refresh_request(src.clone(),
+                                                      src,
+                                                      serialised_refresh,
+                                                      message_id);
+        }
+    }
+
+    // Forgets the allowances which have refilled completely, and the data left with none.
+    fn prune(&mut self, now: SteadyTime) {
+        let data_names = self.data.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        for data_name in data_names {
+            let now_empty = match self.data.get_mut(&data_name) {
+                Some(data_allowances) => {
+                    data_allowances.prune(now);
+                    data_allowances.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                let _ = self.data.remove(&data_name);
+            }
+        }
+    }
+}
+
+impl Default for ClientRateLimits {
+    fn default() -> ClientRateLimits {
+        ClientRateLimits::new(DEFAULT_POST_RATE_LIMIT,
+                              DEFAULT_DELETE_RATE_LIMIT,
+                              DEFAULT_GET_RATE_LIMIT)
+    }
+}
+
+/// Carries a manager shard's `ClientRateLimits` through churn.  The allowances themselves are
+/// reached through the `Context`, as they're charged by whichever persona handles the request.
+pub struct ClientRateLimitRefresher;
+
+impl Persona for ClientRateLimitRefresher {
+    fn routes(&self) -> Vec<Route> {
+        use personas::AuthorityKind::NaeManager;
+        use personas::MessageKind::Refresh;
+        use personas::RefreshKind::ClientAllowances;
+        vec![Route(NaeManager, NaeManager, Refresh(ClientAllowances))]
+    }
+
+    fn on_refresh(&mut self,
+                  context: &mut Context,
+                  refresh: &Refresh)
+                  -> Result<(), InternalError> {
+        if let RefreshValue::ClientAllowances(ref allowances) = refresh.value {
+            unwrap_result!(context.client_rate_limits.lock()).handle_refresh(refresh.name,
+                                                                            allowances);
+            Ok(())
+        } else {
+            Err(InternalError::InvalidMessage)
+        }
+    }
+
+    fn on_churn(&mut self, context: &mut Context, node_changed: &XorName) {
+        unwrap_result!(context.client_rate_limits.lock()).handle_churn(context.routing_node,
+                                                                        node_changed)
+    }
+
+    fn continue_churn(&mut self, context: &mut Context) -> bool {
+        unwrap_result!(context.client_rate_limits.lock()).process_churn(context.routing_node)
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature="use-mock-crust"))]
+mod test {
+    use super::*;
+
+    use clock::Clock;
+    use error::Refusal;
+    use maidsafe_utilities::serialisation;
+    use rand;
+    use routing::{Authority, Data, DataRequest, MessageId, PlainData, RequestContent,
+                  RequestMessage};
+    use sodiumoxide::crypto::sign;
+    use std::sync::mpsc;
+    use time::Duration;
+    use types::{Refresh, RefreshValue};
+    use vault::RoutingNode;
+    use xor_name::XorName;
+
+    fn new_client() -> Authority {
+        Authority::Client {
+            client_key: sign::gen_keypair().0,
+            peer_id: rand::random(),
+            proxy_node_name: rand::random(),
+        }
+    }
+
+    fn request(src: &Authority, content: RequestContent) -> RequestMessage {
+        RequestMessage {
+            src: src.clone(),
+            dst: Authority::NaeManager(rand::random()),
+            content: content,
+        }
+    }
+
+    #[test]
+    fn take_allowance() {
+        let rate_limit = RateLimit {
+            per_second: 2,
+            burst: 3,
+        };
+        let mut allowance = rate_limit.full_allowance();
+        for _ in 0..rate_limit.burst {
+            assert!(rate_limit.take(&mut allowance, 0).is_ok());
+        }
+        assert_eq!(Err(Refusal::RateLimited), rate_limit.take(&mut allowance, 0));
+
+        // Half a second refills one request.
+        assert!(rate_limit.take(&mut allowance, 499).is_err());
+        assert!(rate_limit.take(&mut allowance, 1).is_ok());
+        assert!(rate_limit.take(&mut allowance, 0).is_err());
+
+        // The allowance never exceeds the burst.
+        assert!(rate_limit.take(&mut allowance, 60_000).is_ok());
+        assert_eq!(rate_limit.full_allowance() - ALLOWANCE_PER_REQUEST, allowance);
+    }
+
+    #[test]
+    fn separate_limits() {
+        let clock = Clock::manual();
+        let limit = RateLimit {
+            per_second: 1,
+            burst: 1,
+        };
+        let mut rate_limits = ClientRateLimits::with_clock(limit, limit, limit, clock.clone());
+        let client = new_client();
+        let data = || Data::Plain(PlainData::new(rand::random(), vec![]));
+        let post = request(&client, RequestContent::Post(data(), MessageId::new()));
+        let delete = request(&client, RequestContent::Delete(data(), MessageId::new()));
+        let get_content = RequestContent::Get(DataRequest::Plain(rand::random()), MessageId::new());
+        let get = request(&client, get_content);
+
+        // Each kind of request has its own allowance, as does each client.
+        for request in &[&post, &delete, &get] {
+            assert!(rate_limits.charge(request).is_ok());
+            assert_eq!(Err(Refusal::RateLimited), rate_limits.charge(request));
+        }
+        let other_client = request(&new_client(),
+                                   RequestContent::Post(data(), MessageId::new()));
+        assert!(rate_limits.charge(&other_client).is_ok());
+
+        // Requests from other nodes aren't limited.
+        let mut from_node = post.clone();
+        from_node.src = Authority::ClientManager(rand::random());
+        assert!(rate_limits.charge(&from_node).is_ok());
+        assert!(rate_limits.charge(&from_node).is_ok());
+
+        clock.advance(Duration::seconds(1));
+        assert!(rate_limits.charge(&post).is_ok());
+
+        // Requests for other data come out of separate allowances.
+        let mut other_data = get.clone();
+        other_data.dst = Authority::NaeManager(rand::random());
+        assert!(rate_limits.charge(&other_data).is_ok());
+    }
+
+    #[test]
+    fn allowances_carried_in_refresh() {
+        let routing = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
+        let mut data_name = rand::random::<XorName>();
+        while let Ok(None) = routing.close_group(data_name) {
+            data_name = rand::random();
+        }
+        let limit = RateLimit {
+            per_second: 1,
+            burst: 5,
+        };
+        let client = new_client();
+        let requests = (0..3)
+                           .map(|_| {
+                               let content = RequestContent::Get(DataRequest::Plain(data_name),
+                                                                 MessageId::new());
+                               let mut get = request(&client, content);
+                               get.dst = Authority::NaeManager(data_name);
+                               get
+                           })
+                           .collect::<Vec<_>>();
+        let mut node_added = data_name;
+        node_added.0[63] ^= 1;
+        routing.node_added_event(node_added);
+
+        // Each of the data's managers handles the client's gets, 400ms apart, and the churn a
+        // second after the last of them, all a few milliseconds later than the others.
+        let lags_ms = [[0, 7, 31], [12, 0, 3], [45, 26, 0]];
+        let mut member_refreshes = Vec::new();
+        for member_lags_ms in &lags_ms {
+            let clock = Clock::manual();
+            let mut member = ClientRateLimits::with_clock(limit, limit, limit, clock.clone());
+            let mut elapsed_ms = 0;
+            for (index, request) in requests.iter().enumerate() {
+                let due_ms = 400 * index as i64 + member_lags_ms[index];
+                clock.advance(Duration::milliseconds(due_ms - elapsed_ms));
+                elapsed_ms = due_ms;
+                assert!(member.charge(request).is_ok());
+            }
+            clock.advance(Duration::milliseconds(1000 + member_lags_ms[0]));
+            let given = routing.refresh_requests_given().len();
+            member.handle_churn(&routing, &node_added);
+            member_refreshes.push(routing.refresh_requests_given().split_off(given));
+        }
+
+        // Their refreshes are the same, so accumulate.
+        assert_eq!(1, member_refreshes[0].len());
+        assert!(member_refreshes.iter().all(|refreshes| *refreshes == member_refreshes[0]));
+        let refresh = if let RequestContent::Refresh(ref serialised_refresh, _) =
+                             member_refreshes[0][0].content {
+            unwrap_result!(serialisation::deserialise::<Refresh>(serialised_refresh))
+        } else {
+            unreachable!()
+        };
+
+        // A manager joining the group inherits what's left of the client's gets of the data: with
+        // 3 used and about 1.8 refilled, that's 3.  Its posts and deletes are untouched.
+        let mut new_member = ClientRateLimits::with_clock(limit, limit, limit, Clock::manual());
+        if let RefreshValue::ClientAllowances(ref allowances) = refresh.value {
+            assert_eq!(1, allowances.len());
+            assert_eq!((3, 5, 5),
+                       (allowances[0].gets, allowances[0].posts, allowances[0].deletes));
+            new_member.handle_refresh(refresh.name, allowances);
+        } else {
+            unreachable!()
+        }
+        for request in &requests {
+            assert!(new_member.charge(request).is_ok());
+        }
+        assert_eq!(Err(Refusal::RateLimited), new_member.charge(&requests[0]));
+    }
+}
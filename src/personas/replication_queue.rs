@@ -0,0 +1,307 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::collections::{HashMap, VecDeque};
+
+use routing::{Authority, Data, ImmutableData, MessageId};
+use time::{Duration, SteadyTime};
+use vault::RoutingNode;
+use xor_name::XorName;
+
+// The most replication Puts which can be awaiting a response at any one time.
+const MAX_IN_FLIGHT: usize = 32;
+// The most replications which can be waiting for a free slot or for their backoff to elapse.
+const MAX_WAITING: usize = 1000;
+// How long to wait for a response before treating a Put as failed.
+const PUT_TIMEOUT_SECS: i64 = 120;
+// The delay before the first retry, doubled for each retry after that.
+const INITIAL_BACKOFF_SECS: i64 = 5;
+// How many Puts to send for a replication before giving up on it.
+pub const MAX_REPLICATION_ATTEMPTS: u32 = 5;
+
+// Storing a copy of a chunk, or one of its fragments, on a new holder.
+pub struct Replication {
+    // The chunk the new holder is added to the account of
+    pub chunk_name: XorName,
+    pub data: ImmutableData,
+    pub pmid_node: XorName,
+    message_id: MessageId,
+    attempts: u32,
+    // When to send it if waiting, or when to give up on a response if in flight
+    due: SteadyTime,
+}
+
+impl Replication {
+    // The number of Puts sent so far.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+}
+
+// The replications an ImmutableDataManager is making after churn or a failed holder.  Only a
+// limited number are sent at once, so that mass churn doesn't cause a storm of Puts, and failed
+// ones can be retried on another holder after a backoff which grows with each attempt.
+pub struct ReplicationQueue {
+    waiting: VecDeque<Replication>,
+    // <(Data name, target PmidNode), replication>
+    in_flight: HashMap<(XorName, XorName), Replication>,
+}
+
+impl ReplicationQueue {
+    pub fn new() -> ReplicationQueue {
+        ReplicationQueue {
+            waiting: VecDeque::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    // Queues storing `data` on `pmid_node` for the managers of `chunk_name`, sending it straight
+    // away if there's a slot free.  The first attempt is sent with `message_id`.  Returns `false`
+    // if the queue is full.
+    pub fn push(&mut self,
+                routing_node: &RoutingNode,
+                chunk_name: XorName,
+                data: ImmutableData,
+                pmid_node: XorName,
+                message_id: MessageId,
+                now: SteadyTime)
+                -> bool {
+        if self.contains(&data.name(), &pmid_node) {
+            return true;
+        }
+        if self.waiting.len() >= MAX_WAITING {
+            return false;
+        }
+        self.waiting.push_back(Replication {
+            chunk_name: chunk_name,
+            data: data,
+            pmid_node: pmid_node,
+            message_id: message_id,
+            attempts: 0,
+            due: now,
+        });
+        self.send_due(routing_node, now);
+        true
+    }
+
+    // Queues a failed replication to be sent to `pmid_node` once its backoff has elapsed.  The
+    // retry's ID is derived from the last attempt's, so that the whole group sends it alike.
+    // Returns `false` if the queue is full.
+    pub fn retry(&mut self,
+                 mut replication: Replication,
+                 pmid_node: XorName,
+                 now: SteadyTime)
+                 -> bool {
+        if self.waiting.len() >= MAX_WAITING {
+            return false;
+        }
+        let backoff = INITIAL_BACKOFF_SECS << replication.attempts.saturating_sub(1);
+        replication.pmid_node = pmid_node;
+        replication.message_id = MessageId::increment_first_byte(&replication.message_id);
+        replication.due = now + Duration::seconds(backoff);
+        self.waiting.push_back(replication);
+        true
+    }
+
+    // Removes and returns the in-flight replication of `data_name` to `pmid_node`, if any.
+    pub fn remove(&mut self, data_name: &XorName, pmid_node: &XorName) -> Option<Replication> {
+        self.in_flight.remove(&(*data_name, *pmid_node))
+    }
+
+    // Drops all replications for `chunk_name`, e.g. once we're no longer one of its managers.
+    pub fn remove_data(&mut self, chunk_name: &XorName) {
+        self.waiting.retain(|replication| replication.chunk_name != *chunk_name);
+        let keys = self.in_flight
+                       .iter()
+                       .filter(|&(_, replication)| replication.chunk_name == *chunk_name)
+                       .map(|(key, _)| *key)
+                       .collect::<Vec<_>>();
+        for key in &keys {
+            let _ = self.in_flight.remove(key);
+        }
+    }
+
+    // Removes and returns the in-flight replications which have gone unanswered for too long.
+    pub fn remove_timed_out(&mut self, now: SteadyTime) -> Vec<Replication> {
+        let keys = self.in_flight
+                       .iter()
+                       .filter(|&(_, replication)| replication.due < now)
+                       .map(|(key, _)| *key)
+                       .collect::<Vec<_>>();
+        keys.iter().filter_map(|key| self.in_flight.remove(key)).collect()
+    }
+
+    // Sends the waiting replications whose backoff has elapsed, while there are slots free.
+    pub fn send_due(&mut self, routing_node: &RoutingNode, now: SteadyTime) {
+        let mut index = 0;
+        while self.in_flight.len() < MAX_IN_FLIGHT && index < self.waiting.len() {
+            if self.waiting.get(index).map_or(true, |replication| replication.due > now) {
+                index += 1;
+                continue;
+            }
+            if let Some(mut replication) = self.waiting.remove(index) {
+                let data_name = replication.data.name();
+                trace!("Replicating {} - sending Put to {} (attempt {})",
+                       data_name,
+                       replication.pmid_node,
+                       replication.attempts + 1);
+                let src = Authority::NaeManager(replication.chunk_name);
+                let dst = Authority::NodeManager(replication.pmid_node);
+                let data = Data::Immutable(replication.data.clone());
+                let _ = routing_node.send_put_request(src, dst, data, replication.message_id);
+                replication.attempts += 1;
+                replication.due = now + Duration::seconds(PUT_TIMEOUT_SECS);
+                let _ = self.in_flight.insert((data_name, replication.pmid_node), replication);
+            }
+        }
+    }
+
+    fn contains(&self, data_name: &XorName, pmid_node: &XorName) -> bool {
+        self.in_flight.contains_key(&(*data_name, *pmid_node)) ||
+        self.waiting.iter().any(|replication| {
+            replication.pmid_node == *pmid_node && replication.data.name() == *data_name
+        })
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(feature="clippy", allow(indexing_slicing))]
+#[cfg(not(feature="use-mock-crust"))]
+mod test {
+    use super::*;
+    use clock::Clock;
+    use rand::random;
+    use routing::{Authority, Data, ImmutableData, ImmutableDataType, MessageId, RequestContent};
+    use std::sync::mpsc;
+    use time::Duration;
+    use utils::generate_random_vec_u8;
+    use vault::RoutingNode;
+
+    fn random_data() -> ImmutableData {
+        ImmutableData::new(ImmutableDataType::Normal, generate_random_vec_u8(100))
+    }
+
+    #[test]
+    fn bounded_concurrency() {
+        let routing = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
+        let clock = Clock::manual();
+        let mut queue = ReplicationQueue::new();
+        let replications = (0..MAX_IN_FLIGHT + 2)
+                               .map(|_| (random_data(), random()))
+                               .collect::<Vec<_>>();
+        for &(ref data, pmid_node) in &replications {
+            assert!(queue.push(&routing,
+                               data.name(),
+                               data.clone(),
+                               pmid_node,
+                               MessageId::new(),
+                               clock.now()));
+        }
+        assert_eq!(routing.put_requests_given().len(), MAX_IN_FLIGHT);
+
+        // Each answered Put frees a slot for a waiting one.
+        let (ref data, pmid_node) = replications[0];
+        assert!(queue.remove(&data.name(), &pmid_node).is_some());
+        assert!(queue.remove(&data.name(), &pmid_node).is_none());
+        queue.send_due(&routing, clock.now());
+        assert_eq!(routing.put_requests_given().len(), MAX_IN_FLIGHT + 1);
+    }
+
+    #[test]
+    fn retry_with_backoff() {
+        let routing = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
+        let clock = Clock::manual();
+        let mut queue = ReplicationQueue::new();
+        let data = random_data();
+        let first_holder = random();
+        let message_id = MessageId::new();
+        assert!(queue.push(&routing,
+                           data.name(),
+                           data.clone(),
+                           first_holder,
+                           message_id,
+                           clock.now()));
+        assert_eq!(routing.put_requests_given().len(), 1);
+
+        // The first attempt goes unanswered.
+        clock.advance(Duration::seconds(PUT_TIMEOUT_SECS));
+        assert!(queue.remove_timed_out(clock.now()).is_empty());
+        clock.advance(Duration::milliseconds(1));
+        let mut timed_out = queue.remove_timed_out(clock.now());
+        assert_eq!(timed_out.len(), 1);
+        let replication = unwrap_option!(timed_out.pop(), "");
+        assert_eq!(replication.attempts(), 1);
+
+        // The retry goes to the new holder once the backoff has elapsed, doubling each time.
+        let mut backoff = INITIAL_BACKOFF_SECS;
+        let mut replication = replication;
+        for attempt in 1..4 {
+            let holder = random();
+            let message_id = MessageId::increment_first_byte(&replication.message_id);
+            assert!(queue.retry(replication, holder, clock.now()));
+            clock.advance(Duration::seconds(backoff) - Duration::milliseconds(1));
+            queue.send_due(&routing, clock.now());
+            assert_eq!(routing.put_requests_given().len(), attempt);
+            clock.advance(Duration::milliseconds(1));
+            queue.send_due(&routing, clock.now());
+            let put_requests = routing.put_requests_given();
+            assert_eq!(put_requests.len(), attempt + 1);
+            assert_eq!(put_requests[attempt].dst, Authority::NodeManager(holder));
+            assert_eq!(put_requests[attempt].content,
+                       RequestContent::Put(Data::Immutable(data.clone()), message_id));
+
+            replication = unwrap_option!(queue.remove(&data.name(), &holder), "");
+            assert_eq!(replication.attempts(), attempt as u32 + 1);
+            backoff *= 2;
+        }
+
+        // Nothing more is sent for data we've stopped managing.
+        assert!(queue.retry(replication, random(), clock.now()));
+        queue.remove_data(&data.name());
+        clock.advance(Duration::seconds(backoff));
+        queue.send_due(&routing, clock.now());
+        assert_eq!(routing.put_requests_given().len(), 4);
+    }
+
+    #[test]
+    fn retry_when_full() {
+        let routing = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
+        let clock = Clock::manual();
+        let mut queue = ReplicationQueue::new();
+        let data = random_data();
+        let holder = random();
+        assert!(queue.push(&routing,
+                           data.name(),
+                           data.clone(),
+                           holder,
+                           MessageId::new(),
+                           clock.now()));
+        for _ in 0..MAX_IN_FLIGHT + MAX_WAITING - 1 {
+            let other_data = random_data();
+            assert!(queue.push(&routing,
+                               other_data.name(),
+                               other_data,
+                               random(),
+                               MessageId::new(),
+                               clock.now()));
+        }
+
+        // A failed replication isn't retried while the queue is full.
+        let replication = unwrap_option!(queue.remove(&data.name(), &holder), "");
+        assert!(!queue.retry(replication, random(), clock.now()));
+    }
+}
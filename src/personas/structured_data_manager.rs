@@ -15,27 +15,41 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use std::cmp;
 use std::collections::HashSet;
 use std::convert::From;
 
 use chunk_store::ChunkStore;
+use churn_queue::{CHURN_SLICE_SIZE, ChurnQueue};
 use error::InternalError;
 use maidsafe_utilities::serialisation;
+use neighbourhood::{Neighbourhood, XorMap};
+use personas::{Context, Persona, Route, Success, SuccessKind};
 use routing::{Authority, Data, DataRequest, MessageId, RequestContent, RequestMessage,
               StructuredData};
 use safe_network_common::client_errors::{MutationError, GetError};
 use types::{Refresh, RefreshValue};
+use utils;
 use vault::{CHUNK_STORE_PREFIX, RoutingNode};
 use xor_name::XorName;
 
 pub struct StructuredDataManager {
     chunk_store: ChunkStore,
+    // The names of the data held, kept in step with `chunk_store`
+    chunk_names: XorMap<()>,
+    // Data still to be checked after churn, with the latest node to have changed near each item
+    churn_queue: ChurnQueue<XorName, XorName>,
+    // Success responses sent since the registry last took them
+    successes: Vec<Success>,
 }
 
 impl StructuredDataManager {
     pub fn new(capacity: u64) -> Result<StructuredDataManager, InternalError> {
         Ok(StructuredDataManager {
             chunk_store: try!(ChunkStore::new(CHUNK_STORE_PREFIX, capacity)),
+            chunk_names: XorMap::new(),
+            churn_queue: ChurnQueue::new(),
+            successes: Vec::new(),
         })
     }
 
@@ -49,7 +63,7 @@ impl StructuredDataManager {
                                        ref message_id) = request.content {
                 (data_request.name(), message_id)
             } else {
-                unreachable!("Error in vault demuxing")
+                return Err(InternalError::InvalidMessage);
             };
 
         if let Ok(data) = self.chunk_store.get(&data_name) {
@@ -85,7 +99,7 @@ impl StructuredDataManager {
                                                             ref message_id) = request.content {
             (data, message_id)
         } else {
-            unreachable!("Logic error")
+            return Err(InternalError::InvalidMessage);
         };
 
         let data_name = data.name();
@@ -138,11 +152,14 @@ impl StructuredDataManager {
                                                   *message_id);
             Err(From::from(error))
         } else {
+            let _ = self.chunk_names.insert(data_name, ());
             trace!("SDM sending PutSuccess for data {}", data_name);
-            let _ = routing_node.send_put_success(response_src,
-                                                  response_dst,
-                                                  data_name,
-                                                  *message_id);
+            let success = Success::new(SuccessKind::Put,
+                                       response_src,
+                                       response_dst,
+                                       data_name,
+                                       *message_id);
+            self.successes.push(success.send(routing_node));
             Ok(())
         }
     }
@@ -156,7 +173,7 @@ impl StructuredDataManager {
                    request.content {
                 (structured_data, message_id)
             } else {
-                unreachable!("Error in vault demuxing")
+                return Err(InternalError::InvalidMessage);
             };
 
         if let Ok(serialised_data) = self.chunk_store.get(&new_data.name()) {
@@ -167,10 +184,12 @@ impl StructuredDataManager {
                         if let Ok(()) = self.chunk_store
                                             .put(&existing_data.name(), &serialised_data) {
                             trace!("SDM updated {:?} to {:?}", existing_data, new_data);
-                            let _ = routing_node.send_post_success(request.dst.clone(),
-                                                                   request.src.clone(),
-                                                                   new_data.name(),
-                                                                   *message_id);
+                            let success = Success::new(SuccessKind::Post,
+                                                       request.dst.clone(),
+                                                       request.src.clone(),
+                                                       new_data.name(),
+                                                       *message_id);
+                            self.successes.push(success.send(routing_node));
                             return Ok(());
                         }
                     }
@@ -195,7 +214,7 @@ impl StructuredDataManager {
                                                                ref message_id) = request.content {
             (data.clone(), message_id)
         } else {
-            unreachable!("Error in vault demuxing")
+            return Err(InternalError::InvalidMessage);
         };
 
         if let Ok(serialised_data) = self.chunk_store.get(&data.name()) {
@@ -208,10 +227,12 @@ impl StructuredDataManager {
                         trace!("SDM deleted {:?} with requested new version {:?}",
                                existing_data,
                                data);
-                        let _ = routing_node.send_delete_success(request.dst.clone(),
-                                                                 request.src.clone(),
-                                                                 data.name(),
-                                                                 *message_id);
+                        let success = Success::new(SuccessKind::Delete,
+                                                   request.dst.clone(),
+                                                   request.src.clone(),
+                                                   data.name(),
+                                                   *message_id);
+                        self.successes.push(success.send(routing_node));
                         return Ok(());
                     }
                 }
@@ -240,29 +261,59 @@ impl StructuredDataManager {
                 }
             }
         } else {
-            return Ok(try!(self.chunk_store
-                               .put(&structured_data.name(),
-                                    &try!(serialisation::serialise(&structured_data)))));
+            try!(self.chunk_store
+                     .put(&structured_data.name(),
+                          &try!(serialisation::serialise(&structured_data))));
+            let _ = self.chunk_names.insert(structured_data.name(), ());
         }
         Ok(())
     }
 
+    // Queues the data whose close group could have gained or lost `node_changed`.
     pub fn handle_churn(&mut self, routing_node: &RoutingNode, node_changed: &XorName) {
-        // Only retain data for which we're still in the close group
-        let data_names = self.chunk_store.names();
-        for data_name in data_names {
+        let neighbourhood = Neighbourhood::new(routing_node, node_changed);
+        for data_name in self.chunk_names.names_in(&neighbourhood) {
+            self.churn_queue.push(data_name, *node_changed);
+        }
+        let _ = self.process_churn(routing_node);
+    }
+
+    // Checks the next slice of data queued by churn, only retaining that for which we're still in
+    // the close group.  Returns whether any remains to be checked.
+    fn process_churn(&mut self, routing_node: &RoutingNode) -> bool {
+        for _ in 0..CHURN_SLICE_SIZE {
+            let (data_name, node_changed) = match self.churn_queue.pop() {
+                Some(entry) => entry,
+                None => return false,
+            };
+            if !self.chunk_store.has_chunk(&data_name) {
+                continue;
+            }
             match routing_node.close_group(data_name) {
                 Ok(None) => {
                     trace!("No longer a SDM for {}", data_name);
-                    let _ = self.chunk_store.delete(&data_name);
+                    self.delete(&data_name);
                 }
-                Ok(Some(_)) => self.send_refresh(routing_node, &data_name, node_changed),
+                Ok(Some(_)) => self.send_refresh(routing_node, &data_name, &node_changed),
                 Err(error) => {
                     error!("Failed to get close group: {:?} for {}", error, data_name);
-                    let _ = self.chunk_store.delete(&data_name);
+                    self.delete(&data_name);
                 }
             }
         }
+        !self.churn_queue.is_empty()
+    }
+
+    // Changes the storage allowance.  Structured data has no other copies this vault could hand
+    // off to, so the allowance is never reduced below the space already in use.
+    pub fn set_capacity(&mut self, capacity: u64) -> Result<(), InternalError> {
+        let used_space = self.chunk_store.used_space();
+        if used_space > capacity {
+            warn!("Can't reduce SDM capacity to {} bytes as {} are in use.",
+                  capacity,
+                  used_space);
+        }
+        utils::resize_chunk_store(&mut self.chunk_store, cmp::max(capacity, used_space))
     }
 
     #[cfg(feature = "use-mock-crust")]
@@ -270,6 +321,11 @@ impl StructuredDataManager {
         self.chunk_store.names()
     }
 
+    fn delete(&mut self, data_name: &XorName) {
+        let _ = self.chunk_store.delete(data_name);
+        let _ = self.chunk_names.remove(data_name);
+    }
+
     fn send_refresh(&self,
                     routing_node: &RoutingNode,
                     data_name: &XorName,
@@ -298,6 +354,80 @@ impl StructuredDataManager {
     }
 }
 
+impl Persona for StructuredDataManager {
+    fn routes(&self) -> Vec<Route> {
+        use personas::AuthorityKind::{Client, ClientManager, NaeManager};
+        use personas::DataKind::Structured;
+        use personas::MessageKind::{Delete, Get, Post, Put, Refresh};
+        use personas::RefreshKind;
+        vec![Route(Client, NaeManager, Get(Structured)),
+             Route(ClientManager, NaeManager, Put(Structured)),
+             Route(Client, NaeManager, Post(Structured)),
+             Route(Client, NaeManager, Delete(Structured)),
+             Route(NaeManager, NaeManager, Refresh(RefreshKind::StructuredDataManager))]
+    }
+
+    fn on_request(&mut self,
+                  context: &mut Context,
+                  request: &RequestMessage)
+                  -> Result<(), InternalError> {
+        match request.content {
+            RequestContent::Get(..) => {
+                try!(context.charge_client(request));
+                self.handle_get(context.routing_node, request)
+            }
+            RequestContent::Put(..) => {
+                let full_pmid_nodes = unwrap_result!(context.full_pmid_nodes.read());
+                self.handle_put(context.routing_node, &full_pmid_nodes, request)
+            }
+            RequestContent::Post(..) => {
+                try!(context.charge_client(request));
+                self.handle_post(context.routing_node, request)
+            }
+            RequestContent::Delete(..) => {
+                try!(context.charge_client(request));
+                self.handle_delete(context.routing_node, request)
+            }
+            _ => Err(InternalError::InvalidMessage),
+        }
+    }
+
+    fn on_refresh(&mut self,
+                  _context: &mut Context,
+                  refresh: &Refresh)
+                  -> Result<(), InternalError> {
+        if let RefreshValue::StructuredDataManager(ref structured_data) = refresh.value {
+            self.handle_refresh(structured_data.clone())
+        } else {
+            Err(InternalError::InvalidMessage)
+        }
+    }
+
+    fn on_churn(&mut self, context: &mut Context, node_changed: &XorName) {
+        self.handle_churn(context.routing_node, node_changed)
+    }
+
+    fn continue_churn(&mut self, context: &mut Context) -> bool {
+        self.process_churn(context.routing_node)
+    }
+
+    fn on_capacity_changed(&mut self,
+                           _context: &mut Context,
+                           capacity: u64)
+                           -> Result<(), InternalError> {
+        self.set_capacity(capacity)
+    }
+
+    fn take_successes(&mut self) -> Vec<Success> {
+        self.successes.drain(..).collect()
+    }
+
+    #[cfg(feature = "use-mock-crust")]
+    fn stored_names(&self) -> Vec<XorName> {
+        self.get_stored_names()
+    }
+}
+
 
 
 #[cfg(test)]
@@ -419,7 +549,8 @@ mod test {
                 peer_id: random(),
                 proxy_node_name: random::<XorName>(),
             };
-            let client_manager = Authority::ClientManager(utils::client_name(&client));
+            let client_name = unwrap_result!(utils::client_name(&client));
+            let client_manager = Authority::ClientManager(client_name);
             let request = RequestMessage {
                 src: client_manager.clone(),
                 dst: Authority::NaeManager(sd_data.name()),
@@ -840,7 +971,7 @@ mod test {
 
         let lost_node = env.lose_close_node(&put_env.sd_data.name());
         env.routing.remove_node_from_routing_table(&lost_node);
-        let _ = env.structured_data_manager.handle_churn(&env.routing, &random::<XorName>());
+        let _ = env.structured_data_manager.handle_churn(&env.routing, &lost_node);
 
         let refresh_requests = env.routing.refresh_requests_given();
         assert_eq!(refresh_requests.len(), 1);
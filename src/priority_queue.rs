@@ -0,0 +1,253 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Classes of work, most urgent first.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Priority {
+    /// Churn, refreshes and the vault's own control messages, which keep data safe.
+    Churn,
+    /// Requests and responses exchanged between nodes.
+    Node,
+    /// Requests from clients.
+    Client,
+}
+
+const PRIORITIES: [Priority; 3] = [Priority::Churn, Priority::Node, Priority::Client];
+
+impl Priority {
+    fn index(&self) -> usize {
+        match *self {
+            Priority::Churn => 0,
+            Priority::Node => 1,
+            Priority::Client => 2,
+        }
+    }
+}
+
+/// The most items each class may hold before pushing more blocks or is refused.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueLimits {
+    pub churn: usize,
+    pub node: usize,
+    pub client: usize,
+}
+
+impl QueueLimits {
+    fn of(&self, priority: Priority) -> usize {
+        match priority {
+            Priority::Churn => self.churn,
+            Priority::Node => self.node,
+            Priority::Client => self.client,
+        }
+    }
+}
+
+/// A queue handing items from one thread to another, always yielding the most urgent class first
+/// and items of the same class in the order they were pushed.  Clones share the same queue.
+#[derive(Clone)]
+pub struct PriorityQueue<T> {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    // Signalled when an item is pushed or the queue is closed.
+    not_empty: Condvar,
+    // Signalled when an item is popped or the queue is closed.
+    not_full: Condvar,
+}
+
+struct State<T> {
+    queues: [VecDeque<T>; 3],
+    limits: QueueLimits,
+    closed: bool,
+    // The number of pushes waiting for room.
+    blocked_pushes: usize,
+}
+
+impl<T> PriorityQueue<T> {
+    /// Constructor.
+    pub fn new(limits: QueueLimits) -> PriorityQueue<T> {
+        PriorityQueue {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+                    limits: limits,
+                    closed: false,
+                    blocked_pushes: 0,
+                }),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Pushes `item`, waiting for room if its class is full.  The item is handed back if the
+    /// queue has been closed.
+    pub fn push(&self, priority: Priority, item: T) -> Result<(), T> {
+        let mut state = unwrap_result!(self.shared.state.lock());
+        while !state.closed &&
+              state.queues[priority.index()].len() >= state.limits.of(priority) {
+            state.blocked_pushes += 1;
+            state = unwrap_result!(self.shared.not_full.wait(state));
+            state.blocked_pushes -= 1;
+        }
+        self.push_locked(&mut state, priority, item)
+    }
+
+    /// Pushes `item` only if its class has room, otherwise hands it back.
+    pub fn try_push(&self, priority: Priority, item: T) -> Result<(), T> {
+        let mut state = unwrap_result!(self.shared.state.lock());
+        if state.queues[priority.index()].len() >= state.limits.of(priority) {
+            return Err(item);
+        }
+        self.push_locked(&mut state, priority, item)
+    }
+
+    /// Takes the most urgent item, waiting for one if the queue is empty.  Returns `None` once the
+    /// queue is closed and empty.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = unwrap_result!(self.shared.state.lock());
+        loop {
+            if let Some(item) = self.pop_locked(&mut state) {
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            state = unwrap_result!(self.shared.not_empty.wait(state));
+        }
+    }
+
+    /// Takes the most urgent item without waiting.  Returns `Err(true)` if the queue is empty and
+    /// closed, and `Err(false)` if it's merely empty.
+    pub fn try_pop(&self) -> Result<T, bool> {
+        let mut state = unwrap_result!(self.shared.state.lock());
+        match self.pop_locked(&mut state) {
+            Some(item) => Ok(item),
+            None => Err(state.closed),
+        }
+    }
+
+    /// Stops further pushes.  Items already queued can still be popped.
+    pub fn close(&self) {
+        unwrap_result!(self.shared.state.lock()).closed = true;
+        self.shared.not_empty.notify_all();
+        self.shared.not_full.notify_all();
+    }
+
+    /// Returns the number of pushes waiting for room.
+    #[cfg(test)]
+    pub fn blocked_pushes(&self) -> usize {
+        unwrap_result!(self.shared.state.lock()).blocked_pushes
+    }
+
+    fn push_locked(&self, state: &mut State<T>, priority: Priority, item: T) -> Result<(), T> {
+        if state.closed {
+            return Err(item);
+        }
+        state.queues[priority.index()].push_back(item);
+        self.shared.not_empty.notify_one();
+        Ok(())
+    }
+
+    fn pop_locked(&self, state: &mut State<T>) -> Option<T> {
+        for priority in &PRIORITIES {
+            if let Some(item) = state.queues[priority.index()].pop_front() {
+                self.shared.not_full.notify_all();
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    fn limits(limit: usize) -> QueueLimits {
+        QueueLimits {
+            churn: limit,
+            node: limit,
+            client: limit,
+        }
+    }
+
+    // Waits until `count` pushes are blocked on `queue`.
+    fn wait_for_blocked_pushes<T>(queue: &PriorityQueue<T>, count: usize) {
+        while queue.blocked_pushes() < count {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn most_urgent_first() {
+        let queue = PriorityQueue::new(limits(10));
+        unwrap_result!(queue.push(Priority::Client, 0));
+        unwrap_result!(queue.push(Priority::Node, 1));
+        unwrap_result!(queue.push(Priority::Client, 2));
+        unwrap_result!(queue.push(Priority::Churn, 3));
+        unwrap_result!(queue.push(Priority::Node, 4));
+
+        let popped = (0..5).map(|_| unwrap_option!(queue.pop(), "")).collect::<Vec<_>>();
+        assert_eq!(vec![3, 1, 4, 0, 2], popped);
+        assert_eq!(Err(false), queue.try_pop());
+    }
+
+    #[test]
+    fn full_class() {
+        let queue = PriorityQueue::new(limits(2));
+        unwrap_result!(queue.try_push(Priority::Client, 0));
+        unwrap_result!(queue.try_push(Priority::Client, 1));
+        assert_eq!(Err(2), queue.try_push(Priority::Client, 2));
+        // Other classes have their own limits.
+        unwrap_result!(queue.try_push(Priority::Node, 3));
+
+        // A blocked push completes once an item of its class has been popped.
+        let pusher = queue.clone();
+        let joiner = thread!("Pusher", move || pusher.push(Priority::Client, 4));
+        wait_for_blocked_pushes(&queue, 1);
+        assert_eq!(Ok(3), queue.try_pop());
+        assert_eq!(Ok(0), queue.try_pop());
+        assert_eq!(Some(Ok(())), joiner.join().ok());
+        assert_eq!(Ok(1), queue.try_pop());
+        assert_eq!(Ok(4), queue.try_pop());
+    }
+
+    #[test]
+    fn close() {
+        let queue = PriorityQueue::new(limits(1));
+        unwrap_result!(queue.push(Priority::Node, 0));
+
+        let pusher = queue.clone();
+        let joiner = thread!("Pusher", move || pusher.push(Priority::Node, 1));
+        wait_for_blocked_pushes(&queue, 1);
+        queue.close();
+
+        // The blocked push is refused, but what was queued before closing is still delivered.
+        assert_eq!(Some(Err(1)), joiner.join().ok());
+        assert_eq!(Err(2), queue.try_push(Priority::Churn, 2));
+        assert_eq!(Some(0), queue.pop());
+        assert_eq!(None, queue.pop());
+        assert_eq!(Err(true), queue.try_pop());
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use routing::{Authority, Data, DataRequest, Event, InterfaceError, MessageId, Node,
+              RequestMessage, RoutingError};
+use std::sync::{Arc, Mutex, mpsc};
+use xor_name::XorName;
+
+/// A handle to Routing which can be cloned into each of the vault's shard threads.  Routing's
+/// `Node` can't be shared between threads directly, so every call briefly locks it.
+#[derive(Clone)]
+pub struct SharedRoutingNode {
+    node: Arc<Mutex<Option<Node>>>,
+}
+
+impl SharedRoutingNode {
+    pub fn new(event_sender: mpsc::Sender<Event>,
+               use_data_cache: bool)
+               -> Result<SharedRoutingNode, RoutingError> {
+        let node = try!(Node::new(event_sender, use_data_cache));
+        Ok(SharedRoutingNode { node: Arc::new(Mutex::new(Some(node))) })
+    }
+
+    /// Drops the underlying node, which closes its event channel.  Calls made afterwards through
+    /// any of the handles fail with `InterfaceError::NotConnected`.
+    pub fn stop(&self) {
+        let _ = self.node.lock().map(|mut node| node.take());
+    }
+
+    pub fn send_get_request(&self,
+                            src: Authority,
+                            dst: Authority,
+                            data_request: DataRequest,
+                            id: MessageId)
+                            -> Result<(), InterfaceError> {
+        self.with_node(|node| node.send_get_request(src, dst, data_request, id))
+    }
+
+    pub fn send_put_request(&self,
+                            src: Authority,
+                            dst: Authority,
+                            data: Data,
+                            id: MessageId)
+                            -> Result<(), InterfaceError> {
+        self.with_node(|node| node.send_put_request(src, dst, data, id))
+    }
+
+    pub fn send_post_request(&self,
+                             src: Authority,
+                             dst: Authority,
+                             data: Data,
+                             id: MessageId)
+                             -> Result<(), InterfaceError> {
+        self.with_node(|node| node.send_post_request(src, dst, data, id))
+    }
+
+    pub fn send_get_success(&self,
+                            src: Authority,
+                            dst: Authority,
+                            data: Data,
+                            id: MessageId)
+                            -> Result<(), InterfaceError> {
+        self.with_node(|node| node.send_get_success(src, dst, data, id))
+    }
+
+    pub fn send_get_failure(&self,
+                            src: Authority,
+                            dst: Authority,
+                            request: RequestMessage,
+                            external_error_indicator: Vec<u8>,
+                            id: MessageId)
+                            -> Result<(), InterfaceError> {
+        self.with_node(|node| {
+            node.send_get_failure(src, dst, request, external_error_indicator, id)
+        })
+    }
+
+    pub fn send_put_success(&self,
+                            src: Authority,
+                            dst: Authority,
+                            name: XorName,
+                            id: MessageId)
+                            -> Result<(), InterfaceError> {
+        self.with_node(|node| node.send_put_success(src, dst, name, id))
+    }
+
+    pub fn send_put_failure(&self,
+                            src: Authority,
+                            dst: Authority,
+                            request: RequestMessage,
+                            external_error_indicator: Vec<u8>,
+                            id: MessageId)
+                            -> Result<(), InterfaceError> {
+        self.with_node(|node| {
+            node.send_put_failure(src, dst, request, external_error_indicator, id)
+        })
+    }
+
+    pub fn send_post_success(&self,
+                             src: Authority,
+                             dst: Authority,
+                             name: XorName,
+                             id: MessageId)
+                             -> Result<(), InterfaceError> {
+        self.with_node(|node| node.send_post_success(src, dst, name, id))
+    }
+
+    pub fn send_post_failure(&self,
+                             src: Authority,
+                             dst: Authority,
+                             request: RequestMessage,
+                             external_error_indicator: Vec<u8>,
+                             id: MessageId)
+                             -> Result<(), InterfaceError> {
+        self.with_node(|node| {
+            node.send_post_failure(src, dst, request, external_error_indicator, id)
+        })
+    }
+
+    pub fn send_delete_success(&self,
+                               src: Authority,
+                               dst: Authority,
+                               name: XorName,
+                               id: MessageId)
+                               -> Result<(), InterfaceError> {
+        self.with_node(|node| node.send_delete_success(src, dst, name, id))
+    }
+
+    pub fn send_delete_failure(&self,
+                               src: Authority,
+                               dst: Authority,
+                               request: RequestMessage,
+                               external_error_indicator: Vec<u8>,
+                               id: MessageId)
+                               -> Result<(), InterfaceError> {
+        self.with_node(|node| {
+            node.send_delete_failure(src, dst, request, external_error_indicator, id)
+        })
+    }
+
+    pub fn send_refresh_request(&self,
+                                src: Authority,
+                                dst: Authority,
+                                content: Vec<u8>,
+                                message_id: MessageId)
+                                -> Result<(), InterfaceError> {
+        self.with_node(|node| node.send_refresh_request(src, dst, content, message_id))
+    }
+
+    pub fn close_group(&self, name: XorName) -> Result<Option<Vec<XorName>>, InterfaceError> {
+        self.with_node(|node| node.close_group(name))
+    }
+
+    pub fn name(&self) -> Result<XorName, InterfaceError> {
+        self.with_node(|node| node.name())
+    }
+
+    fn with_node<F, T>(&self, f: F) -> Result<T, InterfaceError>
+        where F: FnOnce(&Node) -> Result<T, InterfaceError>
+    {
+        match *unwrap_result!(self.node.lock()) {
+            Some(ref node) => f(node),
+            None => Err(InterfaceError::NotConnected),
+        }
+    }
+}
@@ -15,50 +15,122 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use std::collections::{BTreeSet, HashMap};
 use std::hash::Hash;
-use std::collections::HashMap;
+use std::usize;
+use clock::Clock;
 use time::{Duration, SteadyTime};
 
+/// What to do when a new key is inserted into a full `TimedBuffer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Make room by removing the entry which is closest to expiring.
+    EvictOldest,
+    /// Leave the buffer unchanged and hand the new entry back.
+    RejectNew,
+}
+
 /// TimedBuffer
 pub struct TimedBuffer<Key, Value> {
     map: HashMap<Key, (Value, SteadyTime)>,
+    // Keys ordered by their timestamps, so that finding the expired ones doesn't involve visiting
+    // every entry.
+    expiry_index: BTreeSet<(SteadyTime, Key)>,
     time_to_live: Duration,
+    capacity: usize,
+    eviction_policy: EvictionPolicy,
+    clock: Clock,
 }
 
 impl<Key: Hash + PartialOrd + Ord + Clone, Value: Clone> TimedBuffer<Key, Value> {
     /// Constructor.
     pub fn new(time_to_live: Duration) -> TimedBuffer<Key, Value> {
+        Self::with_clock(time_to_live, Clock::system())
+    }
+
+    /// Constructor taking the clock used to timestamp and expire entries.
+    pub fn with_clock(time_to_live: Duration, clock: Clock) -> TimedBuffer<Key, Value> {
+        Self::with_capacity(time_to_live, usize::MAX, EvictionPolicy::RejectNew, clock)
+    }
+
+    /// Constructor for a buffer holding at most `capacity` entries.
+    pub fn with_capacity(time_to_live: Duration,
+                         capacity: usize,
+                         eviction_policy: EvictionPolicy,
+                         clock: Clock)
+                         -> TimedBuffer<Key, Value> {
         TimedBuffer {
             map: HashMap::new(),
+            expiry_index: BTreeSet::new(),
             time_to_live: time_to_live,
+            capacity: capacity,
+            eviction_policy: eviction_policy,
+            clock: clock,
         }
     }
 
-    /// Inserts a key-value pair into the buffer with current time.
-    pub fn insert(&mut self, key: Key, value: Value) -> Option<Value> {
-        self.map.insert(key, (value, SteadyTime::now())).map_or(None, |(value, _)| Some(value))
+    /// Inserts a key-value pair into the buffer with current time.  Returns the entry displaced by
+    /// this, if any: the previous value for `key`, or if the buffer is full, the evicted oldest
+    /// entry or the rejected new one, depending on the eviction policy.
+    pub fn insert(&mut self, key: Key, value: Value) -> Option<(Key, Value)> {
+        let now = self.clock.now();
+        let displaced = if let Some(old_value) = self.remove(&key) {
+            Some((key.clone(), old_value))
+        } else if self.map.len() < self.capacity {
+            None
+        } else {
+            match self.eviction_policy {
+                EvictionPolicy::EvictOldest => {
+                    match self.remove_oldest() {
+                        Some(oldest) => Some(oldest),
+                        None => return Some((key, value)),
+                    }
+                }
+                EvictionPolicy::RejectNew => return Some((key, value)),
+            }
+        };
+        let _ = self.expiry_index.insert((now, key.clone()));
+        let _ = self.map.insert(key, (value, now));
+        displaced
+    }
+
+    /// Gets a value without updating its timestamp.
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        self.map.get(key).map(|&(ref value, _)| value)
     }
 
     /// Get a value meanwhile update it's timestamp
     pub fn get_mut(&mut self, key: &Key) -> Option<&mut Value> {
-        self.map.get_mut(key).map(|&mut (ref mut value, ref mut time_stamp)| {
-            *time_stamp = SteadyTime::now();
-            value
-        })
+        let now = self.clock.now();
+        match self.map.get_mut(key) {
+            Some(&mut (ref mut value, ref mut time_stamp)) => {
+                let _ = self.expiry_index.remove(&(*time_stamp, key.clone()));
+                let _ = self.expiry_index.insert((now, key.clone()));
+                *time_stamp = now;
+                Some(value)
+            }
+            None => None,
+        }
     }
 
     /// Removes a value from the buffer.
     pub fn remove(&mut self, key: &Key) -> Option<Value> {
-        self.map.remove(key).map_or(None, |(value, _)| Some(value))
+        match self.map.remove(key) {
+            Some((value, time_stamp)) => {
+                let _ = self.expiry_index.remove(&(time_stamp, key.clone()));
+                Some(value)
+            }
+            None => None,
+        }
     }
 
     /// Get the keys, if any, that have expired.
     pub fn get_expired(&mut self) -> Vec<Key> {
-        let now = SteadyTime::now();
-        self.map
+        let expiry_time = self.clock.now() - self.time_to_live;
+        self.expiry_index
             .iter()
-            .filter(|&(_, &(_, timestamp))| timestamp + self.time_to_live < now)
-            .map(|(key, &(_, _))| key.clone())
+            .take_while(|&&(time_stamp, _)| time_stamp < expiry_time)
+            .map(|&(_, ref key)| key.clone())
             .collect()
     }
 
@@ -72,14 +144,22 @@ impl<Key: Hash + PartialOrd + Ord + Clone, Value: Clone> TimedBuffer<Key, Value>
     pub fn len(&self) -> usize {
         self.map.len()
     }
+
+    fn remove_oldest(&mut self) -> Option<(Key, Value)> {
+        let oldest = match self.expiry_index.iter().next() {
+            Some(&(_, ref key)) => key.clone(),
+            None => return None,
+        };
+        self.remove(&oldest).map(|value| (oldest, value))
+    }
 }
 
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use clock::Clock;
     use time::Duration;
-    use std::thread;
 
     #[test]
     fn construct_insert() {
@@ -96,7 +176,8 @@ mod test {
     #[test]
     fn get_expired() {
         let time_to_live = Duration::milliseconds(100);
-        let mut timed_buffer = TimedBuffer::<usize, usize>::new(time_to_live);
+        let clock = Clock::manual();
+        let mut timed_buffer = TimedBuffer::<usize, usize>::with_clock(time_to_live, clock.clone());
         let insertions = 10;
 
         for i in 0..insertions {
@@ -105,7 +186,9 @@ mod test {
             assert!(timed_buffer.contains_key(&i));
         }
 
-        thread::sleep(::std::time::Duration::from_millis(100));
+        clock.advance(time_to_live);
+        assert!(timed_buffer.get_expired().is_empty());
+        clock.advance(Duration::milliseconds(1));
 
         let mut expired = timed_buffer.get_expired();
 
@@ -120,10 +203,11 @@ mod test {
     #[test]
     fn get_mut() {
         let time_to_live = Duration::milliseconds(100);
-        let mut timed_buffer = TimedBuffer::<usize, usize>::new(time_to_live);
+        let clock = Clock::manual();
+        let mut timed_buffer = TimedBuffer::<usize, usize>::with_clock(time_to_live, clock.clone());
         let key = 1;
         let _ = timed_buffer.insert(key, 1);
-        thread::sleep(::std::time::Duration::from_millis(100));
+        clock.advance(Duration::milliseconds(101));
         if let Some(mut value) = timed_buffer.get_mut(&key) {
             *value = 2;
         } else {
@@ -136,4 +220,157 @@ mod test {
             panic!("unexpected result!");
         }
     }
+
+    #[test]
+    fn get() {
+        let time_to_live = Duration::milliseconds(100);
+        let clock = Clock::manual();
+        let mut timed_buffer = TimedBuffer::<usize, usize>::with_clock(time_to_live, clock.clone());
+        let key = 1;
+        let _ = timed_buffer.insert(key, 1);
+        clock.advance(Duration::milliseconds(101));
+        assert_eq!(Some(&1), timed_buffer.get(&key));
+        assert!(timed_buffer.get(&2).is_none());
+        assert_eq!(vec![key], timed_buffer.get_expired());
+    }
+
+    #[test]
+    fn get_expired_in_order() {
+        let time_to_live = Duration::milliseconds(100);
+        let clock = Clock::manual();
+        let mut timed_buffer = TimedBuffer::<usize, usize>::with_clock(time_to_live, clock.clone());
+
+        for i in 0..10 {
+            let _ = timed_buffer.insert(i, i);
+            clock.advance(Duration::milliseconds(10));
+        }
+        // Refreshing an entry should move it to the back of the queue.
+        let _ = timed_buffer.get_mut(&0);
+
+        clock.advance(Duration::milliseconds(35));
+        assert_eq!(timed_buffer.get_expired(), vec![1, 2, 3]);
+        let _ = timed_buffer.remove(&2);
+        assert_eq!(timed_buffer.get_expired(), vec![1, 3]);
+        clock.advance(Duration::milliseconds(100));
+        assert_eq!(timed_buffer.get_expired(), (1..10).chain(Some(0)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn evict_oldest() {
+        let clock = Clock::manual();
+        let mut timed_buffer =
+            TimedBuffer::<usize, usize>::with_capacity(Duration::minutes(1),
+                                                       3,
+                                                       EvictionPolicy::EvictOldest,
+                                                       clock.clone());
+        for i in 0..3 {
+            assert!(timed_buffer.insert(i, i).is_none());
+            clock.advance(Duration::milliseconds(1));
+        }
+        let _ = timed_buffer.get_mut(&0);
+
+        // Replacing an existing entry doesn't need to evict anything.
+        assert_eq!(timed_buffer.insert(2, 20), Some((2, 2)));
+        assert_eq!(timed_buffer.len(), 3);
+
+        assert_eq!(timed_buffer.insert(3, 3), Some((1, 1)));
+        assert_eq!(timed_buffer.len(), 3);
+        assert!(!timed_buffer.contains_key(&1));
+        assert!(timed_buffer.contains_key(&3));
+    }
+
+    #[test]
+    fn reject_new() {
+        let mut timed_buffer = TimedBuffer::<usize, usize>::with_capacity(Duration::minutes(1),
+                                                                          3,
+                                                                          EvictionPolicy::RejectNew,
+                                                                          Clock::manual());
+        for i in 0..3 {
+            assert!(timed_buffer.insert(i, i).is_none());
+        }
+
+        assert_eq!(timed_buffer.insert(3, 3), Some((3, 3)));
+        assert_eq!(timed_buffer.len(), 3);
+        assert!(!timed_buffer.contains_key(&3));
+
+        let _ = timed_buffer.remove(&0);
+        assert!(timed_buffer.insert(3, 3).is_none());
+        assert!(timed_buffer.contains_key(&3));
+    }
+}
+
+#[cfg(all(test, feature = "bench"))]
+mod bench {
+    use super::*;
+    use clock::Clock;
+    use std::collections::HashMap;
+    use test::Bencher;
+    use time::{Duration, SteadyTime};
+
+    const ENTRIES: usize = 10_000;
+
+    // The original implementation of `get_expired`, which visits every entry.
+    fn get_expired_by_scan(map: &HashMap<usize, (usize, SteadyTime)>,
+                           time_to_live: Duration,
+                           now: SteadyTime)
+                           -> Vec<usize> {
+        map.iter()
+           .filter(|&(_, &(_, timestamp))| timestamp + time_to_live < now)
+           .map(|(key, &(_, _))| *key)
+           .collect()
+    }
+
+    // A full buffer with a handful of expired entries, as seen when checking timeouts under load.
+    fn populate(clock: &Clock) -> TimedBuffer<usize, usize> {
+        let mut timed_buffer = TimedBuffer::with_clock(Duration::minutes(5), clock.clone());
+        for i in 0..ENTRIES {
+            let _ = timed_buffer.insert(i, i);
+            clock.advance(Duration::milliseconds(1));
+        }
+        clock.advance(Duration::minutes(5) - Duration::milliseconds(ENTRIES as i64 - 10));
+        timed_buffer
+    }
+
+    #[bench]
+    fn get_expired_indexed(b: &mut Bencher) {
+        let clock = Clock::manual();
+        let mut timed_buffer = populate(&clock);
+        b.iter(|| timed_buffer.get_expired());
+    }
+
+    #[bench]
+    fn get_expired_scanned(b: &mut Bencher) {
+        let clock = Clock::manual();
+        let mut map = HashMap::new();
+        for i in 0..ENTRIES {
+            let _ = map.insert(i, (i, clock.now()));
+            clock.advance(Duration::milliseconds(1));
+        }
+        clock.advance(Duration::minutes(5) - Duration::milliseconds(ENTRIES as i64 - 10));
+        let now = clock.now();
+        b.iter(|| get_expired_by_scan(&map, Duration::minutes(5), now));
+    }
+
+    #[bench]
+    fn insert_and_remove_indexed(b: &mut Bencher) {
+        let clock = Clock::manual();
+        let mut timed_buffer = populate(&clock);
+        b.iter(|| {
+            let _ = timed_buffer.insert(ENTRIES, ENTRIES);
+            timed_buffer.remove(&ENTRIES)
+        });
+    }
+
+    #[bench]
+    fn insert_and_remove_scanned(b: &mut Bencher) {
+        let clock = Clock::manual();
+        let mut map = HashMap::new();
+        for i in 0..ENTRIES {
+            let _ = map.insert(i, (i, clock.now()));
+        }
+        b.iter(|| {
+            let _ = map.insert(ENTRIES, (ENTRIES, clock.now()));
+            map.remove(&ENTRIES)
+        });
+    }
 }
@@ -15,8 +15,10 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use personas::{immutable_data_manager, maid_manager, pmid_manager, mpid_manager};
-use routing::{PlainData, StructuredData};
+use error::InternalError;
+use maidsafe_utilities::serialisation;
+use personas::{immutable_data_manager, maid_manager, pmid_manager, mpid_manager, rate_limit};
+use routing::{ImmutableData, ImmutableDataType, PlainData, StructuredData};
 use xor_name::XorName;
 
 #[derive(Debug, Clone, Eq, PartialEq, RustcEncodable, RustcDecodable)]
@@ -37,9 +39,95 @@ impl Refresh {
 #[derive(Debug, Clone, Eq, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum RefreshValue {
     MaidManagerAccount(maid_manager::Account),
+    // maid_manager: the whole puts left in the client's allowance
+    MaidManagerPutAllowance(u64),
     ImmutableDataManagerAccount(immutable_data_manager::Account),
     StructuredDataManager(StructuredData),
     PmidManagerAccount(pmid_manager::Account),
     // mpid_manager: account, outbox messages, inbox headers
     MpidManagerAccount(mpid_manager::Account, Vec<PlainData>, Vec<PlainData>),
+    // immutable_data_manager: the reputation last shared for a holder of the chunk
+    PmidNodeReputation(XorName, u8),
+    // data managers: the allowances of the clients which have used some of theirs on the data
+    ClientAllowances(Vec<rate_limit::ClientAllowances>),
+}
+
+// Messages between vault personas for which routing has no message type of its own.  They're sent
+// serialised as the content of `PlainData` in Post requests, with the data named after the chunk
+// they concern.
+#[derive(Debug, Clone, Eq, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum VaultMessage {
+    // From a PmidManager to its PmidNode, asking it to prove it still holds the chunk.
+    StorageChallenge {
+        nonce: Vec<u8>,
+    },
+    // The PmidNode's answer to a `StorageChallenge`.  See `utils::storage_proof`.
+    StorageProof {
+        nonce: Vec<u8>,
+        proof: Vec<u8>,
+    },
+    // From a PmidManager to the chunk's ImmutableDataManagers, reporting that the PmidNode failed
+    // to prove it holds the chunk.
+    FailedStorageChallenge,
+    // From a PmidManager to the rest of the PmidNode's PmidManagers, proposing the salt their
+    // challenges to it are derived with.  See `pmid_manager::Account::challenge_salt`.
+    ChallengeSalt(Vec<u8>),
+    // From a PmidManager to the ImmutableDataManagers it deals with, sharing the PmidNode's
+    // reputation.  See `pmid_manager::MAX_REPUTATION`.
+    PmidNodeReputation(u8),
+    // From a PmidNode to its PmidManagers, and from them to any ImmutableDataManagers sending a
+    // `CapacityQuery`: the PmidNode's used and free space in bytes.
+    CapacityReport {
+        used: u64,
+        free: u64,
+    },
+    // From ImmutableDataManagers to a PmidNode's PmidManagers, asking for its latest
+    // `CapacityReport`.
+    CapacityQuery,
+    // From a chunk's ImmutableDataManagers to a PmidNode which handed it off, once it's stored
+    // elsewhere and the PmidNode can delete its copy.
+    HandOffComplete,
+    // From a PmidNode which has (re)joined the network to the ImmutableDataManagers of a chunk it
+    // holds.
+    ChunkHeld,
+    // From a chunk's ImmutableDataManagers to a PmidNode which announced it, when they already
+    // have enough holders.
+    ChunkNotNeeded,
+    // From a chunk's ImmutableDataManagers to a holder's PmidManagers, when the holder no longer
+    // has the chunk, or its fragment of it, of `size` bytes.
+    ChunkLost {
+        size: u64,
+    },
+}
+
+// The content of one of the erasure-coded fragments a "Normal" chunk is split into.  Each fragment
+// is stored as `ImmutableData` named after this content, so it carries the chunk's name to let its
+// holder deal with the chunk's ImmutableDataManagers, who have the account for it.
+#[derive(Debug, Clone, Eq, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Fragment {
+    pub chunk_name: XorName,
+    pub index: usize,
+    pub value: Vec<u8>,
+}
+
+impl Fragment {
+    // The fragment as it's sent to its holder.
+    pub fn to_data(&self) -> Result<ImmutableData, InternalError> {
+        let content = try!(serialisation::serialise(self));
+        Ok(ImmutableData::new(ImmutableDataType::Normal, content))
+    }
+
+    // Reads back a fragment from the data sent to its holder.
+    pub fn from_data(data: &ImmutableData) -> Result<Fragment, InternalError> {
+        match *data.get_type_tag() {
+            ImmutableDataType::Normal => Ok(try!(serialisation::deserialise(data.value()))),
+            _ => Err(InternalError::InvalidFragments),
+        }
+    }
+
+    // The name of the ImmutableDataManagers responsible for `data`: those of the chunk it's a
+    // fragment of if it is one, otherwise its own.
+    pub fn managers_name(data: &ImmutableData) -> XorName {
+        Self::from_data(data).map(|fragment| fragment.chunk_name).unwrap_or_else(|_| data.name())
+    }
 }
@@ -15,18 +15,149 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use routing::Authority;
+use chunk_store::ChunkStore;
+use error::{InternalError, Refusal};
+use maidsafe_utilities::serialisation;
+use routing::{Authority, Data, MessageId, RequestContent, RequestMessage};
+use safe_network_common::client_errors::{GetError, MutationError};
 use sodiumoxide::crypto::hash::sha512;
+use vault::{CHUNK_STORE_PREFIX, RoutingNode};
 use xor_name::XorName;
 
-pub fn client_name(authority: &Authority) -> XorName {
+pub fn client_name(authority: &Authority) -> Result<XorName, InternalError> {
     if let Authority::Client { ref client_key, .. } = *authority {
-        XorName(sha512::hash(&client_key.0[..]).0)
+        Ok(XorName(sha512::hash(&client_key.0[..]).0))
     } else {
-        unreachable!("Logic error")
+        Err(InternalError::InvalidMessage)
     }
 }
 
+// The size in bytes of the data carried by a Put request, or zero for any other request.
+pub fn put_size(request: &RequestMessage) -> u64 {
+    let size = match request.content {
+        RequestContent::Put(Data::Immutable(ref data), _) => data.value().len(),
+        RequestContent::Put(Data::Structured(ref data), _) => data.get_data().len(),
+        RequestContent::Put(Data::Plain(ref data), _) => data.value().len(),
+        _ => 0,
+    };
+    size as u64
+}
+
+// Answers a client's request with `refusal`, without it having been handled.
+pub fn refuse(routing_node: &RoutingNode,
+              request: &RequestMessage,
+              refusal: Refusal)
+              -> Result<(), InternalError> {
+    let external_error_indicator = try!(serialise_refusal(request, refusal));
+    send_failure(routing_node, request, external_error_indicator)
+}
+
+// Serialises `refusal` as the kind of error a client expects in response to `request`.
+fn serialise_refusal(request: &RequestMessage, refusal: Refusal) -> Result<Vec<u8>, InternalError> {
+    let serialised = if let RequestContent::Get(..) = request.content {
+        try!(serialisation::serialise(&GetError::from(refusal)))
+    } else {
+        try!(serialisation::serialise(&MutationError::from(refusal)))
+    };
+    Ok(serialised)
+}
+
+// The serialised error a requester is told of when handling its request fails with `error`, or
+// `None` if such a failure isn't reported to it.
+pub fn external_error(request: &RequestMessage, error: &InternalError) -> Option<Vec<u8>> {
+    match *error {
+        InternalError::ClientGet(ref error) => serialisation::serialise(error).ok(),
+        InternalError::ClientMutation(ref error) => serialisation::serialise(error).ok(),
+        InternalError::Refused(refusal) => serialise_refusal(request, refusal).ok(),
+        _ => None,
+    }
+}
+
+// Sends the failure response to `request`, carrying `external_error_indicator`.
+pub fn send_failure(routing_node: &RoutingNode,
+                    request: &RequestMessage,
+                    external_error_indicator: Vec<u8>)
+                    -> Result<(), InternalError> {
+    let src = request.dst.clone();
+    let dst = request.src.clone();
+    let indicator = external_error_indicator;
+    match request.content {
+        RequestContent::Get(_, id) => {
+            try!(routing_node.send_get_failure(src, dst, request.clone(), indicator, id));
+        }
+        RequestContent::Put(_, id) => {
+            try!(routing_node.send_put_failure(src, dst, request.clone(), indicator, id));
+        }
+        RequestContent::Post(_, id) => {
+            try!(routing_node.send_post_failure(src, dst, request.clone(), indicator, id));
+        }
+        RequestContent::Delete(_, id) => {
+            try!(routing_node.send_delete_failure(src, dst, request.clone(), indicator, id));
+        }
+        _ => return Err(InternalError::InvalidMessage),
+    }
+    Ok(())
+}
+
+// Replaces `chunk_store` with one of the given capacity, moving the held chunks across one at a
+// time so that no more than one extra chunk is on disk at once.  If they don't fit in the new
+// capacity, an error is returned and the chunks already moved are moved back.
+pub fn resize_chunk_store(chunk_store: &mut ChunkStore,
+                          capacity: u64)
+                          -> Result<(), InternalError> {
+    let mut resized = try!(ChunkStore::new(CHUNK_STORE_PREFIX, capacity));
+    let mut moved = Vec::new();
+    for name in chunk_store.names() {
+        if let Err(error) = move_chunk(chunk_store, &mut resized, &name) {
+            for name in moved {
+                if move_chunk(&mut resized, chunk_store, &name).is_err() {
+                    error!("Failed to restore {} after failing to resize chunk store.", name);
+                }
+            }
+            return Err(error);
+        }
+        moved.push(name);
+    }
+    *chunk_store = resized;
+    Ok(())
+}
+
+// Moves a chunk between stores, only deleting it from the first once it's in the second.
+fn move_chunk(from: &mut ChunkStore,
+              to: &mut ChunkStore,
+              name: &XorName)
+              -> Result<(), InternalError> {
+    let chunk = try!(from.get(name));
+    try!(to.put(name, &chunk));
+    try!(from.delete(name));
+    Ok(())
+}
+
+// The answer to a storage challenge: a hash over the challenge's nonce followed by the chunk as
+// held in a PmidNode's chunk store.  PmidManagers derive nonces with a salt the PmidNode never
+// sees, so it can't be produced without holding the chunk.
+pub fn storage_proof(nonce: &[u8], serialised_data: &[u8]) -> Vec<u8> {
+    let mut input = Vec::with_capacity(nonce.len() + serialised_data.len());
+    input.extend_from_slice(nonce);
+    input.extend_from_slice(serialised_data);
+    sha512::hash(&input).0.to_vec()
+}
+
+// A message ID which every member of a group derives alike from the same `parts`, so that the
+// copies of a message they each send accumulate into one.
+pub fn message_id(parts: &[&[u8]]) -> MessageId {
+    let mut input = Vec::new();
+    for part in parts {
+        input.extend_from_slice(part);
+    }
+    MessageId::from_added_node(XorName(sha512::hash(&input).0))
+}
+
+// The little-endian bytes of `value`, for deriving nonces and message IDs from counters.
+pub fn u64_bytes(value: u64) -> Vec<u8> {
+    (0..8).map(|byte| (value >> (8 * byte)) as u8).collect()
+}
+
 #[cfg(all(test, not(feature = "use-mock-crust")))]
 pub fn generate_random_vec_u8(size: usize) -> Vec<u8> {
     use rand::{self, Rng};
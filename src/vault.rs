@@ -14,38 +14,82 @@
 //
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
-
+#[cfg(not(feature = "use-mock-crust"))]
+use std::cmp;
 use std::collections::HashSet;
 #[cfg(not(feature = "use-mock-crust"))]
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc;
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock, mpsc};
 #[cfg(feature = "use-mock-crust")]
 use std::sync::mpsc::Receiver;
+#[cfg(not(feature = "use-mock-crust"))]
+use std::sync::mpsc::Sender;
+#[cfg(not(feature = "use-mock-crust"))]
+use std::thread::{self, JoinHandle};
+#[cfg(not(feature = "use-mock-crust"))]
+use std::time::Duration as StdDuration;
 
 use config_handler::{self, Config};
 #[cfg(not(feature = "use-mock-crust"))]
 use ctrlc::CtrlC;
 use maidsafe_utilities::serialisation;
-use routing::{Authority, Data, DataRequest, Event, RequestContent, RequestMessage,
-              ResponseContent, ResponseMessage, RoutingMessage};
+#[cfg(not(feature = "use-mock-crust"))]
+use maidsafe_utilities::thread::RaiiThreadJoiner;
+use routing::{Authority, Event, MessageId, RequestContent, RequestMessage, ResponseMessage};
+use time::{Duration, SteadyTime};
 use xor_name::XorName;
 
+use clock::Clock;
+use erasure_coding::FragmentCounts;
 use error::InternalError;
+#[cfg(not(feature = "use-mock-crust"))]
+use error::Refusal;
+use personas::{self, Context, Registry, Success};
 use personas::immutable_data_manager::ImmutableDataManager;
-use personas::maid_manager::MaidManager;
+use personas::maid_manager::{MaidManager, QuotaUnit};
 use personas::mpid_manager::MpidManager;
 use personas::pmid_manager::PmidManager;
 use personas::pmid_node::PmidNode;
+use personas::rate_limit::{self, ClientRateLimitRefresher, ClientRateLimits, RateLimit};
 use personas::structured_data_manager::StructuredDataManager;
-use types::{Refresh, RefreshValue};
+#[cfg(not(feature = "use-mock-crust"))]
+use priority_queue::{Priority, PriorityQueue, QueueLimits};
+use timed_buffer::{EvictionPolicy, TimedBuffer};
+use types::Refresh;
+use utils;
 
 pub const CHUNK_STORE_PREFIX: &'static str = "safe-vault";
 const DEFAULT_MAX_CAPACITY: u64 = 1_073_741_824;
+const DEFAULT_ONGOING_PUT_MEMORY_BUDGET: u64 = 67_108_864;
+const DEFAULT_ONGOING_PUT_DISK_BUDGET: u64 = 268_435_456;
+const DEFAULT_GET_CACHE_MEMORY_BUDGET: u64 = 33_554_432;
+const DEFAULT_GET_HEDGE_DELAY_MS: u64 = 2000;
+const DEFAULT_STORAGE_CHALLENGE_INTERVAL_SECS: u64 = 600;
+const DEFAULT_ERASURE_PARITY_FRAGMENTS: usize = 2;
+#[cfg(not(feature = "use-mock-crust"))]
+const DEFAULT_SHARD_COUNT: usize = 4;
+const DEFAULT_CHUNK_IO_THREAD_COUNT: usize = 4;
+#[cfg(not(feature = "use-mock-crust"))]
+const DEFAULT_CHURN_QUEUE_LIMIT: usize = 10_000;
+#[cfg(not(feature = "use-mock-crust"))]
+const DEFAULT_NODE_QUEUE_LIMIT: usize = 10_000;
+#[cfg(not(feature = "use-mock-crust"))]
+const DEFAULT_CLIENT_QUEUE_LIMIT: usize = 1_000;
 const PMID_NODE_ALLOWANCE: f64 = 0.6;
 const STUCTURED_DATA_MANAGER_ALLOWANCE: f64 = 0.3;
 const MPID_MANAGER_ALLOWANCE: f64 = 0.1;
+#[cfg(not(feature = "use-mock-crust"))]
+const CONFIG_RELOAD_INTERVAL_SECS: i64 = 60;
+// How often timed-out operations are expired, independently of incoming events.
+const TICK_INTERVAL_MS: u64 = 1000;
+// How long a mutating request is remembered, so that retransmissions of it are recognised.
+const SEEN_REQUEST_DURATION_SECS: i64 = 300;
+const MAX_SEEN_REQUESTS: usize = 100_000;
+
+#[cfg(all(not(test), not(feature = "use-mock-crust")))]
+pub use shared_routing_node::SharedRoutingNode as RoutingNode;
 
-#[cfg(any(not(test), feature = "use-mock-crust"))]
+#[cfg(feature = "use-mock-crust")]
 pub use routing::Node as RoutingNode;
 
 #[cfg(all(test, not(feature = "use-mock-crust")))]
@@ -53,90 +97,182 @@ pub use mock_routing::MockRoutingNode as RoutingNode;
 
 /// Main struct to hold all personas and Routing instance
 pub struct Vault {
-    immutable_data_manager: ImmutableDataManager,
-    maid_manager: MaidManager,
-    mpid_manager: MpidManager,
-    pmid_manager: PmidManager,
-    pmid_node: PmidNode,
-    structured_data_manager: StructuredDataManager,
-    full_pmid_nodes: HashSet<XorName>,
+    // The personas, split into shards which each run on their own thread: the managers split by
+    // name, followed by the vault's PmidNode on its own, with its chunk IO on a pool of further
+    // threads.  Under mock-crust there's a single shard running every persona, run inline by
+    // `poll`.
+    shards: Vec<Shard>,
+    max_capacity: u64,
 
+    #[cfg(not(feature = "use-mock-crust"))]
+    last_config_read: SteadyTime,
+    #[cfg(not(feature = "use-mock-crust"))]
+    queue_limits: QueueLimits,
+    #[cfg(feature = "use-mock-crust")]
+    last_tick: SteadyTime,
     #[cfg(feature = "use-mock-crust")]
     routing_node: Option<RoutingNode>,
     #[cfg(feature = "use-mock-crust")]
     routing_receiver: Receiver<Event>,
 }
 
+// The inputs to the vault's event loop.
+#[cfg(not(feature = "use-mock-crust"))]
+enum Action {
+    Routing(Event),
+    Tick,
+    Terminate,
+}
+
+// The inputs to a shard's thread.
+#[cfg(not(feature = "use-mock-crust"))]
+enum ShardAction {
+    Event(Event),
+    Tick,
+    // The vault's new maximum capacity.  The manager shards split it between them, while the
+    // storage shard takes all of the PmidNode's allowance.
+    SetMaxCapacity(u64),
+    // Acknowledged once everything sent to the shard before it, with at least the priority it was
+    // sent with, has been handled.
+    Sync(Sender<()>),
+}
+
+// Which personas a shard runs.  Managers hold state per group name, so they're split by name
+// across any number of shards.  The PmidNode's chunks, storage allowance and reports to its
+// PmidManagers belong to the vault as a whole, so it runs alone on a single shard.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ShardRole {
+    #[cfg(not(feature = "use-mock-crust"))]
+    Managers,
+    #[cfg(not(feature = "use-mock-crust"))]
+    Storage,
+    #[cfg(any(test, feature = "use-mock-crust"))]
+    All,
+}
+
+impl ShardRole {
+    fn runs_managers(self) -> bool {
+        match self {
+            #[cfg(not(feature = "use-mock-crust"))]
+            ShardRole::Storage => false,
+            _ => true,
+        }
+    }
+
+    fn runs_storage(self) -> bool {
+        match self {
+            #[cfg(not(feature = "use-mock-crust"))]
+            ShardRole::Managers => false,
+            _ => true,
+        }
+    }
+}
+
 fn init_components(optional_config: Option<Config>)
-                   -> Result<(ImmutableDataManager,
-                              MaidManager,
-                              MpidManager,
-                              PmidManager,
-                              PmidNode,
-                              StructuredDataManager),
-                             InternalError> {
+                   -> Result<(Vec<Shard>, Config), InternalError> {
     ::sodiumoxide::init();
 
     let config = match optional_config {
         Some(config) => config,
         None => try!(config_handler::read_config_file()),
     };
-    let max_capacity = config.max_capacity.unwrap_or(DEFAULT_MAX_CAPACITY) as f64;
-    let pn_capacity = (max_capacity * PMID_NODE_ALLOWANCE) as u64;
-    let sdm_capacity = (max_capacity * STUCTURED_DATA_MANAGER_ALLOWANCE) as u64;
-    let mpid_capacity = (max_capacity * MPID_MANAGER_ALLOWANCE) as u64;
-
-    Ok((ImmutableDataManager::new(),
-        MaidManager::new(),
-        try!(MpidManager::new(mpid_capacity)),
-        PmidManager::new(),
-        try!(PmidNode::new(pn_capacity)),
-        try!(StructuredDataManager::new(sdm_capacity))))
+    let roles = shard_roles(&config);
+    let manager_count = roles.iter().filter(|role| role.runs_managers()).count();
+    let shared = SharedState::new();
+    let mut shards = Vec::with_capacity(roles.len());
+    for role in roles {
+        shards.push(try!(Shard::new(&config, role, manager_count, shared.clone())));
+    }
+    Ok((shards, config))
+}
+
+// The storage shard comes last, which is where the dispatcher expects it.
+#[cfg(not(feature = "use-mock-crust"))]
+fn shard_roles(config: &Config) -> Vec<ShardRole> {
+    let manager_count = cmp::max(config.shard_count.unwrap_or(DEFAULT_SHARD_COUNT), 1);
+    let mut roles = vec![ShardRole::Managers; manager_count];
+    roles.push(ShardRole::Storage);
+    roles
+}
+
+#[cfg(feature = "use-mock-crust")]
+fn shard_roles(_config: &Config) -> Vec<ShardRole> {
+    vec![ShardRole::All]
+}
+
+fn rate_limit(per_second: Option<u64>, burst: Option<u64>, default: RateLimit) -> RateLimit {
+    RateLimit {
+        per_second: per_second.unwrap_or(default.per_second),
+        burst: burst.unwrap_or(default.burst),
+    }
+}
+
+fn put_rate_limit(config: &Config) -> RateLimit {
+    rate_limit(config.client_puts_per_second,
+               config.client_put_burst,
+               rate_limit::DEFAULT_PUT_RATE_LIMIT)
+}
+
+fn client_rate_limits(config: &Config) -> ClientRateLimits {
+    ClientRateLimits::new(rate_limit(config.client_posts_per_second,
+                                     config.client_post_burst,
+                                     rate_limit::DEFAULT_POST_RATE_LIMIT),
+                          rate_limit(config.client_deletes_per_second,
+                                     config.client_delete_burst,
+                                     rate_limit::DEFAULT_DELETE_RATE_LIMIT),
+                          rate_limit(config.client_gets_per_second,
+                                     config.client_get_burst,
+                                     rate_limit::DEFAULT_GET_RATE_LIMIT))
+}
+
+fn quota_unit(config: &Config) -> QuotaUnit {
+    if config.charge_clients_by_bytes.unwrap_or(false) {
+        QuotaUnit::Bytes
+    } else {
+        QuotaUnit::Chunks
+    }
+}
+
+#[cfg(not(feature = "use-mock-crust"))]
+fn queue_limits(config: &Config) -> QueueLimits {
+    QueueLimits {
+        churn: config.churn_queue_limit.unwrap_or(DEFAULT_CHURN_QUEUE_LIMIT),
+        node: config.node_queue_limit.unwrap_or(DEFAULT_NODE_QUEUE_LIMIT),
+        client: config.client_queue_limit.unwrap_or(DEFAULT_CLIENT_QUEUE_LIMIT),
+    }
 }
 
 impl Vault {
     /// Creates a network Vault instance.
     #[cfg(not(feature = "use-mock-crust"))]
     pub fn new() -> Result<Self, InternalError> {
-        let (immutable_data_manager,
-             maid_manager,
-             mpid_manager,
-             pmid_manager,
-             pmid_node,
-             structured_data_manager) = try!(init_components(None));
+        Self::with_config(None)
+    }
+
+    #[cfg(not(feature = "use-mock-crust"))]
+    fn with_config(config: Option<Config>) -> Result<Self, InternalError> {
+        let (shards, config) = try!(init_components(config));
 
         Ok(Vault {
-            immutable_data_manager: immutable_data_manager,
-            maid_manager: maid_manager,
-            mpid_manager: mpid_manager,
-            pmid_manager: pmid_manager,
-            pmid_node: pmid_node,
-            structured_data_manager: structured_data_manager,
-            full_pmid_nodes: HashSet::new(),
+            shards: shards,
+            max_capacity: config.max_capacity.unwrap_or(DEFAULT_MAX_CAPACITY),
+            last_config_read: SteadyTime::now(),
+            queue_limits: queue_limits(&config),
         })
     }
 
     /// Creates a Vault instance for use with the mock-crust feature enabled.
     #[cfg(feature = "use-mock-crust")]
     pub fn new(config: Option<Config>) -> Result<Self, InternalError> {
-        let (immutable_data_manager,
-             maid_manager,
-             mpid_manager,
-             pmid_manager,
-             pmid_node,
-             structured_data_manager) = try!(init_components(config));
+        let (shards, config) = try!(init_components(config));
 
         let (routing_sender, routing_receiver) = mpsc::channel();
         let routing_node = try!(RoutingNode::new(routing_sender, false));
 
         Ok(Vault {
-            immutable_data_manager: immutable_data_manager,
-            maid_manager: maid_manager,
-            mpid_manager: mpid_manager,
-            pmid_manager: pmid_manager,
-            pmid_node: pmid_node,
-            structured_data_manager: structured_data_manager,
-            full_pmid_nodes: HashSet::new(),
+            shards: shards,
+            max_capacity: config.max_capacity.unwrap_or(DEFAULT_MAX_CAPACITY),
+            last_tick: SteadyTime::now(),
             routing_node: Some(routing_node),
             routing_receiver: routing_receiver,
         })
@@ -147,30 +283,68 @@ impl Vault {
     pub fn run(&mut self) -> Result<(), InternalError> {
         let (routing_sender, routing_receiver) = mpsc::channel();
         let routing_node = try!(RoutingNode::new(routing_sender, true));
-        let routing_node0 = Arc::new(Mutex::new(Some(routing_node)));
-        let routing_node1 = routing_node0.clone();
 
         // Handle Ctrl+C to properly stop the vault instance.
         // TODO: do we really need this to terminate gracefully on Ctrl+C?
+        let ctrlc_routing_node = routing_node.clone();
         CtrlC::set_handler(move || {
-            // Drop the routing node to close the event channel which terminates
+            // Stop the routing node to close the event channel which terminates
             // the receive loop and thus this whole function.
-            let _ = routing_node0.lock().map(|mut node| node.take());
+            ctrlc_routing_node.stop();
         });
 
-        for event in routing_receiver.iter() {
-            let routing_node = unwrap_result!(routing_node1.lock());
+        let (action_sender, action_receiver) = mpsc::channel();
+        let _forwarder = Self::forward_events(routing_receiver, action_sender.clone());
+        let _ticker = Self::send_ticks(action_sender);
+        let dispatcher = Dispatcher::new(mem::replace(&mut self.shards, vec![]),
+                                         &routing_node,
+                                         self.queue_limits);
 
-            if let Some(routing_node) = routing_node.as_ref() {
-                self.process_event(routing_node, event);
-            } else {
-                break;
+        for action in action_receiver.iter() {
+            match action {
+                Action::Routing(event) => dispatcher.dispatch(event),
+                Action::Tick => {
+                    dispatcher.broadcast(|| ShardAction::Tick);
+                    self.reload_config(&dispatcher);
+                }
+                Action::Terminate => break,
             }
         }
 
+        // Dropping the receiver stops the ticker thread so that it can be joined.
+        drop(action_receiver);
+        self.shards = dispatcher.stop();
         Ok(())
     }
 
+    // Passes events from Routing on to the event loop, followed by `Terminate` once Routing has
+    // been dropped.
+    #[cfg(not(feature = "use-mock-crust"))]
+    fn forward_events(routing_receiver: mpsc::Receiver<Event>,
+                      action_sender: mpsc::Sender<Action>)
+                      -> RaiiThreadJoiner {
+        RaiiThreadJoiner::new(thread!("VaultEventForwarder", move || {
+            for event in routing_receiver.iter() {
+                if action_sender.send(Action::Routing(event)).is_err() {
+                    return;
+                }
+            }
+            let _ = action_sender.send(Action::Terminate);
+        }))
+    }
+
+    #[cfg(not(feature = "use-mock-crust"))]
+    fn send_ticks(action_sender: mpsc::Sender<Action>) -> RaiiThreadJoiner {
+        RaiiThreadJoiner::new(thread!("VaultTicker", move || {
+            loop {
+                thread::sleep(StdDuration::from_millis(TICK_INTERVAL_MS));
+                if action_sender.send(Action::Tick).is_err() {
+                    return;
+                }
+            }
+        }))
+    }
+
     /// Non-blocking call to process any events in the event queue, returning true if
     /// any received, otherwise returns false.
     #[cfg(feature = "use-mock-crust")]
@@ -179,225 +353,502 @@ impl Vault {
         let mut result = routing_node.poll();
 
         if let Ok(event) = self.routing_receiver.try_recv() {
-            self.process_event(&routing_node, event);
+            self.shards[0].process_event(&routing_node, event);
+            result = true
+        } else if self.shards[0].registry.has_pending_churn() {
+            self.shards[0].continue_churn(&routing_node);
             result = true
         }
 
+        if SteadyTime::now() - self.last_tick >= Duration::milliseconds(TICK_INTERVAL_MS as i64) {
+            self.last_tick = SteadyTime::now();
+            self.shards[0].tick(&routing_node);
+        }
+
         self.routing_node = Some(routing_node);
         result
     }
 
+    /// Changes the vault's storage allowance while it is running.  When shrinking, chunks which no
+    /// longer fit are handed off to other holders before being deleted.
+    #[cfg(feature = "use-mock-crust")]
+    pub fn set_max_capacity(&mut self, max_capacity: u64) -> Result<(), InternalError> {
+        info!("Changing vault capacity from {} to {} bytes",
+              self.max_capacity,
+              max_capacity);
+        let routing_node = self.routing_node.take().expect("routing_node should never be None");
+        let result = self.shards[0].set_max_capacity(&routing_node, max_capacity);
+        self.routing_node = Some(routing_node);
+        if result.is_ok() {
+            self.max_capacity = max_capacity;
+        }
+        result
+    }
+
     /// Get the names of all the data chunks stored in a personas' chunk store.
     #[cfg(feature = "use-mock-crust")]
     pub fn get_stored_names(&self) -> Vec<XorName> {
-        self.pmid_node
-            .get_stored_names()
-            .iter()
-            .chain(self.structured_data_manager
-                       .get_stored_names()
-                       .iter())
-            .cloned()
-            .collect()
+        self.shards.iter().flat_map(|shard| shard.registry.stored_names()).collect()
+    }
+
+    // Re-reads the config file at most once per `CONFIG_RELOAD_INTERVAL_SECS` so that the storage
+    // allowance can be changed without restarting the vault.
+    #[cfg(not(feature = "use-mock-crust"))]
+    fn reload_config(&mut self, dispatcher: &Dispatcher) {
+        let reload_interval = Duration::seconds(CONFIG_RELOAD_INTERVAL_SECS);
+        if SteadyTime::now() - self.last_config_read < reload_interval {
+            return;
+        }
+        self.last_config_read = SteadyTime::now();
+
+        let max_capacity = match config_handler::read_config_file() {
+            Ok(config) => config.max_capacity.unwrap_or(DEFAULT_MAX_CAPACITY),
+            Err(error) => {
+                warn!("Failed to re-read config file: {:?}", error);
+                return;
+            }
+        };
+        if max_capacity != self.max_capacity {
+            info!("Changing vault capacity from {} to {} bytes",
+                  self.max_capacity,
+                  max_capacity);
+            dispatcher.broadcast(|| ShardAction::SetMaxCapacity(max_capacity));
+            self.max_capacity = max_capacity;
+        }
+    }
+}
+
+// Hands events from the event loop to the shard threads.  Messages for a manager group go to the
+// shard owning the group's name, so those for any one name are still handled in order; requests
+// for our ManagedNode all go to the storage shard, the last one, whose PmidNode passes the chunk
+// IO on to its pool of workers.  Churn and refreshes act as barriers: churn is sent to every shard
+// ahead of whatever it has queued, and no later event is handed out until every shard has caught
+// up with the churn or refresh.
+//
+// Each shard handles churn and refreshes first, then messages from other nodes, then client
+// requests.  Client requests which don't fit in their shard's queue are refused straight away.
+#[cfg(not(feature = "use-mock-crust"))]
+struct Dispatcher {
+    queues: Vec<PriorityQueue<ShardAction>>,
+    threads: Vec<JoinHandle<Shard>>,
+    routing_node: RoutingNode,
+}
+
+#[cfg(not(feature = "use-mock-crust"))]
+impl Dispatcher {
+    fn new(shards: Vec<Shard>, routing_node: &RoutingNode, limits: QueueLimits) -> Dispatcher {
+        let mut queues = Vec::with_capacity(shards.len());
+        let mut threads = Vec::with_capacity(shards.len());
+        for shard in shards {
+            let queue = PriorityQueue::new(limits);
+            let shard_queue = queue.clone();
+            let routing_node = routing_node.clone();
+            queues.push(queue);
+            threads.push(thread!("VaultShard", move || shard.run(routing_node, shard_queue)));
+        }
+        Dispatcher {
+            queues: queues,
+            threads: threads,
+            routing_node: routing_node.clone(),
+        }
+    }
+
+    fn dispatch(&self, event: Event) {
+        let priority = Self::priority(&event);
+        match event {
+            Event::Request(request) => {
+                let index = self.shard_index(&request.dst);
+                if priority == Priority::Client {
+                    let action = ShardAction::Event(Event::Request(request));
+                    if let Err(ShardAction::Event(Event::Request(request))) =
+                           self.queues[index].try_push(priority, action) {
+                        if let Err(error) = self.reject_busy(request) {
+                            warn!("Failed to refuse request: {:?}", error);
+                        }
+                    }
+                    return;
+                }
+                let is_refresh = priority == Priority::Churn;
+                self.send(index, priority, ShardAction::Event(Event::Request(request)));
+                if is_refresh {
+                    self.sync(Priority::Churn);
+                }
+            }
+            Event::Response(response) => {
+                let index = self.shard_index(&response.dst);
+                self.send(index, priority, ShardAction::Event(Event::Response(response)));
+            }
+            Event::NodeAdded(node_added) => {
+                self.broadcast(|| ShardAction::Event(Event::NodeAdded(node_added)));
+                self.sync(Priority::Churn);
+            }
+            Event::NodeLost(node_lost) => {
+                self.broadcast(|| ShardAction::Event(Event::NodeLost(node_lost)));
+                self.sync(Priority::Churn);
+            }
+            // The storage shard's PmidNode announces the chunks it holds.
+            Event::Connected => self.broadcast(|| ShardAction::Event(Event::Connected)),
+            event => self.send(0, priority, ShardAction::Event(event)),
+        }
+    }
+
+    // Sends an action to every shard, ahead of any messages they have queued.
+    fn broadcast<F: Fn() -> ShardAction>(&self, action: F) {
+        for index in 0..self.queues.len() {
+            self.send(index, Priority::Churn, action());
+        }
+    }
+
+    // Blocks until every shard has handled everything sent to it so far with at least the given
+    // priority.
+    fn sync(&self, priority: Priority) {
+        let (ack_sender, ack_receiver) = mpsc::channel();
+        for index in 0..self.queues.len() {
+            self.send(index, priority, ShardAction::Sync(ack_sender.clone()));
+        }
+        drop(ack_sender);
+        // Fails early only if a shard has died, dropping its acknowledgement sender.
+        for _ in 0..self.queues.len() {
+            if ack_receiver.recv().is_err() {
+                break;
+            }
+        }
+    }
+
+    // Closes the shards' queues and waits for them to finish, returning the shards.
+    fn stop(self) -> Vec<Shard> {
+        let Dispatcher { queues, threads, .. } = self;
+        for queue in &queues {
+            queue.close();
+        }
+        threads.into_iter()
+               .filter_map(|thread| {
+                   match thread.join() {
+                       Ok(shard) => Some(shard),
+                       Err(_) => {
+                           error!("A vault shard panicked");
+                           None
+                       }
+                   }
+               })
+               .collect()
+    }
+
+    fn send(&self, index: usize, priority: Priority, action: ShardAction) {
+        if self.queues[index].push(priority, action).is_err() {
+            error!("Vault shard {} has stopped", index);
+        }
+    }
+
+    // The shard handling messages for `dst`.  Managers hold state per group name, so it's the
+    // manager shard owning that name; our ManagedNode's requests go to the storage shard.
+    fn shard_index(&self, dst: &Authority) -> usize {
+        let manager_count = self.queues.len() - 1;
+        if let Authority::ManagedNode(_) = *dst {
+            return manager_count;
+        }
+        let name = dst.name();
+        ((name.0[0] as usize) << 8 | name.0[1] as usize) % manager_count
+    }
+
+    fn priority(event: &Event) -> Priority {
+        match *event {
+            Event::Request(ref request) => {
+                match (&request.src, &request.content) {
+                    (_, &RequestContent::Refresh(..)) => Priority::Churn,
+                    (&Authority::Client { .. }, _) => Priority::Client,
+                    _ => Priority::Node,
+                }
+            }
+            Event::Response(_) => Priority::Node,
+            _ => Priority::Churn,
+        }
+    }
+
+    // Tells the client that the vault is too busy to handle its request.
+    fn reject_busy(&self, request: RequestMessage) -> Result<(), InternalError> {
+        trace!("Vault busy, refusing {:?}", request);
+        utils::refuse(&self.routing_node, &request, Refusal::Busy)
+    }
+}
+
+// State kept for the vault as a whole, which every shard sees.
+#[derive(Clone)]
+struct SharedState {
+    full_pmid_nodes: Arc<RwLock<HashSet<XorName>>>,
+}
+
+impl SharedState {
+    fn new() -> SharedState {
+        SharedState { full_pmid_nodes: Arc::new(RwLock::new(HashSet::new())) }
+    }
+
+    fn context<'a>(&'a self,
+                   routing_node: &'a RoutingNode,
+                   client_rate_limits: &'a Mutex<ClientRateLimits>)
+                   -> Context<'a> {
+        Context::new(routing_node, &self.full_pmid_nodes, client_rate_limits)
+    }
+}
+
+// The personas run by one thread.  A manager shard handles its share of the names managed by the
+// vault, with that share of the managers' storage; the storage shard holds all the vault's chunks.
+struct Shard {
+    registry: Registry,
+    shared: SharedState,
+    // Kept per data name, so each manager shard only holds the allowances for its own names.
+    client_rate_limits: Mutex<ClientRateLimits>,
+    // How many shards split the storage allowance of the personas this one runs.
+    capacity_divisor: u64,
+    // Mutating requests handled recently, with the outcome of handling each.
+    seen_requests: TimedBuffer<(Authority, Authority, MessageId), Outcome>,
+}
+
+// What became of a mutating request, kept so that a retransmission of it gets the same answer.
+#[derive(Clone)]
+enum Outcome {
+    // Handled, with the success response it was sent, if any yet.  A response still to come is
+    // sent under the same message ID, so answers the retransmission too.
+    Handled(Option<Success>),
+    // Failed with the error described, as `InternalError` can't be cloned, and with the error the
+    // requester was sent, if it was told.
+    Failed(String, Option<Vec<u8>>),
+}
+
+impl Shard {
+    fn new(config: &Config,
+           role: ShardRole,
+           manager_count: usize,
+           shared: SharedState)
+           -> Result<Shard, InternalError> {
+        let mut registry = Registry::new();
+        if role.runs_managers() {
+            try!(Self::register_managers(&mut registry, config, manager_count));
+        }
+        if role.runs_storage() {
+            let max_capacity = config.max_capacity.unwrap_or(DEFAULT_MAX_CAPACITY);
+            let pn_capacity = personas::capacity_share(max_capacity, PMID_NODE_ALLOWANCE);
+            let io_thread_count = config.chunk_io_thread_count
+                                        .unwrap_or(DEFAULT_CHUNK_IO_THREAD_COUNT);
+            try!(registry.register(Box::new(try!(PmidNode::new(pn_capacity, io_thread_count))),
+                                   PMID_NODE_ALLOWANCE));
+        }
+        Ok(Shard {
+            registry: registry,
+            shared: shared,
+            client_rate_limits: Mutex::new(client_rate_limits(config)),
+            capacity_divisor: if role.runs_storage() {
+                1
+            } else {
+                manager_count as u64
+            },
+            seen_requests: TimedBuffer::with_capacity(Duration::seconds(SEEN_REQUEST_DURATION_SECS),
+                                                      MAX_SEEN_REQUESTS,
+                                                      EvictionPolicy::EvictOldest,
+                                                      Clock::system()),
+        })
+    }
+
+    // Registers the managers, giving them a `manager_count`th of the vault's budgets for them.
+    fn register_managers(registry: &mut Registry,
+                         config: &Config,
+                         manager_count: usize)
+                         -> Result<(), InternalError> {
+        let share = |value: u64| value / manager_count as u64;
+        let max_capacity = share(config.max_capacity.unwrap_or(DEFAULT_MAX_CAPACITY));
+        let sdm_capacity = personas::capacity_share(max_capacity,
+                                                    STUCTURED_DATA_MANAGER_ALLOWANCE);
+        let mpid_capacity = personas::capacity_share(max_capacity, MPID_MANAGER_ALLOWANCE);
+        let memory_budget = share(config.ongoing_put_memory_budget
+                                        .unwrap_or(DEFAULT_ONGOING_PUT_MEMORY_BUDGET));
+        let disk_budget = share(config.ongoing_put_disk_budget
+                                      .unwrap_or(DEFAULT_ONGOING_PUT_DISK_BUDGET));
+        let cache_budget = share(config.get_cache_memory_budget
+                                       .unwrap_or(DEFAULT_GET_CACHE_MEMORY_BUDGET));
+        let hedge_delay_ms = config.get_hedge_delay_ms.unwrap_or(DEFAULT_GET_HEDGE_DELAY_MS);
+        let hedge_delay = Duration::milliseconds(hedge_delay_ms as i64);
+        let challenge_interval_secs = config.storage_challenge_interval_secs
+                                            .unwrap_or(DEFAULT_STORAGE_CHALLENGE_INTERVAL_SECS);
+        let challenge_interval = Duration::seconds(challenge_interval_secs as i64);
+        let fragment_counts = config.erasure_data_fragments.and_then(|data_fragments| {
+            let parity_fragments = config.erasure_parity_fragments
+                                         .unwrap_or(DEFAULT_ERASURE_PARITY_FRAGMENTS);
+            FragmentCounts::new(data_fragments, parity_fragments)
+        });
+
+        try!(registry.register(Box::new(try!(ImmutableDataManager::new(memory_budget,
+                                                                       disk_budget,
+                                                                       cache_budget,
+                                                                       hedge_delay,
+                                                                       fragment_counts))),
+                               0.0));
+        try!(registry.register(Box::new(MaidManager::new(put_rate_limit(config),
+                                                         quota_unit(config))),
+                               0.0));
+        try!(registry.register(Box::new(try!(MpidManager::new(mpid_capacity))),
+                               MPID_MANAGER_ALLOWANCE));
+        try!(registry.register(Box::new(PmidManager::new(challenge_interval)), 0.0));
+        try!(registry.register(Box::new(ClientRateLimitRefresher), 0.0));
+        registry.register(Box::new(try!(StructuredDataManager::new(sdm_capacity))),
+                          STUCTURED_DATA_MANAGER_ALLOWANCE)
+    }
+
+    // The shard thread's loop.  Work queued by churn is done a slice at a time whenever nothing
+    // else is waiting.  Returns the shard once the dispatcher has gone.
+    #[cfg(not(feature = "use-mock-crust"))]
+    fn run(mut self, routing_node: RoutingNode, queue: PriorityQueue<ShardAction>) -> Shard {
+        loop {
+            let action = if self.registry.has_pending_churn() {
+                match queue.try_pop() {
+                    Ok(action) => action,
+                    Err(false) => {
+                        self.continue_churn(&routing_node);
+                        continue;
+                    }
+                    Err(true) => break,
+                }
+            } else {
+                match queue.pop() {
+                    Some(action) => action,
+                    None => break,
+                }
+            };
+
+            match action {
+                ShardAction::Event(event) => self.process_event(&routing_node, event),
+                ShardAction::Tick => self.tick(&routing_node),
+                ShardAction::SetMaxCapacity(max_capacity) => {
+                    if let Err(error) = self.set_max_capacity(&routing_node, max_capacity) {
+                        warn!("Failed to change capacity to {}: {:?}", max_capacity, error);
+                    }
+                }
+                ShardAction::Sync(ack_sender) => {
+                    let _ = ack_sender.send(());
+                }
+            }
+        }
+        self
+    }
+
+    fn set_max_capacity(&mut self,
+                        routing_node: &RoutingNode,
+                        max_capacity: u64)
+                        -> Result<(), InternalError> {
+        let mut context = self.shared.context(routing_node, &self.client_rate_limits);
+        self.registry.set_max_capacity(&mut context, max_capacity / self.capacity_divisor)
     }
 
     fn process_event(&mut self, routing_node: &RoutingNode, event: Event) {
-        trace!("Vault {} received an event from routing: {:?}",
-               unwrap_result!(routing_node.name()),
-               event);
+        if let Ok(our_name) = routing_node.name() {
+            trace!("Vault {} received an event from routing: {:?}", our_name, event);
+        }
 
         if let Err(error) = match event {
             Event::Request(request) => self.on_request(routing_node, request),
             Event::Response(response) => self.on_response(routing_node, response),
             Event::NodeAdded(node_added) => self.on_node_added(routing_node, node_added),
             Event::NodeLost(node_lost) => self.on_node_lost(routing_node, node_lost),
-            Event::Connected => self.on_connected(),
+            Event::Connected => self.on_connected(routing_node),
             Event::Disconnected => self.on_disconnected(),
         } {
             warn!("Failed to handle event: {:?}", error);
         }
+    }
+
+    // Expires the personas' timed-out operations, and records the success responses sent since
+    // for requests handled earlier.
+    fn tick(&mut self, routing_node: &RoutingNode) {
+        for key in self.seen_requests.get_expired() {
+            let _ = self.seen_requests.remove(&key);
+        }
+        let successes = {
+            let mut context = self.shared.context(routing_node, &self.client_rate_limits);
+            self.registry.handle_tick(&mut context);
+            context.successes
+        };
+        self.record_successes(successes);
+    }
 
-        self.immutable_data_manager.check_timeout(routing_node);
-        self.pmid_manager.check_timeout(routing_node);
+    // Does another slice of the work queued by churn.
+    fn continue_churn(&mut self, routing_node: &RoutingNode) {
+        let mut context = self.shared.context(routing_node, &self.client_rate_limits);
+        let _ = self.registry.continue_churn(&mut context);
     }
 
     fn on_request(&mut self,
                   routing_node: &RoutingNode,
                   request: RequestMessage)
                   -> Result<(), InternalError> {
-        match (&request.src, &request.dst, &request.content) {
-            // ================== Get ==================
-            (&Authority::Client { .. },
-             &Authority::NaeManager(_),
-             &RequestContent::Get(DataRequest::Immutable(_, _), _)) |
-            (&Authority::NaeManager(_),
-             &Authority::NaeManager(_),
-             &RequestContent::Get(DataRequest::Immutable(_, _), _)) => {
-                self.immutable_data_manager.handle_get(routing_node, &request)
-            }
-            (&Authority::Client { .. },
-             &Authority::NaeManager(_),
-             &RequestContent::Get(DataRequest::Structured(_, _), _)) => {
-                self.structured_data_manager.handle_get(routing_node, &request)
-            }
-            (&Authority::NaeManager(_),
-             &Authority::ManagedNode(_),
-             &RequestContent::Get(DataRequest::Immutable(_, _), _)) => {
-                self.pmid_node.handle_get(routing_node, &request)
-            }
-            // ================== Put ==================
-            (&Authority::Client { .. },
-             &Authority::ClientManager(_),
-             &RequestContent::Put(Data::Immutable(_), _)) |
-            (&Authority::Client { .. },
-             &Authority::ClientManager(_),
-             &RequestContent::Put(Data::Structured(_), _)) => {
-                self.maid_manager.handle_put(routing_node, &self.full_pmid_nodes, &request)
-            }
-            (&Authority::Client { .. },
-             &Authority::ClientManager(_),
-             &RequestContent::Put(Data::Plain(_), _)) |
-            (&Authority::ClientManager(_),
-             &Authority::ClientManager(_),
-             &RequestContent::Put(Data::Plain(_), _)) => {
-                self.mpid_manager.handle_put(routing_node, &request)
-            }
-            (&Authority::ClientManager(_),
-             &Authority::NaeManager(_),
-             &RequestContent::Put(Data::Immutable(_), _)) |
-            (&Authority::NaeManager(_),
-             &Authority::NaeManager(_),
-             &RequestContent::Put(Data::Immutable(_), _)) => {
-                self.immutable_data_manager
-                    .handle_put(routing_node, &self.full_pmid_nodes, &request)
-            }
-            (&Authority::ClientManager(_),
-             &Authority::NaeManager(_),
-             &RequestContent::Put(Data::Structured(_), _)) => {
-                self.structured_data_manager
-                    .handle_put(routing_node, &self.full_pmid_nodes, &request)
-            }
-            (&Authority::NaeManager(_),
-             &Authority::NodeManager(_),
-             &RequestContent::Put(Data::Immutable(_), _)) => {
-                self.pmid_manager.handle_put(routing_node, &request)
-            }
-            (&Authority::NodeManager(_),
-             &Authority::ManagedNode(_),
-             &RequestContent::Put(Data::Immutable(_), _)) => {
-                self.pmid_node.handle_put(routing_node, &request)
-            }
-            // ================== Post ==================
-            (&Authority::NaeManager(_),
-             &Authority::NodeManager(_),
-             &RequestContent::Post(_, _)) => self.pmid_manager.handle_post(&request),
-            (&Authority::Client { .. },
-             &Authority::NaeManager(_),
-             &RequestContent::Post(Data::Structured(_), _)) => {
-                self.structured_data_manager.handle_post(routing_node, &request)
-            }
-            (&Authority::Client { .. },
-             &Authority::ClientManager(_),
-             &RequestContent::Post(Data::Plain(_), _)) |
-            (&Authority::ClientManager(_),
-             &Authority::ClientManager(_),
-             &RequestContent::Post(Data::Plain(_), _)) => {
-                self.mpid_manager.handle_post(routing_node, &request)
-            }
-            // ================== Delete ==================
-            (&Authority::Client { .. },
-             &Authority::ClientManager(_),
-             &RequestContent::Delete(Data::Plain(_), _)) => {
-                self.mpid_manager.handle_delete(routing_node, &request)
-            }
-            (&Authority::Client { .. },
-             &Authority::NaeManager(_),
-             &RequestContent::Delete(Data::Structured(_), _)) => {
-                self.structured_data_manager.handle_delete(routing_node, &request)
-            }
-            // ================== Refresh ==================
-            (src, dst, &RequestContent::Refresh(ref serialised_refresh, _)) => {
-                self.on_refresh(src, dst, serialised_refresh)
-            }
-            // ================== Invalid Request ==================
-            _ => Err(InternalError::UnknownMessageType(RoutingMessage::Request(request.clone()))),
+        let message_id = match request.content {
+            RequestContent::Refresh(ref serialised_refresh, _) => {
+                return self.on_refresh(routing_node,
+                                       &request.src,
+                                       &request.dst,
+                                       serialised_refresh);
+            }
+            RequestContent::Put(_, message_id) |
+            RequestContent::Post(_, message_id) |
+            RequestContent::Delete(_, message_id) => message_id,
+            _ => {
+                let mut context = self.shared.context(routing_node, &self.client_rate_limits);
+                return self.registry.handle_request(&mut context, &request);
+            }
+        };
+
+        // A retransmitted mutation isn't applied again, e.g. charging the client twice, but gets
+        // the outcome of the first attempt, re-sending the response in case that was lost.
+        let key = (request.src.clone(), request.dst.clone(), message_id);
+        if let Some(outcome) = self.seen_requests.get_mut(&key) {
+            debug!("Answering retransmitted request {:?} with its earlier outcome", request);
+            return match *outcome {
+                Outcome::Handled(None) => Ok(()),
+                Outcome::Handled(Some(ref success)) => {
+                    let _ = success.clone().send(routing_node);
+                    Ok(())
+                }
+                Outcome::Failed(ref description, ref external_error_indicator) => {
+                    if let Some(ref indicator) = *external_error_indicator {
+                        try!(utils::send_failure(routing_node, &request, indicator.clone()));
+                    }
+                    Err(InternalError::PreviousFailure(description.clone()))
+                }
+            };
         }
+        let (result, successes) = {
+            let mut context = self.shared.context(routing_node, &self.client_rate_limits);
+            let result = self.registry.handle_request(&mut context, &request);
+            (result, context.successes)
+        };
+        let outcome = match result {
+            Ok(()) => Outcome::Handled(None),
+            Err(ref error) => {
+                Outcome::Failed(format!("{:?}", error), utils::external_error(&request, error))
+            }
+        };
+        let _ = self.seen_requests.insert(key, outcome);
+        self.record_successes(successes);
+        result
     }
 
     fn on_response(&mut self,
                    routing_node: &RoutingNode,
                    response: ResponseMessage)
                    -> Result<(), InternalError> {
-        match (&response.src, &response.dst, &response.content) {
-            // ================== GetSuccess ==================
-            (&Authority::ManagedNode(_),
-             &Authority::NaeManager(_),
-             &ResponseContent::GetSuccess(Data::Immutable(_), _)) |
-            (&Authority::NaeManager(_),
-             &Authority::NaeManager(_),
-             &ResponseContent::GetSuccess(Data::Immutable(_), _)) => {
-                self.immutable_data_manager.handle_get_success(routing_node, &response)
-            }
-            // ================== GetFailure ==================
-            (&Authority::ManagedNode(ref pmid_node),
-             &Authority::NaeManager(_),
-             &ResponseContent::GetFailure { ref id, ref request, ref external_error_indicator }) => {
-                self.immutable_data_manager
-                    .handle_get_failure(routing_node,
-                                        pmid_node,
-                                        id,
-                                        request,
-                                        external_error_indicator)
-            }
-            (&Authority::NaeManager(_),
-             &Authority::NaeManager(_),
-             &ResponseContent::GetFailure { ref request, .. }) => {
-                self.immutable_data_manager
-                    .handle_get_from_other_location_failure(routing_node, request)
-            }
-            // ================== PutSuccess ==================
-            (&Authority::NaeManager(_),
-             &Authority::ClientManager(_),
-             &ResponseContent::PutSuccess(ref name, ref message_id)) => {
-                self.maid_manager.handle_put_success(routing_node, name, message_id)
-            }
-            (&Authority::NodeManager(ref pmid_node),
-             &Authority::NaeManager(_),
-             &ResponseContent::PutSuccess(ref name, ref message_id)) => {
-                self.immutable_data_manager.handle_put_success(pmid_node, name, message_id)
-            }
-            (&Authority::ManagedNode(ref pmid_node),
-             &Authority::NodeManager(_),
-             &ResponseContent::PutSuccess(ref name, ref message_id)) => {
-                self.pmid_manager.handle_put_success(routing_node, pmid_node, name, message_id)
-            }
-            // ================== PutFailure ==================
-            (&Authority::NaeManager(_),
-             &Authority::ClientManager(_),
-             &ResponseContent::PutFailure{
-                    ref id,
-                    request: RequestMessage {
-                        content: RequestContent::Put(Data::Structured(_), _), .. },
-                    ref external_error_indicator }) => {
-                self.maid_manager.handle_put_failure(routing_node, id, external_error_indicator)
-            }
-            (&Authority::NodeManager(ref pmid_node),
-             &Authority::NaeManager(_),
-             &ResponseContent::PutFailure { ref id, .. }) => {
-                let _ = self.full_pmid_nodes.insert(*pmid_node);
-                self.immutable_data_manager.handle_put_failure(routing_node, pmid_node, id)
-            }
-            (&Authority::ManagedNode(_),
-             &Authority::NodeManager(_),
-             &ResponseContent::PutFailure { ref request, .. }) => {
-                self.pmid_manager.handle_put_failure(routing_node, request)
-            }
-            (&Authority::ClientManager(_),
-             &Authority::ClientManager(_),
-             &ResponseContent::PutFailure { ref request, .. }) => {
-                self.mpid_manager.handle_put_failure(routing_node, request)
-            }
-            // ================== Invalid Response ==================
-            _ => Err(InternalError::UnknownMessageType(RoutingMessage::Response(response.clone()))),
+        let (result, successes) = {
+            let mut context = self.shared.context(routing_node, &self.client_rate_limits);
+            let result = self.registry.handle_response(&mut context, &response);
+            (result, context.successes)
+        };
+        self.record_successes(successes);
+        result
+    }
+
+    // Keeps the success responses sent as the outcomes of the requests they answer, whether sent
+    // straight away or once the request was handled elsewhere.
+    fn record_successes(&mut self, successes: Vec<Success>) {
+        for success in successes {
+            let key = success.request_key();
+            let _ = self.seen_requests.insert(key, Outcome::Handled(Some(success)));
         }
     }
 
@@ -405,12 +856,8 @@ impl Vault {
                      routing_node: &RoutingNode,
                      node_added: XorName)
                      -> Result<(), InternalError> {
-        self.maid_manager.handle_churn(routing_node, &node_added);
-        self.immutable_data_manager.handle_node_added(routing_node, &node_added);
-        self.structured_data_manager.handle_churn(routing_node, &node_added);
-        self.pmid_manager.handle_churn(routing_node, &node_added);
-        self.pmid_node.handle_churn(routing_node);
-        self.mpid_manager.handle_churn(routing_node, &node_added);
+        let mut context = self.shared.context(routing_node, &self.client_rate_limits);
+        self.registry.handle_node_added(&mut context, &node_added);
         Ok(())
     }
 
@@ -418,19 +865,16 @@ impl Vault {
                     routing_node: &RoutingNode,
                     node_lost: XorName)
                     -> Result<(), InternalError> {
-        let _ = self.full_pmid_nodes.remove(&node_lost);
-        self.maid_manager.handle_churn(routing_node, &node_lost);
-        self.immutable_data_manager.handle_node_lost(routing_node, &node_lost);
-        self.structured_data_manager.handle_churn(routing_node, &node_lost);
-        self.pmid_manager.handle_churn(routing_node, &node_lost);
-        self.pmid_node.handle_churn(routing_node);
-        self.mpid_manager.handle_churn(routing_node, &node_lost);
+        let _ = unwrap_result!(self.shared.full_pmid_nodes.write()).remove(&node_lost);
+        let mut context = self.shared.context(routing_node, &self.client_rate_limits);
+        self.registry.handle_node_lost(&mut context, &node_lost);
         Ok(())
     }
 
-    fn on_connected(&self) -> Result<(), InternalError> {
-        // TODO: what is expected to be done here?
+    fn on_connected(&mut self, routing_node: &RoutingNode) -> Result<(), InternalError> {
         debug!("Vault connected");
+        let mut context = self.shared.context(routing_node, &self.client_rate_limits);
+        self.registry.handle_connected(&mut context);
         Ok(())
     }
 
@@ -441,41 +885,624 @@ impl Vault {
     }
 
     fn on_refresh(&mut self,
+                  routing_node: &RoutingNode,
                   src: &Authority,
                   dst: &Authority,
                   serialised_refresh: &[u8])
                   -> Result<(), InternalError> {
         let refresh = try!(serialisation::deserialise::<Refresh>(serialised_refresh));
-        match (src, dst, &refresh.value) {
-            (&Authority::ClientManager(_),
-             &Authority::ClientManager(_),
-             &RefreshValue::MaidManagerAccount(ref account)) => {
-                Ok(self.maid_manager.handle_refresh(refresh.name, account.clone()))
-            }
-            (&Authority::ClientManager(_),
-             &Authority::ClientManager(_),
-             &RefreshValue::MpidManagerAccount(ref account,
-                                               ref stored_messages,
-                                               ref received_headers)) => {
-                Ok(self.mpid_manager
-                       .handle_refresh(refresh.name, account, stored_messages, received_headers))
-            }
-            (&Authority::NaeManager(_),
-             &Authority::NaeManager(_),
-             &RefreshValue::ImmutableDataManagerAccount(ref account)) => {
-                Ok(self.immutable_data_manager.handle_refresh(refresh.name, account.clone()))
-            }
-            (&Authority::NaeManager(_),
-             &Authority::NaeManager(_),
-             &RefreshValue::StructuredDataManager(ref structured_data)) => {
-                self.structured_data_manager.handle_refresh(structured_data.clone())
-            }
-            (&Authority::NodeManager(_),
-             &Authority::NodeManager(_),
-             &RefreshValue::PmidManagerAccount(ref account)) => {
-                Ok(self.pmid_manager.handle_refresh(refresh.name, account.clone()))
-            }
-            _ => Err(InternalError::UnknownRefreshType(src.clone(), dst.clone(), refresh.clone())),
+        let mut context = self.shared.context(routing_node, &self.client_rate_limits);
+        self.registry.handle_refresh(&mut context, src, dst, &refresh)
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature="use-mock-crust"))]
+mod test {
+    use super::*;
+    use config_handler::Config;
+    use error::{InternalError, Refusal};
+    use maidsafe_utilities::serialisation;
+    use personas::maid_manager::Account;
+    use priority_queue::Priority;
+    use rand::{self, Rng};
+    use routing::{Authority, Data, DataRequest, Event, ImmutableData, ImmutableDataType,
+                  MessageId, PlainData, RequestContent, RequestMessage, ResponseContent,
+                  ResponseMessage, StructuredData};
+    use safe_network_common::client_errors::{GetError, MutationError};
+    use safe_network_common::messaging::MpidMessageWrapper;
+    use sodiumoxide::crypto::sign;
+    use std::sync::mpsc;
+    use types::{Refresh, RefreshValue};
+    use utils::{self, generate_random_vec_u8};
+    use xor_name::XorName;
+
+    const FUZZ_ITERATIONS: usize = 300;
+
+    // A shard running every persona, as under mock-crust.
+    fn new_shard() -> Shard {
+        let config = Config::default();
+        unwrap_result!(Shard::new(&config, ShardRole::All, 1, SharedState::new()))
+    }
+
+    // Names and message IDs are drawn from small pools so that responses sometimes match earlier
+    // requests and reach the deeper parts of the personas' handlers.
+    struct Fuzzer {
+        names: Vec<XorName>,
+        message_ids: Vec<MessageId>,
+        client_key: sign::PublicKey,
+    }
+
+    impl Fuzzer {
+        fn new(our_name: XorName) -> Fuzzer {
+            let mut names = (0..8).map(|_| rand::random()).collect::<Vec<XorName>>();
+            names.push(our_name);
+            Fuzzer {
+                names: names,
+                message_ids: (0..8).map(|_| MessageId::new()).collect(),
+                client_key: sign::gen_keypair().0,
+            }
+        }
+
+        fn name(&self) -> XorName {
+            *unwrap_option!(rand::thread_rng().choose(&self.names), "")
+        }
+
+        fn message_id(&self) -> MessageId {
+            *unwrap_option!(rand::thread_rng().choose(&self.message_ids), "")
+        }
+
+        fn authority(&self) -> Authority {
+            match rand::thread_rng().gen_range(0, 5) {
+                0 => {
+                    Authority::Client {
+                        client_key: self.client_key,
+                        peer_id: rand::random(),
+                        proxy_node_name: self.name(),
+                    }
+                }
+                1 => Authority::ClientManager(self.name()),
+                2 => Authority::NaeManager(self.name()),
+                3 => Authority::NodeManager(self.name()),
+                _ => Authority::ManagedNode(self.name()),
+            }
+        }
+
+        fn immutable_data_type(&self) -> ImmutableDataType {
+            match rand::thread_rng().gen_range(0, 3) {
+                0 => ImmutableDataType::Normal,
+                1 => ImmutableDataType::Backup,
+                _ => ImmutableDataType::Sacrificial,
+            }
+        }
+
+        fn payload(&self) -> Vec<u8> {
+            let wrapper = match rand::thread_rng().gen_range(0, 4) {
+                0 => MpidMessageWrapper::Online,
+                1 => MpidMessageWrapper::GetOutboxHeaders,
+                2 => MpidMessageWrapper::DeleteHeader(self.name()),
+                _ => return generate_random_vec_u8(rand::thread_rng().gen_range(0, 64)),
+            };
+            unwrap_result!(serialisation::serialise(&wrapper))
+        }
+
+        fn data(&self) -> Data {
+            match rand::thread_rng().gen_range(0, 3) {
+                0 => {
+                    Data::Immutable(ImmutableData::new(self.immutable_data_type(),
+                                                       generate_random_vec_u8(64)))
+                }
+                1 => {
+                    let type_tag = rand::thread_rng().gen_range(0, 3);
+                    Data::Structured(unwrap_result!(StructuredData::new(type_tag,
+                                                                        self.name(),
+                                                                        0,
+                                                                        vec![],
+                                                                        vec![self.client_key],
+                                                                        vec![],
+                                                                        None)))
+                }
+                _ => Data::Plain(PlainData::new(self.name(), self.payload())),
+            }
+        }
+
+        fn data_request(&self) -> DataRequest {
+            match rand::thread_rng().gen_range(0, 3) {
+                0 => DataRequest::Immutable(self.name(), self.immutable_data_type()),
+                1 => DataRequest::Structured(self.name(), rand::thread_rng().gen_range(0, 3)),
+                _ => DataRequest::Plain(self.name()),
+            }
+        }
+
+        fn refresh(&self) -> Vec<u8> {
+            if rand::random() {
+                let refresh = Refresh::new(&self.name(),
+                                           RefreshValue::MaidManagerAccount(Account::default()));
+                unwrap_result!(serialisation::serialise(&refresh))
+            } else {
+                generate_random_vec_u8(rand::thread_rng().gen_range(0, 64))
+            }
+        }
+
+        fn request(&self) -> RequestMessage {
+            let content = match rand::thread_rng().gen_range(0, 5) {
+                0 => RequestContent::Get(self.data_request(), self.message_id()),
+                1 => RequestContent::Put(self.data(), self.message_id()),
+                2 => RequestContent::Post(self.data(), self.message_id()),
+                3 => RequestContent::Delete(self.data(), self.message_id()),
+                _ => RequestContent::Refresh(self.refresh(), self.message_id()),
+            };
+            RequestMessage {
+                src: self.authority(),
+                dst: self.authority(),
+                content: content,
+            }
+        }
+
+        fn response(&self) -> ResponseMessage {
+            let id = self.message_id();
+            let content = match rand::thread_rng().gen_range(0, 8) {
+                0 => ResponseContent::GetSuccess(self.data(), id),
+                1 => {
+                    ResponseContent::GetFailure {
+                        id: id,
+                        request: self.request(),
+                        external_error_indicator: self.payload(),
+                    }
+                }
+                2 => ResponseContent::PutSuccess(self.name(), id),
+                3 => {
+                    ResponseContent::PutFailure {
+                        id: id,
+                        request: self.request(),
+                        external_error_indicator: self.payload(),
+                    }
+                }
+                4 => ResponseContent::PostSuccess(self.name(), id),
+                5 => {
+                    ResponseContent::PostFailure {
+                        id: id,
+                        request: self.request(),
+                        external_error_indicator: self.payload(),
+                    }
+                }
+                6 => ResponseContent::DeleteSuccess(self.name(), id),
+                _ => {
+                    ResponseContent::DeleteFailure {
+                        id: id,
+                        request: self.request(),
+                        external_error_indicator: self.payload(),
+                    }
+                }
+            };
+            ResponseMessage {
+                src: self.authority(),
+                dst: self.authority(),
+                content: content,
+            }
+        }
+    }
+
+    #[test]
+    fn random_messages_do_not_panic() {
+        let mut shard = new_shard();
+        let (event_sender, event_receiver) = mpsc::channel();
+        let routing_node = unwrap_result!(RoutingNode::new(event_sender, false));
+        let fuzzer = Fuzzer::new(unwrap_result!(routing_node.name()));
+
+        for _ in 0..FUZZ_ITERATIONS {
+            let event = if rand::random() {
+                Event::Request(fuzzer.request())
+            } else {
+                Event::Response(fuzzer.response())
+            };
+            shard.process_event(&routing_node, event);
+
+            // Also feed back whatever the vault has sent so far, as these are well-formed messages
+            // which are then combined with the random ones above.
+            while let Ok(event) = event_receiver.try_recv() {
+                shard.process_event(&routing_node, event);
+            }
+        }
+    }
+
+    #[test]
+    fn routes_are_unique() {
+        // Registration fails if two personas claim the same route.
+        let _ = unwrap_result!(super::init_components(Some(Config::default())));
+    }
+
+    #[test]
+    fn unknown_route() {
+        let mut shard = new_shard();
+        let routing_node = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
+        let request = RequestMessage {
+            src: Authority::ManagedNode(rand::random()),
+            dst: Authority::ManagedNode(rand::random()),
+            content: RequestContent::Put(Data::Plain(PlainData::new(rand::random(), vec![])),
+                                         MessageId::new()),
+        };
+        match shard.on_request(&routing_node, request) {
+            Err(InternalError::UnknownMessageType(_)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert!(routing_node.put_failures_given().is_empty());
+    }
+
+    // A shard whose structured data manager holds a client's data.
+    struct StructuredDataEnvironment {
+        shard: Shard,
+        routing_node: RoutingNode,
+        client: Authority,
+        keys: (sign::PublicKey, sign::SecretKey),
+        data: StructuredData,
+    }
+
+    impl StructuredDataEnvironment {
+        fn new() -> StructuredDataEnvironment {
+            let mut shard = new_shard();
+            let routing_node = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
+            let keys = sign::gen_keypair();
+            let client = Authority::Client {
+                client_key: keys.0,
+                peer_id: rand::random(),
+                proxy_node_name: rand::random(),
+            };
+            let data;
+            loop {
+                let candidate = unwrap_result!(StructuredData::new(0,
+                                                                   rand::random(),
+                                                                   0,
+                                                                   generate_random_vec_u8(64),
+                                                                   vec![keys.0],
+                                                                   vec![],
+                                                                   Some(&keys.1)));
+                if let Ok(Some(_)) = routing_node.close_group(candidate.name()) {
+                    data = candidate;
+                    break;
+                }
+            }
+
+            let client_name = unwrap_result!(utils::client_name(&client));
+            let put = RequestMessage {
+                src: Authority::ClientManager(client_name),
+                dst: Authority::NaeManager(data.name()),
+                content: RequestContent::Put(Data::Structured(data.clone()), MessageId::new()),
+            };
+            unwrap_result!(shard.on_request(&routing_node, put));
+            assert_eq!(1, routing_node.put_successes_given().len());
+
+            StructuredDataEnvironment {
+                shard: shard,
+                routing_node: routing_node,
+                client: client,
+                keys: keys,
+                data: data,
+            }
+        }
+
+        fn version(&self, version: u64) -> Data {
+            Data::Structured(unwrap_result!(StructuredData::new(0,
+                                                                *self.data.get_identifier(),
+                                                                version,
+                                                                self.data.get_data().clone(),
+                                                                vec![self.keys.0],
+                                                                vec![],
+                                                                Some(&self.keys.1))))
+        }
+
+        fn request(&self, content: RequestContent) -> RequestMessage {
+            RequestMessage {
+                src: self.client.clone(),
+                dst: Authority::NaeManager(self.data.name()),
+                content: content,
+            }
+        }
+    }
+
+    #[test]
+    fn retransmitted_put() {
+        let mut env = StructuredDataEnvironment::new();
+        let client_name = unwrap_result!(utils::client_name(&env.client));
+        let account_request = |env: &StructuredDataEnvironment, type_tag| {
+            let data = unwrap_result!(StructuredData::new(type_tag,
+                                                          rand::random(),
+                                                          0,
+                                                          vec![],
+                                                          vec![env.keys.0],
+                                                          vec![],
+                                                          None));
+            RequestMessage {
+                src: env.client.clone(),
+                dst: Authority::ClientManager(client_name),
+                content: RequestContent::Put(Data::Structured(data), MessageId::new()),
+            }
+        };
+
+        // Putting data without an account fails, and so does its retransmission, which is sent the
+        // same error again.
+        let put = account_request(&env, 1);
+        match env.shard.on_request(&env.routing_node, put.clone()) {
+            Err(InternalError::ClientMutation(MutationError::NoSuchAccount)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        match env.shard.on_request(&env.routing_node, put) {
+            Err(InternalError::PreviousFailure(_)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        let put_failures = env.routing_node.put_failures_given();
+        assert_eq!(2, put_failures.len());
+        assert_eq!(put_failures[0].content, put_failures[1].content);
+        assert_eq!(put_failures[0].dst, put_failures[1].dst);
+
+        // Handled again, the account creation would fail as the account already exists.
+        let put_requests = env.routing_node.put_requests_given().len();
+        let put = account_request(&env, 0);
+        unwrap_result!(env.shard.on_request(&env.routing_node, put.clone()));
+        unwrap_result!(env.shard.on_request(&env.routing_node, put.clone()));
+        assert_eq!(put_requests + 1, env.routing_node.put_requests_given().len());
+        assert_eq!(2, env.routing_node.put_failures_given().len());
+
+        // Once the data's managers confirm the put, the client is sent success, and so is any
+        // later retransmission.
+        let (data_name, message_id) = if let RequestContent::Put(ref data, message_id) =
+                                             put.content {
+            (data.name(), message_id)
+        } else {
+            unreachable!()
+        };
+        let put_successes = env.routing_node.put_successes_given().len();
+        let response = ResponseMessage {
+            src: Authority::NaeManager(data_name),
+            dst: Authority::ClientManager(client_name),
+            content: ResponseContent::PutSuccess(data_name, message_id),
+        };
+        unwrap_result!(env.shard.on_response(&env.routing_node, response));
+        unwrap_result!(env.shard.on_request(&env.routing_node, put));
+        let put_successes_given = env.routing_node.put_successes_given();
+        assert_eq!(put_successes + 2, put_successes_given.len());
+        let resent = &put_successes_given[put_successes..];
+        assert_eq!(resent[0].src, Authority::ClientManager(client_name));
+        assert_eq!(resent[0].dst, env.client);
+        assert_eq!(resent[0].content, resent[1].content);
+        assert_eq!(resent[0].dst, resent[1].dst);
+        assert_eq!(put_requests + 1, env.routing_node.put_requests_given().len());
+    }
+
+    #[test]
+    fn retransmitted_post() {
+        let mut env = StructuredDataEnvironment::new();
+        let post = env.request(RequestContent::Post(env.version(1), MessageId::new()));
+        unwrap_result!(env.shard.on_request(&env.routing_node, post.clone()));
+        unwrap_result!(env.shard.on_request(&env.routing_node, post));
+        // Handled again, the post would fail as it no longer holds a newer version.  Instead the
+        // retransmission is sent the same success.
+        let post_successes = env.routing_node.post_successes_given();
+        assert_eq!(2, post_successes.len());
+        assert_eq!(post_successes[0].content, post_successes[1].content);
+        assert_eq!(post_successes[0].dst, post_successes[1].dst);
+        assert!(env.routing_node.post_failures_given().is_empty());
+
+        // The same post sent afresh is handled.
+        let post = env.request(RequestContent::Post(env.version(1), MessageId::new()));
+        unwrap_result!(env.shard.on_request(&env.routing_node, post));
+        assert_eq!(1, env.routing_node.post_failures_given().len());
+    }
+
+    #[test]
+    fn retransmitted_delete() {
+        let mut env = StructuredDataEnvironment::new();
+        let delete = env.request(RequestContent::Delete(env.version(1), MessageId::new()));
+        unwrap_result!(env.shard.on_request(&env.routing_node, delete.clone()));
+        unwrap_result!(env.shard.on_request(&env.routing_node, delete));
+        // Handled again, the delete would fail as the data is gone.  Instead the retransmission is
+        // sent the same success.
+        let delete_successes = env.routing_node.delete_successes_given();
+        assert_eq!(2, delete_successes.len());
+        assert_eq!(delete_successes[0].content, delete_successes[1].content);
+        assert_eq!(delete_successes[0].dst, delete_successes[1].dst);
+        assert!(env.routing_node.delete_failures_given().is_empty());
+    }
+
+    #[test]
+    fn sharded_puts() {
+        let mut config = Config::default();
+        config.shard_count = Some(4);
+        // Chunks are written inline, so that they're all confirmed once the shards are synced.
+        config.chunk_io_thread_count = Some(0);
+        let (shards, config) = unwrap_result!(super::init_components(Some(config)));
+        let routing_node = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
+        let our_name = unwrap_result!(routing_node.name());
+        let dispatcher = Dispatcher::new(shards, &routing_node, queue_limits(&config));
+
+        const PUTS: usize = 20;
+        for _ in 0..PUTS {
+            let data = ImmutableData::new(ImmutableDataType::Normal, generate_random_vec_u8(64));
+            dispatcher.dispatch(Event::Request(RequestMessage {
+                src: Authority::NodeManager(our_name),
+                dst: Authority::ManagedNode(our_name),
+                content: RequestContent::Put(Data::Immutable(data), MessageId::new()),
+            }));
+        }
+        dispatcher.sync(Priority::Node);
+
+        assert_eq!(PUTS, routing_node.put_successes_given().len());
+        assert!(routing_node.put_failures_given().is_empty());
+        // Four manager shards and the storage shard.
+        let shards = dispatcher.stop();
+        assert_eq!(5, shards.len());
+        // A node found to be full by one shard's managers is avoided by the others'.
+        let full_node = rand::random();
+        let _ = unwrap_result!(shards[0].shared.full_pmid_nodes.write()).insert(full_node);
+        for shard in &shards {
+            assert!(unwrap_result!(shard.shared.full_pmid_nodes.read()).contains(&full_node));
+        }
+    }
+
+    #[test]
+    fn busy_client_requests_refused() {
+        let mut config = Config::default();
+        config.client_queue_limit = Some(0);
+        config.chunk_io_thread_count = Some(0);
+        let (shards, config) = unwrap_result!(super::init_components(Some(config)));
+        let routing_node = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
+        let our_name = unwrap_result!(routing_node.name());
+        let dispatcher = Dispatcher::new(shards, &routing_node, queue_limits(&config));
+
+        let client = Authority::Client {
+            client_key: sign::gen_keypair().0,
+            peer_id: rand::random(),
+            proxy_node_name: rand::random(),
+        };
+        let data = ImmutableData::new(ImmutableDataType::Normal, generate_random_vec_u8(64));
+        dispatcher.dispatch(Event::Request(RequestMessage {
+            src: client.clone(),
+            dst: Authority::ClientManager(rand::random()),
+            content: RequestContent::Put(Data::Immutable(data.clone()), MessageId::new()),
+        }));
+        dispatcher.dispatch(Event::Request(RequestMessage {
+            src: client,
+            dst: Authority::NaeManager(data.name()),
+            content: RequestContent::Get(DataRequest::Immutable(data.name(),
+                                                                ImmutableDataType::Normal),
+                                         MessageId::new()),
+        }));
+        // Requests from other nodes are still queued.
+        dispatcher.dispatch(Event::Request(RequestMessage {
+            src: Authority::NodeManager(our_name),
+            dst: Authority::ManagedNode(our_name),
+            content: RequestContent::Put(Data::Immutable(data), MessageId::new()),
+        }));
+        dispatcher.sync(Priority::Node);
+        let _ = dispatcher.stop();
+
+        let put_failures = routing_node.put_failures_given();
+        assert_eq!(1, put_failures.len());
+        if let ResponseContent::PutFailure { ref external_error_indicator, .. } =
+               put_failures[0].content {
+            match unwrap_result!(serialisation::deserialise(external_error_indicator)) {
+                MutationError::NetworkOther(ref reason) => {
+                    assert_eq!(Some(Refusal::Busy), Refusal::from_description(reason))
+                }
+                error => panic!("Unexpected error {:?}", error),
+            }
+        } else {
+            panic!("Unexpected response {:?}", put_failures[0]);
+        }
+
+        let get_failures = routing_node.get_failures_given();
+        assert_eq!(1, get_failures.len());
+        if let ResponseContent::GetFailure { ref external_error_indicator, .. } =
+               get_failures[0].content {
+            match unwrap_result!(serialisation::deserialise(external_error_indicator)) {
+                GetError::NetworkOther(ref reason) => {
+                    assert_eq!(Some(Refusal::Busy), Refusal::from_description(reason))
+                }
+                error => panic!("Unexpected error {:?}", error),
+            }
+        } else {
+            panic!("Unexpected response {:?}", get_failures[0]);
+        }
+
+        assert_eq!(1, routing_node.put_successes_given().len());
+    }
+
+    #[test]
+    fn ticks_without_events() {
+        let (routing_sender, routing_receiver) = mpsc::channel();
+        let (action_sender, action_receiver) = mpsc::channel();
+        let _forwarder = Vault::forward_events(routing_receiver, action_sender.clone());
+        let _ticker = Vault::send_ticks(action_sender);
+
+        match unwrap_result!(action_receiver.recv()) {
+            Action::Tick => (),
+            _ => panic!("Expected a tick"),
         }
+
+        drop(routing_sender);
+        loop {
+            match unwrap_result!(action_receiver.recv()) {
+                Action::Terminate => break,
+                Action::Tick => (),
+                Action::Routing(event) => panic!("Unexpected event {:?}", event),
+            }
+        }
+        drop(action_receiver);
+    }
+}
+
+#[cfg(all(test, feature = "bench", not(feature = "use-mock-crust")))]
+mod bench {
+    use super::*;
+    use config_handler::Config;
+    use priority_queue::Priority;
+    use rand;
+    use routing::{Authority, Data, DataRequest, Event, MessageId, RequestContent, RequestMessage,
+                  StructuredData};
+    use sodiumoxide::crypto::sign;
+    use std::sync::mpsc;
+    use test::Bencher;
+    use utils::{self, generate_random_vec_u8};
+
+    const CHUNKS: usize = 100;
+
+    // Stores a batch of structured data with the vault's managers, then repeatedly has a client
+    // fetch all of it and waits for every Get to be answered.
+    fn get_chunks(b: &mut Bencher, shard_count: usize) {
+        let mut config = Config::default();
+        config.shard_count = Some(shard_count);
+        let (shards, config) = unwrap_result!(super::init_components(Some(config)));
+        let routing_node = unwrap_result!(RoutingNode::new(mpsc::channel().0, false));
+        let dispatcher = Dispatcher::new(shards, &routing_node, queue_limits(&config));
+
+        let keys = sign::gen_keypair();
+        let client = Authority::Client {
+            client_key: keys.0,
+            peer_id: rand::random(),
+            proxy_node_name: rand::random(),
+        };
+        let client_manager = Authority::ClientManager(unwrap_result!(utils::client_name(&client)));
+        let mut names = Vec::with_capacity(CHUNKS);
+        while names.len() < CHUNKS {
+            let data = unwrap_result!(StructuredData::new(0,
+                                                          rand::random(),
+                                                          0,
+                                                          generate_random_vec_u8(1024),
+                                                          vec![keys.0],
+                                                          vec![],
+                                                          Some(&keys.1)));
+            if let Ok(Some(_)) = routing_node.close_group(data.name()) {
+                names.push(data.name());
+                dispatcher.dispatch(Event::Request(RequestMessage {
+                    src: client_manager.clone(),
+                    dst: Authority::NaeManager(data.name()),
+                    content: RequestContent::Put(Data::Structured(data), MessageId::new()),
+                }));
+            }
+        }
+        dispatcher.sync(Priority::Node);
+
+        b.iter(|| {
+            for name in &names {
+                dispatcher.dispatch(Event::Request(RequestMessage {
+                    src: client.clone(),
+                    dst: Authority::NaeManager(*name),
+                    content: RequestContent::Get(DataRequest::Structured(*name, 0),
+                                                 MessageId::new()),
+                }));
+            }
+            dispatcher.sync(Priority::Client);
+        });
+        let _ = dispatcher.stop();
+    }
+
+    #[bench]
+    fn get_chunks_one_shard(b: &mut Bencher) {
+        get_chunks(b, 1);
+    }
+
+    #[bench]
+    fn get_chunks_four_shards(b: &mut Bencher) {
+        get_chunks(b, 4);
     }
 }
@@ -49,6 +49,7 @@ mod test {
     use rand::distributions::{IndependentSample, Range};
     use routing::{self, Data, DataRequest, ImmutableData, ImmutableDataType, StructuredData};
     use routing::mock_crust::{self, Network};
+    use safe_vault::Config;
     use sodiumoxide::crypto::sign;
     use xor_name::XorName;
 
@@ -295,6 +296,42 @@ mod test {
         //}
     }
 
+    #[test]
+    fn hedged_gets_accumulate() {
+        // Hedging is left too slow to kick in while polling, so each Get is only answered if the
+        // whole group asks the same holder first, whatever each member has seen of the holders.
+        let mut config = Config::default();
+        config.get_hedge_delay_ms = Some(60_000);
+        let network = Network::new();
+        let mut nodes = test_node::create_nodes(&network, 2 * 8, Some(config));
+        let crust_config = mock_crust::Config::with_contacts(&[nodes[0].endpoint()]);
+        let mut client = test_client::TestClient::new(&network, Some(crust_config));
+
+        client.ensure_connected(&mut nodes);
+        client.create_account(&mut nodes);
+
+        let mut rng = thread_rng();
+        let range = Range::new(128, 1024);
+        let mut all_immutable_data = Vec::new();
+        for _ in 0..10 {
+            let content = mock_crust_detail::generate_random_vec_u8(range.ind_sample(&mut rng));
+            let immutable_data = ImmutableData::new(ImmutableDataType::Normal, content);
+            client.put(Data::Immutable(immutable_data.clone()), &mut nodes);
+            all_immutable_data.push(immutable_data);
+        }
+        for immutable_data in &all_immutable_data {
+            match client.get(DataRequest::Immutable(immutable_data.name(),
+                                                    ImmutableDataType::Normal),
+                             &mut nodes) {
+                Data::Immutable(recovered_immutable_data) => {
+                    assert_eq!(recovered_immutable_data.name(), immutable_data.name());
+                    assert!(recovered_immutable_data.value() == immutable_data.value());
+                }
+                data => panic!("Got unexpected data: {:?}", data),
+            }
+        }
+    }
+
     #[test]
     fn put_get_when_churn() {
         let network = Network::new();